@@ -0,0 +1,176 @@
+// The sharkmon dashboard: the current readings in large type, and a chart of
+// one reading, live from /ws or over a longer range from /history.
+
+const config = JSON.parse(document.getElementById("config").textContent);
+const LIVE_WINDOW_MS = 10 * 60 * 1000;
+// Roughly how many points a history chart asks for.
+const HISTORY_POINTS = 600;
+
+const state = {
+  device: config.devices[0],
+  reading: "watts",
+  range: "live",
+  points: [],
+};
+
+const deviceSelect = document.getElementById("device");
+const readingSelect = document.getElementById("reading");
+const message = document.getElementById("message");
+
+for (const name of config.devices) {
+  deviceSelect.add(new Option(name, name));
+}
+deviceSelect.hidden = config.devices.length < 2;
+deviceSelect.addEventListener("change", () => {
+  state.device = deviceSelect.value;
+  connect();
+  load();
+});
+readingSelect.addEventListener("change", () => {
+  state.reading = readingSelect.value;
+  draw();
+});
+for (const button of document.querySelectorAll("button[data-range]")) {
+  button.disabled = !config.history && button.dataset.range !== "live";
+  button.addEventListener("click", () => {
+    document.querySelector("button.active").classList.remove("active");
+    button.classList.add("active");
+    state.range = button.dataset.range;
+    load();
+  });
+}
+if (!config.history) {
+  message.textContent = "start sharkmon with --history for longer charts";
+}
+
+function show(sample) {
+  const fixed = { watts: 1, volts: 2, frequency: 2 };
+  for (const [name, digits] of Object.entries(fixed)) {
+    if (name in sample) {
+      document.getElementById(name).textContent = Number(sample[name]).toFixed(digits);
+    }
+  }
+  const names = Object.keys(sample).filter((k) => k !== "time" && k !== "device");
+  if (readingSelect.options.length !== names.length) {
+    readingSelect.replaceChildren(...names.map((n) => new Option(n, n)));
+    if (!names.includes(state.reading)) state.reading = names[0];
+    readingSelect.value = state.reading;
+  }
+}
+
+// Live samples, over a WebSocket that is reopened when it drops.
+let socket = null;
+let retry = 1000;
+function connect() {
+  if (socket) {
+    socket.onclose = null;
+    socket.close();
+  }
+  const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+  const url = `${scheme}//${location.host}/ws?device=${encodeURIComponent(state.device)}`;
+  socket = new WebSocket(url);
+  socket.onopen = () => (retry = 1000);
+  socket.onmessage = (event) => {
+    const sample = JSON.parse(event.data);
+    show(sample);
+    if (state.range === "live") {
+      state.points.push(sample);
+      const cutoff = Date.parse(sample.time) - LIVE_WINDOW_MS;
+      while (state.points.length && Date.parse(state.points[0].time) < cutoff) {
+        state.points.shift();
+      }
+      draw();
+    }
+  };
+  socket.onclose = () => {
+    setTimeout(connect, retry);
+    retry = Math.min(retry * 2, 30000);
+  };
+}
+
+const RANGES_MS = { live: LIVE_WINDOW_MS, "1h": 3600e3, "24h": 86400e3, "7d": 7 * 86400e3 };
+
+// Load the chart's range from /history, summarised to about HISTORY_POINTS.
+async function load() {
+  state.points = [];
+  draw();
+  if (!config.history) return;
+  const span = RANGES_MS[state.range];
+  const from = Math.round(span / 1000) + "s";
+  let url = `/history/${encodeURIComponent(state.device)}?from=${from}`;
+  if (state.range !== "live") {
+    url += `&step=${Math.max(1, Math.round(span / HISTORY_POINTS / 1000))}s`;
+  }
+  try {
+    const response = await fetch(url);
+    if (!response.ok) throw new Error(await response.text());
+    state.points = await response.json();
+    message.textContent = "";
+  } catch (e) {
+    message.textContent = `could not load history: ${e.message}`;
+  }
+  draw();
+}
+
+function draw() {
+  const canvas = document.getElementById("chart");
+  const scale = window.devicePixelRatio || 1;
+  canvas.width = canvas.clientWidth * scale;
+  canvas.height = canvas.clientHeight * scale;
+  const ctx = canvas.getContext("2d");
+  ctx.scale(scale, scale);
+  const width = canvas.clientWidth;
+  const height = canvas.clientHeight;
+  ctx.clearRect(0, 0, width, height);
+
+  const points = state.points
+    .filter((p) => typeof p[state.reading] === "number")
+    .map((p) => [Date.parse(p.time), p[state.reading]]);
+  if (points.length < 2) return;
+  const end = state.range === "live" ? points[points.length - 1][0] : Date.now();
+  const start = end - RANGES_MS[state.range];
+  let low = Math.min(...points.map((p) => p[1]));
+  let high = Math.max(...points.map((p) => p[1]));
+  if (high === low) {
+    high += 1;
+    low -= 1;
+  }
+  const margin = { left: 70, right: 10, top: 10, bottom: 25 };
+  const x = (t) => margin.left + ((t - start) / (end - start)) * (width - margin.left - margin.right);
+  const y = (v) => height - margin.bottom - ((v - low) / (high - low)) * (height - margin.top - margin.bottom);
+
+  ctx.font = "12px sans-serif";
+  ctx.fillStyle = "#080";
+  ctx.strokeStyle = "#030";
+  for (let i = 0; i <= 4; i++) {
+    const v = low + ((high - low) * i) / 4;
+    ctx.beginPath();
+    ctx.moveTo(margin.left, y(v));
+    ctx.lineTo(width - margin.right, y(v));
+    ctx.stroke();
+    ctx.fillText(v.toPrecision(5), 5, y(v) + 4);
+  }
+  for (let i = 0; i <= 4; i++) {
+    const t = start + ((end - start) * i) / 4;
+    const options = { timeZone: config.timezone };
+    const label =
+      state.range === "7d"
+        ? new Date(t).toLocaleDateString(undefined, options)
+        : new Date(t).toLocaleTimeString(undefined, options);
+    ctx.fillText(label, Math.min(x(t), width - 70), height - 8);
+  }
+
+  ctx.strokeStyle = "#0c0";
+  ctx.lineWidth = 1.5;
+  ctx.beginPath();
+  points.forEach(([t, v], i) => (i ? ctx.lineTo(x(t), y(v)) : ctx.moveTo(x(t), y(v))));
+  ctx.stroke();
+}
+
+window.addEventListener("resize", draw);
+fetch(`/power/${encodeURIComponent(state.device)}`)
+  .then((r) => r.json())
+  .then(show)
+  .catch(() => {});
+connect();
+load();
@@ -11,5 +11,14 @@ fn main() -> std::io::Result<()> {
 
     std::fs::write(out_dir.join("sharkmon.1"), buffer)?;
 
+    // The types served at /power.pb, compiled without needing protoc.
+    #[cfg(feature = "web")]
+    {
+        println!("cargo:rerun-if-changed=proto/sharkmon.proto");
+        let descriptors =
+            protox::compile(["sharkmon.proto"], ["proto"]).map_err(std::io::Error::other)?;
+        prost_build::Config::new().compile_fds(descriptors)?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,41 @@
+//! A block read as a gateway might return it, decoded in every format and
+//! scale: decoding never panics, and a value it returns is a reading.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sharkmon::registers::{Block, BlockData, Format, Function, Register};
+
+const FORMATS: [Format; 8] = [
+    Format::FloatBe,
+    Format::FloatSwapped,
+    Format::Int16,
+    Format::Uint16,
+    Format::Int32,
+    Format::Uint32,
+    Format::Int32Swapped,
+    Format::Uint32Swapped,
+];
+
+fuzz_target!(|input: (u16, u16, Vec<u16>, f64, u16)| {
+    let (start, len, registers, scale, address) = input;
+    let block = Block {
+        function: Function::Holding,
+        start,
+        len,
+    };
+    let mut data = BlockData::default();
+    if data.push(block, registers).is_err() {
+        return;
+    }
+    for format in FORMATS {
+        let register = Register {
+            address,
+            format,
+            scale,
+        };
+        if let Ok(value) = register.decode(&data) {
+            assert!((value as f32).is_finite(), "{format} decoded {value}");
+        }
+    }
+});
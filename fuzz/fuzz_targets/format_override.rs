@@ -0,0 +1,19 @@
+//! The register formats, `NAME=FORMAT[:SCALE]` overrides and register ranges
+//! given on the command line.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sharkmon::registers::{Format, FormatOverride, RegisterRange};
+
+fuzz_target!(|text: &str| {
+    if let Ok(format) = text.parse::<Format>() {
+        assert_eq!(format.to_string(), text);
+    }
+    if let Ok(o) = text.parse::<FormatOverride>() {
+        assert!(o.scale.is_finite(), "{o:?}");
+    }
+    if let Ok(range) = text.parse::<RegisterRange>() {
+        assert!(range.start <= range.end, "{range:?}");
+    }
+});
@@ -0,0 +1,23 @@
+//! Register map files: a map that loads plans block reads that cover its
+//! metrics within the Modbus limits, and decodes from them.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sharkmon::registers::{BlockData, RegisterMap, MAX_BLOCK_LEN};
+
+fuzz_target!(|input: (&str, u16)| {
+    let (text, read_gap) = input;
+    let Ok(map) = RegisterMap::parse(text) else {
+        return;
+    };
+    for group in map.poll_groups(read_gap) {
+        let mut data = BlockData::default();
+        for block in &group.blocks {
+            assert!(block.len <= MAX_BLOCK_LEN, "{block:?}");
+            data.push(*block, vec![0; block.len as usize]).unwrap();
+        }
+        let values = map.decode(&group, &data).unwrap();
+        assert_eq!(values.len(), group.metrics.len());
+    }
+});
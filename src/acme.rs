@@ -0,0 +1,646 @@
+//! Certificates for the web server from Let's Encrypt or another ACME CA
+//! (RFC 8555). The certificate and its key are kept in the `cert` and `key`
+//! files of `[web_tls]`, obtained when they are missing, don't cover the
+//! configured domains or are within `RENEW_BEFORE` of expiring, and swapped
+//! into the running server. The CA checks control of each domain with an
+//! HTTP-01 token served on port 80, or a DNS-01 TXT record published by a
+//! hook.
+
+use crate::config::{AcmeConfig, WebTlsConfig};
+use crate::tls::WebTls;
+use base64::engine::{general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::rand::SystemRandom;
+use ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tracing::{error, info, warn};
+
+/// How long before it expires a certificate is renewed. Let's Encrypt's
+/// last 90 days.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// How often the certificate is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// How long after a failure to try again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often a pending authorization or order is checked on.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many times it is checked before giving up.
+const POLL_ATTEMPTS: u32 = 60;
+
+/// The key authorization for each HTTP-01 token being checked.
+type Tokens = Arc<Mutex<HashMap<String, String>>>;
+
+/// Keep `config`'s certificate current, serving HTTP-01 tokens on
+/// `http_listen` unless DNS-01 is used.
+pub fn start(config: &WebTlsConfig, tls: WebTls) -> std::io::Result<()> {
+    let Some(acme) = config.acme.clone() else {
+        return Ok(());
+    };
+    let tokens = Tokens::default();
+    if acme.dns_hook.is_none() {
+        let listener = std::net::TcpListener::bind(acme.http_listen).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!("ACME HTTP-01 on {}: {e}", acme.http_listen),
+            )
+        })?;
+        listener.set_nonblocking(true)?;
+        let tokens = tokens.clone();
+        let app = axum::Router::new().route(
+            "/.well-known/acme-challenge/:token",
+            axum::routing::get(
+                move |axum::extract::Path(token): axum::extract::Path<String>| {
+                    let answer = tokens.lock().unwrap().get(&token).cloned();
+                    async move { answer.ok_or(axum::http::StatusCode::NOT_FOUND) }
+                },
+            ),
+        );
+        let server = axum::Server::from_tcp(listener)
+            .map_err(Error::other)?
+            .serve(app.into_make_service());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!(error = %e, "the ACME HTTP-01 server failed");
+            }
+        });
+    }
+    tokio::spawn(renew(config.clone(), acme, tls, tokens));
+    Ok(())
+}
+
+async fn renew(config: WebTlsConfig, acme: AcmeConfig, tls: WebTls, tokens: Tokens) {
+    loop {
+        let wait = match renewal_due(&config.cert, &acme.domains) {
+            Ok(wait) if !wait.is_zero() => wait.min(CHECK_INTERVAL),
+            due => {
+                if let Err(e) = due {
+                    info!(cert = %config.cert.display(), reason = %e, "getting a certificate");
+                }
+                let obtained = obtain(&config, &acme, &tokens).await;
+                match obtained.and_then(|()| tls.reload(&config)) {
+                    Ok(()) => {
+                        info!(domains = ?acme.domains, "serving a new certificate");
+                        continue;
+                    }
+                    Err(e) => {
+                        error!(directory = acme.directory, error = %e, "could not get a certificate");
+                        RETRY_INTERVAL
+                    }
+                }
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// How long until the certificate in `cert` should be renewed: now, if it
+/// doesn't cover `domains`, or else `RENEW_BEFORE` before it expires. An
+/// error says why there is no usable certificate.
+fn renewal_due(cert: &Path, domains: &[String]) -> std::io::Result<Duration> {
+    use x509_cert::der::Decode;
+    use x509_cert::ext::pkix::{name::GeneralName, SubjectAltName};
+    let chain = CertificateDer::pem_file_iter(cert)
+        .map_err(|e| Error::new(ErrorKind::NotFound, e.to_string()))?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "no certificate in the file"))?
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    let certificate = x509_cert::Certificate::from_der(&chain)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    let tbs = &certificate.tbs_certificate;
+    let names: Vec<String> = match tbs.get::<SubjectAltName>() {
+        Ok(Some((_, names))) => names
+            .0
+            .iter()
+            .filter_map(|n| match n {
+                GeneralName::DnsName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    if let Some(missing) = domains.iter().find(|d| !names.contains(d)) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("the certificate doesn't cover {missing}"),
+        ));
+    }
+    let expires = tbs.validity.not_after.to_system_time();
+    let renew = expires
+        .checked_sub(RENEW_BEFORE)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    Ok(renew
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Problem>,
+}
+
+/// An error from the CA (RFC 7807).
+#[derive(Debug, Default, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = self.kind.trim_start_matches("urn:ietf:params:acme:error:");
+        write!(f, "{kind}: {}", self.detail)
+    }
+}
+
+/// An ACME account, and a session with its CA.
+struct Account {
+    client: reqwest::Client,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    directory: Directory,
+    /// The account's URL, once registered
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// The account with the key in `path`, made if it doesn't exist,
+    /// registered with the CA.
+    async fn open(acme: &AcmeConfig, path: &Path) -> std::io::Result<Account> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match PrivateKeyDer::from_pem_file(path) {
+            Ok(PrivateKeyDer::Pkcs8(key)) => key.secret_pkcs8_der().to_vec(),
+            Ok(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: not a PKCS#8 key", path.display()),
+                ))
+            }
+            Err(_) if !path.exists() => {
+                let key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| Error::other("could not make an account key"))?;
+                write_private(path, pem("PRIVATE KEY", key.as_ref()).as_bytes())?;
+                info!(path = %path.display(), "made a new ACME account key");
+                key.as_ref().to_vec()
+            }
+            Err(e) => {
+                let message = format!("{}: {e}", path.display());
+                return Err(Error::new(ErrorKind::InvalidData, message));
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {e}", path.display())))?;
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("sharkmon/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(Error::other)?;
+        let directory = client
+            .get(&acme.directory)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(Error::other)?
+            .bytes()
+            .await
+            .map_err(Error::other)?;
+        let directory = serde_json::from_slice(&directory)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {e}", acme.directory)))?;
+        let mut account = Account {
+            client,
+            key,
+            rng,
+            directory,
+            kid: None,
+            nonce: None,
+        };
+        let mut payload = json!({"termsOfServiceAgreed": true});
+        if let Some(email) = &acme.email {
+            payload["contact"] = json!([format!("mailto:{email}")]);
+        }
+        let url = account.directory.new_account.clone();
+        let (location, _) = account.post(&url, Some(&payload)).await?;
+        account.kid = Some(location.ok_or_else(|| Error::other("no account URL"))?);
+        Ok(account)
+    }
+
+    /// The account key's public half as a JWK, with its members in the
+    /// order RFC 7638 hashes them in.
+    fn jwk(&self) -> String {
+        // An uncompressed point: 0x04, then x and y.
+        let point = self.key.public_key().as_ref();
+        let (x, y) = point[1..].split_at(32);
+        format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            URL_SAFE_NO_PAD.encode(x),
+            URL_SAFE_NO_PAD.encode(y)
+        )
+    }
+
+    /// What the CA expects back for a challenge's `token`.
+    fn key_authorization(&self, token: &str) -> String {
+        let thumbprint = ring::digest::digest(&ring::digest::SHA256, self.jwk().as_bytes());
+        format!("{token}.{}", URL_SAFE_NO_PAD.encode(thumbprint))
+    }
+
+    /// POST `payload` to `url` signed with the account key, or with none
+    /// fetch it (POST-as-GET), returning the Location header and the body.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&serde_json::Value>,
+    ) -> std::io::Result<(Option<String>, Vec<u8>)> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = serde_json::from_str(&self.jwk()).unwrap(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let payload = payload.map_or(String::new(), |p| URL_SAFE_NO_PAD.encode(p.to_string()));
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+                .map_err(|_| Error::other("could not sign the request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature),
+            });
+            let response = self
+                .client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(Error::other)?;
+            let header = |name| {
+                let value = response.headers().get(name)?;
+                value.to_str().ok().map(str::to_owned)
+            };
+            self.nonce = header("replay-nonce");
+            let location = header("location");
+            let status = response.status();
+            let body = response.bytes().await.map_err(Error::other)?.to_vec();
+            if status.is_success() {
+                return Ok((location, body));
+            }
+            let problem: Problem = serde_json::from_slice(&body).unwrap_or_default();
+            // A nonce can go stale, and the reply carries a fresh one.
+            if problem.kind.ends_with(":badNonce") && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(Error::other(format!("{url}: {status}: {problem}")));
+        }
+    }
+
+    async fn new_nonce(&self) -> std::io::Result<String> {
+        let response = self
+            .client
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(Error::other)?;
+        let nonce = response.headers().get("replay-nonce");
+        nonce
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned)
+            .ok_or_else(|| Error::other("the CA gave no nonce"))
+    }
+
+    async fn fetch<T: DeserializeOwned>(&mut self, url: &str) -> std::io::Result<T> {
+        let (_, body) = self.post(url, None).await?;
+        serde_json::from_slice(&body).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Fetch `url` until its status is no longer pending or processing.
+    async fn wait<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        status: impl Fn(&T) -> &str,
+    ) -> std::io::Result<T> {
+        for _ in 0..POLL_ATTEMPTS {
+            let value: T = self.fetch(url).await?;
+            if !matches!(status(&value), "pending" | "processing") {
+                return Ok(value);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("{url}: still pending"),
+        ))
+    }
+
+    /// Prove control of the domain of the authorization at `url`.
+    async fn authorize(
+        &mut self,
+        url: &str,
+        acme: &AcmeConfig,
+        tokens: &Tokens,
+    ) -> std::io::Result<()> {
+        let authorization: Authorization = self.fetch(url).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization.identifier.value;
+        let kind = if acme.dns_hook.is_some() {
+            "dns-01"
+        } else {
+            "http-01"
+        };
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|c| c.kind == kind)
+            .ok_or_else(|| Error::other(format!("{domain}: the CA offers no {kind} challenge")))?;
+        let key_authorization = self.key_authorization(&challenge.token);
+        let record = match &acme.dns_hook {
+            Some(hook) => {
+                let name = format!("_acme-challenge.{}", domain.trim_start_matches("*."));
+                let digest =
+                    ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+                let value = URL_SAFE_NO_PAD.encode(digest);
+                run_hook(hook, "set", &name, &value).await?;
+                Some((hook, name, value))
+            }
+            None => {
+                let token = challenge.token.clone();
+                tokens.lock().unwrap().insert(token, key_authorization);
+                None
+            }
+        };
+        info!(
+            domain,
+            challenge = kind,
+            "asking the CA to check the challenge"
+        );
+        let checked = async {
+            self.post(&challenge.url, Some(&json!({}))).await?;
+            self.wait(url, |a: &Authorization| &a.status).await
+        }
+        .await;
+        match record {
+            Some((hook, name, value)) => {
+                if let Err(e) = run_hook(hook, "clear", &name, &value).await {
+                    warn!(name, error = %e, "could not clear the DNS-01 record");
+                }
+            }
+            None => {
+                tokens.lock().unwrap().remove(&challenge.token);
+            }
+        }
+        let authorization = checked?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let problem = authorization
+            .challenges
+            .iter()
+            .find_map(|c| c.error.as_ref())
+            .map_or_else(|| authorization.status.clone(), Problem::to_string);
+        Err(Error::other(format!("{domain}: {problem}")))
+    }
+}
+
+/// Get a certificate for `acme`'s domains, and write it and its new key to
+/// `config`'s files.
+async fn obtain(config: &WebTlsConfig, acme: &AcmeConfig, tokens: &Tokens) -> std::io::Result<()> {
+    let account_key = acme.account_key.clone().unwrap_or_else(|| {
+        let dir = config.key.parent().unwrap_or(Path::new(""));
+        dir.join("acme-account.key")
+    });
+    let mut account = Account::open(acme, &account_key).await?;
+    let identifiers: Vec<_> = acme
+        .domains
+        .iter()
+        .map(|d| json!({"type": "dns", "value": d}))
+        .collect();
+    let url = account.directory.new_order.clone();
+    let (location, body) = account
+        .post(&url, Some(&json!({"identifiers": identifiers})))
+        .await?;
+    let order_url = location.ok_or_else(|| Error::other("no order URL"))?;
+    let order: Order =
+        serde_json::from_slice(&body).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    for url in &order.authorizations {
+        account.authorize(url, acme, tokens).await?;
+    }
+
+    let rng = SystemRandom::new();
+    let key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(|_| Error::other("could not make a certificate key"))?;
+    let request = csr(&acme.domains, key.as_ref())?;
+    let finalize = json!({"csr": URL_SAFE_NO_PAD.encode(request)});
+    account.post(&order.finalize, Some(&finalize)).await?;
+    let order: Order = account.wait(&order_url, |o: &Order| &o.status).await?;
+    let certificate = match (order.status.as_str(), order.certificate) {
+        ("valid", Some(url)) => url,
+        (status, _) => {
+            let problem = order
+                .error
+                .map_or_else(|| status.to_owned(), |p| p.to_string());
+            return Err(Error::other(format!("the order failed: {problem}")));
+        }
+    };
+    let (_, chain) = account.post(&certificate, None).await?;
+    // Write both before replacing either, so a failure leaves them matched.
+    let key_tmp = temporary(&config.key);
+    let cert_tmp = temporary(&config.cert);
+    write_private(&key_tmp, pem("PRIVATE KEY", key.as_ref()).as_bytes())?;
+    std::fs::write(&cert_tmp, chain)?;
+    std::fs::rename(&key_tmp, &config.key)?;
+    std::fs::rename(&cert_tmp, &config.cert)
+}
+
+/// A PKCS#10 certificate signing request for `domains`, signed with the
+/// P-256 key in `pkcs8`.
+fn csr(domains: &[String], pkcs8: &[u8]) -> std::io::Result<Vec<u8>> {
+    use x509_cert::der::asn1::{BitString, Ia5String, OctetString};
+    use x509_cert::der::oid::db::rfc5912::{
+        ECDSA_WITH_SHA_256, ID_CE_SUBJECT_ALT_NAME, ID_EC_PUBLIC_KEY, SECP_256_R_1,
+    };
+    use x509_cert::der::{Any, Encode};
+    use x509_cert::ext::pkix::{name::GeneralName, SubjectAltName};
+    use x509_cert::request::{CertReq, CertReqInfo, ExtensionReq};
+    use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+    let invalid = |e: x509_cert::der::Error| Error::new(ErrorKind::InvalidInput, e.to_string());
+    let rng = SystemRandom::new();
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8, &rng)
+        .map_err(|e| Error::other(e.to_string()))?;
+    let names = domains
+        .iter()
+        .map(|d| Ia5String::new(d).map(GeneralName::DnsName))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let alt_names = x509_cert::ext::Extension {
+        extn_id: ID_CE_SUBJECT_ALT_NAME,
+        critical: false,
+        extn_value: OctetString::new(SubjectAltName(names).to_der().map_err(invalid)?)
+            .map_err(invalid)?,
+    };
+    let mut attributes = x509_cert::attr::Attributes::new();
+    attributes
+        .insert(ExtensionReq(vec![alt_names]).try_into().map_err(invalid)?)
+        .map_err(invalid)?;
+    let info = CertReqInfo {
+        version: Default::default(),
+        subject: format!("CN={}", domains[0]).parse().map_err(invalid)?,
+        public_key: SubjectPublicKeyInfoOwned {
+            algorithm: AlgorithmIdentifierOwned {
+                oid: ID_EC_PUBLIC_KEY,
+                parameters: Some(Any::from(&SECP_256_R_1)),
+            },
+            subject_public_key: BitString::from_bytes(key.public_key().as_ref())
+                .map_err(invalid)?,
+        },
+        attributes,
+    };
+    let signature = key
+        .sign(&rng, &info.to_der().map_err(invalid)?)
+        .map_err(|_| Error::other("could not sign the certificate request"))?;
+    let request = CertReq {
+        info,
+        algorithm: AlgorithmIdentifierOwned {
+            oid: ECDSA_WITH_SHA_256,
+            parameters: None,
+        },
+        signature: BitString::from_bytes(signature.as_ref()).map_err(invalid)?,
+    };
+    request.to_der().map_err(invalid)
+}
+
+async fn run_hook(hook: &Path, action: &str, name: &str, value: &str) -> std::io::Result<()> {
+    let status = tokio::process::Command::new(hook)
+        .args([action, name, value])
+        .status()
+        .await
+        .map_err(|e| Error::new(e.kind(), format!("{}: {e}", hook.display())))?;
+    if !status.success() {
+        return Err(Error::other(format!(
+            "{} {action} {name}: {status}",
+            hook.display()
+        )));
+    }
+    Ok(())
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    use base64::engine::general_purpose::STANDARD;
+    let text = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in text.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+fn temporary(path: &Path) -> PathBuf {
+    let mut temporary = path.to_owned().into_os_string();
+    temporary.push(".tmp");
+    temporary.into()
+}
+
+/// Write a file only its owner can read.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_cert::der::Decode;
+
+    #[test]
+    fn requests_certificates_for_every_domain() {
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let domains = [
+            "power.example.com".to_owned(),
+            "shark.example.com".to_owned(),
+        ];
+        let der = csr(&domains, key.as_ref()).unwrap();
+        let request = x509_cert::request::CertReq::from_der(&der).unwrap();
+        assert_eq!(request.info.subject.to_string(), "CN=power.example.com");
+        let text = String::from_utf8_lossy(&der);
+        assert!(domains.iter().all(|d| text.contains(d.as_str())));
+
+        // The CA checks the request is signed by the key it is for.
+        let public_key = request.info.public_key.subject_public_key.raw_bytes();
+        let verifier = ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ECDSA_P256_SHA256_ASN1,
+            public_key,
+        );
+        use x509_cert::der::Encode;
+        let signed = request.info.to_der().unwrap();
+        verifier
+            .verify(&signed, request.signature.raw_bytes())
+            .unwrap();
+
+        // The key file is one rustls can serve with.
+        let pem = pem("PRIVATE KEY", key.as_ref());
+        assert!(PrivateKeyDer::from_pem_slice(pem.as_bytes()).is_ok());
+    }
+}
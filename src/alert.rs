@@ -0,0 +1,182 @@
+//! Alerts: rules that fire while a reading is above or below a limit. Each
+//! is logged when it fires and when it clears, and sent as an SNMP
+//! notification if `[snmp]` names receivers.
+
+use crate::config::AlertConfig;
+use crate::meter::Device;
+use crate::snmp::Notifier;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// An alert firing or clearing for one device's reading.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub alert: String,
+    pub device: String,
+    pub reading: String,
+    pub value: f32,
+    /// The limit the reading crossed
+    pub limit: f64,
+    pub firing: bool,
+}
+
+pub struct Alerts {
+    rules: Mutex<Vec<AlertConfig>>,
+    /// The limit each firing rule's reading crossed, by rule and device
+    firing: Mutex<HashMap<(usize, String), f64>>,
+    notifier: Option<Notifier>,
+}
+
+impl AlertConfig {
+    /// The reading this rule watches on `device`, if it watches one there:
+    /// "device.reading" names one device, a bare reading every device.
+    fn reading(&self, device: &str) -> Option<&str> {
+        match self.reading.split_once('.') {
+            Some((d, reading)) => (d == device).then_some(reading),
+            None => Some(&self.reading),
+        }
+    }
+
+    /// The limit `value` is beyond, if any.
+    fn crossed(&self, value: f32) -> Option<f64> {
+        let value = value as f64;
+        match (self.above, self.below) {
+            (Some(above), _) if value > above => Some(above),
+            (_, Some(below)) if value < below => Some(below),
+            _ => None,
+        }
+    }
+}
+
+impl Alerts {
+    pub fn new(rules: &[AlertConfig], notifier: Option<Notifier>) -> Alerts {
+        Alerts {
+            rules: Mutex::new(rules.to_vec()),
+            firing: Mutex::new(HashMap::new()),
+            notifier,
+        }
+    }
+
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    pub fn rules(&self) -> Vec<AlertConfig> {
+        self.rules.lock().unwrap().clone()
+    }
+
+    /// Change the rules' limits, from the next readings on. The rules are
+    /// the same ones, in the same order, so those firing carry on firing
+    /// until their readings are back within the new limits.
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    pub fn set_rules(&self, rules: Vec<AlertConfig>) {
+        *self.rules.lock().unwrap() = rules;
+    }
+
+    /// The alerts that fire or clear with the device's current readings.
+    fn evaluate(&self, device: &Device) -> Vec<Event> {
+        let readings = device.readings.lock().unwrap();
+        let mut firing = self.firing.lock().unwrap();
+        let mut events = Vec::new();
+        for (i, rule) in self.rules.lock().unwrap().iter().enumerate() {
+            let Some(reading) = rule.reading(&device.name) else {
+                continue;
+            };
+            let Some(value) = readings.get(reading) else {
+                continue;
+            };
+            let key = (i, device.name.clone());
+            let (limit, now_firing) = match (rule.crossed(value), firing.get(&key)) {
+                (Some(limit), None) => {
+                    firing.insert(key, limit);
+                    (limit, true)
+                }
+                (None, Some(&limit)) => {
+                    firing.remove(&key);
+                    (limit, false)
+                }
+                _ => continue,
+            };
+            events.push(Event {
+                alert: rule.name.clone(),
+                device: device.name.clone(),
+                reading: reading.to_owned(),
+                value,
+                limit,
+                firing: now_firing,
+            });
+        }
+        events
+    }
+
+    /// Check the device's readings against the rules, reporting each alert
+    /// that fires or clears.
+    pub fn record(&self, device: &Device) {
+        for event in self.evaluate(device) {
+            if event.firing {
+                tracing::warn!(
+                    alert = event.alert,
+                    device = event.device,
+                    value = event.value,
+                    limit = event.limit,
+                    "alert firing"
+                );
+            } else {
+                tracing::warn!(
+                    alert = event.alert,
+                    device = event.device,
+                    value = event.value,
+                    "alert cleared"
+                );
+            }
+            if let Some(notifier) = &self.notifier {
+                notifier.notify(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+    use crate::meter::Meter;
+
+    #[tokio::test]
+    async fn fires_and_clears_once_each() {
+        let config: crate::config::Config = toml::from_str(
+            "[[alert]]\nname = \"overload\"\nreading = \"watts\"\nabove = 1000\n\
+             [[alert]]\nname = \"brownout\"\nreading = \"solar.volts\"\nbelow = 250\n\
+             [[meter]]\nname = \"main\"\naddress = \"fake:502\"\n",
+        )
+        .unwrap();
+        let alerts = Alerts::new(&config.alerts, None);
+        let poll = |watts| {
+            let meter = Meter::new(&config.meters[0])
+                .unwrap()
+                .fake(FakeMeter::shark100(watts, 240.0, 60.0));
+            async move {
+                meter.poll_once().await.unwrap();
+                meter
+            }
+        };
+        let meter = poll(1500.0).await;
+        let events = alerts.evaluate(&meter.devices[0]);
+        assert_eq!(
+            events,
+            [Event {
+                alert: "overload".to_owned(),
+                device: "main".to_owned(),
+                reading: "watts".to_owned(),
+                value: 1500.0,
+                limit: 1000.0,
+                firing: true,
+            }]
+        );
+        assert!(alerts.evaluate(&meter.devices[0]).is_empty());
+
+        let meter = poll(900.0).await;
+        let events = alerts.evaluate(&meter.devices[0]);
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].firing);
+        assert_eq!((events[0].value, events[0].limit), (900.0, 1000.0));
+        assert!(alerts.evaluate(&meter.devices[0]).is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+//! Long-term storage of every sample as Parquet files, ready for DuckDB or
+//! Spark. Files are laid out in Hive-style partitions, one directory per day
+//! or hour:
+//!
+//! ```text
+//! archive/date=2024-03-01/part-1709251200000.parquet
+//! archive/date=2024-03-01/hour=17/part-1709312400000.parquet   (hourly)
+//! ```
+//!
+//! Samples are buffered in memory and written as a new part file every flush
+//! interval, when a partition ends, and at shutdown. Each file is written
+//! under a temporary name and renamed into place, so readers never see a
+//! partial file.
+
+use crate::config::{ArchiveConfig, Partition};
+use crate::export::Export;
+use crate::history::Samples;
+use crate::meter::Device;
+use crate::timezone::Zone;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info};
+
+/// Samples waiting to be written, all in one partition.
+#[derive(Default)]
+struct Buffer {
+    partition: PathBuf,
+    devices: BTreeMap<String, Samples>,
+}
+
+pub struct Archive {
+    config: ArchiveConfig,
+    buffer: Mutex<Buffer>,
+    /// Whose days and hours the partitions are
+    zone: Zone,
+}
+
+impl Archive {
+    pub fn new(config: &ArchiveConfig, zone: Zone) -> std::io::Result<Archive> {
+        std::fs::create_dir_all(&config.dir)?;
+        Ok(Archive {
+            config: config.clone(),
+            buffer: Mutex::new(Buffer::default()),
+            zone,
+        })
+    }
+
+    /// The directory, relative to the archive's, for samples taken at `time`.
+    fn partition(&self, time: DateTime<Utc>) -> PathBuf {
+        let time = self.zone.local(time);
+        let date = PathBuf::from(format!("date={}", time.format("%Y-%m-%d")));
+        match self.config.partition {
+            Partition::Daily => date,
+            Partition::Hourly => date.join(format!("hour={}", time.format("%H"))),
+        }
+    }
+
+    /// Buffer the device's current readings. Crossing into a new partition
+    /// writes out the samples of the last one.
+    pub fn record(self: &Arc<Self>, device: &Device) {
+        let readings = device.readings.lock().unwrap().clone();
+        let now = Utc::now();
+        let partition = self.partition(now);
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.partition != partition {
+            let done = std::mem::replace(
+                &mut *buffer,
+                Buffer {
+                    partition,
+                    devices: BTreeMap::new(),
+                },
+            );
+            let archive = self.clone();
+            tokio::task::spawn_blocking(move || archive.write(done));
+        }
+        let samples = buffer
+            .devices
+            .entry(device.name.clone())
+            .or_insert_with(|| Samples {
+                names: readings.names(),
+                points: Vec::new(),
+            });
+        samples
+            .points
+            .push((now, readings.iter().map(|(_, v)| v).collect()));
+    }
+
+    /// Write out what has been buffered.
+    pub fn flush(&self) {
+        let done = {
+            let mut buffer = self.buffer.lock().unwrap();
+            Buffer {
+                partition: buffer.partition.clone(),
+                devices: std::mem::take(&mut buffer.devices),
+            }
+        };
+        self.write(done);
+    }
+
+    /// Flush every flush interval, forever.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.flush);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let archive = self.clone();
+            let _ = tokio::task::spawn_blocking(move || archive.flush()).await;
+        }
+    }
+
+    fn write(&self, buffer: Buffer) {
+        let Some(start) = buffer
+            .devices
+            .values()
+            .filter_map(|s| s.points.first())
+            .map(|(t, _)| *t)
+            .min()
+        else {
+            return;
+        };
+        let rows: usize = buffer.devices.values().map(|s| s.points.len()).sum();
+        let dir = self.config.dir.join(&buffer.partition);
+        let path = dir.join(format!("part-{}.parquet", start.timestamp_millis()));
+        let export = Export::new(buffer.devices.into_iter().collect());
+        let result = (|| {
+            let file = export.parquet()?;
+            std::fs::create_dir_all(&dir)?;
+            let temporary = path.with_extension("parquet.tmp");
+            std::fs::write(&temporary, file)?;
+            std::fs::rename(&temporary, &path)
+        })();
+        match result {
+            Ok(()) => info!(path = %path.display(), rows, "archived samples"),
+            Err(e) => error!(path = %path.display(), rows, error = %e, "could not archive samples"),
+        }
+    }
+}
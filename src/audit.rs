@@ -0,0 +1,225 @@
+//! The audit log: every change made to the meters or to sharkmon itself, such
+//! as register writes, resets, polls paused and resumed and configuration
+//! changes, with who made it and when. Each is logged with the
+//! `sharkmon::audit` target and, with `--audit-log`, appended to that file
+//! as a JSON line, which is never rewritten. The latest are kept in memory
+//! for `/audit`, starting from the end of the file after a restart.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+
+/// Entries kept in memory for `/audit`.
+const ENTRIES_KEPT: usize = 10_000;
+
+/// A change, or an attempt at one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    /// Who made it: the name of their API key or client certificate, if it
+    /// has one, and their address, or "command line"
+    pub actor: String,
+    /// What was done, e.g. "modbus-write", "reset-energy" or "pause"
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// The particulars, such as the registers written
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    pub details: serde_json::Value,
+    /// Why it failed, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Entry {
+    pub fn new(actor: &str, action: &str) -> Entry {
+        Entry {
+            time: Utc::now(),
+            actor: actor.to_owned(),
+            action: action.to_owned(),
+            meter: None,
+            device: None,
+            details: serde_json::Value::Null,
+            error: None,
+        }
+    }
+
+    pub fn meter(mut self, meter: &str) -> Entry {
+        self.meter = Some(meter.to_owned());
+        self
+    }
+
+    pub fn device(mut self, device: &str) -> Entry {
+        self.device = Some(device.to_owned());
+        self
+    }
+
+    pub fn details(mut self, details: serde_json::Value) -> Entry {
+        self.details = details;
+        self
+    }
+
+    /// The entry for a change that ended with `result`.
+    pub fn result<T>(mut self, result: &std::io::Result<T>) -> Entry {
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        self
+    }
+}
+
+/// Which entries `/audit` gives.
+#[cfg(feature = "web")]
+#[derive(Debug, Default, Deserialize)]
+pub struct Query {
+    pub since: Option<DateTime<Utc>>,
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub meter: Option<String>,
+    /// The most recent this many; 100 by default
+    pub limit: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct Audit {
+    file: Option<(PathBuf, Mutex<File>)>,
+    recent: Mutex<VecDeque<Entry>>,
+}
+
+impl Audit {
+    /// The audit log appended to `path`, remembering the entries already
+    /// there. A line that can't be read, perhaps cut short by a crash, is
+    /// skipped.
+    pub fn open(path: &Path) -> std::io::Result<Audit> {
+        let mut recent = VecDeque::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in std::io::BufReader::new(file).lines() {
+                    match serde_json::from_str(&line?) {
+                        Ok(entry) => keep(&mut recent, entry),
+                        Err(e) => {
+                            warn!(path = %path.display(), error = %e, "skipping an unreadable audit entry")
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Audit {
+            file: Some((path.to_owned(), Mutex::new(file))),
+            recent: Mutex::new(recent),
+        })
+    }
+
+    pub fn record(&self, entry: Entry) {
+        let details = (!entry.details.is_null()).then(|| entry.details.to_string());
+        match &entry.error {
+            None => info!(
+                target: "sharkmon::audit",
+                actor = entry.actor,
+                meter = entry.meter,
+                device = entry.device,
+                details,
+                "{}",
+                entry.action
+            ),
+            Some(e) => warn!(
+                target: "sharkmon::audit",
+                actor = entry.actor,
+                meter = entry.meter,
+                device = entry.device,
+                details,
+                error = e,
+                "{} failed",
+                entry.action
+            ),
+        }
+        if let Some((path, file)) = &self.file {
+            let mut line = serde_json::to_vec(&entry).unwrap();
+            line.push(b'\n');
+            // One write per line, so entries from several requests don't mix.
+            if let Err(e) = file.lock().unwrap().write_all(&line) {
+                error!(path = %path.display(), error = %e, "could not append to the audit log");
+            }
+        }
+        keep(&mut self.recent.lock().unwrap(), entry);
+    }
+
+    /// The most recent entries matching `query`, oldest first.
+    #[cfg(feature = "web")]
+    pub fn query(&self, query: &Query) -> Vec<Entry> {
+        let recent = self.recent.lock().unwrap();
+        let matches = |wanted: &Option<String>, value: Option<&str>| {
+            wanted.as_deref().is_none_or(|wanted| Some(wanted) == value)
+        };
+        let mut entries: Vec<Entry> = recent
+            .iter()
+            .rev()
+            .filter(|e| query.since.is_none_or(|since| e.time >= since))
+            .filter(|e| matches(&query.actor, Some(&e.actor)))
+            .filter(|e| matches(&query.action, Some(&e.action)))
+            .filter(|e| matches(&query.meter, e.meter.as_deref()))
+            .take(query.limit.unwrap_or(100))
+            .cloned()
+            .collect();
+        entries.reverse();
+        entries
+    }
+}
+
+fn keep(recent: &mut VecDeque<Entry>, entry: Entry) {
+    if recent.len() == ENTRIES_KEPT {
+        recent.pop_front();
+    }
+    recent.push_back(entry);
+}
+
+#[cfg(all(test, feature = "web"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_entries_and_reads_them_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let audit = Audit::open(&path).unwrap();
+        let failed: std::io::Result<()> = Err(std::io::Error::other("no reply"));
+        audit.record(Entry::new("ops (10.0.0.5)", "pause").meter("main"));
+        audit.record(
+            Entry::new("command line", "reset-energy")
+                .meter("main")
+                .device("main")
+                .result(&failed),
+        );
+        drop(audit);
+
+        // Another run carries on from the file.
+        let audit = Audit::open(&path).unwrap();
+        audit.record(Entry::new("ops (10.0.0.5)", "resume").meter("main"));
+        let all = audit.query(&Query::default());
+        let actions: Vec<_> = all.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(actions, ["pause", "reset-energy", "resume"]);
+        assert_eq!(all[1].error.as_deref(), Some("no reply"));
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 3);
+
+        let query = Query {
+            actor: Some("ops (10.0.0.5)".to_owned()),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let latest = audit.query(&query);
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].action, "resume");
+    }
+}
@@ -0,0 +1,985 @@
+//! A BACnet/IP server, so building automation systems can read the readings
+//! natively. sharkmon is one BACnet device, and each reading of each device
+//! is one of its Analog Input objects, numbered from 0 in the order the
+//! devices are configured: with one Shark 100, `main.watts` is analog-input
+//! 0, `main.volts` 1 and `main.frequency` 2.
+//!
+//! The server answers Who-Is, ReadProperty and ReadPropertyMultiple, which is
+//! what a BMS needs to find the device, browse its objects and poll them. It
+//! doesn't segment responses, so a client reads a long object-list one
+//! element at a time, as BACnet clients do when a device says it can't
+//! segment.
+
+use crate::config::BacnetConfig;
+use crate::meter::Meter;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+/// The standard BACnet/IP port.
+pub const PORT: u16 = 47808;
+
+/// The highest device instance; one more is the wildcard instance that
+/// means whichever device is asked.
+pub const MAX_INSTANCE: u32 = 4_194_302;
+const WILDCARD_INSTANCE: u32 = 4_194_303;
+
+/// The largest APDU accepted, the most that fits an Ethernet frame.
+const MAX_APDU: usize = 1476;
+
+/// sharkmon has no vendor identifier of its own.
+const VENDOR_ID: u32 = 0;
+
+/// Object types.
+const ANALOG_INPUT: u32 = 0;
+const DEVICE: u32 = 8;
+
+/// Properties.
+const APDU_TIMEOUT: u32 = 11;
+const APPLICATION_SOFTWARE_VERSION: u32 = 12;
+const DESCRIPTION: u32 = 28;
+const DEVICE_ADDRESS_BINDING: u32 = 30;
+const EVENT_STATE: u32 = 36;
+const FIRMWARE_REVISION: u32 = 44;
+const MAX_APDU_LENGTH_ACCEPTED: u32 = 62;
+const MODEL_NAME: u32 = 70;
+const NUMBER_OF_APDU_RETRIES: u32 = 73;
+const OBJECT_IDENTIFIER: u32 = 75;
+const OBJECT_LIST: u32 = 76;
+const OBJECT_NAME: u32 = 77;
+const OBJECT_TYPE: u32 = 79;
+const OUT_OF_SERVICE: u32 = 81;
+const PRESENT_VALUE: u32 = 85;
+const PROTOCOL_OBJECT_TYPES_SUPPORTED: u32 = 96;
+const PROTOCOL_SERVICES_SUPPORTED: u32 = 97;
+const PROTOCOL_VERSION: u32 = 98;
+const RELIABILITY: u32 = 103;
+const SEGMENTATION_SUPPORTED: u32 = 107;
+const STATUS_FLAGS: u32 = 111;
+const SYSTEM_STATUS: u32 = 112;
+const UNITS: u32 = 117;
+const VENDOR_IDENTIFIER: u32 = 120;
+const VENDOR_NAME: u32 = 121;
+const PROTOCOL_REVISION: u32 = 139;
+const DATABASE_REVISION: u32 = 155;
+/// Stand-ins for groups of properties in ReadPropertyMultiple.
+const ALL: u32 = 8;
+const OPTIONAL: u32 = 80;
+const REQUIRED: u32 = 105;
+
+const DEVICE_REQUIRED: &[u32] = &[
+    OBJECT_IDENTIFIER,
+    OBJECT_NAME,
+    OBJECT_TYPE,
+    SYSTEM_STATUS,
+    VENDOR_NAME,
+    VENDOR_IDENTIFIER,
+    MODEL_NAME,
+    FIRMWARE_REVISION,
+    APPLICATION_SOFTWARE_VERSION,
+    PROTOCOL_VERSION,
+    PROTOCOL_REVISION,
+    PROTOCOL_SERVICES_SUPPORTED,
+    PROTOCOL_OBJECT_TYPES_SUPPORTED,
+    OBJECT_LIST,
+    MAX_APDU_LENGTH_ACCEPTED,
+    SEGMENTATION_SUPPORTED,
+    APDU_TIMEOUT,
+    NUMBER_OF_APDU_RETRIES,
+    DEVICE_ADDRESS_BINDING,
+    DATABASE_REVISION,
+];
+const INPUT_REQUIRED: &[u32] = &[
+    OBJECT_IDENTIFIER,
+    OBJECT_NAME,
+    OBJECT_TYPE,
+    PRESENT_VALUE,
+    STATUS_FLAGS,
+    EVENT_STATE,
+    OUT_OF_SERVICE,
+    UNITS,
+];
+const INPUT_OPTIONAL: &[u32] = &[DESCRIPTION, RELIABILITY];
+
+/// Services.
+const READ_PROPERTY: u8 = 12;
+const READ_PROPERTY_MULTIPLE: u8 = 14;
+const I_AM: u8 = 0;
+const WHO_IS: u8 = 8;
+/// Their bits in Protocol_Services_Supported.
+const SERVICES_SUPPORTED: &[u32] = &[12, 14, 26, 34];
+
+/// Application tags.
+const BOOLEAN: u8 = 1;
+const UNSIGNED: u8 = 2;
+const REAL: u8 = 4;
+const CHARACTER_STRING: u8 = 7;
+const BIT_STRING: u8 = 8;
+const ENUMERATED: u8 = 9;
+const OBJECT_ID: u8 = 12;
+
+/// Error classes and codes.
+const CLASS_OBJECT: u32 = 1;
+const CLASS_PROPERTY: u32 = 2;
+const INVALID_ARRAY_INDEX: u32 = 42;
+const PROPERTY_IS_NOT_AN_ARRAY: u32 = 50;
+const UNKNOWN_OBJECT: u32 = 31;
+const UNKNOWN_PROPERTY: u32 = 32;
+
+/// Reject and abort reasons.
+const REJECT_INVALID_TAG: u8 = 4;
+const REJECT_UNRECOGNIZED_SERVICE: u8 = 9;
+const ABORT_SEGMENTATION_NOT_SUPPORTED: u8 = 4;
+
+/// BVLC functions.
+const FORWARDED_NPDU: u8 = 0x04;
+const ORIGINAL_UNICAST_NPDU: u8 = 0x0a;
+const ORIGINAL_BROADCAST_NPDU: u8 = 0x0b;
+
+/// A property that can't be read, as its error class and code.
+type PropertyError = (u32, u32);
+
+/// The engineering units of readings in `units`.
+fn engineering_units(reading: &str, units: Option<&str>) -> u32 {
+    match units {
+        Some("A") => 3,
+        Some("V") => 5,
+        Some("kV") => 6,
+        Some("VA") => 8,
+        Some("kVA") => 9,
+        Some("var" | "VAR") => 11,
+        Some("kvar") => 12,
+        Some("Wh") => 18,
+        Some("kWh") => 19,
+        Some("Hz") => 27,
+        Some("W") => 47,
+        Some("kW") => 48,
+        Some("MW") => 49,
+        Some("°C") => 62,
+        Some("°F") => 64,
+        Some("%") => 98,
+        Some("MWh") => 146,
+        _ if reading == "power_factor" || reading == "pf" => 15,
+        // no-units
+        _ => 95,
+    }
+}
+
+/// An Analog Input: one reading of one device.
+struct Input {
+    name: String,
+    description: String,
+    value: f32,
+    units: u32,
+    /// Whether its meter is disconnected, so the value is stale
+    fault: bool,
+}
+
+/// Every reading as an input, in object instance order.
+fn inputs(meters: &[Arc<Meter>]) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    for meter in meters {
+        let fault = !meter.status.lock().unwrap().connected;
+        for device in &meter.devices {
+            // Meter::units takes the lock too.
+            let readings = device.readings.lock().unwrap().clone();
+            for (reading, value) in readings.iter() {
+                inputs.push(Input {
+                    name: format!("{}.{reading}", device.name),
+                    description: format!("{reading} of {} on meter {}", device.name, meter.name),
+                    value,
+                    units: engineering_units(reading, meter.units(reading)),
+                    fault,
+                });
+            }
+        }
+    }
+    inputs
+}
+
+fn object_id(kind: u32, instance: u32) -> u32 {
+    kind << 22 | instance
+}
+
+/// The shortest big-endian encoding of `v`, as BACnet unsigneds are sent.
+fn unsigned_bytes(v: u32) -> Vec<u8> {
+    let bytes = v.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(3);
+    bytes[skip..].to_vec()
+}
+
+/// An encoder for tagged BACnet values.
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn tag(&mut self, number: u8, context: bool, len: usize) {
+        let class = if context { 0x08 } else { 0 };
+        if len <= 4 {
+            self.0.push(number << 4 | class | len as u8);
+        } else {
+            self.0.push(number << 4 | class | 5);
+            if len <= 253 {
+                self.0.push(len as u8);
+            } else {
+                self.0.push(254);
+                self.0.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+    }
+
+    fn value(&mut self, number: u8, context: bool, bytes: &[u8]) {
+        self.tag(number, context, bytes.len());
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn open(&mut self, number: u8) {
+        self.0.push(number << 4 | 0x0e);
+    }
+
+    fn close(&mut self, number: u8) {
+        self.0.push(number << 4 | 0x0f);
+    }
+
+    fn boolean(&mut self, v: bool) {
+        // The value is the tag's length.
+        self.tag(BOOLEAN, false, v as usize);
+    }
+
+    fn unsigned(&mut self, v: u32) {
+        self.value(UNSIGNED, false, &unsigned_bytes(v));
+    }
+
+    fn enumerated(&mut self, v: u32) {
+        self.value(ENUMERATED, false, &unsigned_bytes(v));
+    }
+
+    fn real(&mut self, v: f32) {
+        self.value(REAL, false, &v.to_be_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        // Character set 0, UTF-8.
+        let mut bytes = vec![0];
+        bytes.extend_from_slice(s.as_bytes());
+        self.value(CHARACTER_STRING, false, &bytes);
+    }
+
+    fn bits(&mut self, bits: &[bool]) {
+        let mut bytes = vec![((8 - bits.len() % 8) % 8) as u8];
+        for chunk in bits.chunks(8) {
+            let byte = chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |b, (i, set)| b | (*set as u8) << (7 - i));
+            bytes.push(byte);
+        }
+        self.value(BIT_STRING, false, &bytes);
+    }
+
+    fn object(&mut self, id: u32) {
+        self.value(OBJECT_ID, false, &id.to_be_bytes());
+    }
+
+    fn context_unsigned(&mut self, number: u8, v: u32) {
+        self.value(number, true, &unsigned_bytes(v));
+    }
+
+    fn context_object(&mut self, number: u8, id: u32) {
+        self.value(number, true, &id.to_be_bytes());
+    }
+}
+
+/// A tag as decoded.
+#[derive(Debug, PartialEq)]
+enum Tag {
+    Context(u8, usize),
+    Application(u8, usize),
+    Open(u8),
+    Close(u8),
+}
+
+/// A decoder for tagged BACnet values.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Some(taken)
+    }
+
+    /// The next tag, and how many bytes it takes.
+    fn peek(&self) -> Option<(Tag, usize)> {
+        let &first = self.data.first()?;
+        let mut used = 1;
+        let mut number = first >> 4;
+        if number == 0x0f {
+            number = *self.data.get(used)?;
+            used += 1;
+        }
+        let context = first & 0x08 != 0;
+        let len = match first & 0x07 {
+            6 if context => return Some((Tag::Open(number), used)),
+            7 if context => return Some((Tag::Close(number), used)),
+            5 => {
+                let len = *self.data.get(used)?;
+                used += 1;
+                match len {
+                    254 => {
+                        let bytes = self.data.get(used..used + 2)?;
+                        used += 2;
+                        u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+                    }
+                    255 => {
+                        let bytes = self.data.get(used..used + 4)?;
+                        used += 4;
+                        u32::from_be_bytes(bytes.try_into().ok()?) as usize
+                    }
+                    len => len as usize,
+                }
+            }
+            len => len as usize,
+        };
+        let tag = if context {
+            Tag::Context(number, len)
+        } else {
+            Tag::Application(number, len)
+        };
+        Some((tag, used))
+    }
+
+    fn tag(&mut self) -> Option<Tag> {
+        let (tag, used) = self.peek()?;
+        self.take(used)?;
+        Some(tag)
+    }
+
+    /// Whether the next tag is `tag`, taking it if so.
+    fn next_is(&mut self, tag: Tag) -> bool {
+        match self.peek() {
+            Some((next, used)) if next == tag => {
+                self.data = &self.data[used..];
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The value of context tag `number`, if it is next.
+    fn optional_unsigned(&mut self, number: u8) -> Option<Option<u32>> {
+        match self.peek() {
+            Some((Tag::Context(n, len), used)) if n == number => {
+                if !(1..=4).contains(&len) {
+                    return None;
+                }
+                self.take(used)?;
+                let bytes = self.take(len)?;
+                Some(Some(bytes.iter().fold(0, |v, b| v << 8 | *b as u32)))
+            }
+            _ => Some(None),
+        }
+    }
+
+    fn unsigned(&mut self, number: u8) -> Option<u32> {
+        self.optional_unsigned(number)?
+    }
+
+    fn object(&mut self, number: u8) -> Option<u32> {
+        match self.tag()? {
+            Tag::Context(n, 4) if n == number => {
+                Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// An object of the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Object {
+    Device,
+    Input(usize),
+}
+
+/// A property asked for, with its array index if one was given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Reference {
+    property: u32,
+    index: Option<u32>,
+}
+
+/// The BACnet device, answering requests from the current readings.
+pub struct Server {
+    id: u32,
+    name: String,
+    meters: Vec<Arc<Meter>>,
+}
+
+impl Server {
+    pub fn new(config: &BacnetConfig, meters: Vec<Arc<Meter>>) -> Server {
+        Server {
+            id: config.device_id,
+            name: config.name.clone().unwrap_or_else(|| "sharkmon".to_owned()),
+            meters,
+        }
+    }
+
+    fn device_id(&self) -> u32 {
+        object_id(DEVICE, self.id)
+    }
+
+    fn object(&self, id: u32, inputs: &[Input]) -> Option<Object> {
+        let (kind, instance) = (id >> 22, id & 0x3f_ffff);
+        match kind {
+            DEVICE if instance == self.id || instance == WILDCARD_INSTANCE => Some(Object::Device),
+            ANALOG_INPUT if (instance as usize) < inputs.len() => {
+                Some(Object::Input(instance as usize))
+            }
+            _ => None,
+        }
+    }
+
+    fn object_id(&self, object: Object) -> u32 {
+        match object {
+            Object::Device => self.device_id(),
+            Object::Input(i) => object_id(ANALOG_INPUT, i as u32),
+        }
+    }
+
+    /// The properties that `property` stands for in ReadPropertyMultiple.
+    fn expand(object: Object, property: u32) -> Vec<u32> {
+        let (required, optional) = match object {
+            Object::Device => (DEVICE_REQUIRED, &[][..]),
+            Object::Input(_) => (INPUT_REQUIRED, INPUT_OPTIONAL),
+        };
+        match property {
+            ALL => [required, optional].concat(),
+            REQUIRED => required.to_vec(),
+            OPTIONAL => optional.to_vec(),
+            property => vec![property],
+        }
+    }
+
+    /// Encode the value of a property.
+    fn read(
+        &self,
+        object: Object,
+        reference: Reference,
+        inputs: &[Input],
+        out: &mut Writer,
+    ) -> Result<(), PropertyError> {
+        let Reference { property, index } = reference;
+        if property == OBJECT_LIST && object == Object::Device {
+            let count = 1 + inputs.len() as u32;
+            match index {
+                None => {
+                    out.object(self.device_id());
+                    for i in 0..inputs.len() {
+                        out.object(object_id(ANALOG_INPUT, i as u32));
+                    }
+                }
+                Some(0) => out.unsigned(count),
+                Some(1) => out.object(self.device_id()),
+                Some(i) if i <= count => out.object(object_id(ANALOG_INPUT, i - 2)),
+                Some(_) => return Err((CLASS_PROPERTY, INVALID_ARRAY_INDEX)),
+            }
+            return Ok(());
+        }
+        let known = Self::expand(object, ALL).contains(&property);
+        if !known {
+            return Err((CLASS_PROPERTY, UNKNOWN_PROPERTY));
+        }
+        if index.is_some() {
+            return Err((CLASS_PROPERTY, PROPERTY_IS_NOT_AN_ARRAY));
+        }
+        match (object, property) {
+            (_, OBJECT_IDENTIFIER) => out.object(self.object_id(object)),
+            (Object::Device, OBJECT_NAME) => out.string(&self.name),
+            (Object::Device, OBJECT_TYPE) => out.enumerated(DEVICE),
+            // operational
+            (Object::Device, SYSTEM_STATUS) => out.enumerated(0),
+            (Object::Device, VENDOR_NAME | MODEL_NAME) => out.string("sharkmon"),
+            (Object::Device, VENDOR_IDENTIFIER) => out.unsigned(VENDOR_ID),
+            (Object::Device, FIRMWARE_REVISION | APPLICATION_SOFTWARE_VERSION) => {
+                out.string(env!("CARGO_PKG_VERSION"))
+            }
+            (Object::Device, PROTOCOL_VERSION) => out.unsigned(1),
+            (Object::Device, PROTOCOL_REVISION) => out.unsigned(14),
+            (Object::Device, PROTOCOL_SERVICES_SUPPORTED) => {
+                let bits: Vec<bool> = (0..40).map(|s| SERVICES_SUPPORTED.contains(&s)).collect();
+                out.bits(&bits)
+            }
+            (Object::Device, PROTOCOL_OBJECT_TYPES_SUPPORTED) => {
+                let bits: Vec<bool> = (0..64).map(|t| t == ANALOG_INPUT || t == DEVICE).collect();
+                out.bits(&bits)
+            }
+            (Object::Device, MAX_APDU_LENGTH_ACCEPTED) => out.unsigned(MAX_APDU as u32),
+            // no-segmentation
+            (Object::Device, SEGMENTATION_SUPPORTED) => out.enumerated(3),
+            (Object::Device, APDU_TIMEOUT) => out.unsigned(3000),
+            (Object::Device, NUMBER_OF_APDU_RETRIES) => out.unsigned(3),
+            // An empty list.
+            (Object::Device, DEVICE_ADDRESS_BINDING) => {}
+            (Object::Device, DATABASE_REVISION) => out.unsigned(0),
+            (Object::Input(i), property) => {
+                let input = &inputs[i];
+                match property {
+                    OBJECT_NAME => out.string(&input.name),
+                    OBJECT_TYPE => out.enumerated(ANALOG_INPUT),
+                    PRESENT_VALUE => out.real(input.value),
+                    // in-alarm, fault, overridden, out-of-service
+                    STATUS_FLAGS => out.bits(&[false, input.fault, false, false]),
+                    // normal
+                    EVENT_STATE => out.enumerated(0),
+                    OUT_OF_SERVICE => out.boolean(false),
+                    UNITS => out.enumerated(input.units),
+                    DESCRIPTION => out.string(&input.description),
+                    // communication-failure, or no-fault-detected
+                    RELIABILITY => out.enumerated(if input.fault { 12 } else { 0 }),
+                    _ => return Err((CLASS_PROPERTY, UNKNOWN_PROPERTY)),
+                }
+            }
+            _ => return Err((CLASS_PROPERTY, UNKNOWN_PROPERTY)),
+        }
+        Ok(())
+    }
+
+    /// An I-Am announcing the device.
+    fn i_am(&self) -> Vec<u8> {
+        let mut out = Writer(vec![0x10, I_AM]);
+        out.object(self.device_id());
+        out.unsigned(MAX_APDU as u32);
+        out.enumerated(3);
+        out.unsigned(VENDOR_ID);
+        out.0
+    }
+
+    /// The answer to a Who-Is, if it asks for this device.
+    fn who_is(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut reader = Reader { data };
+        let low = reader.optional_unsigned(0)?;
+        let high = reader.optional_unsigned(1)?;
+        match (low, high) {
+            (None, None) => Some(self.i_am()),
+            (Some(low), Some(high)) if (low..=high).contains(&self.id) => Some(self.i_am()),
+            _ => None,
+        }
+    }
+
+    fn read_property(&self, data: &[u8], out: &mut Writer) -> Option<Result<(), PropertyError>> {
+        let mut reader = Reader { data };
+        let id = reader.object(0)?;
+        let reference = Reference {
+            property: reader.unsigned(1)?,
+            index: reader.optional_unsigned(2)?,
+        };
+        if !reader.is_empty() {
+            return None;
+        }
+        let inputs = inputs(&self.meters);
+        let Some(object) = self.object(id, &inputs) else {
+            return Some(Err((CLASS_OBJECT, UNKNOWN_OBJECT)));
+        };
+        out.context_object(0, self.object_id(object));
+        out.context_unsigned(1, reference.property);
+        if let Some(index) = reference.index {
+            out.context_unsigned(2, index);
+        }
+        out.open(3);
+        let mut value = Writer::default();
+        if let Err(e) = self.read(object, reference, &inputs, &mut value) {
+            return Some(Err(e));
+        }
+        out.0.extend(value.0);
+        out.close(3);
+        Some(Ok(()))
+    }
+
+    fn read_property_multiple(&self, data: &[u8], out: &mut Writer) -> Option<()> {
+        let mut reader = Reader { data };
+        let mut requests = Vec::new();
+        while !reader.is_empty() {
+            let id = reader.object(0)?;
+            if !reader.next_is(Tag::Open(1)) {
+                return None;
+            }
+            let mut references = Vec::new();
+            while !reader.next_is(Tag::Close(1)) {
+                references.push(Reference {
+                    property: reader.unsigned(0)?,
+                    index: reader.optional_unsigned(1)?,
+                });
+            }
+            requests.push((id, references));
+        }
+        if requests.is_empty() {
+            return None;
+        }
+        let inputs = inputs(&self.meters);
+        for (id, references) in requests {
+            let object = self.object(id, &inputs);
+            out.context_object(0, object.map_or(id, |o| self.object_id(o)));
+            out.open(1);
+            for reference in references {
+                let properties = match object {
+                    Some(object) => Self::expand(object, reference.property),
+                    None => vec![reference.property],
+                };
+                for property in properties {
+                    let reference = Reference {
+                        property,
+                        ..reference
+                    };
+                    out.context_unsigned(2, property);
+                    if let Some(index) = reference.index {
+                        out.context_unsigned(3, index);
+                    }
+                    let mut value = Writer::default();
+                    let result = match object {
+                        Some(object) => self.read(object, reference, &inputs, &mut value),
+                        None => Err((CLASS_OBJECT, UNKNOWN_OBJECT)),
+                    };
+                    match result {
+                        Ok(()) => {
+                            out.open(4);
+                            out.0.extend(value.0);
+                            out.close(4);
+                        }
+                        Err((class, code)) => {
+                            out.open(5);
+                            out.enumerated(class);
+                            out.enumerated(code);
+                            out.close(5);
+                        }
+                    }
+                }
+            }
+            out.close(1);
+        }
+        Some(())
+    }
+
+    /// The answer to an APDU, if it needs one.
+    fn apdu(&self, apdu: &[u8]) -> Option<Vec<u8>> {
+        match apdu.first()? >> 4 {
+            // Unconfirmed requests.
+            1 => match *apdu.get(1)? {
+                WHO_IS => self.who_is(&apdu[2..]),
+                _ => None,
+            },
+            // Confirmed requests.
+            0 => {
+                let &[flags, max, invoke, service, ..] = apdu else {
+                    return None;
+                };
+                if flags & 0x08 != 0 {
+                    return Some(vec![0x71, invoke, ABORT_SEGMENTATION_NOT_SUPPORTED]);
+                }
+                let max_apdu = match max & 0x0f {
+                    0 => 50,
+                    1 => 128,
+                    2 => 206,
+                    3 => 480,
+                    4 => 1024,
+                    _ => MAX_APDU,
+                };
+                let data = &apdu[4..];
+                let mut out = Writer(vec![0x30, invoke, service]);
+                let result = match service {
+                    READ_PROPERTY => self.read_property(data, &mut out),
+                    READ_PROPERTY_MULTIPLE => self.read_property_multiple(data, &mut out).map(Ok),
+                    _ => return Some(vec![0x60, invoke, REJECT_UNRECOGNIZED_SERVICE]),
+                };
+                match result {
+                    None => Some(vec![0x60, invoke, REJECT_INVALID_TAG]),
+                    Some(Err((class, code))) => {
+                        let mut out = Writer(vec![0x50, invoke, service]);
+                        out.enumerated(class);
+                        out.enumerated(code);
+                        Some(out.0)
+                    }
+                    Some(Ok(())) if out.0.len() > max_apdu.min(MAX_APDU) => {
+                        Some(vec![0x71, invoke, ABORT_SEGMENTATION_NOT_SUPPORTED])
+                    }
+                    Some(Ok(())) => Some(out.0),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The answer to a BACnet/IP packet from `from`, and where it goes.
+    pub fn respond(&self, packet: &[u8], from: SocketAddr) -> Option<(SocketAddr, Vec<u8>)> {
+        let &[0x81, function, hi, lo, ..] = packet else {
+            return None;
+        };
+        if u16::from_be_bytes([hi, lo]) as usize != packet.len() {
+            return None;
+        }
+        let (to, npdu) = match function {
+            ORIGINAL_UNICAST_NPDU | ORIGINAL_BROADCAST_NPDU => (from, &packet[4..]),
+            // Relayed by a BBMD, with the address it came from.
+            FORWARDED_NPDU => {
+                let address = packet.get(4..10)?;
+                let ip = Ipv4Addr::new(address[0], address[1], address[2], address[3]);
+                let port = u16::from_be_bytes([address[4], address[5]]);
+                (SocketAddrV4::new(ip, port).into(), packet.get(10..)?)
+            }
+            _ => return None,
+        };
+
+        let &[1, control, ..] = npdu else {
+            return None;
+        };
+        // Network layer messages are for routers.
+        if control & 0x80 != 0 {
+            return None;
+        }
+        let mut rest = &npdu[2..];
+        if control & 0x20 != 0 {
+            let &[hi, lo, len, ..] = rest else {
+                return None;
+            };
+            // Only global broadcasts reach this device through a DNET.
+            if u16::from_be_bytes([hi, lo]) != 0xffff {
+                return None;
+            }
+            rest = rest.get(3 + len as usize..)?;
+        }
+        let mut source = None;
+        if control & 0x08 != 0 {
+            let &[_, _, len, ..] = rest else {
+                return None;
+            };
+            let end = 3 + len as usize;
+            source = Some(rest.get(..end)?);
+            rest = &rest[end..];
+        }
+        if control & 0x20 != 0 {
+            // The hop count.
+            rest = rest.get(1..)?;
+        }
+
+        let apdu = self.apdu(rest)?;
+        // Back through the router the request came from, if any.
+        let mut reply = vec![0x81, ORIGINAL_UNICAST_NPDU, 0, 0, 1];
+        match source {
+            Some(source) => {
+                reply.push(0x20);
+                reply.extend_from_slice(source);
+                reply.push(0xff);
+            }
+            None => reply.push(0),
+        }
+        reply.extend(apdu);
+        let len = (reply.len() as u16).to_be_bytes();
+        reply[2..4].copy_from_slice(&len);
+        Some((to, reply))
+    }
+}
+
+/// Serve the readings over BACnet/IP until the task is dropped.
+pub async fn serve(config: BacnetConfig, meters: Vec<Arc<Meter>>) -> io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.port)).await?;
+    socket.set_broadcast(true)?;
+    let server = Server::new(&config, meters);
+    info!(
+        port = config.port,
+        device_id = config.device_id,
+        "serving BACnet/IP"
+    );
+
+    // Announce the device, as BACnet devices do when they start.
+    let mut announce = vec![0x81, ORIGINAL_BROADCAST_NPDU, 0, 0, 1, 0];
+    announce.extend(server.i_am());
+    let len = (announce.len() as u16).to_be_bytes();
+    announce[2..4].copy_from_slice(&len);
+    let broadcast = SocketAddr::from((Ipv4Addr::BROADCAST, config.port));
+    if let Err(e) = socket.send_to(&announce, broadcast).await {
+        warn!(error = %e, "couldn't broadcast BACnet I-Am");
+    }
+
+    let mut buf = vec![0; 2048];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Some((to, reply)) = server.respond(&buf[..len], from) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&reply, to).await {
+            debug!(error = %e, %to, "couldn't send BACnet reply");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+
+    async fn server() -> Server {
+        let config: crate::config::Config =
+            toml::from_str("[[meter]]\nname = \"main\"\naddress = \"fake:502\"\n").unwrap();
+        let meter = Meter::new(&config.meters[0])
+            .unwrap()
+            .fake(FakeMeter::shark100(1500.0, 240.0, 60.0));
+        meter.poll_once().await.unwrap();
+        let bacnet = BacnetConfig {
+            device_id: 1234,
+            name: None,
+            port: PORT,
+        };
+        Server::new(&bacnet, vec![Arc::new(meter)])
+    }
+
+    fn from() -> SocketAddr {
+        "192.168.1.10:47808".parse().unwrap()
+    }
+
+    /// A local unicast request carrying `apdu`.
+    fn request(apdu: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x81, ORIGINAL_UNICAST_NPDU, 0, 0, 1, 0x04];
+        packet.extend_from_slice(apdu);
+        let len = (packet.len() as u16).to_be_bytes();
+        packet[2..4].copy_from_slice(&len);
+        packet
+    }
+
+    /// The APDU answering a local request.
+    fn answer(server: &Server, apdu: &[u8]) -> Vec<u8> {
+        let (to, reply) = server.respond(&request(apdu), from()).unwrap();
+        assert_eq!(to, from());
+        assert_eq!(&reply[..6], &[0x81, 0x0a, 0, reply.len() as u8, 1, 0]);
+        reply[6..].to_vec()
+    }
+
+    #[tokio::test]
+    async fn answers_who_is() {
+        let server = server().await;
+        let i_am = [
+            0x10, 0x00, 0xc4, 0x02, 0x00, 0x04, 0xd2, 0x22, 0x05, 0xc4, 0x91, 0x03, 0x21, 0x00,
+        ];
+        assert_eq!(answer(&server, &[0x10, 0x08]), i_am);
+        assert_eq!(
+            answer(&server, &[0x10, 0x08, 0x0a, 0x04, 0x00, 0x1a, 0x04, 0xd2]),
+            i_am
+        );
+        // Outside the range asked for.
+        assert!(server
+            .respond(&request(&[0x10, 0x08, 0x09, 0x01, 0x19, 0x02]), from())
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_properties() {
+        let server = server().await;
+        // ReadProperty analog-input 0, present-value.
+        let reply = answer(
+            &server,
+            &[
+                0x00, 0x05, 0x07, 0x0c, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x19, 0x55,
+            ],
+        );
+        let mut expected = vec![
+            0x30, 0x07, 0x0c, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x19, 0x55, 0x3e, 0x44,
+        ];
+        expected.extend_from_slice(&1500f32.to_be_bytes());
+        expected.push(0x3f);
+        assert_eq!(reply, expected);
+
+        // Object-list length, through the wildcard device instance.
+        let reply = answer(
+            &server,
+            &[
+                0x00, 0x05, 0x08, 0x0c, 0x0c, 0x02, 0x3f, 0xff, 0xff, 0x19, 0x4c, 0x29, 0x00,
+            ],
+        );
+        assert_eq!(
+            reply,
+            [
+                0x30, 0x08, 0x0c, 0x0c, 0x02, 0x00, 0x04, 0xd2, 0x19, 0x4c, 0x29, 0x00, 0x3e, 0x21,
+                0x04, 0x3f
+            ]
+        );
+
+        // An analog input that doesn't exist.
+        let reply = answer(
+            &server,
+            &[
+                0x00, 0x05, 0x09, 0x0c, 0x0c, 0x00, 0x00, 0x00, 0x09, 0x19, 0x55,
+            ],
+        );
+        assert_eq!(reply, [0x50, 0x09, 0x0c, 0x91, 0x01, 0x91, 0x1f]);
+
+        // WriteProperty isn't supported.
+        let reply = answer(&server, &[0x00, 0x05, 0x0a, 0x0f]);
+        assert_eq!(reply, [0x60, 0x0a, 0x09]);
+    }
+
+    #[tokio::test]
+    async fn reads_multiple_properties() {
+        let server = server().await;
+        // analog-input 1: units and an unknown property; analog-input 5: present-value.
+        let reply = answer(
+            &server,
+            &[
+                0x00, 0x05, 0x01, 0x0e, 0x0c, 0x00, 0x00, 0x00, 0x01, 0x1e, 0x09, 0x75, 0x09, 0xff,
+                0x1f, 0x0c, 0x00, 0x00, 0x00, 0x05, 0x1e, 0x09, 0x55, 0x1f,
+            ],
+        );
+        assert_eq!(
+            reply,
+            [
+                0x30, 0x01, 0x0e, // ComplexACK
+                0x0c, 0x00, 0x00, 0x00, 0x01, 0x1e, // analog-input 1
+                0x29, 0x75, 0x4e, 0x91, 0x05, 0x4f, // units: volts
+                0x29, 0xff, 0x5e, 0x91, 0x02, 0x91, 0x20, 0x5f, // unknown-property
+                0x1f, 0x0c, 0x00, 0x00, 0x00, 0x05, 0x1e, // analog-input 5
+                0x29, 0x55, 0x5e, 0x91, 0x01, 0x91, 0x1f, 0x5f, // unknown-object
+                0x1f,
+            ]
+        );
+
+        // Everything about analog-input 2 fits a 480 byte APDU, but not a
+        // 50 byte one.
+        let all = [0x0c, 0x00, 0x00, 0x00, 0x02, 0x1e, 0x09, 0x08, 0x1f];
+        let reply = answer(
+            &server,
+            &[[0x00, 0x03, 0x02, 0x0e].as_slice(), &all].concat(),
+        );
+        assert_eq!(&reply[..3], &[0x30, 0x02, 0x0e]);
+        let name = b"\x75\x0f\x00main.frequency";
+        assert!(reply.windows(name.len()).any(|w| w == name), "{reply:02x?}");
+        let reply = answer(
+            &server,
+            &[[0x00, 0x00, 0x03, 0x0e].as_slice(), &all].concat(),
+        );
+        assert_eq!(reply, [0x71, 0x03, 0x04]);
+    }
+
+    #[tokio::test]
+    async fn answers_back_through_routers() {
+        let server = server().await;
+        // From network 5, MAC 0x42, relayed by a BBMD from 10.0.0.2.
+        let mut packet = vec![
+            0x81, 0x04, 0x00, 0x00, 10, 0, 0, 2, 0xba, 0xc0, 0x01, 0x28, 0xff, 0xff, 0x00, 0x00,
+            0x05, 0x01, 0x42, 0xfe, 0x10, 0x08,
+        ];
+        packet[3] = packet.len() as u8;
+        let (to, reply) = server.respond(&packet, from()).unwrap();
+        assert_eq!(to, "10.0.0.2:47808".parse().unwrap());
+        assert_eq!(&reply[4..11], &[0x01, 0x20, 0x00, 0x05, 0x01, 0x42, 0xff]);
+        assert_eq!(&reply[11..13], &[0x10, 0x00]);
+    }
+}
@@ -0,0 +1,161 @@
+//! Baselines to compare the power against: the average watts at the same
+//! time yesterday and a week ago, which `/power` shows alongside the
+//! readings, so a dashboard can show that today runs 20% over yesterday
+//! without a query language. Each device's watts are averaged over
+//! 15-minute slots, of which eight days' are kept in memory, so baselines
+//! start again when sharkmon restarts.
+
+use crate::meter::Device;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// The reading baselines are kept for.
+const READING: &str = "watts";
+
+/// The length of the slots the watts are averaged over, in seconds.
+const SLOT_SECS: i64 = 15 * 60;
+
+const DAY_SLOTS: i64 = 24 * 60 * 60 / SLOT_SECS;
+
+/// Slots kept for each device: a week, and a day to spare.
+const SLOTS_KEPT: i64 = 8 * DAY_SLOTS;
+
+/// The baselines of a device's current watts, where they are known.
+#[cfg_attr(not(feature = "web"), allow(dead_code))]
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Comparison {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watts_yesterday: Option<f32>,
+    /// How far the watts are above (or below) yesterday's, in %
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta_pct: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watts_last_week: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta_pct_last_week: Option<f32>,
+}
+
+/// How far `now` is from `then`, in %, or None if `then` is zero.
+#[cfg_attr(not(feature = "web"), allow(dead_code))]
+fn delta(now: f32, then: f32) -> Option<f32> {
+    (then != 0.0).then(|| 100.0 * (now - then) / then.abs())
+}
+
+fn slot(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(SLOT_SECS)
+}
+
+/// A device's sums of watts and the samples in them, by slot.
+type Slots = BTreeMap<i64, (f64, u32)>;
+
+/// Each device's slots.
+pub type Saved = HashMap<String, Slots>;
+
+#[derive(Default)]
+pub struct Baselines {
+    devices: Mutex<Saved>,
+}
+
+impl Baselines {
+    /// Add the device's latest watts to the current slot's average.
+    pub fn record(&self, device: &Device) {
+        let watts = device.readings.lock().unwrap().get(READING);
+        if let Some(watts) = watts {
+            self.observe(&device.name, Utc::now(), watts);
+        }
+    }
+
+    fn observe(&self, device: &str, time: DateTime<Utc>, watts: f32) {
+        let mut devices = self.devices.lock().unwrap();
+        let slots = devices.entry(device.to_owned()).or_default();
+        let slot = slot(time);
+        let (sum, count) = slots.entry(slot).or_default();
+        *sum += watts as f64;
+        *count += 1;
+        let oldest = slot - SLOTS_KEPT + 1;
+        if slots.first_key_value().is_some_and(|(s, _)| *s < oldest) {
+            *slots = slots.split_off(&oldest);
+        }
+    }
+
+    /// The slots, to be saved across a restart.
+    pub fn save(&self) -> Saved {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// Carry on from saved slots.
+    pub fn restore(&self, saved: Saved) {
+        *self.devices.lock().unwrap() = saved;
+    }
+
+    /// How `watts` compares with the device's watts at the same time
+    /// yesterday and a week ago.
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    pub fn compare(&self, device: &str, watts: Option<f32>) -> Comparison {
+        self.compare_at(device, Utc::now(), watts)
+    }
+
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    fn compare_at(&self, device: &str, time: DateTime<Utc>, watts: Option<f32>) -> Comparison {
+        let devices = self.devices.lock().unwrap();
+        let Some(slots) = devices.get(device) else {
+            return Comparison::default();
+        };
+        let average = |days: i64| {
+            let (sum, count) = slots.get(&(slot(time) - days * DAY_SLOTS))?;
+            Some((sum / *count as f64) as f32)
+        };
+        let (yesterday, last_week) = (average(1), average(7));
+        let delta = |then: Option<f32>| delta(watts?, then?);
+        Comparison {
+            watts_yesterday: yesterday,
+            delta_pct: delta(yesterday),
+            watts_last_week: last_week,
+            delta_pct_last_week: delta(last_week),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    #[test]
+    fn compares_with_yesterday_and_last_week() {
+        let baselines = Baselines::default();
+        let now = Utc::now();
+        let ago = |days| now - TimeDelta::days(days);
+        baselines.observe("main", ago(7), 500.0);
+        baselines.observe("main", ago(1), 1000.0);
+        baselines.observe("main", ago(1) + TimeDelta::seconds(1), 1000.0);
+        assert_eq!(
+            baselines.compare_at("main", now, Some(1200.0)),
+            Comparison {
+                watts_yesterday: Some(1000.0),
+                delta_pct: Some(20.0),
+                watts_last_week: Some(500.0),
+                delta_pct_last_week: Some(140.0),
+            }
+        );
+        // Nothing was recorded at this time yesterday.
+        let later = now + TimeDelta::hours(1);
+        assert_eq!(
+            baselines.compare_at("main", later, Some(1200.0)),
+            Comparison::default()
+        );
+        assert_eq!(
+            baselines.compare_at("solar", now, None),
+            Comparison::default()
+        );
+
+        // Slots older than eight days are forgotten.
+        baselines.observe("main", now, 1200.0);
+        baselines.observe("main", now + TimeDelta::days(1), 1200.0);
+        let slots = baselines.devices.lock().unwrap();
+        assert!(!slots["main"].contains_key(&slot(ago(7))));
+        assert!(slots["main"].contains_key(&slot(ago(1))));
+    }
+}
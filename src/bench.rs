@@ -0,0 +1,216 @@
+//! `sharkmon bench`: how fast a meter answers, to size polling intervals.
+//! Every block the meter is polled with is read back to back for a while,
+//! timing each read. On a chained RS-485 segment every meter shares the bus,
+//! so the report's poll time is what each meter on it adds to a cycle.
+
+use crate::registers::Block;
+use std::fmt;
+use std::time::Duration;
+
+/// Headroom left for other traffic and slow moments when working out how
+/// often polls can safely run.
+const HEADROOM: f64 = 1.25;
+
+/// The reads of one block, as the meter is polled with it.
+pub struct BlockTimes {
+    pub device: String,
+    pub unit: u8,
+    /// The interval its group is polled at
+    pub interval: Duration,
+    pub block: Block,
+    /// How long each successful read took
+    pub latencies: Vec<Duration>,
+    pub errors: u64,
+}
+
+impl BlockTimes {
+    pub fn new(device: &str, unit: u8, interval: Duration, block: Block) -> BlockTimes {
+        BlockTimes {
+            device: device.to_owned(),
+            unit,
+            interval,
+            block,
+            latencies: Vec::new(),
+            errors: 0,
+        }
+    }
+
+    /// The latency that a fraction `p` of reads took no longer than.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort_unstable();
+        let i = ((sorted.len() as f64 * p).ceil() as usize).max(1) - 1;
+        sorted.get(i).copied()
+    }
+}
+
+/// What a benchmark measured.
+#[derive(Default)]
+pub struct Report {
+    pub meter: String,
+    pub elapsed: Duration,
+    pub blocks: Vec<BlockTimes>,
+}
+
+impl Report {
+    pub fn reads(&self) -> u64 {
+        self.blocks.iter().map(|b| b.latencies.len() as u64).sum()
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.blocks.iter().map(|b| b.errors).sum()
+    }
+
+    /// Registers read per second.
+    pub fn throughput(&self) -> f64 {
+        let registers: u64 = self
+            .blocks
+            .iter()
+            .map(|b| b.latencies.len() as u64 * b.block.len as u64)
+            .sum();
+        registers as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// How long one poll of every block takes, going by each block's 99th
+    /// percentile latency.
+    pub fn poll_time(&self) -> Option<Duration> {
+        self.blocks.iter().map(|b| b.percentile(0.99)).sum()
+    }
+
+    /// The shortest interval that every block can safely be polled at.
+    pub fn safe_interval(&self) -> Option<Duration> {
+        let poll = self.poll_time()?;
+        let safe = Duration::from_secs_f64(poll.as_secs_f64() * HEADROOM);
+        // Round up to a whole millisecond.
+        Some(Duration::from_millis(
+            safe.as_nanos().div_ceil(1_000_000) as u64
+        ))
+    }
+}
+
+fn millis(d: Option<Duration>) -> String {
+    match d {
+        Some(d) => format!("{:.1}", d.as_secs_f64() * 1000.0),
+        None => "-".to_owned(),
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "meter '{}': {} reads in {secs:.1}s ({:.1} reads/s, {:.0} registers/s), {} failed",
+            self.meter,
+            self.reads(),
+            self.reads() as f64 / secs,
+            self.throughput(),
+            self.errors()
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<12} {:>4} {:>8} {:>9} {:>4} {:>8} {:>8} {:>8} {:>8} {:>6}",
+            "device",
+            "unit",
+            "interval",
+            "register",
+            "len",
+            "p50 ms",
+            "p90 ms",
+            "p99 ms",
+            "max ms",
+            "errors"
+        )?;
+        for b in &self.blocks {
+            writeln!(
+                f,
+                "{:<12} {:>4} {:>8} {:>9} {:>4} {:>8} {:>8} {:>8} {:>8} {:>6}",
+                b.device,
+                b.unit,
+                humantime::format_duration(b.interval).to_string(),
+                format!("{:#06x}", b.block.start),
+                b.block.len,
+                millis(b.percentile(0.5)),
+                millis(b.percentile(0.9)),
+                millis(b.percentile(0.99)),
+                millis(b.latencies.iter().max().copied()),
+                b.errors
+            )?;
+        }
+        writeln!(f)?;
+        let (Some(poll), Some(safe)) = (self.poll_time(), self.safe_interval()) else {
+            return writeln!(
+                f,
+                "some blocks were never read, so no safe poll rate can be given"
+            );
+        };
+        writeln!(
+            f,
+            "polling every block takes {} ms; polls can safely run every {} ({:.1} per second)",
+            millis(Some(poll)),
+            humantime::format_duration(safe),
+            1.0 / safe.as_secs_f64()
+        )?;
+        if let Some(fastest) = self.blocks.iter().map(|b| b.interval).min() {
+            if fastest < safe {
+                writeln!(
+                    f,
+                    "the fastest poll group runs every {}, faster than the meter can keep up with",
+                    humantime::format_duration(fastest)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::Function;
+
+    fn times(latencies: &[u64]) -> BlockTimes {
+        let block = Block {
+            function: Function::Holding,
+            start: 0x0383,
+            len: 10,
+        };
+        let mut times = BlockTimes::new("main", 1, Duration::from_secs(1), block);
+        times.latencies = latencies
+            .iter()
+            .map(|ms| Duration::from_millis(*ms))
+            .collect();
+        times
+    }
+
+    #[test]
+    fn percentiles() {
+        let b = times(&[5, 1, 4, 2, 3, 6, 7, 8, 9, 10]);
+        assert_eq!(b.percentile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(b.percentile(0.9), Some(Duration::from_millis(9)));
+        assert_eq!(b.percentile(0.99), Some(Duration::from_millis(10)));
+        assert_eq!(b.percentile(0.0), Some(Duration::from_millis(1)));
+        assert_eq!(times(&[]).percentile(0.5), None);
+    }
+
+    #[test]
+    fn works_out_the_safe_interval() {
+        let report = Report {
+            meter: "main".to_owned(),
+            elapsed: Duration::from_secs(2),
+            blocks: vec![times(&[10; 100]), times(&[30; 100])],
+        };
+        assert_eq!(report.reads(), 200);
+        assert_eq!(report.throughput(), 1000.0);
+        assert_eq!(report.poll_time(), Some(Duration::from_millis(40)));
+        assert_eq!(report.safe_interval(), Some(Duration::from_millis(50)));
+
+        let failed = Report {
+            blocks: vec![times(&[10]), times(&[])],
+            ..report
+        };
+        assert_eq!(failed.safe_interval(), None);
+        assert!(failed.to_string().contains("never read"));
+    }
+}
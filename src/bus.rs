@@ -0,0 +1,165 @@
+//! The readings bus: every device's readings, published by the poll loops as
+//! they are polled, for the sinks, the history, the alerts, the web streams
+//! and everything else that consumes them. Each consumer has its own task and
+//! goes at its own pace, so a slow one holds up neither polling nor the
+//! others. One that falls too far behind misses the readings it was behind
+//! by, which are counted in `/metrics`.
+
+use crate::meter::Device;
+use crate::sink::Sample;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// Readings each consumer may fall behind by before it misses some.
+pub const QUEUE_LEN: usize = 1024;
+
+/// A device's readings, as they were when it was polled.
+#[derive(Clone)]
+pub struct Reading {
+    pub time: DateTime<Utc>,
+    /// A copy of the device, with the readings of this poll
+    pub device: Arc<Device>,
+}
+
+impl Reading {
+    pub fn new(device: &Device) -> Reading {
+        Reading {
+            time: Utc::now(),
+            device: Arc::new(Device {
+                name: device.name.clone(),
+                unit: device.unit,
+                readings: Mutex::new(device.readings.lock().unwrap().clone()),
+                total: device.total,
+                labels: device.labels.clone(),
+                counters: device.counters.clone(),
+            }),
+        }
+    }
+
+    /// The reading as it is sent to sinks and web clients.
+    pub fn sample(&self) -> Sample {
+        Sample {
+            time: self.time,
+            ..Sample::new(&self.device)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Bus {
+    readings: broadcast::Sender<Reading>,
+    /// Readings each named consumer missed by falling behind
+    missed: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl Default for Bus {
+    fn default() -> Bus {
+        Bus {
+            readings: broadcast::channel(QUEUE_LEN).0,
+            missed: Default::default(),
+        }
+    }
+}
+
+impl Bus {
+    /// Publish the device's current readings, without waiting.
+    pub fn publish(&self, device: &Device) {
+        if self.readings.receiver_count() > 0 {
+            let _ = self.readings.send(Reading::new(device));
+        }
+    }
+
+    /// Every reading published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Reading> {
+        self.readings.subscribe()
+    }
+
+    /// Subscribers, including web clients.
+    pub fn receivers(&self) -> usize {
+        self.readings.receiver_count()
+    }
+
+    /// A task that gives every reading to `consume`, called `name` in logs
+    /// and `/metrics`, until the bus closes. It is subscribed before this
+    /// returns, so it sees every reading published after.
+    pub fn consume(
+        &self,
+        name: &str,
+        mut consume: impl FnMut(&Reading) + Send + 'static,
+    ) -> impl std::future::Future<Output = io::Result<()>> + Send + 'static {
+        let mut readings = self.subscribe();
+        let missed = self.missed.clone();
+        let name = name.to_owned();
+        missed.lock().unwrap().insert(name.clone(), 0);
+        async move {
+            loop {
+                match readings.recv().await {
+                    Ok(reading) => consume(&reading),
+                    Err(RecvError::Lagged(n)) => {
+                        warn!(consumer = %name, missed = n, "fell behind the readings, skipping some");
+                        *missed.lock().unwrap().entry(name.clone()).or_default() += n;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Readings each consumer missed, by its name.
+    pub fn missed(&self) -> BTreeMap<String, u64> {
+        self.missed.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Labels, Total};
+    use crate::meter::PowerEwma;
+
+    fn device(watts: f32) -> Device {
+        Device {
+            name: "main".to_owned(),
+            unit: 1,
+            readings: Mutex::new(PowerEwma::from_values(
+                vec!["watts".to_owned()],
+                vec![watts],
+            )),
+            total: Total::Add,
+            labels: Arc::new(Labels::new()),
+            counters: Arc::new([]),
+        }
+    }
+
+    #[tokio::test]
+    async fn consumers_go_at_their_own_pace() {
+        let bus = Bus::default();
+        let (tx, mut seen) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(bus.consume("fast", move |r| {
+            tx.send(r.device.readings.lock().unwrap().get("watts"))
+                .unwrap()
+        }));
+        // A consumer that never gets to run falls behind, and misses what
+        // it was behind by.
+        let slow = bus.consume("slow", |_| {});
+        let device = device(1.0);
+        for watts in 0..QUEUE_LEN + 10 {
+            *device.readings.lock().unwrap() =
+                PowerEwma::from_values(vec!["watts".to_owned()], vec![watts as f32]);
+            bus.publish(&device);
+            // A reading is a copy, unchanged by later polls.
+            assert_eq!(seen.recv().await.unwrap(), Some(watts as f32));
+        }
+        let slow = tokio::spawn(slow);
+        while bus.missed()["slow"] == 0 {
+            tokio::task::yield_now().await;
+        }
+        let missed = BTreeMap::from([("fast".to_owned(), 0), ("slow".to_owned(), 10)]);
+        assert_eq!(bus.missed(), missed);
+        slow.abort();
+    }
+}
@@ -0,0 +1,204 @@
+//! `sharkmon check`: a Nagios plugin, for Icinga and other monitoring systems
+//! that run a command and read its exit code. One poll of every meter gives
+//! a status line with each device's load, and perfdata with every reading.
+
+use crate::meter::Meter;
+use std::fmt::{self, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A plugin's result, which is also its exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum State {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            State::Ok => "OK",
+            State::Warning => "WARNING",
+            State::Critical => "CRITICAL",
+            State::Unknown => "UNKNOWN",
+        })
+    }
+}
+
+/// The levels of a device's watts above which it is a warning or critical.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Thresholds {
+    pub warn: Option<f32>,
+    pub crit: Option<f32>,
+}
+
+impl Thresholds {
+    fn state(&self, value: f32) -> State {
+        if self.crit.is_some_and(|crit| value > crit) {
+            State::Critical
+        } else if self.warn.is_some_and(|warn| value > warn) {
+            State::Warning
+        } else {
+            State::Ok
+        }
+    }
+}
+
+/// A perfdata label, quoted if it has to be.
+fn label(name: &str) -> String {
+    if name.contains([' ', '\'', '=']) {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Poll every meter once, giving up on those that don't answer within
+/// `timeout`, and report on their watts. Meters that can't be read are
+/// critical.
+pub async fn check(meters: &[Arc<Meter>], watts: Thresholds, timeout: Duration) -> (State, String) {
+    let polls: Vec<_> = meters
+        .iter()
+        .map(|m| {
+            let m = m.clone();
+            tokio::spawn(async move { tokio::time::timeout(timeout, m.poll_once()).await })
+        })
+        .collect();
+    let mut failures = Vec::new();
+    for (m, poll) in meters.iter().zip(polls) {
+        let error = match poll.await {
+            Ok(Ok(Ok(()))) => continue,
+            Ok(Ok(Err(e))) => e.to_string(),
+            Ok(Err(_)) => format!("no answer in {}", humantime::format_duration(timeout)),
+            Err(e) => e.to_string(),
+        };
+        failures.push((m.name.clone(), error));
+    }
+    for m in meters {
+        for device in &m.devices {
+            m.process(device, meters);
+        }
+    }
+    report(meters, &failures, watts)
+}
+
+/// The state and output line for meters that have just been polled, except
+/// those named in `failures`, with their errors.
+fn report(
+    meters: &[Arc<Meter>],
+    failures: &[(String, String)],
+    watts: Thresholds,
+) -> (State, String) {
+    let mut state = if failures.is_empty() {
+        State::Ok
+    } else {
+        State::Critical
+    };
+    let mut summary: Vec<String> = failures
+        .iter()
+        .map(|(meter, error)| format!("meter '{meter}' unreachable: {error}"))
+        .collect();
+    let mut perfdata = String::new();
+    let devices = meters.iter().map(|m| m.devices.len()).sum::<usize>();
+    for m in meters {
+        if failures.iter().any(|(meter, _)| *meter == m.name) {
+            continue;
+        }
+        for device in &m.devices {
+            let readings = device.readings.lock().unwrap().clone();
+            for (reading, value) in readings.iter() {
+                let name = match devices {
+                    1 => reading.to_owned(),
+                    _ => format!("{}.{reading}", device.name),
+                };
+                let _ = write!(perfdata, " {}={value}", label(&name));
+                if reading != "watts" {
+                    continue;
+                }
+                let level = |t: Option<f32>| t.map(|t| t.to_string()).unwrap_or_default();
+                let _ = write!(perfdata, ";{};{}", level(watts.warn), level(watts.crit));
+                let device_state = watts.state(value);
+                state = state.max(device_state);
+                let units = m.units(reading).unwrap_or("W");
+                summary.push(match device_state {
+                    State::Critical => format!(
+                        "{}: {value} {units} (critical above {})",
+                        device.name,
+                        level(watts.crit)
+                    ),
+                    State::Warning => format!(
+                        "{}: {value} {units} (warning above {})",
+                        device.name,
+                        level(watts.warn)
+                    ),
+                    _ => format!("{}: {value} {units}", device.name),
+                });
+            }
+        }
+    }
+    if summary.is_empty() {
+        summary.push("no device has a watts reading".to_owned());
+    }
+    let mut line = summary.join(", ");
+    if !perfdata.is_empty() {
+        line.push_str(" |");
+        line.push_str(&perfdata);
+    }
+    (state, line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+
+    async fn meter(watts: f32) -> Arc<Meter> {
+        let config: crate::config::Config =
+            toml::from_str("[[meter]]\nname = \"main\"\naddress = \"fake:502\"\n").unwrap();
+        let meter = Meter::new(&config.meters[0])
+            .unwrap()
+            .fake(FakeMeter::shark100(watts, 240.0, 60.0));
+        Arc::new(meter)
+    }
+
+    #[tokio::test]
+    async fn reports_load_against_thresholds() {
+        let watts = Thresholds {
+            warn: Some(10000.0),
+            crit: Some(15000.0),
+        };
+        let meters = [meter(1500.0).await];
+        let timeout = Duration::from_secs(1);
+        let (state, line) = check(&meters, watts, timeout).await;
+        assert_eq!(state, State::Ok);
+        assert_eq!(
+            line,
+            "main: 1500 W | watts=1500;10000;15000 volts=240 frequency=60"
+        );
+
+        let meters = [meter(12000.0).await];
+        let (state, line) = check(&meters, watts, timeout).await;
+        assert_eq!(state, State::Warning);
+        assert!(
+            line.starts_with("main: 12000 W (warning above 10000) |"),
+            "{line}"
+        );
+
+        let meters = [meter(16000.0).await];
+        assert_eq!(check(&meters, watts, timeout).await.0, State::Critical);
+        let (state, _) = check(&meters, Thresholds::default(), timeout).await;
+        assert_eq!(state, State::Ok);
+    }
+
+    #[tokio::test]
+    async fn unreachable_meters_are_critical() {
+        let meters = [meter(1500.0).await];
+        let failures = [("main".to_owned(), "connection refused".to_owned())];
+        let (state, line) = report(&meters, &failures, Thresholds::default());
+        assert_eq!(state, State::Critical);
+        assert_eq!(line, "meter 'main' unreachable: connection refused");
+        assert_eq!(label("main watts"), "'main watts'");
+    }
+}
@@ -0,0 +1,371 @@
+//! The connection to a meter as the poll loop uses it: register reads and
+//! writes addressed to one unit at a time. It is a tokio-modbus connection,
+//! Modbus over UDP, a recording being replayed, or a [`FakeMeter`] held in
+//! memory, so meter handling can be tested without hardware.
+//!
+//! A meter refusing a request is an error wrapping the Modbus
+//! [`ExceptionCode`], which `registers::is_exception` tells apart from a
+//! failed connection.
+
+use crate::registers::Function;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_modbus::client::{Client, Context, Reader, Writer};
+use tokio_modbus::slave::SlaveContext;
+use tokio_modbus::{ExceptionCode, Slave};
+
+#[async_trait]
+pub trait MeterClient: Send {
+    /// Send the requests that follow to the device with this unit ID.
+    fn set_unit(&mut self, unit: u8);
+
+    /// Read `len` registers starting at `start`.
+    async fn read(&mut self, function: Function, start: u16, len: u16)
+        -> std::io::Result<Vec<u16>>;
+
+    /// Write `values` to the holding registers starting at `address`.
+    async fn write(&mut self, address: u16, values: &[u16]) -> std::io::Result<()>;
+
+    async fn disconnect(&mut self) -> std::io::Result<()>;
+}
+
+#[async_trait]
+impl MeterClient for Context {
+    fn set_unit(&mut self, unit: u8) {
+        self.set_slave(Slave(unit));
+    }
+
+    async fn read(
+        &mut self,
+        function: Function,
+        start: u16,
+        len: u16,
+    ) -> std::io::Result<Vec<u16>> {
+        match function {
+            Function::Holding => self.read_holding_registers(start, len).await,
+            Function::Input => self.read_input_registers(start, len).await,
+        }
+        .map_err(Error::other)?
+        .map_err(Error::other)
+    }
+
+    /// This always uses "write multiple registers", which meters such as the
+    /// Shark require even for a single register.
+    async fn write(&mut self, address: u16, values: &[u16]) -> std::io::Result<()> {
+        self.write_multiple_registers(address, values)
+            .await
+            .map_err(Error::other)?
+            .map_err(Error::other)
+    }
+
+    async fn disconnect(&mut self) -> std::io::Result<()> {
+        Client::disconnect(self).await
+    }
+}
+
+/// The error of a meter refusing a request with `code`.
+pub fn exception(code: ExceptionCode) -> Error {
+    Error::other(code)
+}
+
+/// How long an unanswered Modbus/UDP request waits before it is first sent
+/// again.
+const RETRANSMIT_AFTER: Duration = Duration::from_millis(250);
+
+/// Modbus over UDP, as some RTU bridges speak it: a Modbus/TCP frame in each
+/// datagram. A request that goes unanswered is sent again, after
+/// `RETRANSMIT_AFTER` and then twice as long each time, until the meter's
+/// timeout gives up on it. Each request has its own transaction ID, so a
+/// late reply to one given up on isn't taken for the reply to the next.
+pub struct UdpClient {
+    socket: tokio::net::UdpSocket,
+    unit: u8,
+    transaction: u16,
+}
+
+impl UdpClient {
+    pub async fn connect(address: &str) -> std::io::Result<UdpClient> {
+        let remote = tokio::net::lookup_host(address)
+            .await?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{address} has no address")))?;
+        let local = match remote {
+            std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+            std::net::SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = tokio::net::UdpSocket::bind(local).await?;
+        socket.connect(remote).await?;
+        Ok(UdpClient {
+            socket,
+            unit: Slave::tcp_device().0,
+            transaction: 0,
+        })
+    }
+
+    /// Send the request `pdu` to the unit, returning the reply's.
+    async fn request(&mut self, pdu: &[u8]) -> std::io::Result<Vec<u8>> {
+        self.transaction = self.transaction.wrapping_add(1);
+        let mut frame = self.transaction.to_be_bytes().to_vec();
+        frame.extend([0, 0]);
+        frame.extend((pdu.len() as u16 + 1).to_be_bytes());
+        frame.push(self.unit);
+        frame.extend(pdu);
+        self.socket.send(&frame).await?;
+        let mut wait = RETRANSMIT_AFTER;
+        let mut buf = [0; 260];
+        loop {
+            let len = match tokio::time::timeout(wait, self.socket.recv(&mut buf)).await {
+                Ok(received) => received?,
+                Err(_) => {
+                    tracing::debug!(
+                        transaction = self.transaction,
+                        "resending a Modbus/UDP request"
+                    );
+                    self.socket.send(&frame).await?;
+                    wait *= 2;
+                    continue;
+                }
+            };
+            let reply = &buf[..len];
+            if len < 9 || reply[..2] != frame[..2] || reply[6] != self.unit {
+                continue;
+            }
+            let pdu_reply = &reply[7..];
+            if pdu_reply[0] == pdu[0] | 0x80 {
+                return Err(exception(ExceptionCode::new(pdu_reply[1])));
+            }
+            if pdu_reply[0] != pdu[0] {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("reply with function code {} to {}", pdu_reply[0], pdu[0]),
+                ));
+            }
+            return Ok(pdu_reply.to_vec());
+        }
+    }
+}
+
+#[async_trait]
+impl MeterClient for UdpClient {
+    fn set_unit(&mut self, unit: u8) {
+        self.unit = unit;
+    }
+
+    async fn read(
+        &mut self,
+        function: Function,
+        start: u16,
+        len: u16,
+    ) -> std::io::Result<Vec<u16>> {
+        let code = match function {
+            Function::Holding => 0x03,
+            Function::Input => 0x04,
+        };
+        let mut pdu = vec![code];
+        pdu.extend(start.to_be_bytes());
+        pdu.extend(len.to_be_bytes());
+        let reply = self.request(&pdu).await?;
+        let data = &reply[2..];
+        if reply[1] as usize != data.len() || data.len() != len as usize * 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} bytes in reply to a read of {len} registers", data.len()),
+            ));
+        }
+        Ok(data
+            .chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]))
+            .collect())
+    }
+
+    async fn write(&mut self, address: u16, values: &[u16]) -> std::io::Result<()> {
+        let mut pdu = vec![0x10];
+        pdu.extend(address.to_be_bytes());
+        pdu.extend((values.len() as u16).to_be_bytes());
+        pdu.push((values.len() * 2) as u8);
+        pdu.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+        self.request(&pdu).await.map(drop)
+    }
+
+    async fn disconnect(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A meter held in memory, for tests. Registers that haven't been set are
+/// refused with an "illegal data address" exception, as a real meter refuses
+/// unmapped ones, and writes land in the holding registers. Clones share
+/// their registers, so a test can change them while a meter polls.
+#[derive(Clone, Default)]
+pub struct FakeMeter {
+    state: Arc<Mutex<FakeState>>,
+    unit: u8,
+}
+
+#[derive(Default)]
+struct FakeState {
+    registers: HashMap<(u8, Function, u16), u16>,
+    /// What every request and connection fails with, until cleared
+    failure: Option<ErrorKind>,
+    reads: u64,
+    connects: u64,
+}
+
+impl FakeMeter {
+    pub fn new() -> FakeMeter {
+        FakeMeter::default()
+    }
+
+    /// A Shark 100 as unit 1, reading `watts`, `volts` and `frequency`. The
+    /// registers around them read as zero, as on the real meter, so block
+    /// reads spanning them succeed.
+    pub fn shark100(watts: f32, volts: f32, frequency: f32) -> FakeMeter {
+        let fake = FakeMeter::new();
+        fake.set(1, Function::Holding, 0x0383, &[0; 2]);
+        fake.set(1, Function::Holding, 0x03ed, &[0; 22]);
+        fake.set_float(1, 0x0383, watts);
+        fake.set_float(1, 0x03ed, volts);
+        fake.set_float(1, 0x0401, frequency);
+        fake
+    }
+
+    /// Set registers of `unit` starting at `address`.
+    pub fn set(&self, unit: u8, function: Function, address: u16, values: &[u16]) {
+        let mut state = self.state.lock().unwrap();
+        for (i, value) in values.iter().enumerate() {
+            state
+                .registers
+                .insert((unit, function, address + i as u16), *value);
+        }
+    }
+
+    /// Set two holding registers to `value` as the Shark encodes it: an IEEE
+    /// 754 float, high word first.
+    pub fn set_float(&self, unit: u8, address: u16, value: f32) {
+        let bits = value.to_bits();
+        let words = [(bits >> 16) as u16, bits as u16];
+        self.set(unit, Function::Holding, address, &words);
+    }
+
+    /// A register's value, if it has been set or written.
+    pub fn get(&self, unit: u8, function: Function, address: u16) -> Option<u16> {
+        let state = self.state.lock().unwrap();
+        state.registers.get(&(unit, function, address)).copied()
+    }
+
+    /// Fail every connection and request with an error of `kind`, as if the
+    /// meter had gone away, or with `None` work again.
+    pub fn fail(&self, kind: Option<ErrorKind>) {
+        self.state.lock().unwrap().failure = kind;
+    }
+
+    /// How many reads have been answered.
+    pub fn reads(&self) -> u64 {
+        self.state.lock().unwrap().reads
+    }
+
+    /// How many times the meter has been connected to.
+    pub fn connects(&self) -> u64 {
+        self.state.lock().unwrap().connects
+    }
+
+    /// A new connection to the meter.
+    pub fn connect(&self) -> std::io::Result<FakeMeter> {
+        let mut state = self.state.lock().unwrap();
+        check(&state)?;
+        state.connects += 1;
+        Ok(FakeMeter {
+            state: self.state.clone(),
+            unit: Slave::tcp_device().0,
+        })
+    }
+}
+
+fn check(state: &FakeState) -> std::io::Result<()> {
+    match state.failure {
+        Some(kind) => Err(Error::new(kind, "the fake meter is failing")),
+        None => Ok(()),
+    }
+}
+
+#[async_trait]
+impl MeterClient for FakeMeter {
+    fn set_unit(&mut self, unit: u8) {
+        self.unit = unit;
+    }
+
+    async fn read(
+        &mut self,
+        function: Function,
+        start: u16,
+        len: u16,
+    ) -> std::io::Result<Vec<u16>> {
+        let mut state = self.state.lock().unwrap();
+        check(&state)?;
+        let values = (start..start.saturating_add(len))
+            .map(|address| {
+                state
+                    .registers
+                    .get(&(self.unit, function, address))
+                    .copied()
+            })
+            .collect::<Option<Vec<u16>>>()
+            .ok_or_else(|| exception(ExceptionCode::IllegalDataAddress))?;
+        state.reads += 1;
+        Ok(values)
+    }
+
+    async fn write(&mut self, address: u16, values: &[u16]) -> std::io::Result<()> {
+        check(&self.state.lock().unwrap())?;
+        self.set(self.unit, Function::Holding, address, values);
+        Ok(())
+    }
+
+    async fn disconnect(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn udp_requests_are_sent_again_until_answered() {
+        let meter = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = meter.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let mut buf = [0; 260];
+            // Ignore the first copy, then answer the second twice, first
+            // with a reply to some earlier request.
+            let _ = meter.recv_from(&mut buf).await.unwrap();
+            let (len, client) = meter.recv_from(&mut buf).await.unwrap();
+            let request = buf[..len].to_vec();
+            assert_eq!(&request[6..], [7, 0x03, 0x03, 0x83, 0x00, 0x02]);
+            let mut reply = request[..4].to_vec();
+            reply.extend([0, 7, 7, 0x03, 4, 0x12, 0x34, 0x56, 0x78]);
+            let mut stale = reply.clone();
+            stale[1] ^= 0xff;
+            meter.send_to(&stale, client).await.unwrap();
+            meter.send_to(&reply, client).await.unwrap();
+
+            // Refuse the next.
+            let (len, client) = meter.recv_from(&mut buf).await.unwrap();
+            let mut refusal = buf[..4].to_vec();
+            refusal.extend([0, 3, 7, 0x83, 0x02]);
+            assert_eq!(len, 12);
+            meter.send_to(&refusal, client).await.unwrap();
+        });
+
+        let mut client = UdpClient::connect(&address).await.unwrap();
+        client.set_unit(7);
+        let values = client.read(Function::Holding, 0x0383, 2).await.unwrap();
+        assert_eq!(values, vec![0x1234, 0x5678]);
+        let refused = client.read(Function::Holding, 0x0383, 2).await.unwrap_err();
+        assert!(crate::registers::is_exception(&refused));
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,1968 @@
+//! The configuration file, which describes each meter to monitor. A single
+//! meter can instead be described entirely on the command line, which builds
+//! the same structures.
+
+use crate::derived::{self, Expression};
+use crate::registers::{FormatOverride, RegisterRange};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The version of the configuration format this sharkmon reads. A file for a
+/// later version is refused rather than read as far as it happens to parse.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// A sharkmon configuration file, with one `[[meter]]` table per meter
+/// connection:
+///
+/// ```toml
+/// [[meter]]
+/// name = "main"
+/// address = "192.168.1.100:502"
+/// profile = "shark200"
+///
+/// [[meter]]
+/// name = "solar"
+/// address = "192.168.1.101:502"
+/// sunspec = true
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The version of the file's format, `CONFIG_VERSION` for files written
+    /// for this sharkmon; a file without one is read as version 1
+    pub version: Option<u32>,
+    /// Bearer tokens accepted by the web endpoints that change meter state,
+    /// each a key or a table giving its role; see `ApiKey`
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    /// How many web requests are served at once; any more are refused with
+    /// 503. Unlimited by default
+    pub max_requests_in_flight: Option<usize>,
+    /// How many web requests a second each client address may make, with
+    /// bursts of up to a second's worth; any more are refused with 429.
+    /// Unlimited by default
+    pub requests_per_second: Option<f64>,
+    /// Let `/power` wrap its JSON in the function named by `?callback=`, for
+    /// old browsers and microcontrollers that can't make CORS requests
+    #[serde(default)]
+    pub jsonp: bool,
+    /// Where the web server listens; 0.0.0.0:8081 by default
+    pub web_listen: Option<SocketAddr>,
+    /// Serve the web server over HTTPS, optionally requiring client
+    /// certificates; see `WebTlsConfig`
+    pub web_tls: Option<WebTlsConfig>,
+    /// How many meters may be polled at the same moment; unlimited by default
+    pub max_concurrent_polls: Option<usize>,
+    /// Spread the meters' first connections evenly over this long, so they
+    /// don't all poll in step
+    #[serde(with = "humantime_serde", default)]
+    pub stagger: Duration,
+    /// Give meters at the same address, such as several meters behind one
+    /// serial gateway, poll offsets spread evenly over their fastest
+    /// interval, unless they set `poll_offset` themselves
+    #[serde(default = "default_stagger_gateways")]
+    pub stagger_gateways: bool,
+    /// Exit if a meter can't be reached in this many connection attempts
+    /// from startup, rather than keep retrying it
+    pub fail_fast: Option<u32>,
+    /// Whether to exit or keep polling when one of the servers fails; see
+    /// `ServerFailure`
+    #[serde(default)]
+    pub server_failure: ServerFailure,
+    /// How long to keep every reading in memory for `/history`; off by default
+    #[serde(with = "humantime_serde", default)]
+    pub history: Option<Duration>,
+    /// IANA timezone of CSV timestamps, the dashboard and daily boundaries;
+    /// UTC if not given
+    pub timezone: Option<String>,
+    /// Averages kept for longer than the readings themselves, e.g. "1m:30d"
+    #[serde(default)]
+    pub history_tiers: Vec<HistoryTier>,
+    /// Where state worked out from the readings is saved across restarts
+    pub state_file: Option<PathBuf>,
+    /// Where meter writes, resets and other changes are recorded
+    pub audit_log: Option<PathBuf>,
+    /// Parquet files that every sample is archived to
+    pub archive: Option<ArchiveConfig>,
+    /// Rhai script whose `on_reading` hook sees every poll's readings
+    pub script: Option<PathBuf>,
+    /// Advertise the web API over mDNS as `_sharkmon._tcp`, so apps on the
+    /// LAN can find it
+    #[serde(default)]
+    pub mdns: bool,
+    /// The name to advertise under; the host's name by default
+    pub mdns_name: Option<String>,
+    /// Expose the readings as BACnet Analog Inputs; off by default
+    pub bacnet: Option<BacnetConfig>,
+    /// Expose the readings as DNP3 analog points; off by default
+    pub dnp3: Option<Dnp3Config>,
+    /// Expose the readings as OPC UA variables; off by default
+    pub opcua: Option<OpcUaConfig>,
+    /// Poll only while leading a pair of instances; see `ha`
+    pub ha: Option<HaConfig>,
+    /// Where alerts are sent as SNMP notifications
+    pub snmp: Option<SnmpConfig>,
+    #[serde(rename = "alert", default)]
+    pub alerts: Vec<AlertConfig>,
+    /// How generator transfers are recognised, for `/events`
+    #[serde(default)]
+    pub transfer: TransferConfig,
+    /// Which readings the hourly power quality scores come from
+    #[serde(default)]
+    pub quality: QualityConfig,
+    #[serde(rename = "meter")]
+    pub meters: Vec<MeterConfig>,
+    #[serde(rename = "sink", default)]
+    pub sinks: Vec<SinkConfig>,
+}
+
+/// How samples are encoded for a sink.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub enum SinkFormat {
+    /// One JSON object per request, as printed by `--output json-lines`
+    #[default]
+    Json,
+    /// InfluxDB line protocol, for its HTTP write API
+    Influx,
+    /// Each reading as the state of an openHAB item, through its REST API
+    Openhab,
+    /// Each reading as the value of a Domoticz device, through its JSON API
+    Domoticz,
+    /// Line protocol pushed to a Grafana Live stream, for live panels
+    Grafana,
+    /// A sink added with `sink::register` by a crate embedding sharkmon
+    Other(String),
+}
+
+impl From<String> for SinkFormat {
+    fn from(s: String) -> SinkFormat {
+        match s.as_str() {
+            "json" => SinkFormat::Json,
+            "influx" => SinkFormat::Influx,
+            "openhab" => SinkFormat::Openhab,
+            "domoticz" => SinkFormat::Domoticz,
+            "grafana" => SinkFormat::Grafana,
+            _ => SinkFormat::Other(s),
+        }
+    }
+}
+
+impl std::fmt::Display for SinkFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            SinkFormat::Json => "json",
+            SinkFormat::Influx => "influx",
+            SinkFormat::Openhab => "openhab",
+            SinkFormat::Domoticz => "domoticz",
+            SinkFormat::Grafana => "grafana",
+            SinkFormat::Other(name) => name,
+        })
+    }
+}
+
+impl Serialize for SinkFormat {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// An exporter every sample is sent to as it is polled, by default an HTTP
+/// endpoint. On the command line this is written `[FORMAT=]URL`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SinkConfig {
+    /// Used in logs and `/status`; defaults to the URL
+    pub name: Option<String>,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub format: SinkFormat,
+    /// Extra request headers, e.g. `Authorization = "Token ..."` for
+    /// InfluxDB; see `Secret` for keeping tokens out of the file
+    #[serde(default)]
+    pub headers: BTreeMap<String, Secret>,
+    #[serde(with = "humantime_serde", default = "default_timeout")]
+    pub timeout: Duration,
+    /// File that samples are kept in while the sink is down
+    pub buffer: Option<PathBuf>,
+    /// Size limit of the buffer file, beyond which new samples are dropped
+    #[serde(default = "default_buffer_max_bytes")]
+    pub buffer_max_bytes: u64,
+    /// Most samples sent in one request, for the sinks that can send several
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// How long a batch is collected for before it is sent short; without
+    /// one, a batch is whatever is waiting when the sink is ready
+    #[serde(with = "humantime_serde", default)]
+    pub flush_interval: Option<Duration>,
+    /// Samples waiting for the sink, beyond which new samples are dropped
+    #[serde(default = "default_queue_depth")]
+    pub queue_depth: usize,
+    /// How far each reading must move from what the sink was last sent
+    /// before it is sent again, by name, with "*" for the readings not named;
+    /// readings without one are always sent
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub deadband: BTreeMap<String, Deadband>,
+    /// How long a reading held back by its deadband may go unsent
+    #[serde(with = "humantime_serde", default)]
+    pub max_interval: Option<Duration>,
+    /// Settings for a registered sink format, which the built-in ones ignore
+    #[serde(default)]
+    pub options: toml::Table,
+}
+
+pub fn default_buffer_max_bytes() -> u64 {
+    16 << 20
+}
+
+pub fn default_batch_size() -> usize {
+    1
+}
+
+pub fn default_queue_depth() -> usize {
+    1024
+}
+
+impl SinkConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.url)
+    }
+
+    /// The deadband of the named reading, if it has one.
+    pub fn deadband(&self, reading: &str) -> Option<Deadband> {
+        self.deadband
+            .get(reading)
+            .or_else(|| self.deadband.get("*"))
+            .copied()
+    }
+}
+
+/// How far a reading must move to be sent again: by so much, written as a
+/// number, or by a percentage of its last value, written e.g. "0.5%".
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "DeadbandEntry")]
+pub enum Deadband {
+    Absolute(f32),
+    Percent(f32),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeadbandEntry {
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<DeadbandEntry> for Deadband {
+    type Error = String;
+
+    fn try_from(entry: DeadbandEntry) -> Result<Deadband, String> {
+        match entry {
+            DeadbandEntry::Number(n) => Ok(Deadband::Absolute(n as f32)),
+            DeadbandEntry::Text(s) => s.parse(),
+        }
+    }
+}
+
+impl FromStr for Deadband {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Deadband, String> {
+        let invalid = || format!("invalid deadband '{s}'; expected e.g. 5 or \"0.5%\"");
+        match s.trim().strip_suffix('%') {
+            Some(percent) => percent.trim().parse().map(Deadband::Percent),
+            None => s.trim().parse().map(Deadband::Absolute),
+        }
+        .map_err(|_| invalid())
+    }
+}
+
+impl std::fmt::Display for Deadband {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Deadband::Absolute(band) => write!(f, "{band}"),
+            Deadband::Percent(band) => write!(f, "{band}%"),
+        }
+    }
+}
+
+impl Serialize for Deadband {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Deadband::Absolute(band) => serializer.serialize_f32(*band),
+            Deadband::Percent(_) => serializer.collect_str(self),
+        }
+    }
+}
+
+impl Deadband {
+    fn band(self) -> f32 {
+        match self {
+            Deadband::Absolute(band) | Deadband::Percent(band) => band,
+        }
+    }
+
+    /// Whether `value` has moved past the band from `last`. A reading that
+    /// has become, or stopped being, unavailable (NaN) has always moved.
+    pub fn exceeded(self, last: f32, value: f32) -> bool {
+        if last.is_nan() || value.is_nan() {
+            return last.is_nan() != value.is_nan();
+        }
+        let moved = (value - last).abs();
+        match self {
+            Deadband::Absolute(band) => moved > band,
+            Deadband::Percent(band) => moved > last.abs() * band / 100.0,
+        }
+    }
+}
+
+impl FromStr for SinkConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SinkConfig, String> {
+        let (format, url) = match s.split_once('=') {
+            Some((format, url))
+                if matches!(
+                    format,
+                    "json" | "influx" | "openhab" | "domoticz" | "grafana"
+                ) || crate::sink::is_registered(format) =>
+            {
+                (SinkFormat::from(format.to_owned()), url)
+            }
+            _ => (SinkFormat::Json, s),
+        };
+        Ok(SinkConfig {
+            name: None,
+            url: url.to_owned(),
+            format,
+            headers: Default::default(),
+            timeout: default_timeout(),
+            buffer: None,
+            buffer_max_bytes: default_buffer_max_bytes(),
+            batch_size: default_batch_size(),
+            flush_interval: None,
+            queue_depth: default_queue_depth(),
+            deadband: BTreeMap::new(),
+            max_interval: None,
+            options: toml::Table::new(),
+        })
+    }
+}
+
+/// The BACnet/IP server, which makes sharkmon one BACnet device with an
+/// Analog Input object for every reading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BacnetConfig {
+    /// The device's instance number, unique on the BACnet internetwork
+    pub device_id: u32,
+    /// The device's name; "sharkmon" by default
+    pub name: Option<String>,
+    #[serde(default = "default_bacnet_port")]
+    pub port: u16,
+}
+
+pub fn default_bacnet_port() -> u16 {
+    crate::bacnet::PORT
+}
+
+/// Leader/standby pairing with another sharkmon watching the same meters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HaConfig {
+    /// The other instance's heartbeat address, e.g. "10.0.0.2:8099"
+    pub peer: String,
+    /// Where the peer's heartbeats arrive
+    #[serde(default = "default_ha_listen")]
+    pub listen: SocketAddr,
+    /// Of two instances both up, the one with the higher priority leads
+    #[serde(default)]
+    pub priority: u8,
+    /// How long the peer may be silent before this instance takes over
+    #[serde(with = "humantime_serde", default = "default_ha_timeout")]
+    pub timeout: Duration,
+}
+
+pub fn default_ha_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], crate::ha::PORT))
+}
+
+pub fn default_ha_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// The DNP3 outstation, which presents readings as analog input points.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dnp3Config {
+    /// Where masters connect
+    #[serde(default = "default_dnp3_listen")]
+    pub listen: SocketAddr,
+    /// The outstation's link address
+    #[serde(default = "default_dnp3_address")]
+    pub address: u16,
+    /// The link address of the master it answers
+    #[serde(default = "default_dnp3_master")]
+    pub master: u16,
+    /// The readings to present, in point order, each "device.reading" (or
+    /// just the reading with one device); every reading by default
+    #[serde(default)]
+    pub points: Vec<String>,
+    /// How far a reading must move to report a change event; any change by
+    /// default
+    #[serde(default)]
+    pub deadband: f64,
+}
+
+pub fn default_dnp3_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 20000))
+}
+
+pub fn default_dnp3_address() -> u16 {
+    1024
+}
+
+pub fn default_dnp3_master() -> u16 {
+    1
+}
+
+/// The OPC UA server, which presents readings as variables.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpcUaConfig {
+    /// Where clients connect
+    #[serde(default = "default_opcua_listen")]
+    pub listen: SocketAddr,
+    /// The application name clients see
+    #[serde(default = "default_opcua_name")]
+    pub name: String,
+    /// Where the server keeps its own certificate, created on first run,
+    /// and those of the clients it has seen
+    #[serde(default = "default_opcua_pki_dir")]
+    pub pki_dir: PathBuf,
+}
+
+pub fn default_opcua_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 4840))
+}
+
+pub fn default_opcua_name() -> String {
+    "sharkmon".to_owned()
+}
+
+pub fn default_opcua_pki_dir() -> PathBuf {
+    PathBuf::from("pki")
+}
+
+/// How generator transfers show in the readings: the voltage dropping out,
+/// and then the frequency off nominal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TransferConfig {
+    pub volts: String,
+    pub frequency: String,
+    /// The fraction of the utility's voltage below which it is out
+    pub dropout: f64,
+    /// How far from 50 or 60 Hz the frequency is off nominal
+    pub tolerance: f64,
+}
+
+impl Default for TransferConfig {
+    fn default() -> TransferConfig {
+        TransferConfig {
+            volts: "volts".to_owned(),
+            frequency: "frequency".to_owned(),
+            dropout: 0.5,
+            tolerance: 0.5,
+        }
+    }
+}
+
+/// The readings power quality is scored on, and the supply's nominal
+/// voltage and frequency if they aren't to be guessed from the first reading.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct QualityConfig {
+    pub volts: String,
+    pub frequency: String,
+    /// Total harmonic distortion of the voltage, in %, counted if read
+    pub thd: String,
+    pub nominal_volts: Option<f64>,
+    pub nominal_frequency: Option<f64>,
+}
+
+impl Default for QualityConfig {
+    fn default() -> QualityConfig {
+        QualityConfig {
+            volts: "volts".to_owned(),
+            frequency: "frequency".to_owned(),
+            thd: "thd".to_owned(),
+            nominal_volts: None,
+            nominal_frequency: None,
+        }
+    }
+}
+
+/// A rule that fires while a reading is above or below a limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    pub name: String,
+    /// "device.reading", or a reading of every device that has it
+    pub reading: String,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+}
+
+impl AlertConfig {
+    /// Whether the limits make sense, saying what is wrong if not.
+    pub fn check_limits(&self) -> Result<(), String> {
+        match (self.above, self.below) {
+            (None, None) => Err("needs a limit: above or below".to_owned()),
+            (Some(above), Some(below)) if below >= above => {
+                Err("is always firing: below is not less than above".to_owned())
+            }
+            (above, below) if above.into_iter().chain(below).any(|l| !l.is_finite()) => {
+                Err("has a limit that isn't a number".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The receivers that alerts are sent to as SNMPv2c notifications.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnmpConfig {
+    /// Each "host[:port]", on port 162 by default
+    pub receivers: Vec<String>,
+    #[serde(default = "default_snmp_community")]
+    pub community: Secret,
+    /// Send informs, which receivers acknowledge, rather than traps
+    #[serde(default)]
+    pub inform: bool,
+    /// The OID that notifications and their variables are under
+    #[serde(default = "default_snmp_oid")]
+    pub oid: String,
+    /// How long to wait for an inform's acknowledgement before sending it
+    /// again
+    #[serde(with = "humantime_serde", default = "default_snmp_timeout")]
+    pub timeout: Duration,
+}
+
+pub fn default_snmp_community() -> Secret {
+    Secret("public".to_owned())
+}
+
+pub fn default_snmp_oid() -> String {
+    crate::snmp::DEFAULT_OID.to_owned()
+}
+
+pub fn default_snmp_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// What happens when a server sharkmon runs besides polling, such as the web
+/// server, the BACnet server or a Modbus/TCP proxy, fails to start or stops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerFailure {
+    /// Stop polling too, and exit with the server's error
+    #[default]
+    Exit,
+    /// Log the error and keep polling without the server
+    Continue,
+}
+
+/// How often the archive starts a new directory of files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Partition {
+    Hourly,
+    #[default]
+    Daily,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub partition: Partition,
+    /// How often buffered samples are written out as a new file
+    #[serde(with = "humantime_serde", default = "default_archive_flush")]
+    pub flush: Duration,
+}
+
+pub fn default_archive_flush() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+
+/// Averages over `step`, kept for `keep`, written as `STEP:KEEP`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HistoryTier {
+    pub step: Duration,
+    pub keep: Duration,
+}
+
+impl FromStr for HistoryTier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HistoryTier, String> {
+        let (step, keep) = s
+            .split_once(':')
+            .ok_or_else(|| format!("'{s}' should be STEP:KEEP, e.g. 1m:30d"))?;
+        let parse = |d: &str| humantime::parse_duration(d).map_err(|e| format!("'{d}': {e}"));
+        Ok(HistoryTier {
+            step: parse(step)?,
+            keep: parse(keep)?,
+        })
+    }
+}
+
+impl TryFrom<String> for HistoryTier {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<HistoryTier, String> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for HistoryTier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let format = humantime::format_duration;
+        write!(f, "{}:{}", format(self.step), format(self.keep))
+    }
+}
+
+impl Serialize for HistoryTier {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Names and values attached to every reading of a meter's devices, such as
+/// the site or panel it measures.
+pub type Labels = BTreeMap<String, String>;
+
+/// Label names that the outputs already use for something else.
+const RESERVED_LABELS: [&str; 5] = ["meter", "device", "reading", "sink", "le"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MeterConfig {
+    pub name: String,
+    /// Hostname or IP address, and port
+    pub address: String,
+    /// Added to Prometheus series, InfluxDB tags and JSON samples, e.g.
+    /// `labels = { site = "hq", panel = "B" }`
+    #[serde(default)]
+    pub labels: Labels,
+    /// Other addresses of the same meter, such as its second Ethernet port or
+    /// a backup gateway, tried in order when `address` can't be reached
+    #[serde(default)]
+    pub failover: Vec<String>,
+    /// How Modbus reaches the meter: over TCP, or over UDP for RTU bridges
+    /// that speak it
+    #[serde(default)]
+    pub transport: Transport,
+    /// Built-in register map; "shark100" unless a map or SunSpec is given
+    pub profile: Option<String>,
+    pub register_map: Option<PathBuf>,
+    #[serde(default)]
+    pub sunspec: bool,
+    /// The firmware version that picks the register map's variant, instead
+    /// of the version the meter reports, e.g. for a meter that reports it
+    /// wrongly or not at all
+    pub firmware: Option<String>,
+    #[serde(default)]
+    pub register_formats: Vec<FormatOverride>,
+    /// Units to report readings in instead of the register map's, e.g.
+    /// `output_units = { watts = "kW", kwh = "MWh" }`
+    #[serde(default)]
+    pub output_units: BTreeMap<String, String>,
+    /// Decimal places to give readings to in JSON and other serialized
+    /// output, e.g. `precision = { watts = 1, frequency = 3 }`
+    #[serde(default)]
+    pub precision: BTreeMap<String, u8>,
+    /// Readings worked out from others after every poll, e.g.
+    /// `amps_est = "watts / volts"`
+    #[serde(default)]
+    pub derived: BTreeMap<String, Expression>,
+    /// Which way the meter's power readings count as positive
+    #[serde(default)]
+    pub sign: Sign,
+    /// Signed readings to also give as `NAME_import` and `NAME_export`, each
+    /// positive or zero, e.g. `split = ["watts"]`
+    #[serde(default)]
+    pub split: Vec<String>,
+    #[serde(default = "default_read_gap")]
+    pub read_gap: u16,
+    /// Idle time before TCP keepalive probes start; zero disables them
+    #[serde(with = "humantime_serde", default = "default_keepalive")]
+    pub keepalive: Duration,
+    /// How long to wait for the meter to connect or answer a read
+    #[serde(with = "humantime_serde", default = "default_timeout")]
+    pub timeout: Duration,
+    /// Read one register when nothing else has been polled for this long, so a
+    /// dead connection is noticed; zero disables it
+    #[serde(with = "humantime_serde", default = "default_heartbeat")]
+    pub heartbeat: Duration,
+    /// How many times to retry a failed read before reconnecting
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Poll each group on multiples of its interval in wall-clock time, e.g.
+    /// on :00, :15, :30 and :45 for a 15-minute group
+    #[serde(default)]
+    pub align: bool,
+    /// With `align`, delay each poll by a random amount up to this long, so
+    /// many instances don't all read their gateways at the same instant
+    #[serde(with = "humantime_serde", default)]
+    pub align_jitter: Duration,
+    /// Poll this long after each boundary of the wall clock a group's
+    /// interval would align to, so meters sharing a gateway take turns
+    #[serde(with = "humantime_serde", default)]
+    pub poll_offset: Option<Duration>,
+    /// Slow each group's polling, up to this interval, while its readings
+    /// hold steady, and go back to its own interval when they change
+    #[serde(with = "humantime_serde", default)]
+    pub adaptive: Option<Duration>,
+    /// How much a reading must move between polls, as a fraction of its
+    /// value, to count as changing
+    #[serde(default = "default_adaptive_threshold")]
+    pub adaptive_threshold: f64,
+    /// How often the readings polled most often are polled, in place of the
+    /// register map's interval for them (1s for the Shark profiles)
+    #[serde(with = "humantime_serde", default)]
+    pub poll_interval: Option<Duration>,
+    /// The weight of each new reading in the smoothed readings, from just
+    /// above 0 for heavy smoothing to 1 for none
+    #[serde(default = "default_ewma_alpha")]
+    pub ewma_alpha: f32,
+    /// How often to check the meter's clock, and set it if it has drifted;
+    /// needs a `[clock]` table in the register map
+    #[serde(with = "humantime_serde", default)]
+    pub clock_sync: Option<Duration>,
+    /// After reconnecting, add what the meter logged while it couldn't be
+    /// polled to the history; needs a `[log]` table in the register map
+    #[serde(default)]
+    pub backfill: bool,
+    /// Holding registers that `/api/v1/modbus/write` may write
+    #[serde(default)]
+    pub writable: Vec<RegisterRange>,
+    /// Modbus unit IDs polled over this connection
+    #[serde(rename = "unit", default = "default_units")]
+    pub units: Vec<UnitConfig>,
+    pub tls: Option<TlsConfig>,
+    /// Pass Modbus/TCP requests from other clients through to the meter
+    pub proxy: Option<ProxyConfig>,
+}
+
+pub fn default_read_gap() -> u16 {
+    32
+}
+
+fn default_stagger_gateways() -> bool {
+    true
+}
+
+pub fn default_keepalive() -> Duration {
+    Duration::from_secs(10)
+}
+
+pub fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+pub fn default_heartbeat() -> Duration {
+    Duration::from_secs(10)
+}
+
+pub fn default_adaptive_threshold() -> f64 {
+    0.01
+}
+
+pub fn default_ewma_alpha() -> f32 {
+    0.2
+}
+
+/// The settings of a meter that can be changed while it is polled, through
+/// `/api/v1/config`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MeterSettings {
+    #[serde(with = "humantime_serde")]
+    pub poll_interval: Option<Duration>,
+    pub ewma_alpha: f32,
+}
+
+impl MeterSettings {
+    pub fn validate(&self) -> Result<(), Invalid> {
+        if self.poll_interval.is_some_and(|i| i.is_zero()) {
+            return Err(Invalid::new(
+                "poll_interval",
+                "poll_interval must be longer than zero, e.g. \"1s\"",
+            ));
+        }
+        if !(self.ewma_alpha > 0.0 && self.ewma_alpha <= 1.0) {
+            return Err(Invalid::new(
+                "ewma_alpha",
+                format!(
+                    "ewma_alpha must be more than 0 and at most 1; {} by default",
+                    default_ewma_alpha()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn default_retries() -> u32 {
+    2
+}
+
+fn default_units() -> Vec<UnitConfig> {
+    vec![UnitConfig {
+        id: 1,
+        name: None,
+        total: Total::Add,
+    }]
+}
+
+/// The direction of power flow a meter reads as positive. Readings in W or
+/// var from a meter reading export as positive are negated, so that power
+/// drawn from the grid is always positive and power sent back negative.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sign {
+    #[default]
+    Import,
+    Export,
+}
+
+/// How Modbus requests reach a meter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// Modbus/TCP frames sent as datagrams, each sent again until answered
+    Udp,
+}
+
+/// A Modbus/TCP server that passes requests through to a meter, for
+/// software that needs its own view of the meter's registers. Requests are
+/// sent over sharkmon's connection between its polls, so the meter still sees
+/// one client, and at no more than `requests_per_second` from all the clients
+/// together.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub listen: SocketAddr,
+    /// Registers that may be read; all of them if empty
+    #[serde(default)]
+    pub readable: Vec<RegisterRange>,
+    /// Function codes passed through: 3 and 4 to read holding and input
+    /// registers, and 6 and 16 to write holding registers within `writable`
+    #[serde(default = "default_proxy_functions")]
+    pub functions: Vec<u8>,
+    #[serde(default = "default_proxy_rate")]
+    pub requests_per_second: f64,
+}
+
+pub fn default_proxy_functions() -> Vec<u8> {
+    vec![3, 4]
+}
+
+pub fn default_proxy_rate() -> f64 {
+    5.0
+}
+
+/// How a device counts towards `/power/total`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Total {
+    #[default]
+    Add,
+    /// For meters that read positive for power flowing the other way, such as
+    /// on a solar inverter
+    Subtract,
+    Exclude,
+}
+
+/// A device behind the meter connection. On the command line this is
+/// written `ID[=NAME]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnitConfig {
+    pub id: u8,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub total: Total,
+}
+
+impl FromStr for UnitConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UnitConfig, String> {
+        let (id, name) = match s.split_once('=') {
+            Some((id, name)) => (id, Some(name.to_owned())),
+            None => (s, None),
+        };
+        let id = id
+            .parse()
+            .map_err(|e| format!("invalid unit ID '{id}': {e}"))?;
+        Ok(UnitConfig {
+            id,
+            name,
+            total: Total::Add,
+        })
+    }
+}
+
+/// HTTPS for the web server. With a `client_ca`, clients must present a
+/// certificate it signed, and the certificate's common name (CN) decides
+/// what they may do:
+///
+/// ```toml
+/// [web_tls]
+/// cert = "/etc/sharkmon/server.pem"
+/// key = "/etc/sharkmon/server.key"
+/// client_ca = "/etc/sharkmon/clients.pem"
+/// access = { "ops-laptop" = "operator" }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebTlsConfig {
+    /// PEM file with the server's certificate chain
+    pub cert: PathBuf,
+    /// PEM file with the private key for `cert`
+    pub key: PathBuf,
+    /// PEM file with the CA certificates that client certificates must be
+    /// signed by; client certificates aren't asked for if not given
+    pub client_ca: Option<PathBuf>,
+    /// The role of client certificates by common name; any other is a
+    /// viewer
+    #[serde(default)]
+    pub access: BTreeMap<String, Role>,
+    /// Obtain `cert` and `key` from an ACME CA such as Let's Encrypt, and
+    /// renew them before they expire
+    pub acme: Option<AcmeConfig>,
+}
+
+/// Certificates for the web server from an ACME CA, kept in the `cert` and
+/// `key` files of `[web_tls]`. The CA checks each of the `domains` resolves
+/// to sharkmon by fetching a token over HTTP on port 80 (HTTP-01), or, with
+/// a `dns_hook`, by looking up a TXT record the hook publishes (DNS-01):
+///
+/// ```toml
+/// [web_tls.acme]
+/// domains = ["power.example.com"]
+/// email = "ops@example.com"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    /// Where the CA may send expiry warnings
+    pub email: Option<String>,
+    /// The CA's directory URL; Let's Encrypt's by default
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    /// PEM file with the ACME account's key, made if it doesn't exist;
+    /// `acme-account.key` beside `key` by default
+    pub account_key: Option<PathBuf>,
+    /// Where HTTP-01 tokens are served
+    #[serde(default = "default_acme_http_listen")]
+    pub http_listen: SocketAddr,
+    /// Program run as `hook set <name> <value>` to publish a DNS-01 TXT
+    /// record, returning once it can be looked up, and `hook clear <name>
+    /// <value>` to remove it
+    pub dns_hook: Option<PathBuf>,
+}
+
+pub fn default_acme_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_owned()
+}
+
+pub fn default_acme_http_listen() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 80))
+}
+
+/// What the holder of an API key or client certificate may do through the
+/// web server, each role able to do everything the one before it can.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Read the readings, which needs no key
+    #[default]
+    #[serde(alias = "read-only")]
+    Viewer,
+    /// Also reset meters and write registers
+    Operator,
+    /// Also change the configuration while sharkmon runs
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+/// A key accepted by the web server, given either as the key alone, for an
+/// operator, or as a table:
+///
+/// ```toml
+/// api_keys = [
+///     "key-for-a-script",
+///     { key = "...", role = "admin", name = "ops" },
+/// ]
+/// ```
+///
+/// On the command line this is written `[ROLE=]KEY`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "ApiKeyEntry")]
+pub struct ApiKey {
+    pub key: Secret,
+    pub role: Role,
+    /// Who holds the key, for the audit log
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ApiKeyEntry {
+    Key(String),
+    Table {
+        key: String,
+        #[serde(default = "default_key_role")]
+        role: Role,
+        name: Option<String>,
+    },
+}
+
+fn default_key_role() -> Role {
+    Role::Operator
+}
+
+impl TryFrom<ApiKeyEntry> for ApiKey {
+    type Error = String;
+
+    fn try_from(entry: ApiKeyEntry) -> Result<ApiKey, String> {
+        Ok(match entry {
+            ApiKeyEntry::Key(key) => ApiKey {
+                key: key.parse()?,
+                role: default_key_role(),
+                name: None,
+            },
+            ApiKeyEntry::Table { key, role, name } => ApiKey {
+                key: key.parse()?,
+                role,
+                name,
+            },
+        })
+    }
+}
+
+impl FromStr for ApiKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ApiKey, String> {
+        let roles = [Role::Viewer, Role::Operator, Role::Admin];
+        let role = s.split_once('=').and_then(|(role, key)| {
+            Some((roles.into_iter().find(|r| r.to_string() == role)?, key))
+        });
+        let (role, key) = role.unwrap_or((default_key_role(), s));
+        Ok(ApiKey {
+            key: key.parse()?,
+            role,
+            name: None,
+        })
+    }
+}
+
+/// A password, token or key. It can be written as is, or kept out of the
+/// configuration as `file:/run/secrets/name`, read from that file less a
+/// trailing newline, or `env:NAME`, taken from that environment variable.
+/// Debug output and configuration dumps show it as `[redacted]`.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// The secret `s` stands for, taking `env:` variables from `env`.
+    fn resolve(
+        s: &str,
+        env: impl Fn(&str) -> Result<String, std::env::VarError>,
+    ) -> Result<Secret, String> {
+        if let Some(path) = s.strip_prefix("file:") {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+            let text = text.strip_suffix('\n').unwrap_or(&text);
+            Ok(Secret(text.strip_suffix('\r').unwrap_or(text).to_owned()))
+        } else if let Some(name) = s.strip_prefix("env:") {
+            env(name)
+                .map(Secret)
+                .map_err(|e| format!("environment variable {name}: {e}"))
+        } else {
+            Ok(Secret(s.to_owned()))
+        }
+    }
+}
+
+impl FromStr for Secret {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Secret, String> {
+        Secret::resolve(s, |name| std::env::var(name))
+    }
+}
+
+impl TryFrom<String> for Secret {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Secret, String> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("[redacted]")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub ca: PathBuf,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub server_name: Option<String>,
+}
+
+impl MeterConfig {
+    pub fn settings(&self) -> MeterSettings {
+        MeterSettings {
+            poll_interval: self.poll_interval,
+            ewma_alpha: self.ewma_alpha,
+        }
+    }
+
+    /// The name of each unit's device. A lone unit is named after the meter;
+    /// otherwise unnamed units are called "unit<ID>".
+    pub fn device_names(&self) -> Vec<String> {
+        self.units
+            .iter()
+            .map(|u| match &u.name {
+                Some(name) => name.clone(),
+                None if self.units.len() == 1 => self.name.clone(),
+                None => format!("unit{}", u.id),
+            })
+            .collect()
+    }
+}
+
+/// What is wrong with a configuration, and the setting it is wrong in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invalid {
+    /// The setting as a dotted path of keys and array indexes, such as
+    /// `meter.0.timeout`; empty where it isn't known
+    pub key: String,
+    pub message: String,
+    /// The line of the file the setting is on, where it has been found
+    pub line: Option<usize>,
+}
+
+impl Invalid {
+    pub fn new(key: impl Into<String>, message: impl Into<String>) -> Invalid {
+        Invalid {
+            key: key.into(),
+            message: message.into(),
+            line: None,
+        }
+    }
+
+    /// The same error in the setting `key`, which this one's key is
+    /// relative to.
+    fn within(mut self, key: &str) -> Invalid {
+        self.key = format!("{key}.{}", self.key);
+        self
+    }
+}
+
+impl std::fmt::Display for Invalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+/// The line that byte `offset` of `text` is on.
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// The line that the setting `key` is on in `text`, or the nearest table or
+/// array around it that is there, such as the `[[meter]]` table of a
+/// meter's setting left at its default.
+fn locate(text: &str, key: &str) -> Option<usize> {
+    use toml::de::{DeTable, DeValue};
+    let root = DeValue::Table(DeTable::parse(text).ok()?.into_inner());
+    let mut span = None;
+    let mut value = &root;
+    for segment in key.split('.').filter(|s| !s.is_empty()) {
+        let next = match value {
+            DeValue::Table(table) => table.get(segment),
+            DeValue::Array(array) => segment.parse().ok().and_then(|i: usize| array.get(i)),
+            _ => None,
+        };
+        let Some(next) = next else { break };
+        span = Some(next.span());
+        value = next.get_ref();
+    }
+    span.map(|span| line_of(text, span.start))
+}
+
+/// `message` from the TOML parser, with the closest match among the keys or
+/// values it expected for one it didn't: "unknown field `timout`, expected
+/// one of ..." becomes "unknown field `timout`; did you mean `timeout`?".
+fn suggest(message: &str) -> String {
+    let unknown = ["unknown field `", "unknown variant `"];
+    let Some(rest) = unknown.iter().find_map(|u| message.strip_prefix(u)) else {
+        return message.to_owned();
+    };
+    let Some((given, expected)) = rest.split_once('`') else {
+        return message.to_owned();
+    };
+    let closest = expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|candidate| (strsim::levenshtein(given, candidate), candidate))
+        .min();
+    match closest {
+        Some((distance, candidate)) if distance <= given.len().div_ceil(3).max(1) => {
+            let what = &message[..message.len() - rest.len()];
+            format!("{what}{given}`; did you mean `{candidate}`?")
+        }
+        _ => message.to_owned(),
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> std::io::Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        Config::parse(&text).map_err(|e| {
+            let place = match e.line {
+                Some(line) => format!("{}:{line}", path.display()),
+                None => path.display().to_string(),
+            };
+            let key = if e.key.is_empty() {
+                String::new()
+            } else {
+                format!("{}: ", e.key)
+            };
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{place}: {key}{}", e.message),
+            )
+        })
+    }
+
+    /// Read and validate the text of a configuration file, finding the line
+    /// of anything wrong with it.
+    pub fn parse(text: &str) -> Result<Config, Invalid> {
+        let config: Config = toml::from_str(text).map_err(|e| Invalid {
+            key: String::new(),
+            message: suggest(e.message()),
+            line: e.span().map(|span| line_of(text, span.start)),
+        })?;
+        config.validate().map_err(|e| Invalid {
+            line: locate(text, &e.key),
+            ..e
+        })?;
+        Ok(config)
+    }
+
+    /// For each meter staggered with the others at its address, its place
+    /// among them and how many there are.
+    pub fn gateway_shares(&self) -> Vec<Option<(usize, usize)>> {
+        let staggered = |m: &MeterConfig| self.stagger_gateways && m.poll_offset.is_none();
+        self.meters
+            .iter()
+            .map(|m| {
+                if !staggered(m) {
+                    return None;
+                }
+                let sharing: Vec<&MeterConfig> = self
+                    .meters
+                    .iter()
+                    .filter(|o| staggered(o) && o.address.eq_ignore_ascii_case(&m.address))
+                    .collect();
+                let index = sharing.iter().position(|o| std::ptr::eq(*o, m))?;
+                (sharing.len() > 1).then_some((index, sharing.len()))
+            })
+            .collect()
+    }
+
+    /// Write the meter `settings` and alert limits changed while sharkmon
+    /// runs back to the configuration file at `path`, leaving the rest as
+    /// it was, apart from comments and layout, which aren't kept.
+    pub fn save_settings(
+        path: &Path,
+        settings: &BTreeMap<String, MeterSettings>,
+        alerts: &[AlertConfig],
+    ) -> std::io::Result<()> {
+        let invalid =
+            |e: String| Error::new(ErrorKind::InvalidData, format!("{}: {e}", path.display()));
+        let text = std::fs::read_to_string(path)?;
+        let mut table: toml::Table = toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        let set = |table: &mut toml::Table, key: &str, value: Option<toml::Value>| match value {
+            Some(value) => table.insert(key.to_owned(), value),
+            None => table.remove(key),
+        };
+        fn tables<'a>(table: &'a mut toml::Table, key: &str) -> Vec<&'a mut toml::Table> {
+            match table.get_mut(key) {
+                Some(toml::Value::Array(entries)) => entries
+                    .iter_mut()
+                    .filter_map(toml::Value::as_table_mut)
+                    .collect(),
+                _ => Vec::new(),
+            }
+        }
+        let name = |table: &toml::Table| table.get("name")?.as_str().map(str::to_owned);
+        for meter in tables(&mut table, "meter") {
+            let Some(settings) = name(meter).and_then(|n| settings.get(&n)) else {
+                continue;
+            };
+            let interval = settings
+                .poll_interval
+                .map(|i| toml::Value::String(humantime::format_duration(i).to_string()));
+            set(meter, "poll_interval", interval);
+            // Written as shown, rather than as the nearest f64 to the f32.
+            let alpha = settings.ewma_alpha.to_string().parse().unwrap();
+            if settings.ewma_alpha != default_ewma_alpha() || meter.contains_key("ewma_alpha") {
+                set(meter, "ewma_alpha", Some(toml::Value::Float(alpha)));
+            }
+        }
+        for rule in tables(&mut table, "alert") {
+            let Some(alert) = name(rule).and_then(|n| alerts.iter().find(|a| a.name == n)) else {
+                continue;
+            };
+            set(rule, "above", alert.above.map(toml::Value::Float));
+            set(rule, "below", alert.below.map(toml::Value::Float));
+        }
+        let text = toml::to_string(&table).map_err(|e| invalid(e.to_string()))?;
+        let mut temporary = path.to_owned().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, text)?;
+        std::fs::rename(&temporary, path)
+    }
+
+    pub fn validate(&self) -> Result<(), Invalid> {
+        if let Some(version) = self.version {
+            if !(1..=CONFIG_VERSION).contains(&version) {
+                return Err(Invalid::new(
+                    "version",
+                    format!(
+                        "version {version} isn't one this sharkmon reads, which is up to \
+                         version {CONFIG_VERSION}; a later sharkmon may read it"
+                    ),
+                ));
+            }
+        }
+        if self.meters.is_empty() {
+            return Err(Invalid::new(
+                "meter",
+                "no meters configured; add a [[meter]] table with a name and address",
+            ));
+        }
+        if self.fail_fast == Some(0) {
+            return Err(Invalid::new(
+                "fail_fast",
+                "fail_fast must be at least one attempt",
+            ));
+        }
+        let mut sinks = HashSet::new();
+        for (i, s) in self.sinks.iter().enumerate() {
+            let key = |field: &str| format!("sink.{i}.{field}");
+            if s.name().is_empty() {
+                return Err(Invalid::new(key("url"), "a sink needs a url or a name"));
+            }
+            if !sinks.insert(s.name()) {
+                return Err(Invalid::new(
+                    format!("sink.{i}"),
+                    format!("sink '{}' is defined more than once", s.name()),
+                ));
+            }
+            if s.timeout.is_zero() {
+                return Err(Invalid::new(
+                    key("timeout"),
+                    format!(
+                        "sink '{}' has a zero timeout; leave it out for the default of {}",
+                        s.name(),
+                        humantime::format_duration(default_timeout())
+                    ),
+                ));
+            }
+            for (field, value) in [("batch_size", s.batch_size), ("queue_depth", s.queue_depth)] {
+                if value == 0 {
+                    return Err(Invalid::new(
+                        key(field),
+                        format!("sink '{}' needs a {field} of at least one", s.name()),
+                    ));
+                }
+            }
+            if s.flush_interval.is_some_and(|i| i.is_zero()) {
+                return Err(Invalid::new(
+                    key("flush_interval"),
+                    format!("sink '{}' has a zero flush_interval", s.name()),
+                ));
+            }
+            for (reading, band) in &s.deadband {
+                if !(band.band() >= 0.0 && band.band().is_finite()) {
+                    return Err(Invalid::new(
+                        format!("sink.{i}.deadband.{reading}"),
+                        format!(
+                            "sink '{}' needs a finite deadband of at least zero for '{reading}'",
+                            s.name()
+                        ),
+                    ));
+                }
+            }
+            match s.max_interval {
+                Some(interval) if interval.is_zero() => {
+                    return Err(Invalid::new(
+                        key("max_interval"),
+                        format!("sink '{}' has a zero max_interval", s.name()),
+                    ))
+                }
+                Some(_) if s.deadband.is_empty() => {
+                    return Err(Invalid::new(
+                        key("max_interval"),
+                        format!(
+                            "sink '{}' has a max_interval but no deadband for it to limit",
+                            s.name()
+                        ),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        if let Some(bacnet) = &self.bacnet {
+            if bacnet.device_id > crate::bacnet::MAX_INSTANCE {
+                return Err(Invalid::new(
+                    "bacnet.device_id",
+                    format!(
+                        "the BACnet device_id must be at most {}",
+                        crate::bacnet::MAX_INSTANCE
+                    ),
+                ));
+            }
+        }
+        if let Some(ha) = &self.ha {
+            if ha.timeout <= 2 * crate::ha::HEARTBEAT {
+                return Err(Invalid::new(
+                    "ha.timeout",
+                    format!(
+                        "the ha timeout must be longer than two heartbeats, {:?}",
+                        2 * crate::ha::HEARTBEAT
+                    ),
+                ));
+            }
+        }
+        let mut alerts = HashSet::new();
+        for (i, a) in self.alerts.iter().enumerate() {
+            if !alerts.insert(a.name.as_str()) {
+                return Err(Invalid::new(
+                    format!("alert.{i}.name"),
+                    format!("alert '{}' is defined more than once", a.name),
+                ));
+            }
+            a.check_limits().map_err(|e| {
+                Invalid::new(format!("alert.{i}"), format!("alert '{}' {e}", a.name))
+            })?;
+        }
+        if !(0.0..1.0).contains(&self.transfer.dropout) {
+            return Err(Invalid::new(
+                "transfer.dropout",
+                "transfer needs a dropout between 0 and 1, e.g. 0.5",
+            ));
+        }
+        if self.transfer.tolerance <= 0.0 {
+            return Err(Invalid::new(
+                "transfer.tolerance",
+                "transfer needs a tolerance above 0",
+            ));
+        }
+        if let Some(zone) = &self.timezone {
+            crate::timezone::Zone::new(zone)
+                .map_err(|e| Invalid::new("timezone", e.to_string()))?;
+        }
+        let nominal = [
+            ("nominal_volts", self.quality.nominal_volts),
+            ("nominal_frequency", self.quality.nominal_frequency),
+        ];
+        for (field, value) in nominal {
+            if value.is_some_and(|n| n <= 0.0) {
+                return Err(Invalid::new(
+                    format!("quality.{field}"),
+                    "quality needs a nominal voltage and frequency above 0",
+                ));
+            }
+        }
+        if let Some(snmp) = &self.snmp {
+            if snmp.receivers.is_empty() {
+                return Err(Invalid::new(
+                    "snmp.receivers",
+                    "snmp needs at least one receiver, e.g. [\"192.168.1.5\"]",
+                ));
+            }
+            crate::snmp::parse_oid(&snmp.oid).map_err(|e| Invalid::new("snmp.oid", e))?;
+        }
+        if let Some(i) = self.api_keys.iter().position(|k| k.key.expose().is_empty()) {
+            return Err(Invalid::new(format!("api_keys.{i}"), "an API key is empty"));
+        }
+        if self.history.is_some_and(|h| h.is_zero()) {
+            return Err(Invalid::new(
+                "history",
+                "history must be longer than zero, e.g. \"1d\"",
+            ));
+        }
+        if self.archive.as_ref().is_some_and(|a| a.flush.is_zero()) {
+            return Err(Invalid::new(
+                "archive.flush",
+                "the archive's flush interval must be longer than zero",
+            ));
+        }
+        if !self.history_tiers.is_empty() && self.history.is_none() {
+            return Err(Invalid::new(
+                "history_tiers",
+                "history_tiers needs history to be set, e.g. history = \"1d\"",
+            ));
+        }
+        let mut keep = self.history.unwrap_or_default();
+        let mut step = Duration::ZERO;
+        for (i, tier) in self.history_tiers.iter().enumerate() {
+            if tier.step <= step || tier.keep <= keep || tier.step >= tier.keep {
+                return Err(Invalid::new(
+                    format!("history_tiers.{i}"),
+                    format!(
+                        "history tier {}:{} should average over a longer step, and keep \
+                         longer, than the tier before it",
+                        humantime::format_duration(tier.step),
+                        humantime::format_duration(tier.keep)
+                    ),
+                ));
+            }
+            (step, keep) = (tier.step, tier.keep);
+        }
+        if self.max_concurrent_polls == Some(0) {
+            return Err(Invalid::new(
+                "max_concurrent_polls",
+                "max_concurrent_polls must be at least 1; leave it out for no limit",
+            ));
+        }
+        if let Some(tls) = &self.web_tls {
+            if tls.client_ca.is_none() && !tls.access.is_empty() {
+                return Err(Invalid::new(
+                    "web_tls.access",
+                    "web_tls: access is given by client certificate, so needs a client_ca",
+                ));
+            }
+            if tls
+                .acme
+                .as_ref()
+                .is_some_and(|acme| acme.domains.is_empty())
+            {
+                return Err(Invalid::new(
+                    "web_tls.acme.domains",
+                    "web_tls.acme: no domains to get a certificate for",
+                ));
+            }
+        }
+        if self.max_requests_in_flight == Some(0) {
+            return Err(Invalid::new(
+                "max_requests_in_flight",
+                "max_requests_in_flight must be at least 1; leave it out for no limit",
+            ));
+        }
+        if let Some(rate) = self.requests_per_second {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(Invalid::new(
+                    "requests_per_second",
+                    "requests_per_second must be more than 0; leave it out for no limit",
+                ));
+            }
+        }
+        let mut meters = HashSet::new();
+        let mut devices = HashSet::new();
+        for (i, m) in self.meters.iter().enumerate() {
+            let key = |field: &str| format!("meter.{i}.{field}");
+            let invalid = |field: &str, message: String| Invalid::new(key(field), message);
+            if !meters.insert(m.name.as_str()) {
+                return Err(invalid(
+                    "name",
+                    format!("meter '{}' is defined more than once", m.name),
+                ));
+            }
+            let sources = [
+                ("profile", m.profile.is_some()),
+                ("register_map", m.register_map.is_some()),
+                ("sunspec", m.sunspec),
+            ];
+            if let Some((second, _)) = sources.iter().filter(|(_, given)| *given).nth(1) {
+                return Err(invalid(
+                    second,
+                    format!(
+                        "meter '{}': only one of profile, register_map and sunspec may be given",
+                        m.name
+                    ),
+                ));
+            }
+            if m.transport == Transport::Udp && m.tls.is_some() {
+                return Err(invalid(
+                    "tls",
+                    format!("meter '{}': TLS needs the tcp transport", m.name),
+                ));
+            }
+            if let Some(proxy) = &m.proxy {
+                if let Some(f) = proxy.functions.iter().find(|f| ![3, 4, 6, 16].contains(*f)) {
+                    return Err(invalid(
+                        "proxy.functions",
+                        format!(
+                            "meter '{}': the proxy can't pass through function code {f}; \
+                             it can pass 3, 4, 6 and 16",
+                            m.name
+                        ),
+                    ));
+                }
+                let rate = proxy.requests_per_second;
+                if !(rate.is_finite() && rate > 0.0) {
+                    return Err(invalid(
+                        "proxy.requests_per_second",
+                        format!(
+                            "meter '{}': proxy requests_per_second must be more than 0",
+                            m.name
+                        ),
+                    ));
+                }
+            }
+            if m.timeout.is_zero() {
+                return Err(invalid(
+                    "timeout",
+                    format!(
+                        "meter '{}' has a zero timeout; leave it out for the default of {}",
+                        m.name,
+                        humantime::format_duration(default_timeout())
+                    ),
+                ));
+            }
+            if m.clock_sync.is_some_and(|i| i.is_zero()) {
+                return Err(invalid(
+                    "clock_sync",
+                    format!("meter '{}' has a zero clock_sync interval", m.name),
+                ));
+            }
+            if m.adaptive.is_some_and(|i| i.is_zero()) {
+                return Err(invalid(
+                    "adaptive",
+                    format!("meter '{}' has a zero adaptive interval", m.name),
+                ));
+            }
+            m.settings().validate().map_err(|e| Invalid {
+                message: format!("meter '{}': {}", m.name, e.message),
+                ..e.within(&format!("meter.{i}"))
+            })?;
+            if !(m.adaptive_threshold >= 0.0 && m.adaptive_threshold.is_finite()) {
+                return Err(invalid(
+                    "adaptive_threshold",
+                    format!("meter '{}' has an invalid adaptive_threshold", m.name),
+                ));
+            }
+            if !m.align && !m.align_jitter.is_zero() {
+                return Err(invalid(
+                    "align_jitter",
+                    format!(
+                        "meter '{}' sets align_jitter without align; add align = true",
+                        m.name
+                    ),
+                ));
+            }
+            for name in m.labels.keys() {
+                let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    && !name.starts_with("__");
+                if !valid || RESERVED_LABELS.contains(&name.as_str()) {
+                    return Err(invalid(
+                        &format!("labels.{name}"),
+                        format!("meter '{}': '{name}' can't be used as a label name", m.name),
+                    ));
+                }
+            }
+            if let Some(name) = m.derived.keys().find(|n| !derived::is_name(n)) {
+                return Err(invalid(
+                    &format!("derived.{name}"),
+                    format!(
+                        "meter '{}': '{name}' can't be used as the name of a derived reading",
+                        m.name
+                    ),
+                ));
+            }
+            if m.units.is_empty() {
+                return Err(invalid("unit", format!("meter '{}' has no units", m.name)));
+            }
+            for name in m.device_names() {
+                if name == "total" {
+                    return Err(invalid(
+                        "unit",
+                        "the device name 'total' is reserved for /power/total".to_owned(),
+                    ));
+                }
+                if !devices.insert(name.clone()) {
+                    return Err(invalid(
+                        "unit",
+                        format!("device name '{name}' is used more than once"),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METER: &str = "[[meter]]\nname = \"main\"\naddress = \"192.168.1.100:502\"\n";
+
+    fn parse(text: &str) -> Result<Config, String> {
+        Config::parse(text).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let config = parse(METER).unwrap();
+        let meter = &config.meters[0];
+        assert_eq!(meter.read_gap, 32);
+        assert_eq!(meter.timeout, Duration::from_secs(5));
+        assert_eq!(meter.units.len(), 1);
+        assert_eq!(meter.device_names(), ["main"]);
+        assert!(config.sinks.is_empty());
+    }
+
+    #[test]
+    fn names_devices_after_their_units() {
+        let text = format!("{METER}unit = [{{ id = 1 }}, {{ id = 2, name = \"solar\" }}]\n");
+        let config = parse(&text).unwrap();
+        assert_eq!(config.meters[0].device_names(), ["unit1", "solar"]);
+    }
+
+    #[test]
+    fn rejects_invalid_configurations() {
+        let bad = [
+            "".to_owned(),
+            format!("{METER}{METER}"),
+            format!("{METER}timeout = \"0s\""),
+            format!("{METER}unit = []"),
+            format!("{METER}unit = [{{ id = 1, name = \"total\" }}]"),
+            format!("{METER}labels = {{ device = \"x\" }}"),
+            format!("{METER}labels = {{ \"bad-name\" = \"x\" }}"),
+            format!("{METER}align_jitter = \"1s\""),
+            format!("{METER}profile = \"shark100\"\nsunspec = true"),
+            format!("history_tiers = [\"1m:30d\"]\n{METER}"),
+            format!("history = \"1h\"\nhistory_tiers = [\"1m:30m\"]\n{METER}"),
+            format!("api_keys = [\"\"]\n{METER}"),
+            format!("{METER}[[sink]]\nurl = \"http://a\"\n[[sink]]\nurl = \"http://a\""),
+            format!("{METER}[[sink]]\nformat = \"influx\""),
+            format!("{METER}[[sink]]\nurl = \"http://a\"\nbatch_size = 0"),
+            format!("{METER}[[sink]]\nurl = \"http://a\"\nflush_interval = \"0s\""),
+            format!("{METER}[[sink]]\nurl = \"http://a\"\ndeadband = {{ watts = -1 }}"),
+            format!("{METER}[[sink]]\nurl = \"http://a\"\ndeadband = {{ watts = \"5 W\" }}"),
+            format!("{METER}[[sink]]\nurl = \"http://a\"\nmax_interval = \"5m\""),
+            format!("version = {}\n{METER}", CONFIG_VERSION + 1),
+        ];
+        for text in bad {
+            assert!(parse(&text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn finds_the_line_of_an_error() {
+        let text = format!("history = \"1h\"\n{METER}timout = \"1s\"\n");
+        let e = Config::parse(&text).unwrap_err();
+        assert_eq!(e.line, Some(5));
+        assert_eq!(e.message, "unknown field `timout`; did you mean `timeout`?");
+
+        let text = format!("{METER}{}timeout = \"0s\"\n", METER.replace("main", "b"));
+        let e = Config::parse(&text).unwrap_err();
+        assert_eq!((e.key.as_str(), e.line), ("meter.1.timeout", Some(7)));
+        // A setting that isn't in the file is found at its table.
+        let text = format!("{METER}\n[[meter]]\nname = \"total\"\naddress = \"a:502\"\n");
+        let e = Config::parse(&text).unwrap_err();
+        assert_eq!((e.key.as_str(), e.line), ("meter.1.unit", Some(5)));
+    }
+
+    #[test]
+    fn gives_api_keys_roles() {
+        let text = format!(
+            "api_keys = [\"plain\", {{ key = \"k\", role = \"viewer\", name = \"kiosk\" }}]\n{METER}"
+        );
+        let config = parse(&text).unwrap();
+        assert_eq!(config.api_keys[0].role, Role::Operator);
+        assert_eq!(
+            config.api_keys[1],
+            ApiKey {
+                key: "k".parse().unwrap(),
+                role: Role::Viewer,
+                name: Some("kiosk".to_owned())
+            }
+        );
+        let key: ApiKey = "admin=abc=".parse().unwrap();
+        assert_eq!((key.role, key.key.expose()), (Role::Admin, "abc="));
+        let key: ApiKey = "abc==".parse().unwrap();
+        assert_eq!((key.role, key.key.expose()), (Role::Operator, "abc=="));
+        assert!(Role::Admin > Role::Operator && Role::Operator > Role::Viewer);
+    }
+
+    #[test]
+    fn reads_secrets_from_files_and_the_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("influx");
+        std::fs::write(&path, "Token abc\n").unwrap();
+        let env = |name: &str| match name {
+            "SHARKMON_TEST_KEY" => Ok("from-env".to_owned()),
+            _ => Err(std::env::VarError::NotPresent),
+        };
+        let key = Secret::resolve("env:SHARKMON_TEST_KEY", env).unwrap();
+        assert_eq!(key.expose(), "from-env");
+        let e = Secret::resolve("env:SHARKMON_NOT_SET", env).unwrap_err();
+        assert!(
+            e.starts_with("environment variable SHARKMON_NOT_SET: "),
+            "{e}"
+        );
+
+        let text = format!(
+            "{METER}[[sink]]\nurl = \"http://a\"\n\
+             headers = {{ Authorization = \"file:{}\" }}",
+            path.display()
+        );
+        let config = parse(&text).unwrap();
+        let header = &config.sinks[0].headers["Authorization"];
+        assert_eq!(header.expose(), "Token abc");
+        assert!(!format!("{config:?}").contains("abc"));
+        assert_eq!(serde_json::to_string(header).unwrap(), "\"[redacted]\"");
+
+        let missing = format!(
+            "api_keys = [\"file:{}/none\"]\n{METER}",
+            dir.path().display()
+        );
+        assert!(parse(&missing).unwrap_err().contains("none"));
+    }
+
+    #[test]
+    fn prints_as_toml_that_parses_back() {
+        let text = format!(
+            "api_keys = [\"hunter2\"]\nhistory = \"1d\"\nhistory_tiers = [\"1m:30d\"]\n{METER}\
+             writable = [\"0x1000-0x1005\"]\nregister_formats = [\"watts=int32:0.1\"]\n\
+             derived = {{ amps = \"watts / volts\" }}\n\
+             [[sink]]\nurl = \"http://a\"\nformat = \"influx\"\n"
+        );
+        let printed = toml::to_string(&parse(&text).unwrap()).unwrap();
+        assert!(!printed.contains("hunter2"));
+        assert!(printed.contains("key = \"[redacted]\""));
+        let config = parse(&printed).unwrap();
+        let meter = &config.meters[0];
+        assert_eq!(meter.writable[0].end, 0x1005);
+        assert_eq!(meter.register_formats[0].scale, 0.1);
+        assert_eq!(meter.derived["amps"].to_string(), "watts / volts");
+        assert_eq!(meter.read_gap, 32);
+        assert_eq!(
+            config.history_tiers[0].keep,
+            Duration::from_secs(30 * 86400)
+        );
+        assert_eq!(config.sinks[0].format, SinkFormat::Influx);
+    }
+
+    #[test]
+    fn staggers_meters_sharing_an_address() {
+        let meter = |name: &str, address: &str, extra: &str| {
+            format!("[[meter]]\nname = \"{name}\"\naddress = \"{address}\"\n{extra}\n")
+        };
+        let text = [
+            meter("a", "10.0.0.5:502", ""),
+            meter("b", "10.0.0.6:502", ""),
+            meter("c", "10.0.0.5:502", ""),
+            meter("d", "10.0.0.5:502", "poll_offset = \"100ms\""),
+            meter("e", "10.0.0.5:502", ""),
+        ]
+        .concat();
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(
+            config.gateway_shares(),
+            [Some((0, 3)), None, Some((1, 3)), None, Some((2, 3))]
+        );
+        let fixed: Config = toml::from_str(&format!("stagger_gateways = false\n{text}")).unwrap();
+        assert!(fixed.gateway_shares().iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn parses_units() {
+        let unit: UnitConfig = "2=solar".parse().unwrap();
+        assert_eq!((unit.id, unit.name.as_deref()), (2, Some("solar")));
+        let unit: UnitConfig = "7".parse().unwrap();
+        assert_eq!((unit.id, unit.name), (7, None));
+        assert!("x".parse::<UnitConfig>().is_err());
+        assert!("300".parse::<UnitConfig>().is_err());
+    }
+
+    #[test]
+    fn parses_sinks() {
+        let sink: SinkConfig = "influx=http://localhost:8086/api/v2/write".parse().unwrap();
+        assert_eq!(sink.format, SinkFormat::Influx);
+        assert_eq!(sink.url, "http://localhost:8086/api/v2/write");
+        // An unregistered format is part of the URL.
+        let sink: SinkConfig = "http://example.com/hook?a=b".parse().unwrap();
+        assert_eq!(sink.format, SinkFormat::Json);
+        assert_eq!(sink.name(), "http://example.com/hook?a=b");
+    }
+
+    #[test]
+    fn parses_history_tiers() {
+        let tier: HistoryTier = "1m:30d".parse().unwrap();
+        assert_eq!(tier.step, Duration::from_secs(60));
+        assert_eq!(tier.keep, Duration::from_secs(30 * 86400));
+        assert!("1m".parse::<HistoryTier>().is_err());
+        assert!("1m:forever".parse::<HistoryTier>().is_err());
+    }
+}
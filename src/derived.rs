@@ -0,0 +1,406 @@
+//! Derived readings: arithmetic on a device's own readings, and on other
+//! devices', worked out after every poll and served like the readings read
+//! from the meter. In a `[[meter]]` table:
+//!
+//! ```toml
+//! derived = { amps_est = "watts / volts", net_watts = "watts - solar.watts" }
+//! ```
+//!
+//! A plain name is one of the device's own readings and `DEVICE.NAME` is
+//! another device's. Expressions have `+ - * /`, parentheses, numbers and
+//! the functions `abs`, `min` and `max`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A parsed expression, and the text it was parsed from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expression(Node, String);
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    Reading {
+        device: Option<String>,
+        name: String,
+    },
+    Neg(Box<Node>),
+    Binary(Op, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Function {
+    Abs,
+    Min,
+    Max,
+}
+
+/// A reading an expression refers to: another device's, or the device's own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reference<'a> {
+    pub device: Option<&'a str>,
+    pub name: &'a str,
+}
+
+impl fmt::Display for Reference<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.device {
+            Some(device) => write!(f, "{device}.{}", self.name),
+            None => f.write_str(self.name),
+        }
+    }
+}
+
+impl Expression {
+    /// Work out the value, looking up each reading with `reading`. Gives
+    /// `None` if a reading can't be found.
+    pub fn eval(&self, reading: &dyn Fn(Reference) -> Option<f64>) -> Option<f64> {
+        self.0.eval(reading)
+    }
+
+    /// Every reading the expression uses.
+    pub fn references(&self) -> Vec<Reference<'_>> {
+        let mut out = Vec::new();
+        self.0.references(&mut out);
+        out
+    }
+}
+
+impl Node {
+    fn eval(&self, reading: &dyn Fn(Reference) -> Option<f64>) -> Option<f64> {
+        Some(match self {
+            Node::Number(n) => *n,
+            Node::Reading { device, name } => reading(Reference {
+                device: device.as_deref(),
+                name,
+            })?,
+            Node::Neg(a) => -a.eval(reading)?,
+            Node::Binary(op, a, b) => {
+                let (a, b) = (a.eval(reading)?, b.eval(reading)?);
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                }
+            }
+            Node::Call(function, args) => {
+                let args: Vec<f64> = args
+                    .iter()
+                    .map(|a| a.eval(reading))
+                    .collect::<Option<_>>()?;
+                match function {
+                    Function::Abs => args[0].abs(),
+                    Function::Min => args.into_iter().fold(f64::INFINITY, f64::min),
+                    Function::Max => args.into_iter().fold(f64::NEG_INFINITY, f64::max),
+                }
+            }
+        })
+    }
+
+    fn references<'a>(&'a self, out: &mut Vec<Reference<'a>>) {
+        match self {
+            Node::Number(_) => {}
+            Node::Reading { device, name } => out.push(Reference {
+                device: device.as_deref(),
+                name,
+            }),
+            Node::Neg(a) => a.references(out),
+            Node::Binary(_, a, b) => {
+                a.references(out);
+                b.references(out);
+            }
+            Node::Call(_, args) => args.iter().for_each(|a| a.references(out)),
+        }
+    }
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            text: s,
+            tokens: tokenize(s)?,
+            next: 0,
+        };
+        let node = parser.sum()?;
+        match parser.tokens.get(parser.next) {
+            None => Ok(Expression(node, s.to_owned())),
+            Some((at, _)) => Err(parser.error(*at, "expected an operator")),
+        }
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Expression, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.1)
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Whether `s` can name a derived reading, so other expressions can use it.
+pub fn is_name(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    /// A name, or `DEVICE.NAME`
+    Name(String),
+    Symbol(char),
+}
+
+/// The tokens of `s`, each with its byte offset.
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(at, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = at;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            let number = s[at..end]
+                .parse()
+                .map_err(|_| format!("invalid number '{}' at {at}", &s[at..end]))?;
+            tokens.push((at, Token::Number(number)));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = at;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push((at, Token::Name(s[at..end].to_owned())));
+        } else if "+-*/(),".contains(c) {
+            tokens.push((at, Token::Symbol(c)));
+            chars.next();
+        } else {
+            return Err(format!("unexpected '{c}' at {at} in '{s}'"));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, at: usize, message: &str) -> String {
+        format!("{message} at {at} in '{}'", self.text)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, t)| t)
+    }
+
+    /// Consume the next token if it is `symbol`.
+    fn eat(&mut self, symbol: char) -> bool {
+        let found = self.peek() == Some(&Token::Symbol(symbol));
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        let mut node = self.product()?;
+        loop {
+            let op = if self.eat('+') {
+                Op::Add
+            } else if self.eat('-') {
+                Op::Sub
+            } else {
+                return Ok(node);
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        loop {
+            let op = if self.eat('*') {
+                Op::Mul
+            } else if self.eat('/') {
+                Op::Div
+            } else {
+                return Ok(node);
+            };
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.eat('-') {
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        let Some((at, token)) = self.tokens.get(self.next).cloned() else {
+            return Err(format!("'{}' ends too soon", self.text));
+        };
+        self.next += 1;
+        match token {
+            Token::Number(n) => Ok(Node::Number(n)),
+            Token::Symbol('(') => {
+                let node = self.sum()?;
+                if !self.eat(')') {
+                    return Err(self.error(at, "unclosed '('"));
+                }
+                Ok(node)
+            }
+            Token::Name(name) if self.eat('(') => self.call(at, &name),
+            Token::Name(name) => {
+                let (device, name) = match name.split_once('.') {
+                    Some((device, name)) => (Some(device.to_owned()), name.to_owned()),
+                    None => (None, name),
+                };
+                if device.as_deref().is_some_and(str::is_empty) || !is_name(&name) {
+                    return Err(self.error(at, "expected NAME or DEVICE.NAME"));
+                }
+                Ok(Node::Reading { device, name })
+            }
+            Token::Symbol(_) => Err(self.error(at, "expected a number or reading")),
+        }
+    }
+
+    /// The arguments of a call to `name`, after its '('.
+    fn call(&mut self, at: usize, name: &str) -> Result<Node, String> {
+        let function = match name {
+            "abs" => Function::Abs,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            _ => return Err(self.error(at, &format!("unknown function '{name}'"))),
+        };
+        let mut args = Vec::new();
+        if !self.eat(')') {
+            loop {
+                args.push(self.sum()?);
+                if self.eat(')') {
+                    break;
+                }
+                if !self.eat(',') {
+                    return Err(self.error(at, &format!("expected ',' or ')' in {name}()")));
+                }
+            }
+        }
+        let arity_ok = match function {
+            Function::Abs => args.len() == 1,
+            Function::Min | Function::Max => !args.is_empty(),
+        };
+        if !arity_ok {
+            return Err(self.error(at, &format!("wrong number of arguments to {name}()")));
+        }
+        Ok(Node::Call(function, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Option<f64> {
+        let expression: Expression = expression.parse().unwrap();
+        expression.eval(&|r| match (r.device, r.name) {
+            (None, "watts") => Some(2400.0),
+            (None, "volts") => Some(240.0),
+            (Some("solar"), "watts") => Some(-600.0),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn follows_precedence_and_parentheses() {
+        assert_eq!(eval("1 + 2 * 3"), Some(7.0));
+        assert_eq!(eval("(1 + 2) * 3"), Some(9.0));
+        assert_eq!(eval("10 - 4 - 3"), Some(3.0));
+        assert_eq!(eval("-2 * -3"), Some(6.0));
+        assert_eq!(eval("1.5"), Some(1.5));
+    }
+
+    #[test]
+    fn looks_up_readings() {
+        assert_eq!(eval("watts / volts"), Some(10.0));
+        assert_eq!(eval("watts + solar.watts"), Some(1800.0));
+        assert_eq!(eval("abs(solar.watts)"), Some(600.0));
+        assert_eq!(eval("max(watts, volts, 3000)"), Some(3000.0));
+        assert_eq!(eval("min(watts, volts)"), Some(240.0));
+        assert_eq!(eval("watts / amps"), None);
+    }
+
+    #[test]
+    fn lists_references() {
+        let expression: Expression = "watts - solar.watts * 2".parse().unwrap();
+        let references: Vec<String> = expression
+            .references()
+            .iter()
+            .map(|r| r.to_string())
+            .collect();
+        assert_eq!(references, ["watts", "solar.watts"]);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for bad in [
+            "",
+            "1 +",
+            "(1",
+            "1 2",
+            "watts $ 2",
+            "sqrt(4)",
+            "abs(1, 2)",
+            "max()",
+            ".watts.x",
+            "solar.",
+            "1..2",
+        ] {
+            assert!(bad.parse::<Expression>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn names_are_identifiers() {
+        assert!(is_name("amps_est"));
+        assert!(is_name("_x1"));
+        assert!(!is_name("1x"));
+        assert!(!is_name("solar.watts"));
+        assert!(!is_name(""));
+    }
+}
@@ -0,0 +1,469 @@
+//! `sharkmon discover`: find Modbus meters on the local network and print a
+//! `[[meter]]` table for each. Candidates come from mDNS (devices announcing
+//! `_modbus._tcp`), SSDP (any UPnP device, since gateways rarely say what
+//! they are) and a sweep of the Modbus port over the given networks. Each
+//! candidate that accepts a connection is asked for the Shark's ID block,
+//! then for a SunSpec model chain.
+
+use crate::client::MeterClient;
+use crate::mdns;
+use crate::registers::{self, Function};
+use crate::sunspec;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const MDNS_SERVICE: &str = "_modbus._tcp.local";
+const SSDP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+/// Connection attempts in flight at once during a sweep.
+const SWEEP_CONNECTIONS: usize = 256;
+
+/// The largest network a sweep covers, a /16.
+const MAX_SWEEP_HOSTS: u32 = 1 << 16;
+
+/// The Shark's ID block: meter name, serial number, meter type and firmware
+/// version, from holding register 0x0000.
+const ID_BLOCK: registers::Block = registers::Block {
+    function: Function::Holding,
+    start: 0x0000,
+    len: 0x13,
+};
+
+/// An IPv4 network, written `ADDRESS/PREFIX`, e.g. `192.168.1.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub network: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    /// Every host address in the network, leaving out the network and
+    /// broadcast addresses unless it is a /31 or /32.
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let size = 1u64 << (32 - self.prefix);
+        let first = u32::from(self.network) as u64;
+        let (start, end) = match size {
+            1 | 2 => (first, first + size),
+            _ => (first + 1, first + size - 1),
+        };
+        (start..end).map(|ip| Ipv4Addr::from(ip as u32))
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (address, prefix) = s.split_once('/').unwrap_or((s, "32"));
+        let address: Ipv4Addr = address
+            .parse()
+            .map_err(|e| format!("invalid network '{s}': {e}"))?;
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|p| *p <= 32)
+            .ok_or_else(|| format!("invalid prefix length in '{s}'"))?;
+        if (1u64 << (32 - prefix)) > MAX_SWEEP_HOSTS as u64 {
+            return Err(format!("network '{s}' is larger than a /16"));
+        }
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        Ok(Cidr {
+            network: Ipv4Addr::from(u32::from(address) & mask),
+            prefix,
+        })
+    }
+}
+
+/// How a candidate was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    Mdns,
+    Ssdp,
+    Sweep,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Mdns => "mDNS",
+            Source::Ssdp => "SSDP",
+            Source::Sweep => "port sweep",
+        })
+    }
+}
+
+/// What answered on a candidate's Modbus port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identity {
+    Shark {
+        name: String,
+        serial: String,
+        firmware: String,
+        /// The V-Switch key, which sets what the meter measures and whether
+        /// it logs, if the meter type register gives one
+        vswitch: Option<u8>,
+    },
+    SunSpec,
+    /// It speaks Modbus, but isn't a meter sharkmon knows
+    Unknown,
+}
+
+/// A meter found on the network.
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub address: SocketAddr,
+    /// Empty if the address was given rather than found
+    pub sources: Vec<Source>,
+    pub identity: Identity,
+}
+
+impl Found {
+    /// A name for the `[[meter]]` table, from the meter's own name if it has
+    /// one.
+    pub fn name(&self) -> String {
+        let own = match &self.identity {
+            Identity::Shark { name, .. } => name
+                .to_lowercase()
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join("-"),
+            _ => String::new(),
+        };
+        if own.is_empty() {
+            let ip = self.address.ip().to_string().replace(['.', ':'], "-");
+            format!("meter-{ip}")
+        } else {
+            own
+        }
+    }
+
+    /// The meter's `[[meter]]` table, named `name`, with a comment saying
+    /// what it is.
+    pub fn config(&self, name: &str) -> String {
+        let quote = |s: &str| toml::Value::String(s.to_owned()).to_string();
+        let sources: Vec<String> = self.sources.iter().map(Source::to_string).collect();
+        let (what, settings) = match &self.identity {
+            Identity::Shark {
+                name,
+                serial,
+                firmware,
+                vswitch,
+            } => {
+                let profile = if name.contains("200") {
+                    "shark200"
+                } else {
+                    "shark100"
+                };
+                let mut what = format!("{name:?}, serial {serial:?}, firmware {firmware:?}");
+                if let Some(v) = vswitch {
+                    what += &format!(", V-Switch {v}");
+                }
+                (what, format!("profile = {}\n", quote(profile)))
+            }
+            Identity::SunSpec => ("a SunSpec device".to_owned(), "sunspec = true\n".to_owned()),
+            Identity::Unknown => (
+                "a Modbus device of unknown make".to_owned(),
+                "# profile = \"...\" or register_map = \"...\"\n".to_owned(),
+            ),
+        };
+        let found = if sources.is_empty() {
+            String::new()
+        } else {
+            format!(", found by {}", sources.join(" and "))
+        };
+        format!(
+            "# {what}{found}\n[[meter]]\nname = {}\naddress = {}\n{settings}",
+            quote(name),
+            quote(&self.address.to_string()),
+        )
+    }
+}
+
+/// Print a `[[meter]]` table for each meter, with the names made unique.
+pub fn print(found: &[Found]) {
+    let mut taken = HashSet::new();
+    for (i, meter) in found.iter().enumerate() {
+        let base = meter.name();
+        let mut name = base.clone();
+        let mut n = 2;
+        while !taken.insert(name.clone()) {
+            name = format!("{base}-{n}");
+            n += 1;
+        }
+        if i > 0 {
+            println!();
+        }
+        print!("{}", meter.config(&name));
+    }
+}
+
+/// Ask the device on the other end of `ctx` what it is. A device that
+/// refuses every request still speaks Modbus, so counts as unknown; one that
+/// doesn't answer at all is an error.
+pub async fn identify(
+    ctx: &mut dyn MeterClient,
+    unit: u8,
+    timeout: Duration,
+) -> std::io::Result<Identity> {
+    ctx.set_unit(unit);
+    let read = ctx.read(ID_BLOCK.function, ID_BLOCK.start, ID_BLOCK.len);
+    match tokio::time::timeout(timeout, read).await {
+        Ok(Ok(r)) if r.len() == ID_BLOCK.len as usize => {
+            let name = registers::ascii(&r[0x00..0x08]);
+            if !name.is_empty() {
+                // The meter type register: a transducer bit, and the
+                // V-Switch key in the low three bits.
+                let vswitch = (r[0x10] & 0x7) as u8;
+                return Ok(Identity::Shark {
+                    name,
+                    serial: registers::ascii(&r[0x08..0x10]),
+                    firmware: registers::ascii(&r[0x11..0x13]),
+                    vswitch: (vswitch != 0).then_some(vswitch),
+                });
+            }
+        }
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) if registers::is_exception(&e) => {}
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(Error::new(ErrorKind::TimedOut, "no answer")),
+    }
+    match tokio::time::timeout(timeout * 4, sunspec::discover(ctx)).await {
+        Ok(Ok(_)) => Ok(Identity::SunSpec),
+        _ => Ok(Identity::Unknown),
+    }
+}
+
+/// Send `query` to the multicast group `group`, and collect who answers
+/// within `wait`, with what they said.
+async fn multicast(
+    group: SocketAddrV4,
+    query: &[u8],
+    wait: Duration,
+) -> std::io::Result<Vec<(SocketAddr, Vec<u8>)>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.send_to(query, group).await?;
+    let mut answers = Vec::new();
+    let mut buf = vec![0; 9000];
+    let deadline = tokio::time::Instant::now() + wait;
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        answers.push((from, buf[..len].to_vec()));
+    }
+    Ok(answers)
+}
+
+/// Devices announcing a Modbus service over mDNS.
+async fn mdns(wait: Duration, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    let answers = multicast(mdns::GROUP, &mdns::query(MDNS_SERVICE), wait).await?;
+    Ok(answers
+        .into_iter()
+        .map(|(from, packet)| {
+            let port = mdns::srv_ports(&packet).first().copied().unwrap_or(port);
+            SocketAddr::new(from.ip(), port)
+        })
+        .collect())
+}
+
+/// Every UPnP device answering an SSDP search, on the Modbus port.
+async fn ssdp(wait: Duration, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    let query = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: ssdp:all\r\n\r\n",
+        wait.as_secs().max(1)
+    );
+    let answers = multicast(SSDP, query.as_bytes(), wait).await?;
+    Ok(answers
+        .into_iter()
+        .map(|(from, _)| SocketAddr::new(from.ip(), port))
+        .collect())
+}
+
+/// The hosts in `networks` accepting connections on `port`.
+async fn sweep(networks: &[Cidr], port: u16, timeout: Duration) -> Vec<SocketAddr> {
+    let permits = Arc::new(tokio::sync::Semaphore::new(SWEEP_CONNECTIONS));
+    let mut tries = tokio::task::JoinSet::new();
+    for ip in networks.iter().flat_map(Cidr::hosts) {
+        let permits = permits.clone();
+        let address = SocketAddr::from((ip, port));
+        tries.spawn(async move {
+            let _permit = permits.acquire().await;
+            let connect = tokio::net::TcpStream::connect(address);
+            matches!(tokio::time::timeout(timeout, connect).await, Ok(Ok(_))).then_some(address)
+        });
+    }
+    let mut open = Vec::new();
+    while let Some(result) = tries.join_next().await {
+        if let Ok(Some(address)) = result {
+            open.push(address);
+        }
+    }
+    open
+}
+
+/// Connect to `address` and ask what it is.
+pub async fn probe(address: SocketAddr, unit: u8, timeout: Duration) -> std::io::Result<Identity> {
+    use tokio_modbus::prelude::*;
+    let stream = tokio::time::timeout(timeout, tokio::net::TcpStream::connect(address))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out connecting"))??;
+    let mut ctx = tcp::attach_slave(stream, Slave(unit));
+    let identity = identify(&mut ctx, unit, timeout).await;
+    let _ = MeterClient::disconnect(&mut ctx).await;
+    identity
+}
+
+/// What to look for.
+pub struct Options {
+    /// Networks to sweep for the Modbus port
+    pub networks: Vec<Cidr>,
+    pub port: u16,
+    pub unit: u8,
+    /// How long to wait for connections and answers
+    pub timeout: Duration,
+    /// Ask mDNS and SSDP
+    pub multicast: bool,
+}
+
+/// Find the meters on the network, in address order.
+pub async fn discover(options: &Options) -> std::io::Result<Vec<Found>> {
+    let mut candidates: BTreeMap<SocketAddr, Vec<Source>> = BTreeMap::new();
+    let mut add = |addresses: Vec<SocketAddr>, source| {
+        for address in addresses {
+            let sources = candidates.entry(address).or_default();
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+    };
+    if options.multicast {
+        let wait = options.timeout.max(Duration::from_secs(1));
+        eprintln!("asking mDNS and SSDP...");
+        let (mdns, ssdp) = tokio::join!(mdns(wait, options.port), ssdp(wait, options.port));
+        match mdns {
+            Ok(found) => add(found, Source::Mdns),
+            Err(e) => tracing::warn!(error = %e, "mDNS query failed"),
+        }
+        match ssdp {
+            Ok(found) => add(found, Source::Ssdp),
+            Err(e) => tracing::warn!(error = %e, "SSDP search failed"),
+        }
+    }
+    if !options.networks.is_empty() {
+        let hosts: usize = options.networks.iter().map(|n| n.hosts().count()).sum();
+        eprintln!("sweeping {hosts} addresses for port {}...", options.port);
+        add(
+            sweep(&options.networks, options.port, options.timeout).await,
+            Source::Sweep,
+        );
+    }
+    eprintln!("probing {} candidates...", candidates.len());
+    let mut probes = tokio::task::JoinSet::new();
+    for (address, sources) in candidates {
+        let (unit, timeout) = (options.unit, options.timeout);
+        probes.spawn(async move { (address, sources, probe(address, unit, timeout).await) });
+    }
+    let mut found = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        let (address, sources, identity) = result.map_err(Error::other)?;
+        match identity {
+            Ok(identity) => found.push(Found {
+                address,
+                sources,
+                identity,
+            }),
+            Err(e) => tracing::debug!(%address, error = %e, "not a Modbus device"),
+        }
+    }
+    found.sort_by_key(|f| f.address);
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+
+    #[test]
+    fn parses_networks() {
+        let net: Cidr = "192.168.1.77/24".parse().unwrap();
+        assert_eq!(net.network, Ipv4Addr::new(192, 168, 1, 0));
+        let hosts: Vec<_> = net.hosts().collect();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+        let single: Cidr = "10.0.0.5".parse().unwrap();
+        assert_eq!(
+            single.hosts().collect::<Vec<_>>(),
+            [Ipv4Addr::new(10, 0, 0, 5)]
+        );
+        assert!("10.0.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("meter.local/24".parse::<Cidr>().is_err());
+    }
+
+    fn text(s: &str, len: usize) -> Vec<u16> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize(len * 2, b' ');
+        bytes
+            .chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn identifies_sharks() {
+        let fake = FakeMeter::new();
+        fake.set(1, Function::Holding, 0x0000, &text("Shark 200 Main", 8));
+        fake.set(1, Function::Holding, 0x0008, &text("0042", 8));
+        fake.set(1, Function::Holding, 0x0010, &[0x0003, 0x3130, 0x3031]);
+        let mut ctx = fake.connect().unwrap();
+        let identity = identify(&mut ctx, 1, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(
+            identity,
+            Identity::Shark {
+                name: "Shark 200 Main".to_owned(),
+                serial: "0042".to_owned(),
+                firmware: "1001".to_owned(),
+                vswitch: Some(3),
+            }
+        );
+        let found = Found {
+            address: "192.168.1.10:502".parse().unwrap(),
+            sources: vec![Source::Mdns, Source::Sweep],
+            identity,
+        };
+        assert_eq!(found.name(), "shark-200-main");
+        let config = found.config("main");
+        assert!(config.starts_with("# \"Shark 200 Main\""), "{config}");
+        assert!(
+            config.contains("V-Switch 3, found by mDNS and port sweep\n"),
+            "{config}"
+        );
+        let table: toml::Table = toml::from_str(&config).unwrap();
+        let meter = &table["meter"].as_array().unwrap()[0];
+        assert_eq!(meter["address"].as_str(), Some("192.168.1.10:502"));
+        assert_eq!(meter["profile"].as_str(), Some("shark200"));
+    }
+
+    #[tokio::test]
+    async fn other_modbus_devices_are_unknown() {
+        let fake = FakeMeter::new();
+        let mut ctx = fake.connect().unwrap();
+        let identity = identify(&mut ctx, 1, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(identity, Identity::Unknown);
+        let found = Found {
+            address: "10.0.0.7:502".parse().unwrap(),
+            sources: vec![Source::Ssdp],
+            identity,
+        };
+        assert_eq!(found.name(), "meter-10-0-0-7");
+        toml::from_str::<toml::Table>(&found.config("x")).unwrap();
+    }
+}
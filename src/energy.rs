@@ -0,0 +1,282 @@
+//! Energy used each day, from the meters' energy counters: readings in Wh,
+//! varh or VAh, with or without a prefix such as kWh. A counter can go
+//! backwards: an integer register rolls over when it fills, and a utility
+//! reprogramming the meter may reset it. Taken at face value, either would
+//! be a huge negative day, so a fall from near the top of the register's
+//! range to near the bottom counts as a rollover, with the energy across it
+//! still counted, and any other large fall as a reset, which counts nothing
+//! and carries on from the new value. Days run from midnight in the
+//! `--timezone`, and a month of them is kept in memory.
+
+use crate::meter::Device;
+use crate::registers;
+use crate::timezone::Zone;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Days of totals kept for each counter, before today.
+const DAYS_KEPT: usize = 31;
+
+/// How near each end of its range, as a fraction of the range, a counter
+/// must be for a fall to be a rollover.
+const ROLLOVER_MARGIN: f64 = 0.1;
+
+/// The largest fall, as a fraction of the counter, taken as energy flowing
+/// back through a net counter, rather than a reset.
+const MAX_RETURN: f64 = 0.01;
+
+/// Units counting energy, without their prefixes.
+const ENERGY_UNITS: [&str; 3] = ["Wh", "varh", "VAh"];
+
+/// An energy counter among a device's readings.
+#[derive(Debug, Clone)]
+pub struct Counter {
+    /// The reading's index among the device's readings
+    pub index: usize,
+    pub units: String,
+    /// What the counter's register holds, in the reading's units, if it
+    /// rolls over
+    pub range: Option<(f64, f64)>,
+}
+
+/// Whether readings in `units` count energy.
+pub fn is_energy(units: &str) -> bool {
+    ENERGY_UNITS
+        .iter()
+        .any(|base| registers::conversion(units, base).is_some())
+}
+
+/// How a counter got from one reading to the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Used(f64),
+    /// The counter filled its register and started again from the bottom,
+    /// having counted this much
+    Rollover(f64),
+    Reset,
+}
+
+/// How a counter in registers holding `range` got from `last` to `value`.
+pub fn change(last: f64, value: f64, range: Option<(f64, f64)>) -> Change {
+    if value >= last || last - value <= last.abs() * MAX_RETURN {
+        return Change::Used(value - last);
+    }
+    if let Some((min, max)) = range {
+        let margin = (max - min) * ROLLOVER_MARGIN;
+        if last >= max - margin && value <= min + margin {
+            return Change::Rollover(max - last + value - min);
+        }
+    }
+    Change::Reset
+}
+
+/// The energy counted on a day.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Day {
+    pub date: NaiveDate,
+    pub used: f64,
+}
+
+/// A counter's energy, as reported by `/energy`.
+#[cfg_attr(not(feature = "web"), allow(dead_code))]
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub units: String,
+    /// The counter's latest reading
+    pub counter: f64,
+    /// The energy counted so far today
+    pub today: f64,
+    /// Earlier days, oldest first
+    pub days: Vec<Day>,
+    pub rollovers: u64,
+    pub resets: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_reset: Option<DateTime<Utc>>,
+}
+
+/// A counter's totals.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Totals {
+    units: String,
+    last: f64,
+    today: Day,
+    days: VecDeque<Day>,
+    rollovers: u64,
+    resets: u64,
+    last_reset: Option<DateTime<Utc>>,
+}
+
+/// Each device's counters' totals, by reading.
+pub type Saved = BTreeMap<String, BTreeMap<String, Totals>>;
+
+pub struct Energy {
+    zone: Zone,
+    devices: Mutex<Saved>,
+}
+
+impl Energy {
+    pub fn new(zone: Zone) -> Energy {
+        Energy {
+            zone,
+            devices: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count the energy since the device's last readings.
+    pub fn record(&self, device: &Device) {
+        if device.counters.is_empty() {
+            return;
+        }
+        let values: Vec<_> = {
+            let readings = device.readings.lock().unwrap();
+            let readings: Vec<_> = readings.iter().collect();
+            device
+                .counters
+                .iter()
+                .filter_map(|c| {
+                    let (name, value) = readings.get(c.index)?;
+                    Some((name.to_string(), *value as f64, c))
+                })
+                .collect()
+        };
+        for (reading, value, counter) in values {
+            let Counter { units, range, .. } = counter;
+            self.observe(&device.name, &reading, units, Utc::now(), value, *range);
+        }
+    }
+
+    fn observe(
+        &self,
+        device: &str,
+        reading: &str,
+        units: &str,
+        time: DateTime<Utc>,
+        value: f64,
+        range: Option<(f64, f64)>,
+    ) {
+        let date = self.zone.local(time).date_naive();
+        let mut devices = self.devices.lock().unwrap();
+        let counters = devices.entry(device.to_owned()).or_default();
+        let totals = counters
+            .entry(reading.to_owned())
+            .or_insert_with(|| Totals {
+                units: units.to_owned(),
+                last: value,
+                today: Day { date, used: 0.0 },
+                days: VecDeque::new(),
+                rollovers: 0,
+                resets: 0,
+                last_reset: None,
+            });
+        if totals.today.date != date {
+            let day = std::mem::replace(&mut totals.today, Day { date, used: 0.0 });
+            if totals.days.len() == DAYS_KEPT {
+                totals.days.pop_front();
+            }
+            totals.days.push_back(day);
+        }
+        match change(totals.last, value, range) {
+            Change::Used(used) => totals.today.used += used,
+            Change::Rollover(used) => {
+                warn!(
+                    device,
+                    reading,
+                    from = totals.last,
+                    to = value,
+                    "energy counter rolled over"
+                );
+                totals.today.used += used;
+                totals.rollovers += 1;
+            }
+            Change::Reset => {
+                warn!(
+                    device,
+                    reading,
+                    from = totals.last,
+                    to = value,
+                    "energy counter was reset"
+                );
+                totals.resets += 1;
+                totals.last_reset = Some(time);
+            }
+        }
+        totals.last = value;
+    }
+
+    /// The totals, to be saved across a restart.
+    pub fn save(&self) -> Saved {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// Carry on from saved totals.
+    pub fn restore(&self, saved: Saved) {
+        *self.devices.lock().unwrap() = saved;
+    }
+
+    /// Each device's counters, by reading.
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    pub fn summaries(&self) -> BTreeMap<String, BTreeMap<String, Summary>> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .map(|(device, counters)| {
+                let counters = counters
+                    .iter()
+                    .map(|(reading, totals)| {
+                        let summary = Summary {
+                            units: totals.units.clone(),
+                            counter: totals.last,
+                            today: totals.today.used,
+                            days: totals.days.iter().cloned().collect(),
+                            rollovers: totals.rollovers,
+                            resets: totals.resets,
+                            last_reset: totals.last_reset,
+                        };
+                        (reading.clone(), summary)
+                    })
+                    .collect();
+                (device.clone(), counters)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    #[test]
+    fn counts_across_rollovers_and_resets() {
+        let energy = Energy::new(Zone::default());
+        let range = Some((0.0, u32::MAX as f64 * 0.1));
+        let max = u32::MAX as f64 * 0.1;
+        let yesterday = Utc::now() - TimeDelta::days(1);
+        let now = Utc::now();
+        let observe = |time, value| energy.observe("main", "kwh", "kWh", time, value, range);
+        observe(yesterday, max - 15.0);
+        observe(yesterday, max - 5.0);
+        // The register filled up, then someone zeroed it.
+        observe(now, 3.0);
+        observe(now, 4.0);
+        observe(now, 0.5);
+
+        let summary = &energy.summaries()["main"]["kwh"];
+        assert_eq!(summary.units, "kWh");
+        let date = Zone::default().local(yesterday).date_naive();
+        assert_eq!(summary.days.len(), 1);
+        assert_eq!(summary.days[0].date, date);
+        assert!((summary.days[0].used - 10.0).abs() < 1e-3);
+        assert!((summary.today - 9.0).abs() < 1e-3);
+        assert_eq!((summary.rollovers, summary.resets), (1, 1));
+        assert_eq!(summary.counter, 0.5);
+        assert!(summary.last_reset.is_some());
+
+        assert_eq!(change(100.0, 99.5, None), Change::Used(-0.5));
+        assert_eq!(change(100.0, 0.0, range), Change::Reset);
+        assert!(is_energy("kWh") && is_energy("varh") && !is_energy("W"));
+    }
+}
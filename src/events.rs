@@ -0,0 +1,333 @@
+//! Events picked out of the readings, served from `/events`. Generator
+//! transfers are recognised from the readings: the voltage drops out, and
+//! when it comes back the frequency is off nominal, as a generator's is. The
+//! transfer lasts until another dropout is followed by nominal frequency
+//! again, as the transfer switch goes back to the utility. Limits are the
+//! meter's own alarms, from its limits status register: each lasts from the
+//! read that finds it tripped to the one that finds it clear.
+
+use crate::config::TransferConfig;
+use crate::meter::Device;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Events kept, beyond which the oldest are forgotten.
+const MAX_EVENTS: usize = 1000;
+
+/// The events `/events` can be asked for by type.
+#[cfg(feature = "web")]
+pub const TYPES: [&str; 2] = ["transfer", "limit"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub device: String,
+    /// When the voltage dropped out, or the limit was found tripped
+    pub start: DateTime<Utc>,
+    /// When the utility came back or the limit cleared, or None while the
+    /// event goes on
+    pub end: Option<DateTime<Utc>>,
+    /// The limit tripped, as the register map names it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<String>,
+    /// How long the voltage was out before the generator took over
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outage_secs: Option<f64>,
+    /// How long the generator ran the load, once it has handed back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generator_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_frequency: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_frequency: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Utility,
+    Outage {
+        start: DateTime<Utc>,
+    },
+    Generator {
+        id: u64,
+        since: DateTime<Utc>,
+    },
+    /// Out again while on the generator, since `out`
+    Retransfer {
+        id: u64,
+        since: DateTime<Utc>,
+        out: DateTime<Utc>,
+    },
+}
+
+/// What is known of one device's supply.
+struct Supply {
+    phase: Phase,
+    /// The last voltage seen on the utility, which a dropout is relative to
+    volts: Option<f32>,
+    /// 50 or 60 Hz, from the first reading on the utility
+    nominal: Option<f32>,
+}
+
+fn seconds(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    (to - from).num_milliseconds() as f64 / 1000.0
+}
+
+/// Add `event`, numbered after the last one, forgetting the oldest if there
+/// are too many, and return its id.
+fn push(events: &mut VecDeque<Event>, mut event: Event) -> u64 {
+    event.id = events.back().map_or(1, |e| e.id + 1);
+    if events.len() == MAX_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(event);
+    events.back().unwrap().id
+}
+
+pub struct Events {
+    config: TransferConfig,
+    supplies: Mutex<HashMap<String, Supply>>,
+    /// The event of each limit tripped on each device
+    tripped: Mutex<HashMap<String, HashMap<String, u64>>>,
+    events: Mutex<VecDeque<Event>>,
+}
+
+impl Events {
+    pub fn new(config: &TransferConfig) -> Events {
+        Events {
+            config: config.clone(),
+            supplies: Mutex::new(HashMap::new()),
+            tripped: Mutex::new(HashMap::new()),
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Look for transfers in the device's latest readings.
+    pub fn record(&self, device: &Device) {
+        let (volts, frequency) = {
+            let readings = device.readings.lock().unwrap();
+            let get = |name: &str| readings.get(name);
+            match (get(&self.config.volts), get(&self.config.frequency)) {
+                (Some(volts), Some(frequency)) => (volts, frequency),
+                _ => return,
+            }
+        };
+        self.observe(&device.name, Utc::now(), volts, frequency);
+    }
+
+    fn observe(&self, device: &str, time: DateTime<Utc>, volts: f32, frequency: f32) {
+        let mut supplies = self.supplies.lock().unwrap();
+        let supply = supplies.entry(device.to_owned()).or_insert(Supply {
+            phase: Phase::Utility,
+            volts: None,
+            nominal: None,
+        });
+        let out = supply
+            .volts
+            .is_some_and(|v| volts < v * self.config.dropout as f32);
+        if out {
+            supply.phase = match supply.phase {
+                Phase::Utility => Phase::Outage { start: time },
+                Phase::Generator { id, since } => Phase::Retransfer {
+                    id,
+                    since,
+                    out: time,
+                },
+                phase => phase,
+            };
+            return;
+        }
+        let nominal = *supply
+            .nominal
+            .get_or_insert_with(|| crate::quality::nominal_frequency(frequency));
+        let off = (frequency - nominal).abs() as f64 > self.config.tolerance;
+        let mut events = self.events.lock().unwrap();
+        supply.phase = match supply.phase {
+            Phase::Utility => {
+                supply.volts = Some(volts);
+                Phase::Utility
+            }
+            Phase::Outage { start } if off => {
+                let outage_secs = seconds(start, time);
+                tracing::warn!(device, outage_secs, frequency, "transferred to generator");
+                let event = Event {
+                    id: 0,
+                    kind: "transfer",
+                    device: device.to_owned(),
+                    start,
+                    end: None,
+                    limit: None,
+                    outage_secs: Some(outage_secs),
+                    generator_secs: None,
+                    min_frequency: Some(frequency),
+                    max_frequency: Some(frequency),
+                };
+                let id = push(&mut events, event);
+                Phase::Generator { id, since: time }
+            }
+            // Back on the utility after a plain outage
+            Phase::Outage { .. } => Phase::Utility,
+            Phase::Generator { id, since } | Phase::Retransfer { id, since, .. } => {
+                match events.iter_mut().find(|e| e.id == id) {
+                    // Forgotten, with too many events since
+                    None => Phase::Utility,
+                    Some(event) => {
+                        event.min_frequency = event.min_frequency.map(|f| f.min(frequency));
+                        event.max_frequency = event.max_frequency.map(|f| f.max(frequency));
+                        match supply.phase {
+                            Phase::Retransfer { out, .. } if !off => {
+                                event.end = Some(time);
+                                event.generator_secs = Some(seconds(since, out));
+                                tracing::warn!(
+                                    device,
+                                    generator_secs = event.generator_secs,
+                                    "transferred back to the utility"
+                                );
+                                Phase::Utility
+                            }
+                            _ => Phase::Generator { id, since },
+                        }
+                    }
+                }
+            }
+        };
+    }
+
+    /// Note the limits the meter says are tripped on the device, as just
+    /// read: a limit newly tripped starts an event, and one no longer tripped
+    /// ends its event.
+    pub fn limits(&self, device: &str, tripped: &[String]) {
+        self.observe_limits(device, Utc::now(), tripped);
+    }
+
+    fn observe_limits(&self, device: &str, time: DateTime<Utc>, tripped: &[String]) {
+        let mut limits = self.tripped.lock().unwrap();
+        let open = limits.entry(device.to_owned()).or_default();
+        let mut events = self.events.lock().unwrap();
+        open.retain(|limit, id| {
+            if tripped.contains(limit) {
+                return true;
+            }
+            tracing::warn!(device, limit, "meter limit cleared");
+            if let Some(event) = events.iter_mut().find(|e| e.id == *id) {
+                event.end = Some(time);
+            }
+            false
+        });
+        for limit in tripped {
+            if open.contains_key(limit) {
+                continue;
+            }
+            tracing::warn!(device, limit, "meter limit tripped");
+            let event = Event {
+                id: 0,
+                kind: "limit",
+                device: device.to_owned(),
+                start: time,
+                end: None,
+                limit: Some(limit.clone()),
+                outage_secs: None,
+                generator_secs: None,
+                min_frequency: None,
+                max_frequency: None,
+            };
+            open.insert(limit.clone(), push(&mut events, event));
+        }
+    }
+
+    /// The events of type `kind`, or of every type, oldest first.
+    #[cfg(any(test, feature = "web"))]
+    pub fn list(&self, kind: Option<&str>) -> Vec<Event> {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .filter(|e| kind.is_none_or(|kind| e.kind == kind))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    #[test]
+    fn detects_generator_transfers() {
+        let events = Events::new(&TransferConfig::default());
+        let start = Utc::now();
+        let at = |secs| start + TimeDelta::seconds(secs);
+        let supply = [
+            (0, 240.0, 60.0),
+            (10, 0.0, 0.0),
+            (12, 0.0, 0.0),
+            (20, 238.0, 61.2),
+            (30, 238.0, 60.1),
+            (40, 238.0, 59.3),
+            (100, 0.0, 0.0),
+            (101, 241.0, 60.0),
+            (110, 241.0, 60.0),
+            // an outage without a generator isn't a transfer
+            (200, 10.0, 60.0),
+            (201, 240.0, 60.0),
+        ];
+        for (secs, volts, frequency) in supply {
+            events.observe("main", at(secs), volts, frequency);
+            if secs == 30 {
+                let ongoing = events.list(Some("transfer"));
+                assert_eq!(ongoing.len(), 1);
+                assert_eq!(ongoing[0].end, None);
+            }
+        }
+        let transfers = events.list(Some("transfer"));
+        assert_eq!(transfers.len(), 1);
+        let transfer = &transfers[0];
+        assert_eq!((transfer.start, transfer.end), (at(10), Some(at(101))));
+        assert_eq!(transfer.outage_secs, Some(10.0));
+        assert_eq!(transfer.generator_secs, Some(80.0));
+        assert_eq!(
+            (transfer.min_frequency, transfer.max_frequency),
+            (Some(59.3), Some(61.2))
+        );
+        assert!(events.list(Some("outage")).is_empty());
+    }
+
+    #[test]
+    fn follows_limits_tripping_and_clearing() {
+        let events = Events::new(&TransferConfig::default());
+        let start = Utc::now();
+        let at = |secs| start + TimeDelta::seconds(secs);
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        events.observe_limits("main", at(0), &[]);
+        events.observe_limits("main", at(5), &names(&["limit1_above"]));
+        events.observe_limits("main", at(10), &names(&["limit1_above", "limit2_below"]));
+        events.observe_limits("main", at(15), &names(&["limit2_below"]));
+        events.observe_limits("sub", at(15), &names(&["limit1_above"]));
+
+        let limits = events.list(Some("limit"));
+        let seen: Vec<_> = limits
+            .iter()
+            .map(|e| {
+                (
+                    e.device.as_str(),
+                    e.limit.as_deref().unwrap(),
+                    e.start,
+                    e.end,
+                )
+            })
+            .collect();
+        assert_eq!(
+            seen,
+            [
+                ("main", "limit1_above", at(5), Some(at(15))),
+                ("main", "limit2_below", at(10), None),
+                ("sub", "limit1_above", at(15), None),
+            ]
+        );
+        assert!(events.list(Some("transfer")).is_empty());
+    }
+}
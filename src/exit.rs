@@ -0,0 +1,88 @@
+//! Exit codes, so that a supervisor such as systemd or a container runtime
+//! can tell a configuration that will never work, which restarting won't
+//! fix, from a failure that may pass. The codes are those of sysexits.h.
+//!
+//! An error that ends sharkmon is an `io::Error` like any other; one whose
+//! cause is known carries its exit code inside, put there with `tag`, and
+//! anything else exits with 1.
+
+use std::fmt;
+use std::io;
+
+/// Why sharkmon exited, as its exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Anything without a code of its own, such as a full disk
+    Failure = 1,
+    /// With --fail-fast, a meter that couldn't be reached at all
+    Unreachable = 69,
+    /// A server sharkmon runs, such as the web server or a Modbus/TCP
+    /// proxy, couldn't start listening, e.g. because its port is taken
+    Bind = 71,
+    /// The configuration is invalid, or a file it names can't be loaded
+    Config = 78,
+}
+
+/// An error carrying the exit code it ends sharkmon with.
+#[derive(Debug)]
+struct Fatal {
+    exit: Exit,
+    error: io::Error,
+}
+
+impl fmt::Display for Fatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for Fatal {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// `e`, ending sharkmon with `exit` if it gets that far. An error already
+/// tagged keeps its code.
+pub fn tag(exit: Exit, e: io::Error) -> io::Error {
+    if code(&e) != Exit::Failure {
+        return e;
+    }
+    io::Error::new(e.kind(), Fatal { exit, error: e })
+}
+
+/// `e` with `what` it was doing put before its message, keeping its exit
+/// code.
+pub fn context(what: &str, e: io::Error) -> io::Error {
+    let exit = code(&e);
+    let e = io::Error::new(e.kind(), format!("{what}: {e}"));
+    match exit {
+        Exit::Failure => e,
+        exit => tag(exit, e),
+    }
+}
+
+/// The exit code that `e` ends sharkmon with.
+pub fn code(e: &io::Error) -> Exit {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<Fatal>())
+        .map_or(Exit::Failure, |fatal| fatal.exit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tagged_errors_keep_their_first_code() {
+        let e = io::Error::new(io::ErrorKind::AddrInUse, "address in use");
+        assert_eq!(code(&e), Exit::Failure);
+        let e = tag(Exit::Bind, e);
+        assert_eq!(code(&e), Exit::Bind);
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(e.to_string(), "address in use");
+        let e = context("web server", e);
+        assert_eq!(e.to_string(), "web server: address in use");
+        assert_eq!(code(&tag(Exit::Config, e)), Exit::Bind);
+    }
+}
@@ -0,0 +1,230 @@
+//! Stored history as a file to download from `/history/export`: CSV, a JSON
+//! array, or Parquet for analysis tools. Each row is one device's readings at
+//! one time, with a column for every reading of any device. The archive
+//! writes its files the same way, so without the web server only Parquet is
+//! used.
+#![cfg_attr(not(feature = "web"), allow(dead_code))]
+
+use crate::history::Samples;
+use crate::output;
+use crate::timezone::Zone;
+use chrono::SecondsFormat;
+use serde::Deserialize;
+use std::fmt::Write;
+
+/// Rows encoded into each chunk of a streamed CSV or JSON file.
+const CHUNK_ROWS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Csv,
+    Json,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Json => "application/json",
+            #[cfg(feature = "parquet")]
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Json => "json",
+            #[cfg(feature = "parquet")]
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+/// The samples of each device being exported, and the columns they fill.
+pub struct Export {
+    names: Vec<String>,
+    devices: Vec<(String, Samples)>,
+    /// The timezone of CSV timestamps
+    zone: Zone,
+}
+
+impl Export {
+    pub fn new(devices: Vec<(String, Samples)>) -> Export {
+        let mut names: Vec<String> = Vec::new();
+        for (_, samples) in &devices {
+            for name in samples.names.iter() {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        Export {
+            names,
+            devices,
+            zone: Zone::default(),
+        }
+    }
+
+    /// Give CSV timestamps in `zone`.
+    pub fn in_zone(mut self, zone: Zone) -> Export {
+        self.zone = zone;
+        self
+    }
+
+    /// Each row: the device, the time, and a value for every column.
+    fn rows(
+        &self,
+    ) -> impl Iterator<Item = (&str, &chrono::DateTime<chrono::Utc>, Vec<Option<f32>>)> {
+        self.devices.iter().flat_map(move |(device, samples)| {
+            let columns: Vec<Option<usize>> = self
+                .names
+                .iter()
+                .map(|n| samples.names.iter().position(|m| m == n))
+                .collect();
+            samples.points.iter().map(move |(time, values)| {
+                let row = columns.iter().map(|c| c.map(|i| values[i])).collect();
+                (device.as_str(), time, row)
+            })
+        })
+    }
+
+    /// Encode the file, passing it to `emit` a piece at a time so it can be
+    /// streamed. Stops early if `emit` returns false, e.g. once the client
+    /// has gone.
+    pub fn write(
+        &self,
+        format: Format,
+        mut emit: impl FnMut(Vec<u8>) -> bool,
+    ) -> std::io::Result<()> {
+        match format {
+            Format::Csv => {
+                let mut out = format!("time,device,{}\n", self.names.join(","));
+                for (n, (device, time, row)) in self.rows().enumerate() {
+                    write!(
+                        out,
+                        "{},{}",
+                        self.zone
+                            .local(*time)
+                            .to_rfc3339_opts(SecondsFormat::Millis, true),
+                        output::csv_field(device)
+                    )
+                    .unwrap();
+                    for value in row {
+                        out.push(',');
+                        if let Some(value) = value {
+                            write!(out, "{value}").unwrap();
+                        }
+                    }
+                    out.push('\n');
+                    if n % CHUNK_ROWS == CHUNK_ROWS - 1
+                        && !emit(std::mem::take(&mut out).into_bytes())
+                    {
+                        return Ok(());
+                    }
+                }
+                emit(out.into_bytes());
+            }
+            Format::Json => {
+                let mut out = String::from("[");
+                for (n, (device, time, row)) in self.rows().enumerate() {
+                    if n > 0 {
+                        out.push(',');
+                    }
+                    write!(
+                        out,
+                        "{{\"time\":\"{}\",\"device\":{}",
+                        time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                        serde_json::Value::from(device)
+                    )
+                    .unwrap();
+                    for (name, value) in self.names.iter().zip(row) {
+                        if let Some(value) = value {
+                            let value = serde_json::Value::from(value);
+                            write!(out, ",{}:{value}", serde_json::Value::from(name.as_str()))
+                                .unwrap();
+                        }
+                    }
+                    out.push('}');
+                    if n % CHUNK_ROWS == CHUNK_ROWS - 1
+                        && !emit(std::mem::take(&mut out).into_bytes())
+                    {
+                        return Ok(());
+                    }
+                }
+                out.push_str("]\n");
+                emit(out.into_bytes());
+            }
+            #[cfg(feature = "parquet")]
+            Format::Parquet => {
+                emit(self.parquet()?);
+            }
+        }
+        Ok(())
+    }
+
+    /// The whole file as Parquet: times as UTC milliseconds, and a nullable
+    /// 32-bit float column per reading.
+    #[cfg(feature = "parquet")]
+    pub fn parquet(&self) -> std::io::Result<Vec<u8>> {
+        use arrow_array::{
+            ArrayRef, Float32Array, RecordBatch, StringArray, TimestampMillisecondArray,
+        };
+        use arrow_schema::{DataType, Field, Schema, TimeUnit};
+        use parquet::arrow::ArrowWriter;
+        use parquet::basic::Compression;
+        use parquet::file::properties::WriterProperties;
+        use std::sync::Arc;
+
+        let utc = Some(Arc::from("UTC"));
+        let mut fields = vec![
+            Field::new(
+                "time",
+                DataType::Timestamp(TimeUnit::Millisecond, utc.clone()),
+                false,
+            ),
+            Field::new("device", DataType::Utf8, false),
+        ];
+        fields.extend(
+            self.names
+                .iter()
+                .map(|n| Field::new(n, DataType::Float32, true)),
+        );
+        let schema = Arc::new(Schema::new(fields));
+
+        let mut times = Vec::new();
+        let mut devices = Vec::new();
+        let mut values: Vec<Vec<Option<f32>>> = vec![Vec::new(); self.names.len()];
+        for (device, time, row) in self.rows() {
+            times.push(time.timestamp_millis());
+            devices.push(device);
+            for (column, value) in values.iter_mut().zip(row) {
+                column.push(value);
+            }
+        }
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(TimestampMillisecondArray::from(times).with_timezone_opt(utc)),
+            Arc::new(StringArray::from(devices)),
+        ];
+        columns.extend(
+            values
+                .into_iter()
+                .map(|v| Arc::new(Float32Array::from(v)) as ArrayRef),
+        );
+        let batch = RecordBatch::try_new(schema.clone(), columns).map_err(std::io::Error::other)?;
+
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let mut file = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut file, schema, Some(properties))
+            .map_err(std::io::Error::other)?;
+        writer.write(&batch).map_err(std::io::Error::other)?;
+        writer.close().map_err(std::io::Error::other)?;
+        Ok(file)
+    }
+}
@@ -0,0 +1,179 @@
+//! The readings sent to WebSocket clients. Each client has a bounded queue
+//! of messages between the readings bus and its socket, so a client on a
+//! slow link neither holds up the others nor makes sharkmon buffer without
+//! limit: once its queue is full, the oldest message is dropped for each new
+//! one. A client that takes nothing while `MAX_DROPPED` messages are dropped
+//! in a row is disconnected, since it is too far behind to catch up.
+
+use crate::metrics;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Messages waiting for each client, beyond which the oldest are dropped.
+pub const QUEUE_LEN: usize = 64;
+
+/// Messages dropped in a row, with none sent, that disconnect a client.
+pub const MAX_DROPPED: u64 = 256;
+
+/// Counts across every WebSocket client, for `/metrics`.
+#[derive(Debug, Default)]
+pub struct Stats {
+    clients: AtomicU64,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+impl Stats {
+    pub fn render(&self, out: &mut String) {
+        metrics::scalar(
+            out,
+            "sharkmon_websocket_clients",
+            "gauge",
+            "WebSocket clients connected.",
+            self.clients.load(Ordering::Relaxed),
+        );
+        metrics::scalar(
+            out,
+            "sharkmon_websocket_dropped_total",
+            "counter",
+            "Messages dropped because a WebSocket client fell behind.",
+            self.dropped.load(Ordering::Relaxed),
+        );
+        metrics::scalar(
+            out,
+            "sharkmon_websocket_disconnected_total",
+            "counter",
+            "WebSocket clients disconnected for falling too far behind.",
+            self.disconnected.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// One client's messages, waiting to be sent.
+pub struct Queue<'a> {
+    stats: &'a Stats,
+    state: Mutex<State>,
+    ready: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    messages: VecDeque<String>,
+    /// Dropped since the client last took a message
+    dropped: u64,
+    /// Whether the client has been given up on
+    overrun: bool,
+    /// Whether any message was dropped since `take_lost` last asked
+    lost: bool,
+}
+
+impl<'a> Queue<'a> {
+    /// A new client's queue, counted in `stats` until it is dropped.
+    pub fn new(stats: &'a Stats) -> Queue<'a> {
+        stats.clients.fetch_add(1, Ordering::Relaxed);
+        Queue {
+            stats,
+            state: Mutex::default(),
+            ready: Notify::new(),
+        }
+    }
+
+    /// Add a message, dropping the oldest if the queue is full. False once
+    /// the client has fallen too far behind, and should be disconnected.
+    pub fn push(&self, message: String) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.messages.len() == QUEUE_LEN {
+            state.messages.pop_front();
+            self.dropped(&mut state, 1);
+        }
+        state.messages.push_back(message);
+        self.ready.notify_one();
+        !state.overrun
+    }
+
+    /// Count `n` messages the client missed before they were queued, e.g.
+    /// readings it fell behind on.
+    pub fn missed(&self, n: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.dropped(&mut state, n);
+        !state.overrun
+    }
+
+    fn dropped(&self, state: &mut State, n: u64) {
+        self.stats.dropped.fetch_add(n, Ordering::Relaxed);
+        state.dropped += n;
+        state.lost |= n > 0;
+        if state.dropped >= MAX_DROPPED && !state.overrun {
+            state.overrun = true;
+            self.stats.disconnected.fetch_add(1, Ordering::Relaxed);
+            self.ready.notify_one();
+        }
+    }
+
+    /// Whether any message was dropped since this was last asked, e.g. so
+    /// that a client sent only what changed can be sent everything again.
+    pub fn take_lost(&self) -> bool {
+        std::mem::take(&mut self.state.lock().unwrap().lost)
+    }
+
+    /// The oldest message, waiting for one; None once the client has fallen
+    /// too far behind. Cancelling the wait loses nothing.
+    pub async fn pop(&self) -> Option<String> {
+        loop {
+            let ready = self.ready.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.overrun {
+                    return None;
+                }
+                if let Some(message) = state.messages.pop_front() {
+                    state.dropped = 0;
+                    return Some(message);
+                }
+            }
+            ready.await;
+        }
+    }
+}
+
+impl Drop for Queue<'_> {
+    fn drop(&mut self) {
+        self.stats.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drops_the_oldest_and_gives_up_on_clients_far_behind() {
+        let stats = Stats::default();
+        let queue = Queue::new(&stats);
+        for i in 0..QUEUE_LEN + 2 {
+            assert!(queue.push(i.to_string()));
+        }
+        // The two oldest went to make room.
+        assert_eq!(queue.pop().await.as_deref(), Some("2"));
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 2);
+        assert!(queue.take_lost());
+        assert!(!queue.take_lost());
+
+        // Taking a message starts the count again.
+        assert!(queue.missed(MAX_DROPPED - 1));
+        assert_eq!(queue.pop().await.as_deref(), Some("3"));
+        assert!(queue.missed(MAX_DROPPED - 1));
+        assert!(!queue.missed(1));
+        assert_eq!(queue.pop().await, None);
+        assert!(!queue.push("late".to_owned()));
+        assert_eq!(stats.disconnected.load(Ordering::Relaxed), 1);
+
+        let mut out = String::new();
+        stats.render(&mut out);
+        assert!(out.contains("sharkmon_websocket_clients 1\n"), "{out}");
+        drop(queue);
+        assert_eq!(stats.clients.load(Ordering::Relaxed), 0);
+    }
+}
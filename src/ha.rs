@@ -0,0 +1,142 @@
+//! Leader/standby pairs: two sharkmons watching the same meters, of which
+//! only the leader polls them, since a Shark's Ethernet card takes very few
+//! Modbus connections at once. The two send each other a UDP heartbeat every
+//! second. One that hears nothing from its peer for the `timeout` takes
+//! over, and while both are up the one with the higher `priority` leads.
+//! In a configuration file:
+//!
+//! ```toml
+//! [ha]
+//! peer = "10.0.0.2:8099"
+//! priority = 10
+//! ```
+
+use crate::config::HaConfig;
+use serde::{Deserialize, Serialize};
+use std::hash::BuildHasher;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// The port heartbeats are sent to, unless `listen` says otherwise.
+pub const PORT: u16 = 8099;
+
+/// How often each instance tells the other how it is.
+pub const HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// What each instance tells the other.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Heartbeat {
+    priority: u8,
+    /// Picked at random on startup, to break a tie in priority
+    id: u64,
+    leading: bool,
+}
+
+impl Heartbeat {
+    fn outranks(&self, other: &Heartbeat) -> bool {
+        (self.priority, self.id) > (other.priority, other.id)
+    }
+}
+
+/// Whether an instance sending `own` should lead, given the last heartbeat
+/// from its peer, if the peer isn't silent.
+fn should_lead(own: &Heartbeat, peer: Option<&Heartbeat>) -> bool {
+    match peer {
+        None => true,
+        // Both lead after the network between them comes back.
+        Some(peer) if peer.leading && own.leading => own.outranks(peer),
+        Some(peer) if peer.leading => false,
+        // A leader stays put, rather than handing over to a peer restarting.
+        Some(peer) => own.leading || own.outranks(peer),
+    }
+}
+
+/// Join the pair, returning whether this instance leads, which changes as
+/// the two hear from each other.
+pub async fn start(config: &HaConfig) -> std::io::Result<watch::Receiver<bool>> {
+    let socket = UdpSocket::bind(config.listen).await?;
+    let (leading, receiver) = watch::channel(false);
+    // Each RandomState is freshly keyed, which is random enough for a tie.
+    let id = std::collections::hash_map::RandomState::new().hash_one(config.listen);
+    let own = Heartbeat {
+        priority: config.priority,
+        id,
+        leading: false,
+    };
+    tokio::spawn(run(socket, config.clone(), own, leading));
+    Ok(receiver)
+}
+
+async fn run(
+    socket: UdpSocket,
+    config: HaConfig,
+    mut own: Heartbeat,
+    leading: watch::Sender<bool>,
+) {
+    let started = Instant::now();
+    let mut peer: Option<(Heartbeat, Instant)> = None;
+    let mut interval = tokio::time::interval(HEARTBEAT);
+    let mut buf = [0u8; 256];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let message = serde_json::to_vec(&own).unwrap();
+                if let Err(e) = socket.send_to(&message, &config.peer).await {
+                    debug!(peer = %config.peer, error = %e, "could not send the heartbeat");
+                }
+            }
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, from)) => match serde_json::from_slice(&buf[..len]) {
+                    Ok(heartbeat) => peer = Some((heartbeat, Instant::now())),
+                    Err(e) => warn!(%from, error = %e, "ignoring a message that isn't a heartbeat"),
+                },
+                // Some systems report the peer's port being closed this way.
+                Err(e) => debug!(error = %e, "no heartbeat"),
+            }
+        }
+        // Give a peer that already leads the timeout to say so.
+        if peer.is_none() && started.elapsed() < config.timeout {
+            continue;
+        }
+        let heard = peer
+            .as_ref()
+            .filter(|(_, at)| at.elapsed() < config.timeout)
+            .map(|(heartbeat, _)| heartbeat);
+        let lead = should_lead(&own, heard);
+        if lead != own.leading {
+            own.leading = lead;
+            if lead {
+                warn!(peer = %config.peer, "leading: polling the meters");
+            } else {
+                info!(peer = %config.peer, "standing by while the peer polls the meters");
+            }
+            let _ = leading.send(lead);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_higher_priority_leads() {
+        let beat = |priority, leading| Heartbeat {
+            priority,
+            id: priority.into(),
+            leading,
+        };
+        // A silent peer is taken to be down.
+        assert!(should_lead(&beat(1, false), None));
+        assert!(should_lead(&beat(2, false), Some(&beat(1, false))));
+        assert!(!should_lead(&beat(1, false), Some(&beat(2, false))));
+        assert!(!should_lead(&beat(2, false), Some(&beat(1, true))));
+        assert!(should_lead(&beat(1, true), Some(&beat(2, false))));
+        // Two leaders settle on one.
+        assert!(should_lead(&beat(2, true), Some(&beat(1, true))));
+        assert!(!should_lead(&beat(1, true), Some(&beat(2, true))));
+    }
+}
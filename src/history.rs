@@ -0,0 +1,373 @@
+//! Recent readings kept in memory for `/history`, so a dashboard can draw a
+//! chart without a database of its own. Queries can summarise the samples on
+//! the server, e.g. into one-minute averages, so a week-long chart doesn't
+//! download every one-second sample.
+//!
+//! Every reading is kept for the retention period. Tiers of averages, such
+//! as one-minute averages for a month and 15-minute averages for a year, are
+//! built as readings arrive and kept for longer, so months of history take
+//! little memory.
+
+use crate::config::HistoryTier;
+use crate::meter::Device;
+use crate::meterlog::Record;
+use crate::timezone::Zone;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How the samples in each step are summarised.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    #[default]
+    Avg,
+    Min,
+    Max,
+    /// 95th percentile
+    P95,
+}
+
+type Points = VecDeque<(DateTime<Utc>, Box<[f32]>)>;
+
+/// A tier's averages for one device.
+struct TierPoints {
+    points: Points,
+    /// The step being averaged: its number since the epoch, the sums of
+    /// each reading, and how many samples they add up
+    pending: Option<(i64, Vec<f64>, u32)>,
+}
+
+impl TierPoints {
+    fn add(&mut self, tier: &HistoryTier, time: DateTime<Utc>, values: &[f32]) {
+        let step = step_number(time, tier.step);
+        if let Some((n, sums, count)) = &mut self.pending {
+            if *n == step {
+                for (sum, value) in sums.iter_mut().zip(values) {
+                    *sum += *value as f64;
+                }
+                *count += 1;
+                return;
+            }
+            if let Some(point) = average(*n, sums, *count, tier.step) {
+                self.points.push_back(point);
+            }
+        }
+        self.pending = Some((step, values.iter().map(|&v| v as f64).collect(), 1));
+        expire(&mut self.points, time, tier.keep);
+    }
+
+    /// The averages, including the step still being filled.
+    fn all(&self, step: Duration) -> Vec<(DateTime<Utc>, Box<[f32]>)> {
+        let pending = self
+            .pending
+            .as_ref()
+            .and_then(|(n, sums, count)| average(*n, sums, *count, step));
+        self.points.iter().cloned().chain(pending).collect()
+    }
+}
+
+fn step_number(time: DateTime<Utc>, step: Duration) -> i64 {
+    time.timestamp_millis()
+        .div_euclid(step.as_millis().max(1) as i64)
+}
+
+fn average(
+    n: i64,
+    sums: &[f64],
+    count: u32,
+    step: Duration,
+) -> Option<(DateTime<Utc>, Box<[f32]>)> {
+    let time = DateTime::from_timestamp_millis(n * step.as_millis().max(1) as i64)?;
+    let values = sums.iter().map(|s| (s / count as f64) as f32).collect();
+    Some((time, values))
+}
+
+/// Add a point at `time`, in order, unless there is one already.
+fn insert(points: &mut Points, time: DateTime<Utc>, values: &[f32]) -> bool {
+    let at = points.partition_point(|(t, _)| *t < time);
+    if points.get(at).is_some_and(|(t, _)| *t == time) {
+        return false;
+    }
+    points.insert(at, (time, values.into()));
+    true
+}
+
+/// Forget points more than `keep` before `now`.
+fn expire(points: &mut Points, now: DateTime<Utc>, keep: Duration) {
+    let cutoff = now - keep;
+    while points.front().is_some_and(|(t, _)| *t < cutoff) {
+        points.pop_front();
+    }
+}
+
+/// One device's samples, oldest first, and its tiers.
+struct Series {
+    names: Arc<[String]>,
+    samples: Points,
+    tiers: Vec<TierPoints>,
+}
+
+/// Every device's readings over the retention period.
+pub struct History {
+    retention: Duration,
+    tiers: Vec<HistoryTier>,
+    series: Mutex<BTreeMap<String, Series>>,
+    /// Where query steps start from: local midnight, for steps of a day
+    zone: Zone,
+}
+
+/// The result of a query: the reading names, and a time and value for each.
+pub struct Samples {
+    pub names: Arc<[String]>,
+    pub points: Vec<(DateTime<Utc>, Vec<f32>)>,
+}
+
+impl Serialize for Samples {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        struct Point<'a>(&'a [String], &'a DateTime<Utc>, &'a [f32]);
+        impl Serialize for Point<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(Some(self.0.len() + 1))?;
+                map.serialize_entry("time", &self.1.to_rfc3339_opts(SecondsFormat::Millis, true))?;
+                for (name, value) in self.0.iter().zip(self.2) {
+                    map.serialize_entry(name, value)?;
+                }
+                map.end()
+            }
+        }
+        let mut seq = serializer.serialize_seq(Some(self.points.len()))?;
+        for (time, values) in &self.points {
+            seq.serialize_element(&Point(&self.names, time, values))?;
+        }
+        seq.end()
+    }
+}
+
+impl History {
+    /// Keep readings for `retention`, and averages for each of `tiers`,
+    /// which get coarser and longer.
+    pub fn new(retention: Duration, tiers: &[HistoryTier], zone: Zone) -> History {
+        History {
+            retention,
+            tiers: tiers.to_vec(),
+            series: Mutex::new(BTreeMap::new()),
+            zone,
+        }
+    }
+
+    /// Add the device's current readings, and forget those past retention.
+    pub fn record(&self, device: &Device) {
+        let readings = device.readings.lock().unwrap().clone();
+        let now = Utc::now();
+        let values: Box<[f32]> = readings.iter().map(|(_, v)| v).collect();
+        let mut series = self.series.lock().unwrap();
+        let series = series
+            .entry(device.name.clone())
+            .or_insert_with(|| self.series(readings.names()));
+        for (tier, points) in self.tiers.iter().zip(&mut series.tiers) {
+            points.add(tier, now, &values);
+        }
+        series.samples.push_back((now, values));
+        expire(&mut series.samples, now, self.retention);
+    }
+
+    fn series(&self, names: Arc<[String]>) -> Series {
+        Series {
+            names,
+            samples: VecDeque::new(),
+            tiers: self
+                .tiers
+                .iter()
+                .map(|_| TierPoints {
+                    points: VecDeque::new(),
+                    pending: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Fill in readings of the device from the past, such as records of a
+    /// log the meter kept while it couldn't be polled, giving the readings
+    /// `names`. Readings they don't include are left out as NaN, which shows
+    /// as null. Times that already have samples, or averages, are left as
+    /// they are. Returns how many records were added to the samples.
+    pub fn backfill(&self, device: &Device, names: &[String], records: &[Record]) -> usize {
+        let all = device.readings.lock().unwrap().names();
+        let index: Vec<Option<usize>> = names
+            .iter()
+            .map(|n| all.iter().position(|a| a == n))
+            .collect();
+        let now = Utc::now();
+        let mut series = self.series.lock().unwrap();
+        let series = series
+            .entry(device.name.clone())
+            .or_insert_with(|| self.series(all.clone()));
+        let width = series.names.len();
+        let points: Vec<(DateTime<Utc>, Box<[f32]>)> = records
+            .iter()
+            .map(|r| {
+                let mut values = vec![f32::NAN; width];
+                for (i, value) in index.iter().zip(&r.values) {
+                    if let Some(i) = i.filter(|&i| i < width) {
+                        values[i] = *value as f32;
+                    }
+                }
+                (r.time, values.into())
+            })
+            .collect();
+        let mut added = 0;
+        for (time, values) in &points {
+            if *time >= now - self.retention && insert(&mut series.samples, *time, values) {
+                added += 1;
+            }
+        }
+        for (tier, tier_points) in self.tiers.iter().zip(&mut series.tiers) {
+            let mut steps: BTreeMap<i64, (Vec<f64>, u32)> = BTreeMap::new();
+            for (time, values) in points.iter().filter(|(t, _)| *t >= now - tier.keep) {
+                let (sums, count) = steps
+                    .entry(step_number(*time, tier.step))
+                    .or_insert_with(|| (vec![0.0; width], 0));
+                for (sum, value) in sums.iter_mut().zip(values.iter()) {
+                    *sum += *value as f64;
+                }
+                *count += 1;
+            }
+            let pending = tier_points.pending.as_ref().map(|(n, _, _)| *n);
+            for (n, (sums, count)) in steps {
+                if pending == Some(n) {
+                    continue;
+                }
+                if let Some((time, values)) = average(n, &sums, count, tier.step) {
+                    insert(&mut tier_points.points, time, &values);
+                }
+            }
+        }
+        added
+    }
+
+    /// The devices with history, in name order.
+    pub fn devices(&self) -> Vec<String> {
+        self.series.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// The device's samples between `from` and `to`, each `step` long and
+    /// summarised with `agg` if a step is given. They come from the readings
+    /// themselves if they go back far enough, or else from the first tier
+    /// that does. `None` if the device has no history.
+    pub fn query(
+        &self,
+        device: &str,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        step: Option<Duration>,
+        agg: Aggregate,
+    ) -> Option<Samples> {
+        let series = self.series.lock().unwrap();
+        let series = series.get(device)?;
+        let reaches = |keep: Duration| from.is_some_and(|from| from >= Utc::now() - keep);
+        // The finest level that goes back to `from`, or else the coarsest.
+        let tier = if self.tiers.is_empty() || reaches(self.retention) {
+            None
+        } else {
+            let i = self.tiers.iter().position(|t| reaches(t.keep));
+            Some(i.unwrap_or(self.tiers.len() - 1))
+        };
+        let averages;
+        let source = match tier {
+            None => &series.samples,
+            Some(i) => {
+                averages = series.tiers[i].all(self.tiers[i].step).into();
+                &averages
+            }
+        };
+        let in_range = source
+            .iter()
+            .filter(|(t, _)| from.is_none_or(|from| *t >= from) && to.is_none_or(|to| *t <= to));
+        let points = match step {
+            None => in_range.map(|(t, v)| (*t, v.to_vec())).collect(),
+            Some(step) => aggregate(in_range, series.names.len(), step, agg, &self.zone),
+        };
+        Some(Samples {
+            names: series.names.clone(),
+            points,
+        })
+    }
+}
+
+/// Group samples into steps counted from midnight in `zone`, e.g. on whole
+/// minutes or local days, each timestamped with its start.
+fn aggregate<'a>(
+    samples: impl Iterator<Item = &'a (DateTime<Utc>, Box<[f32]>)>,
+    width: usize,
+    step: Duration,
+    agg: Aggregate,
+    zone: &Zone,
+) -> Vec<(DateTime<Utc>, Vec<f32>)> {
+    let step_ms = step.as_millis().max(1) as i64;
+
+    let mut points = Vec::new();
+    let mut bucket: Option<i64> = None;
+    let mut columns: Vec<Vec<f32>> = vec![Vec::new(); width];
+    let mut flush = |start: i64, columns: &mut Vec<Vec<f32>>| {
+        let values = columns.iter_mut().map(|c| summarise(c, agg)).collect();
+        if let Some(time) = DateTime::from_timestamp_millis(start) {
+            points.push((time, values));
+        }
+        columns.iter_mut().for_each(Vec::clear);
+    };
+    for (time, values) in samples {
+        let start = zone.step_start(time.timestamp_millis(), step_ms);
+        if bucket.is_some_and(|b| b != start) {
+            flush(bucket.unwrap(), &mut columns);
+        }
+        bucket = Some(start);
+        for (column, value) in columns.iter_mut().zip(values.iter()) {
+            column.push(*value);
+        }
+    }
+    if let Some(b) = bucket {
+        flush(b, &mut columns);
+    }
+    points
+}
+
+fn summarise(values: &mut [f32], agg: Aggregate) -> f32 {
+    if values.is_empty() {
+        return f32::NAN;
+    }
+    match agg {
+        Aggregate::Avg => values.iter().sum::<f32>() / values.len() as f32,
+        Aggregate::Min => values.iter().copied().fold(f32::INFINITY, f32::min),
+        Aggregate::Max => values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        Aggregate::P95 => {
+            // Nearest rank: the smallest value at or above 95% of the samples.
+            values.sort_by(f32::total_cmp);
+            let rank = (values.len() as f64 * 0.95).ceil() as usize;
+            values[rank.clamp(1, values.len()) - 1]
+        }
+    }
+}
+
+/// A time in a query: an RFC 3339 timestamp, or a duration such as `1h`
+/// meaning that long ago.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct QueryTime(pub DateTime<Utc>);
+
+impl TryFrom<String> for QueryTime {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<QueryTime, String> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(&s) {
+            return Ok(QueryTime(time.to_utc()));
+        }
+        let ago = humantime::parse_duration(&s)
+            .map_err(|_| format!("'{s}' is neither an RFC 3339 time nor a duration"))?;
+        let ago = chrono::Duration::from_std(ago).map_err(|e| e.to_string())?;
+        Ok(QueryTime(Utc::now() - ago))
+    }
+}
@@ -0,0 +1,146 @@
+//! Readings in the shape Home Assistant's RESTful sensors want: each one with
+//! the unit, device class and state class that tell Home Assistant how to
+//! show and record it, and a `configuration.yaml` snippet setting them all
+//! up.
+
+use crate::meter::{Device, Meter};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// How often the generated configuration polls, in seconds.
+const SCAN_INTERVAL: u32 = 10;
+
+/// One reading as a Home Assistant sensor.
+#[derive(Debug, Serialize)]
+pub struct Sensor {
+    pub state: f32,
+    pub name: String,
+    pub unique_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<&'static str>,
+    pub state_class: &'static str,
+}
+
+/// Home Assistant's device class for readings in `units`, and whether the
+/// reading only ever grows.
+fn classify(reading: &str, units: Option<&str>) -> (Option<&'static str>, bool) {
+    match units {
+        Some("W" | "kW") => (Some("power"), false),
+        Some("V") => (Some("voltage"), false),
+        Some("A") => (Some("current"), false),
+        Some("Hz") => (Some("frequency"), false),
+        Some("VA" | "kVA") => (Some("apparent_power"), false),
+        Some("var" | "VAR" | "kvar") => (Some("reactive_power"), false),
+        Some("Wh" | "kWh" | "MWh") => (Some("energy"), true),
+        Some("°C" | "°F") => (Some("temperature"), false),
+        _ if reading == "power_factor" || reading == "pf" => (Some("power_factor"), false),
+        _ => (None, false),
+    }
+}
+
+impl Sensor {
+    pub fn new(meter: &Meter, device: &Device, reading: &str, state: f32) -> Sensor {
+        let units = meter.units(reading);
+        let (device_class, total) = classify(reading, units);
+        Sensor {
+            state,
+            name: format!("{} {}", device.name, reading.replace('_', " ")),
+            unique_id: unique_id(device, reading),
+            unit_of_measurement: units.map(str::to_owned),
+            device_class,
+            state_class: if total {
+                "total_increasing"
+            } else {
+                "measurement"
+            },
+        }
+    }
+}
+
+fn unique_id(device: &Device, reading: &str) -> String {
+    format!("sharkmon_{}_{reading}", device.name)
+}
+
+/// Every reading of `device` as a sensor, by reading name.
+pub fn sensors(meter: &Meter, device: &Device) -> BTreeMap<String, Sensor> {
+    let readings = device.readings.lock().unwrap().clone();
+    readings
+        .iter()
+        .map(|(name, value)| (name.to_owned(), Sensor::new(meter, device, name, value)))
+        .collect()
+}
+
+/// A JSON string, which YAML reads as the same string.
+fn quote(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
+
+/// A `rest:` section for `configuration.yaml` with a sensor for every
+/// reading, each device's read with one request to `base`, e.g.
+/// "http://sharkmon.local:8081".
+pub fn config(base: &str, meters: &[Arc<Meter>]) -> String {
+    let mut yaml = String::from(
+        "# Home Assistant RESTful sensors for sharkmon, for configuration.yaml\nrest:\n",
+    );
+    for meter in meters {
+        for device in &meter.devices {
+            let resource = format!("{base}/ha/sensors/{}", device.name);
+            let _ = writeln!(yaml, "  - resource: {}", quote(&resource));
+            let _ = writeln!(yaml, "    scan_interval: {SCAN_INTERVAL}");
+            let _ = writeln!(yaml, "    sensor:");
+            for (reading, sensor) in sensors(meter, device) {
+                let template = format!("{{{{ value_json['{reading}'].state }}}}");
+                let _ = writeln!(yaml, "      - name: {}", quote(&sensor.name));
+                let _ = writeln!(yaml, "        unique_id: {}", quote(&sensor.unique_id));
+                let _ = writeln!(yaml, "        value_template: {}", quote(&template));
+                if let Some(units) = &sensor.unit_of_measurement {
+                    let _ = writeln!(yaml, "        unit_of_measurement: {}", quote(units));
+                }
+                if let Some(class) = sensor.device_class {
+                    let _ = writeln!(yaml, "        device_class: {class}");
+                }
+                let _ = writeln!(yaml, "        state_class: {}", sensor.state_class);
+            }
+        }
+    }
+    yaml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_readings_by_units() {
+        assert_eq!(classify("watts", Some("W")), (Some("power"), false));
+        assert_eq!(classify("kwh", Some("kWh")), (Some("energy"), true));
+        assert_eq!(
+            classify("power_factor", None),
+            (Some("power_factor"), false)
+        );
+        assert_eq!(classify("status", None), (None, false));
+    }
+
+    #[test]
+    fn configures_a_sensor_per_reading() {
+        let parsed: crate::config::Config =
+            toml::from_str("[[meter]]\nname = \"main\"\naddress = \"fake:502\"\n").unwrap();
+        let meter = Arc::new(Meter::new(&parsed.meters[0]).unwrap());
+        let sensor = &sensors(&meter, &meter.devices[0])["watts"];
+        assert_eq!(sensor.unique_id, "sharkmon_main_watts");
+        assert_eq!(sensor.unit_of_measurement.as_deref(), Some("W"));
+
+        let yaml = config("http://pi.local:8081", &[meter]);
+        assert!(yaml.contains("  - resource: \"http://pi.local:8081/ha/sensors/main\"\n"));
+        assert!(yaml.contains(
+            "      - name: \"main watts\"\n        unique_id: \"sharkmon_main_watts\"\n        \
+             value_template: \"{{ value_json['watts'].state }}\"\n        \
+             unit_of_measurement: \"W\"\n        device_class: power\n"
+        ));
+        assert_eq!(yaml.matches("- name:").count(), 3);
+    }
+}
@@ -0,0 +1,304 @@
+//! `sharkmon init`: a first configuration file, written from the answers to a
+//! few questions. The meter is probed at the address given, or looked for on
+//! a network or with mDNS and SSDP, and identified as `sharkmon discover`
+//! does, so the file has the right profile; a Shark 200 whose V-Switch key
+//! gives it logging memory also backfills the history from its logs. The
+//! file turns the web server on, with a day of readings and a month of minute
+//! averages for the dashboard.
+
+use crate::config::Config;
+use crate::discover::{self, Cidr, Found, Identity};
+use crate::meter::Meter;
+use std::io::{self, BufRead, Error, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+/// Where the web server listens unless told otherwise.
+const DEFAULT_WEB_LISTEN: &str = "0.0.0.0:8081";
+
+/// How the meter is reached.
+pub struct Options {
+    pub port: u16,
+    pub unit: u8,
+    /// How long to wait for connections and answers
+    pub timeout: Duration,
+}
+
+/// Ask the questions on `output`, reading the answers from `input`, and
+/// write the configuration to `path`.
+pub async fn run(
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    path: &Path,
+    options: &Options,
+) -> io::Result<()> {
+    if path.exists() {
+        let question = format!("{} already exists. Replace it?", path.display());
+        if !ask(input, output, &question, Some("n"))?.eq_ignore_ascii_case("y") {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} left as it was", path.display()),
+            ));
+        }
+    }
+    let found = loop {
+        let answer = ask(
+            input,
+            output,
+            "Meter address (HOST[:PORT]), a network to scan such as 192.168.1.0/24, \
+             or nothing to ask mDNS and SSDP",
+            None,
+        )?;
+        match find(&answer, options).await {
+            Ok(found) if found.is_empty() => writeln!(output, "No meters found.")?,
+            Ok(found) => break choose(input, output, found)?,
+            Err(e) => writeln!(output, "{e}")?,
+        }
+    };
+    writeln!(
+        output,
+        "Found {} at {}.",
+        describe(&found.identity),
+        found.address
+    )?;
+    let name = loop {
+        let name = ask(input, output, "Name for the meter", Some(&found.name()))?;
+        if !name.is_empty() {
+            break name;
+        }
+    };
+    let web_listen = loop {
+        let answer = ask(
+            input,
+            output,
+            "Address for the web server",
+            Some(DEFAULT_WEB_LISTEN),
+        )?;
+        match answer.parse::<SocketAddr>() {
+            Ok(address) => break address,
+            Err(e) => writeln!(output, "'{answer}': {e}")?,
+        }
+    };
+    let text = starter(&found, &name, web_listen);
+    check(&text)?;
+    std::fs::write(path, &text)?;
+    writeln!(
+        output,
+        "Wrote {0}. Start sharkmon with: sharkmon --config {0}",
+        path.display()
+    )?;
+    Ok(())
+}
+
+/// Print `question`, with the answer given by pressing enter if there is
+/// one, and read the answer.
+fn ask(
+    input: &mut dyn BufRead,
+    output: &mut dyn Write,
+    question: &str,
+    default: Option<&str>,
+) -> io::Result<String> {
+    match default {
+        Some(default) => write!(output, "{question} [{default}]: ")?,
+        None => write!(output, "{question}: ")?,
+    }
+    output.flush()?;
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "no answer; nothing written",
+        ));
+    }
+    let answer = line.trim();
+    Ok(match default {
+        Some(default) if answer.is_empty() => default.to_owned(),
+        _ => answer.to_owned(),
+    })
+}
+
+/// The meters an answer to the address question stands for: the one at the
+/// address, those on the network, or those mDNS and SSDP know of.
+async fn find(answer: &str, options: &Options) -> io::Result<Vec<Found>> {
+    let mut discover = discover::Options {
+        networks: Vec::new(),
+        port: options.port,
+        unit: options.unit,
+        timeout: options.timeout,
+        multicast: false,
+    };
+    if answer.is_empty() {
+        discover.multicast = true;
+        return discover::discover(&discover).await;
+    }
+    if answer.contains('/') {
+        discover.networks.push(
+            answer
+                .parse::<Cidr>()
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?,
+        );
+        return discover::discover(&discover).await;
+    }
+    let lookup = |e: io::Error| Error::new(e.kind(), format!("{answer}: {e}"));
+    let addresses: Vec<SocketAddr> = match answer.parse() {
+        Ok(address) => vec![address],
+        Err(_) if answer.contains(':') => tokio::net::lookup_host(answer)
+            .await
+            .map_err(lookup)?
+            .collect(),
+        Err(_) => tokio::net::lookup_host((answer, options.port))
+            .await
+            .map_err(lookup)?
+            .collect(),
+    };
+    let address = *addresses
+        .first()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{answer} has no addresses")))?;
+    let identity = discover::probe(address, options.unit, options.timeout)
+        .await
+        .map_err(|e| Error::new(e.kind(), format!("couldn't reach {address}: {e}")))?;
+    Ok(vec![Found {
+        address,
+        sources: Vec::new(),
+        identity,
+    }])
+}
+
+/// The meter to configure, asking which if more than one was found.
+fn choose(input: &mut dyn BufRead, output: &mut dyn Write, found: Vec<Found>) -> io::Result<Found> {
+    if found.len() == 1 {
+        return Ok(found.into_iter().next().unwrap());
+    }
+    for (i, meter) in found.iter().enumerate() {
+        writeln!(
+            output,
+            "{}) {} at {}",
+            i + 1,
+            describe(&meter.identity),
+            meter.address
+        )?;
+    }
+    loop {
+        let answer = ask(input, output, "Which meter?", Some("1"))?;
+        match answer.parse::<usize>() {
+            Ok(n @ 1..) if n <= found.len() => return Ok(found.into_iter().nth(n - 1).unwrap()),
+            _ => writeln!(output, "Give a number from 1 to {}.", found.len())?,
+        }
+    }
+}
+
+fn describe(identity: &Identity) -> String {
+    match identity {
+        Identity::Shark {
+            name,
+            serial,
+            firmware,
+            vswitch,
+        } => {
+            let vswitch = vswitch
+                .map(|v| format!(", V-Switch {v}"))
+                .unwrap_or_default();
+            format!("{name} (serial {serial}, firmware {firmware}{vswitch})")
+        }
+        Identity::SunSpec => "a SunSpec device".to_owned(),
+        Identity::Unknown => "a Modbus device of unknown make".to_owned(),
+    }
+}
+
+/// The configuration file for the meter `found`, named `name`.
+pub fn starter(found: &Found, name: &str, web_listen: SocketAddr) -> String {
+    let quote = |s: &str| toml::Value::String(s.to_owned()).to_string();
+    let mut text = format!(
+        "# Written by sharkmon init; the README describes everything else that can\n\
+         # go in this file.\n\n\
+         # The version of this file's format\n\
+         version = {}\n\n\
+         # The web server, with its dashboard, /power and /metrics\n\
+         web_listen = {}\n\
+         # Every reading for a day, and minute averages for a month\n\
+         history = \"1d\"\n\
+         history_tiers = [\"1m:30d\"]\n\n",
+        crate::config::CONFIG_VERSION,
+        quote(&web_listen.to_string())
+    );
+    text += &found.config(name);
+    match &found.identity {
+        Identity::Shark { name, vswitch, .. } => {
+            text += "poll_interval = \"1s\"\n";
+            // V-Switch keys from V2 up give the Shark 200 logging memory.
+            if name.contains("200") && vswitch.is_some_and(|v| v >= 2) {
+                text += "# Fill gaps in the history from the meter's own logs\nbackfill = true\n";
+            }
+        }
+        _ => text += "poll_interval = \"5s\"\n",
+    }
+    text
+}
+
+/// Make sure sharkmon will run with the file it is about to write.
+fn check(text: &str) -> io::Result<()> {
+    let config = Config::parse(text).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("the new configuration: {e}"),
+        )
+    })?;
+    for meter in &config.meters {
+        Meter::new(meter)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_configuration_for_the_meter_found() {
+        let shark = |name: &str, vswitch| Found {
+            address: "192.168.1.10:502".parse().unwrap(),
+            sources: Vec::new(),
+            identity: Identity::Shark {
+                name: name.to_owned(),
+                serial: "0042".to_owned(),
+                firmware: "1001".to_owned(),
+                vswitch,
+            },
+        };
+        let web = DEFAULT_WEB_LISTEN.parse().unwrap();
+
+        let text = starter(&shark("Shark 200", Some(3)), "main", web);
+        check(&text).unwrap();
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(config.web_listen, Some(web));
+        let meter = &config.meters[0];
+        assert_eq!(meter.profile.as_deref(), Some("shark200"));
+        assert_eq!(meter.poll_interval, Some(Duration::from_secs(1)));
+        assert!(meter.backfill);
+
+        // Without logging memory there is no log to backfill from.
+        let text = starter(&shark("Shark 200", Some(1)), "main", web);
+        let config: Config = toml::from_str(&text).unwrap();
+        assert!(!config.meters[0].backfill);
+    }
+
+    #[test]
+    fn takes_the_default_for_an_empty_answer() {
+        let mut output = Vec::new();
+        let mut input = io::Cursor::new("\n  solar \n");
+        let question = "Name for the meter";
+        assert_eq!(
+            ask(&mut input, &mut output, question, Some("main")).unwrap(),
+            "main"
+        );
+        assert_eq!(
+            ask(&mut input, &mut output, question, Some("main")).unwrap(),
+            "solar"
+        );
+        let eof = ask(&mut input, &mut output, question, None).unwrap_err();
+        assert_eq!(eof.kind(), ErrorKind::UnexpectedEof);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("Name for the meter [main]: "));
+    }
+}
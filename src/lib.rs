@@ -0,0 +1,1881 @@
+//! Sharkmon runs a small web server that displays the current power status of
+//! the monitored Shark power monitor. It can instead run as a command line
+//! utility to display the status locally.
+//!
+//! The `sharkmon` binary only calls [`main`]. A crate embedding sharkmon can
+//! do the same after registering exporters of its own; see [`sink`].
+//!
+//! See the 'Opt' struct for a description of command-line options.
+
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use registers::ResetKind;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::Instrument;
+
+#[cfg(feature = "acme")]
+mod acme;
+mod alert;
+#[cfg(feature = "parquet")]
+mod archive;
+mod audit;
+mod bacnet;
+mod baseline;
+mod bench;
+mod bus;
+mod check;
+pub mod client;
+pub mod config;
+mod derived;
+mod discover;
+mod energy;
+mod events;
+mod exit;
+#[cfg(any(feature = "web", feature = "parquet"))]
+mod export;
+#[cfg(feature = "web")]
+mod fanout;
+mod ha;
+mod history;
+#[cfg(feature = "web")]
+mod homeassistant;
+mod init;
+mod mdns;
+pub mod meter;
+mod meterlog;
+mod metrics;
+#[cfg(feature = "opcua")]
+mod opcua;
+mod output;
+#[cfg(feature = "dnp3")]
+mod outstation;
+#[cfg(feature = "web")]
+mod proto;
+mod proxy;
+mod quality;
+mod recording;
+pub mod registers;
+mod schema;
+#[cfg(feature = "scripting")]
+mod script;
+mod service;
+pub mod sink;
+mod snmp;
+mod state;
+mod sunspec;
+mod systemd;
+mod tasks;
+mod timezone;
+mod tls;
+#[cfg(feature = "web")]
+mod web;
+
+use exit::Exit;
+
+/// Shark 100S (and other Modbus) power meter web gateway
+#[derive(Parser)]
+#[clap(
+    name = "sharkmon",
+    about,
+    author,
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Show every message received from the power meter
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// The IP address/hostname and port of meter, e.g., 192.168.1.100:502
+    #[clap(required_unless_present_any = ["config", "replay"])]
+    meter: Option<String>,
+
+    /// Another address of the same meter, e.g. its second network card or a
+    /// backup serial gateway, tried when the first can't be reached. Repeat
+    /// for several.
+    #[clap(long, value_name = "ADDRESS")]
+    failover: Vec<String>,
+
+    /// TOML file describing the meters to monitor, instead of a single meter
+    /// given on the command line
+    #[clap(short, long, env = "SHARKMON_CONFIG", value_name = "FILE", conflicts_with_all = [
+        "meter", "failover", "transport", "tls", "read_gap", "register_formats", "keepalive", "timeout", "heartbeat", "retries", "align", "align_jitter", "poll_offset", "adaptive", "adaptive_threshold", "poll_interval", "ewma_alpha", "clock_sync", "backfill", "writable", "proxy", "proxy_readable", "proxy_functions", "proxy_rate", "profile", "register_map", "sunspec", "firmware", "units", "total_subtract", "labels", "derived", "output_units", "precision", "sign", "split",
+    ])]
+    config: Option<PathBuf>,
+
+    /// Log filter directives, e.g. "info" or "warn,sharkmon::meter=debug".
+    /// Defaults to RUST_LOG, or "warn,sharkmon::audit=info" if that isn't set.
+    #[clap(long, env = "SHARKMON_LOG", value_name = "FILTER")]
+    log: Option<String>,
+
+    /// Write log messages as plain text, as one JSON object per line, or to
+    /// the systemd journal. Defaults to the journal when run by systemd.
+    #[clap(long, env = "SHARKMON_LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// Poll every meter once, print the readings as JSON and exit. The exit
+    /// status is nonzero if any meter couldn't be read.
+    #[clap(long, conflicts_with_all = ["no_web", "service"])]
+    once: bool,
+
+    /// Check everything without sending anything: poll every meter once,
+    /// check each sink can be reached, and print the readings in every
+    /// output format and as each sink would be sent them. The exit status is
+    /// nonzero if any meter or sink failed.
+    #[clap(long, conflicts_with_all = ["no_web", "service", "once"])]
+    dry_run: bool,
+
+    /// Exit with status 69 if a meter can't be reached in this many
+    /// connection attempts from startup (3 if not given), rather than keep
+    /// retrying it. A meter that has been polled is retried as usual when
+    /// its connection is lost.
+    #[clap(
+        long,
+        value_name = "ATTEMPTS",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "3",
+        conflicts_with_all = ["once", "dry_run"]
+    )]
+    fail_fast: Option<u32>,
+
+    /// When the web server or another server sharkmon runs fails to start or
+    /// stops: exit with its error, or log it and keep polling
+    #[clap(
+        long,
+        env = "SHARKMON_SERVER_FAILURE",
+        value_enum,
+        value_name = "ACTION"
+    )]
+    server_failure: Option<config::ServerFailure>,
+
+    /// Answer reads from a recording made with `sharkmon record` instead of
+    /// connecting to the meters. Give the same meter options or --config as
+    /// when recording.
+    #[clap(long, value_name = "FILE", conflicts_with = "tls")]
+    replay: Option<PathBuf>,
+
+    /// Key that authorizes web requests that change meter state, such as
+    /// resets, sent as "Authorization: Bearer KEY". An operator's unless
+    /// given another role: viewer=KEY can change nothing, and admin=KEY can
+    /// also change the configuration. KEY may be file:PATH or env:NAME to
+    /// read it from a file or environment variable. Repeat for several
+    /// keys; without any, those endpoints are disabled.
+    #[clap(
+        long = "api-key",
+        env = "SHARKMON_API_KEY",
+        hide_env_values = true,
+        value_name = "[ROLE=]KEY"
+    )]
+    api_keys: Vec<config::ApiKey>,
+
+    /// Serve at most this many web requests at once, refusing any more with
+    /// 503 Service Unavailable
+    #[clap(long, env = "SHARKMON_MAX_REQUESTS_IN_FLIGHT", value_name = "N")]
+    max_requests_in_flight: Option<usize>,
+
+    /// Let each client address make this many web requests a second, in
+    /// bursts of up to a second's worth, refusing any more with 429 Too Many
+    /// Requests
+    #[clap(long, env = "SHARKMON_REQUESTS_PER_SECOND", value_name = "N")]
+    requests_per_second: Option<f64>,
+
+    /// Let /power and /power/DEVICE answer ?callback=NAME with JSONP, a
+    /// script calling NAME with the readings, for kiosk browsers and
+    /// microcontroller HTTP clients that can't make CORS requests
+    #[clap(long, conflicts_with = "no_web")]
+    jsonp: bool,
+
+    /// Serve the web server on this address instead of 0.0.0.0:8081, e.g.
+    /// 127.0.0.1:8080 to serve only this machine
+    #[clap(
+        long,
+        env = "SHARKMON_WEB_LISTEN",
+        value_name = "ADDRESS",
+        conflicts_with = "no_web"
+    )]
+    web_listen: Option<std::net::SocketAddr>,
+
+    /// Serve the web server over HTTPS with the certificate chain in this
+    /// PEM file
+    #[clap(
+        long,
+        env = "SHARKMON_WEB_TLS_CERT",
+        value_name = "FILE",
+        requires = "web_tls_key",
+        conflicts_with = "no_web"
+    )]
+    web_tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key for --web-tls-cert
+    #[clap(
+        long,
+        env = "SHARKMON_WEB_TLS_KEY",
+        value_name = "FILE",
+        requires = "web_tls_cert"
+    )]
+    web_tls_key: Option<PathBuf>,
+
+    /// Require HTTPS clients to present a certificate signed by one of the
+    /// CAs in this PEM file. Such clients are viewers, who can change meter
+    /// state only with an API key, unless given --web-admin.
+    #[clap(long, value_name = "FILE", requires = "web_tls_cert")]
+    web_client_ca: Option<PathBuf>,
+
+    /// Make the holder of the client certificate with this common name (CN)
+    /// an admin, who can reset meters, write registers and change the
+    /// configuration without an API key. Repeat for several.
+    #[clap(long = "web-admin", value_name = "CN", requires = "web_client_ca")]
+    web_admins: Vec<String>,
+
+    /// Get the HTTPS certificate for this public hostname from Let's Encrypt,
+    /// keeping it in --web-tls-cert and --web-tls-key and renewing it before
+    /// it expires. The CA checks the name by connecting to port 80, which
+    /// must reach sharkmon. Repeat for several names.
+    #[clap(long = "acme-domain", value_name = "HOST", requires = "web_tls_cert")]
+    acme_domains: Vec<String>,
+
+    /// An email address the CA may send expiry warnings to
+    #[clap(long, value_name = "ADDRESS", requires = "acme_domains")]
+    acme_email: Option<String>,
+
+    /// Post every sample to this HTTP endpoint: json (the default) for
+    /// webhooks, influx for the InfluxDB write API, e.g.
+    /// influx=http://localhost:8086/api/v2/write?org=home&bucket=power, or
+    /// grafana for Grafana Live, e.g. grafana=http://localhost:3000.
+    /// Repeat for several sinks.
+    #[clap(long = "sink", value_name = "[FORMAT=]URL")]
+    sinks: Vec<config::SinkConfig>,
+
+    /// Keep readings in memory for this long, for charts from /history
+    #[clap(long, env = "SHARKMON_HISTORY", value_name = "DURATION", value_parser = humantime::parse_duration)]
+    history: Option<std::time::Duration>,
+
+    /// Give CSV and console timestamps, the dashboard, and daily history
+    /// steps and archive partitions in this IANA timezone rather than UTC,
+    /// e.g. America/Chicago
+    #[clap(long, env = "SHARKMON_TIMEZONE", value_name = "ZONE")]
+    timezone: Option<String>,
+
+    /// With --history, also keep averages over STEP for KEEP, e.g. 1m:30d.
+    /// Repeat for coarser tiers, e.g. --history-tier 15m:1y.
+    #[clap(long = "history-tier", value_name = "STEP:KEEP")]
+    history_tiers: Vec<config::HistoryTier>,
+
+    /// Save the smoothed readings, daily energy totals, baselines and power
+    /// quality scores to this file every minute and on exit, and carry on
+    /// from them after a restart
+    #[clap(long, env = "SHARKMON_STATE_FILE", value_name = "FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Append every meter write, reset, and poll paused or resumed to this
+    /// file, with who did it and when, for /audit
+    #[clap(long, env = "SHARKMON_AUDIT_LOG", value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// Archive every sample to Parquet files in this directory, in one
+    /// subdirectory per day (or hour)
+    #[clap(long, value_name = "DIR")]
+    archive: Option<PathBuf>,
+
+    /// How the archive is divided into directories
+    #[clap(
+        long,
+        value_name = "PARTITION",
+        default_value = "daily",
+        requires = "archive"
+    )]
+    archive_partition: config::Partition,
+
+    /// Advertise the web API over mDNS/DNS-SD as _sharkmon._tcp, so
+    /// dashboards and apps on the LAN can find it
+    #[clap(long, conflicts_with = "no_web")]
+    mdns: bool,
+
+    /// The instance name to advertise; the host's name by default
+    #[clap(long, value_name = "NAME", requires = "mdns")]
+    mdns_name: Option<String>,
+
+    /// Serve the readings over BACnet/IP as Analog Input objects of a
+    /// device with this instance number
+    #[clap(
+        long,
+        value_name = "DEVICE_ID",
+        value_parser = clap::value_parser!(u32).range(0..=bacnet::MAX_INSTANCE as i64)
+    )]
+    bacnet: Option<u32>,
+
+    /// Serve the readings as a DNP3 outstation listening on this address,
+    /// e.g. 0.0.0.0:20000. Needs the "dnp3" feature.
+    #[clap(long, value_name = "ADDRESS")]
+    dnp3: Option<std::net::SocketAddr>,
+
+    /// Serve the readings over OPC UA, listening on this address, e.g.
+    /// 0.0.0.0:4840. Needs the "opcua" feature.
+    #[clap(long, value_name = "ADDRESS")]
+    opcua: Option<std::net::SocketAddr>,
+
+    /// Pair with another sharkmon watching the same meters, whose heartbeats
+    /// go to this address, e.g. 10.0.0.2:8099; only the pair's leader polls
+    #[clap(long, value_name = "ADDRESS")]
+    ha_peer: Option<String>,
+
+    /// Where the peer's heartbeats arrive
+    #[clap(long, value_name = "ADDRESS", default_value_t = config::default_ha_listen(), requires = "ha_peer")]
+    ha_listen: std::net::SocketAddr,
+
+    /// Of the pair, the instance with the higher priority leads while both
+    /// are up
+    #[clap(long, value_name = "N", default_value_t = 0, requires = "ha_peer")]
+    ha_priority: u8,
+
+    /// Rhai script whose on_reading hook can change or add to every
+    /// device's readings after each poll. Needs the "scripting" feature.
+    #[clap(long, env = "SHARKMON_SCRIPT", value_name = "FILE")]
+    script: Option<PathBuf>,
+
+    /// Set by `sharkmon record`
+    #[clap(skip)]
+    record: Option<PathBuf>,
+
+    /// Run under the Windows service control manager; see `sharkmon service`
+    #[clap(long)]
+    service: bool,
+
+    /// How readings are printed with --verbose, --no-web or --once
+    #[clap(long, value_enum, default_value_t)]
+    output: output::Format,
+
+    /// Disable the built in web server and show updates on the command line
+    #[clap(short, long = "no-web")]
+    no_web: bool,
+
+    /// Reach the meter over Modbus/TCP (the default) or, for RTU bridges
+    /// that speak it, Modbus over UDP, sending unanswered requests again
+    /// until --timeout
+    #[clap(long, value_enum, conflicts_with = "tls")]
+    transport: Option<config::Transport>,
+
+    /// Connect to the meter using secure Modbus over TLS (usually port 802)
+    #[clap(long, requires = "tls_ca")]
+    tls: bool,
+
+    /// PEM file of CA certificates used to verify the meter's certificate
+    #[clap(long, value_name = "FILE", requires = "tls")]
+    tls_ca: Option<PathBuf>,
+
+    /// PEM file with the client certificate chain to present to the meter
+    #[clap(long, value_name = "FILE", requires_all = ["tls", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key for --tls-cert
+    #[clap(long, value_name = "FILE", requires_all = ["tls", "tls_cert"])]
+    tls_key: Option<PathBuf>,
+
+    /// Name to verify the meter's certificate against, if not the meter's hostname
+    #[clap(long, value_name = "NAME", requires = "tls")]
+    tls_server_name: Option<String>,
+
+    /// Read registers up to this many apart in a single request. Use 0 for
+    /// meters that reject reads spanning unmapped registers.
+    #[clap(long, value_name = "REGISTERS", default_value_t = 32)]
+    read_gap: u16,
+
+    /// Idle time before TCP keepalive probes are sent on the meter connection;
+    /// 0s disables them
+    #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = humantime::parse_duration)]
+    keepalive: std::time::Duration,
+
+    /// Give up on connecting to the meter, or on a read, after this long
+    #[clap(long, value_name = "DURATION", default_value = "5s", value_parser = humantime::parse_duration)]
+    timeout: std::time::Duration,
+
+    /// Read a register when nothing has been polled for this long, to detect
+    /// dead connections; 0s disables it
+    #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = humantime::parse_duration)]
+    heartbeat: std::time::Duration,
+
+    /// Retry a failed read this many times before reconnecting to the meter
+    #[clap(long, value_name = "COUNT", default_value_t = 2)]
+    retries: u32,
+
+    /// Poll on wall-clock boundaries: each group at multiples of its interval,
+    /// e.g. on whole seconds, or on :00, :15, :30 and :45 for a 15-minute group
+    #[clap(long)]
+    align: bool,
+
+    /// With --align, delay each poll by a random amount up to this long
+    #[clap(long, value_name = "DURATION", default_value = "0s", value_parser = humantime::parse_duration, requires = "align")]
+    align_jitter: std::time::Duration,
+
+    /// Poll this long after each wall-clock boundary of the polling interval,
+    /// so several pollers of one gateway take turns, e.g. 500ms
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    poll_offset: Option<std::time::Duration>,
+
+    /// Poll more slowly, backing off up to this interval, while readings hold
+    /// steady, and at the usual rate again when they change
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    adaptive: Option<std::time::Duration>,
+
+    /// With --adaptive, how much a reading must move between polls to count as
+    /// changing, as a fraction of its value
+    #[clap(long, value_name = "FRACTION", default_value_t = config::default_adaptive_threshold())]
+    adaptive_threshold: f64,
+
+    /// Poll the readings polled most often this often, instead of at the
+    /// register map's interval for them
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    poll_interval: Option<std::time::Duration>,
+
+    /// The weight of each new reading in the smoothed readings, from just
+    /// above 0 for heavy smoothing to 1 for none
+    #[clap(long, value_name = "FRACTION", default_value_t = config::default_ewma_alpha())]
+    ewma_alpha: f32,
+
+    /// Check the meter's clock this often, and set it when it has drifted.
+    /// The register map needs a [clock] table.
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    clock_sync: Option<std::time::Duration>,
+
+    /// After reconnecting, fill the gap in --history with the records the
+    /// meter logged meanwhile. The register map needs a [log] table.
+    #[clap(long, requires = "history")]
+    backfill: bool,
+
+    /// Holding registers that may be written through the web API, as START or
+    /// START-END, e.g. 0x1000-0x1005. Repeat for several ranges.
+    #[clap(long, value_name = "RANGE")]
+    writable: Vec<registers::RegisterRange>,
+
+    /// Serve Modbus/TCP on ADDRESS, e.g. 0.0.0.0:5020, passing other clients'
+    /// requests through to the meter between polls
+    #[clap(long, value_name = "ADDRESS")]
+    proxy: Option<std::net::SocketAddr>,
+
+    /// Registers that clients of --proxy may read, as START or START-END.
+    /// Repeat for several ranges; all registers may be read if not given.
+    #[clap(long, value_name = "RANGE", requires = "proxy")]
+    proxy_readable: Vec<registers::RegisterRange>,
+
+    /// Function codes passed through by --proxy: 3 and 4 read holding and
+    /// input registers, 6 and 16 write holding registers within --writable
+    #[clap(long, value_name = "CODE", value_delimiter = ',', requires = "proxy",
+           default_values_t = config::default_proxy_functions())]
+    proxy_functions: Vec<u8>,
+
+    /// Requests a second --proxy sends the meter, from all clients together
+    #[clap(long, value_name = "N", requires = "proxy",
+           default_value_t = config::default_proxy_rate())]
+    proxy_rate: f64,
+
+    /// Decode a reading (watts, volts or frequency) from a different register
+    /// format, optionally scaled, e.g. watts=int32:0.1. Formats are float-be,
+    /// float-swapped, int16, uint16, int32, uint32, int32-swapped and uint32-swapped.
+    #[clap(long = "register-format", value_name = "NAME=FORMAT[:SCALE]")]
+    register_formats: Vec<registers::FormatOverride>,
+
+    /// The meter model, which selects the registers to poll
+    #[clap(long, default_value = "shark100",
+           value_parser = clap::builder::PossibleValuesParser::new(registers::PROFILE_NAMES))]
+    profile: String,
+
+    /// TOML file describing the registers to poll, instead of a built-in profile
+    #[clap(long, value_name = "FILE", conflicts_with = "profile")]
+    register_map: Option<PathBuf>,
+
+    /// Discover the registers of a SunSpec inverter or meter when connecting,
+    /// instead of using a profile
+    #[clap(long, conflicts_with_all = ["profile", "register_map"])]
+    sunspec: bool,
+
+    /// Pick the register map's variant for this firmware version, instead of
+    /// the version the meter reports
+    #[clap(long, value_name = "VERSION", conflicts_with = "sunspec")]
+    firmware: Option<String>,
+
+    /// Poll the meter with this Modbus unit ID, optionally naming it. Repeat to
+    /// poll several meters behind one gateway, e.g. --unit 1=main --unit 2=solar
+    #[clap(short, long = "unit", value_name = "ID[=NAME]", default_value = "1")]
+    units: Vec<config::UnitConfig>,
+
+    /// Subtract this device's power and energy in /power/total rather than
+    /// adding them, e.g. --total-subtract solar for an inverter's meter.
+    /// Repeat for several.
+    #[clap(long, value_name = "NAME")]
+    total_subtract: Vec<String>,
+
+    /// Attach a label to every reading in /metrics, InfluxDB sinks and JSON
+    /// output, e.g. --label site=hq. Repeat for several.
+    #[clap(long = "label", value_name = "NAME=VALUE", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// Add a reading worked out from the others after every poll, e.g.
+    /// --derive "amps_est=watts / volts". Other devices' readings are
+    /// written DEVICE.NAME. Repeat for several.
+    #[clap(long = "derive", value_name = "NAME=EXPRESSION", value_parser = parse_derived)]
+    derived: Vec<(String, derived::Expression)>,
+
+    /// Report a reading in other units than the register map's, with a
+    /// different SI prefix, e.g. --output-unit watts=kW. This applies to
+    /// every output, and to the readings derived readings use. Repeat for
+    /// several.
+    #[clap(long = "output-unit", value_name = "NAME=UNITS", value_parser = parse_label)]
+    output_units: Vec<(String, String)>,
+
+    /// Give a reading to so many decimal places in JSON output, the sinks
+    /// and the web API, e.g. --precision frequency=3. Repeat for several.
+    #[clap(long, value_name = "NAME=PLACES", value_parser = parse_precision)]
+    precision: Vec<(String, u8)>,
+
+    /// Which way the meter reads power as positive: import from the grid
+    /// (the default), or export to it, whose W and var readings are then
+    /// negated
+    #[clap(long, value_enum)]
+    sign: Option<config::Sign>,
+
+    /// Also give a signed reading as NAME_import and NAME_export, each
+    /// positive or zero, e.g. --split watts for a net-metered solar site.
+    /// Repeat for several.
+    #[clap(long, value_name = "NAME")]
+    split: Vec<String>,
+}
+
+fn parse_precision(s: &str) -> Result<(String, u8), String> {
+    let (name, places) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{s}' should be NAME=PLACES"))?;
+    let places = places
+        .parse()
+        .map_err(|_| format!("'{places}' isn't a number of decimal places"))?;
+    Ok((name.to_owned(), places))
+}
+
+fn parse_derived(s: &str) -> Result<(String, derived::Expression), String> {
+    let (name, expression) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{s}' should be NAME=EXPRESSION"))?;
+    Ok((name.trim().to_owned(), expression.parse()?))
+}
+
+fn parse_label(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{s}' should be NAME=VALUE"))?;
+    Ok((name.to_owned(), value.to_owned()))
+}
+
+/// The meter the named device is on, and the device.
+fn find_device<'a>(
+    meters: &'a [Arc<meter::Meter>],
+    name: &str,
+) -> std::io::Result<(&'a meter::Meter, &'a meter::Device)> {
+    meters
+        .iter()
+        .find_map(|m| Some((m.as_ref(), m.device(name)?)))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no device named '{name}'"),
+            )
+        })
+}
+
+/// The audit log given by `config`, or one kept only in memory. One that
+/// can't be opened is a configuration error.
+fn open_audit(config: &config::Config) -> std::io::Result<audit::Audit> {
+    match &config.audit_log {
+        Some(path) => audit::Audit::open(path).map_err(|e| {
+            let e = std::io::Error::new(e.kind(), format!("audit log {}: {e}", path.display()));
+            exit::tag(Exit::Config, e)
+        }),
+        None => Ok(audit::Audit::default()),
+    }
+}
+
+/// Write the device's reset register for `kind`, recording who asked for it,
+/// and the outcome, in the audit log.
+async fn reset_device(
+    meters: &[Arc<meter::Meter>],
+    device: &str,
+    kind: ResetKind,
+    client: &str,
+    audit: &audit::Audit,
+) -> std::io::Result<()> {
+    let (meter, d) = find_device(meters, device)?;
+    let result = match meter.reset_register(kind) {
+        Ok(register) => {
+            meter
+                .write(d.unit, register.address, vec![register.value])
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    audit.record(
+        audit::Entry::new(client, &format!("reset-{kind}"))
+            .meter(&meter.name)
+            .device(device)
+            .result(&result),
+    );
+    result
+}
+
+impl Opt {
+    fn bacnet_config(&self) -> Option<config::BacnetConfig> {
+        self.bacnet.map(|device_id| config::BacnetConfig {
+            device_id,
+            name: None,
+            port: bacnet::PORT,
+        })
+    }
+
+    fn dnp3_config(&self) -> Option<config::Dnp3Config> {
+        self.dnp3.map(|listen| config::Dnp3Config {
+            listen,
+            address: config::default_dnp3_address(),
+            master: config::default_dnp3_master(),
+            points: Vec::new(),
+            deadband: 0.0,
+        })
+    }
+
+    fn opcua_config(&self) -> Option<config::OpcUaConfig> {
+        self.opcua.map(|listen| config::OpcUaConfig {
+            listen,
+            name: config::default_opcua_name(),
+            pki_dir: config::default_opcua_pki_dir(),
+        })
+    }
+
+    fn ha_config(&self) -> Option<config::HaConfig> {
+        Some(config::HaConfig {
+            peer: self.ha_peer.clone()?,
+            listen: self.ha_listen,
+            priority: self.ha_priority,
+            timeout: config::default_ha_timeout(),
+        })
+    }
+
+    fn web_tls_config(&self) -> Option<config::WebTlsConfig> {
+        Some(config::WebTlsConfig {
+            cert: self.web_tls_cert.clone()?,
+            key: self.web_tls_key.clone()?,
+            client_ca: self.web_client_ca.clone(),
+            access: self
+                .web_admins
+                .iter()
+                .map(|name| (name.clone(), config::Role::Admin))
+                .collect(),
+            acme: (!self.acme_domains.is_empty()).then(|| config::AcmeConfig {
+                domains: self.acme_domains.clone(),
+                email: self.acme_email.clone(),
+                directory: config::default_acme_directory(),
+                account_key: None,
+                http_listen: config::default_acme_http_listen(),
+                dns_hook: None,
+            }),
+        })
+    }
+
+    fn archive_config(&self) -> Option<config::ArchiveConfig> {
+        Some(config::ArchiveConfig {
+            dir: self.archive.clone()?,
+            partition: self.archive_partition,
+            flush: config::default_archive_flush(),
+        })
+    }
+
+    /// Parse the options of a normal run given to a subcommand such as
+    /// `record`, which can't include another subcommand or --replay.
+    fn parse_run(subcommand: &str, args: Vec<OsString>) -> Opt {
+        let program = std::iter::once("sharkmon".into());
+        let opt = Opt::parse_from(program.chain(args));
+        if opt.command.is_some() || opt.replay.is_some() {
+            Opt::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    format!("{subcommand} takes the options of a normal run, without a subcommand or --replay"),
+                )
+                .exit();
+        }
+        opt
+    }
+
+    /// The configuration from --config, or else the single meter described
+    /// by the other options. API keys given on the command line are added to
+    /// those in the file. Options taken from `SHARKMON_*` environment
+    /// variables, which clap fills in where no flag is given, override the
+    /// file like flags do.
+    fn config(&self) -> std::io::Result<config::Config> {
+        self.merged_config().map_err(|e| exit::tag(Exit::Config, e))
+    }
+
+    fn merged_config(&self) -> std::io::Result<config::Config> {
+        if let Some(path) = &self.config {
+            let mut config = config::Config::load(path)?;
+            config.api_keys.extend(self.api_keys.iter().cloned());
+            config.max_requests_in_flight = self
+                .max_requests_in_flight
+                .or(config.max_requests_in_flight);
+            config.requests_per_second = self.requests_per_second.or(config.requests_per_second);
+            config.jsonp |= self.jsonp;
+            config.web_listen = self.web_listen.or(config.web_listen);
+            config.fail_fast = self.fail_fast.or(config.fail_fast);
+            if let Some(server_failure) = self.server_failure {
+                config.server_failure = server_failure;
+            }
+            if let Some(web_tls) = self.web_tls_config() {
+                config.web_tls = Some(web_tls);
+            }
+            config.sinks.extend(self.sinks.iter().cloned());
+            config.history = self.history.or(config.history);
+            config.timezone = self.timezone.clone().or(config.timezone);
+            config.state_file = self.state_file.clone().or(config.state_file);
+            config.audit_log = self.audit_log.clone().or(config.audit_log);
+            config.script = self.script.clone().or(config.script);
+            config.mdns |= self.mdns;
+            config.mdns_name = self.mdns_name.clone().or(config.mdns_name);
+            if let Some(bacnet) = self.bacnet_config() {
+                config.bacnet = Some(bacnet);
+            }
+            if let Some(dnp3) = self.dnp3_config() {
+                config.dnp3 = Some(dnp3);
+            }
+            if let Some(opcua) = self.opcua_config() {
+                config.opcua = Some(opcua);
+            }
+            if let Some(ha) = self.ha_config() {
+                config.ha = Some(ha);
+            }
+            if !self.history_tiers.is_empty() {
+                config.history_tiers = self.history_tiers.clone();
+            }
+            if let Some(archive) = self.archive_config() {
+                config.archive = Some(archive);
+            }
+            config
+                .validate()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.message))?;
+            return Ok(config);
+        }
+        let tls = match &self.tls_ca {
+            Some(ca) if self.tls => Some(config::TlsConfig {
+                ca: ca.clone(),
+                cert: self.tls_cert.clone(),
+                key: self.tls_key.clone(),
+                server_name: self.tls_server_name.clone(),
+            }),
+            _ => None,
+        };
+        // Devices keep their "unit<ID>" names rather than taking the meter's.
+        let units: Vec<config::UnitConfig> = self
+            .units
+            .iter()
+            .map(|u| {
+                let name = u.name.clone().unwrap_or_else(|| format!("unit{}", u.id));
+                let total = if self.total_subtract.contains(&name) {
+                    config::Total::Subtract
+                } else {
+                    config::Total::Add
+                };
+                config::UnitConfig {
+                    id: u.id,
+                    name: Some(name),
+                    total,
+                }
+            })
+            .collect();
+        if let Some(name) = self
+            .total_subtract
+            .iter()
+            .find(|n| !units.iter().any(|u| u.name.as_ref() == Some(n)))
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("--total-subtract: no device is named '{name}'"),
+            ));
+        }
+        let config = config::Config {
+            version: None,
+            api_keys: self.api_keys.clone(),
+            max_requests_in_flight: self.max_requests_in_flight,
+            requests_per_second: self.requests_per_second,
+            jsonp: self.jsonp,
+            web_listen: self.web_listen,
+            web_tls: self.web_tls_config(),
+            max_concurrent_polls: None,
+            stagger: std::time::Duration::ZERO,
+            stagger_gateways: true,
+            fail_fast: self.fail_fast,
+            server_failure: self.server_failure.unwrap_or_default(),
+            history: self.history,
+            timezone: self.timezone.clone(),
+            history_tiers: self.history_tiers.clone(),
+            state_file: self.state_file.clone(),
+            audit_log: self.audit_log.clone(),
+            archive: self.archive_config(),
+            script: self.script.clone(),
+            mdns: self.mdns,
+            mdns_name: self.mdns_name.clone(),
+            bacnet: self.bacnet_config(),
+            dnp3: self.dnp3_config(),
+            opcua: self.opcua_config(),
+            ha: self.ha_config(),
+            snmp: None,
+            alerts: Vec::new(),
+            transfer: Default::default(),
+            quality: Default::default(),
+            meters: vec![config::MeterConfig {
+                name: "meter".to_owned(),
+                address: self.meter.clone().unwrap_or_default(),
+                labels: self.labels.iter().cloned().collect(),
+                failover: self.failover.clone(),
+                transport: self.transport.unwrap_or_default(),
+                profile: (!self.sunspec && self.register_map.is_none())
+                    .then(|| self.profile.clone()),
+                register_map: self.register_map.clone(),
+                sunspec: self.sunspec,
+                firmware: self.firmware.clone(),
+                register_formats: self.register_formats.clone(),
+                output_units: self.output_units.iter().cloned().collect(),
+                precision: self.precision.iter().cloned().collect(),
+                derived: self.derived.iter().cloned().collect(),
+                sign: self.sign.unwrap_or_default(),
+                split: self.split.clone(),
+                read_gap: self.read_gap,
+                keepalive: self.keepalive,
+                timeout: self.timeout,
+                heartbeat: self.heartbeat,
+                retries: self.retries,
+                align: self.align,
+                align_jitter: self.align_jitter,
+                poll_offset: self.poll_offset,
+                adaptive: self.adaptive,
+                adaptive_threshold: self.adaptive_threshold,
+                poll_interval: self.poll_interval,
+                ewma_alpha: self.ewma_alpha,
+                clock_sync: self.clock_sync,
+                backfill: self.backfill,
+                writable: self.writable.clone(),
+                units,
+                tls,
+                proxy: self.proxy.map(|listen| config::ProxyConfig {
+                    listen,
+                    readable: self.proxy_readable.clone(),
+                    functions: self.proxy_functions.clone(),
+                    requests_per_second: self.proxy_rate,
+                }),
+            }],
+            sinks: self.sinks.clone(),
+        };
+        config
+            .validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.message))?;
+        Ok(config)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print a shell completion script, e.g. sharkmon completions bash
+    Completions { shell: clap_complete::Shell },
+    /// Load and validate a configuration file, including the register maps and
+    /// certificates it refers to and the meters' addresses, then exit
+    CheckConfig { file: PathBuf },
+    /// Work with the configuration a run would use
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+    /// Run as usual, also recording every register read to a file for
+    /// --replay, e.g. sharkmon record --out session.jsonl 192.168.1.100:502
+    Record {
+        #[clap(long, short, value_name = "FILE")]
+        out: PathBuf,
+        /// Options and meter, as for a normal run
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+    /// Measure how fast a meter answers: read throughput, the latency of each
+    /// block read, and how often it can safely be polled, e.g.
+    /// sharkmon bench --duration 30s 192.168.1.100:502
+    Bench(BenchArgs),
+    /// Print the records a meter has logged itself, read through the log
+    /// registers of its register map, as JSON lines, e.g.
+    /// sharkmon meter-log --since 1d --profile shark200 192.168.1.100:502
+    MeterLog(MeterLogArgs),
+    /// Write a first configuration file, asking for the meter's address and
+    /// probing it (or scanning the network for it) to choose its profile
+    Init(InitArgs),
+    /// Look for meters on the local network with mDNS, SSDP and a sweep of the
+    /// Modbus port, and print a [[meter]] table for each, e.g.
+    /// sharkmon discover 192.168.1.0/24
+    Discover(DiscoverArgs),
+    /// Poll the meters once as a Nagios plugin: print their load and
+    /// perfdata, and exit 0 (OK), 1 (WARNING), 2 (CRITICAL, also when a meter
+    /// can't be read) or 3 (UNKNOWN), e.g.
+    /// sharkmon check --warn-watts 10000 --crit-watts 15000 192.168.1.100:502
+    Check(CheckArgs),
+    /// Reset a meter's peak demand readings, e.g.
+    /// sharkmon reset-demand --confirm 192.168.1.100:502
+    ResetDemand(ResetArgs),
+    /// Reset a meter's recorded minimum and maximum readings
+    ResetMinmax(ResetArgs),
+    /// Reset a meter's energy totals
+    ResetEnergy(ResetArgs),
+    /// Install or remove sharkmon as a Windows service
+    Service {
+        #[clap(subcommand)]
+        action: service::Action,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the configuration a run with these options would use, the
+    /// file's merged with the command line's and defaults filled in, as TOML
+    /// with secrets redacted, e.g.
+    /// sharkmon config print --config sharkmon.toml --history 1h
+    Print {
+        /// Options and meter, as for a normal run
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
+    },
+    /// Print a JSON Schema for the configuration file, for editors that
+    /// check and complete TOML against one, e.g.
+    /// sharkmon config schema > sharkmon.schema.json
+    Schema,
+}
+
+#[derive(clap::Args)]
+struct ResetArgs {
+    /// Reset the meter; without this, only show the register that would be
+    /// written
+    #[clap(long)]
+    confirm: bool,
+    /// The device to reset, if the options describe more than one
+    #[clap(long, value_name = "NAME")]
+    device: Option<String>,
+    /// Options and meter, as for a normal run
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<OsString>,
+}
+
+#[derive(clap::Args)]
+struct BenchArgs {
+    /// How long to keep reading
+    #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = humantime::parse_duration)]
+    duration: std::time::Duration,
+    /// The meter to measure, if the options describe more than one
+    #[clap(long, value_name = "NAME")]
+    meter: Option<String>,
+    /// Options and meter, as for a normal run
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<OsString>,
+}
+
+#[derive(clap::Args)]
+struct MeterLogArgs {
+    /// Only the records logged after this, an RFC 3339 time or a duration
+    /// ago, such as 1d
+    #[clap(long, value_name = "TIME", value_parser = |s: &str| history::QueryTime::try_from(s.to_owned()))]
+    since: Option<history::QueryTime>,
+    /// The meter to read, if the options describe more than one
+    #[clap(long, value_name = "NAME")]
+    meter: Option<String>,
+    /// Options and meter, as for a normal run
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<OsString>,
+}
+
+#[derive(clap::Args)]
+struct CheckArgs {
+    /// Warn when a device's watts are above this
+    #[clap(long, value_name = "WATTS")]
+    warn_watts: Option<f32>,
+    /// Critical when a device's watts are above this
+    #[clap(long, value_name = "WATTS")]
+    crit_watts: Option<f32>,
+    /// How long to wait for the meters
+    #[clap(long, value_name = "DURATION", default_value = "10s", value_parser = humantime::parse_duration)]
+    timeout: std::time::Duration,
+    /// Options and meter, as for a normal run
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<OsString>,
+}
+
+#[derive(clap::Args)]
+struct DiscoverArgs {
+    /// Networks to sweep for the Modbus port, e.g. 192.168.1.0/24
+    #[clap(value_name = "CIDR")]
+    networks: Vec<discover::Cidr>,
+    #[clap(long, default_value_t = 502)]
+    port: u16,
+    /// The unit ID to ask for the meter's identity
+    #[clap(long, default_value_t = 1)]
+    unit: u8,
+    /// How long to wait for each connection and answer
+    #[clap(long, value_name = "DURATION", default_value = "500ms", value_parser = humantime::parse_duration)]
+    timeout: std::time::Duration,
+    /// Only sweep the networks given, without asking mDNS and SSDP
+    #[clap(long)]
+    no_multicast: bool,
+    #[clap(long, value_name = "FILTER")]
+    log: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct InitArgs {
+    /// The configuration file to write
+    #[clap(long, short, value_name = "FILE", default_value = "sharkmon.toml")]
+    out: PathBuf,
+    /// The Modbus port, if the address doesn't give one
+    #[clap(long, default_value_t = 502)]
+    port: u16,
+    #[clap(long, default_value_t = 1)]
+    unit: u8,
+    /// How long to wait for each connection and answer
+    #[clap(long, value_name = "DURATION", default_value = "1s", value_parser = humantime::parse_duration)]
+    timeout: std::time::Duration,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+    Journald,
+}
+
+fn init_logging(filter: Option<&str>, format: Option<LogFormat>) -> std::io::Result<()> {
+    use tracing_subscriber::{prelude::*, EnvFilter};
+    let filter = match filter {
+        Some(directives) => EnvFilter::try_new(directives),
+        None => EnvFilter::try_from_default_env()
+            .or_else(|_| EnvFilter::try_new("warn,sharkmon::audit=info")),
+    }
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // systemd connects stderr to the journal and says so in JOURNAL_STREAM.
+    let format = format.unwrap_or(match std::env::var_os("JOURNAL_STREAM") {
+        Some(_) if cfg!(target_os = "linux") => LogFormat::Journald,
+        _ => LogFormat::Text,
+    });
+    let logger = tracing_subscriber::registry().with(filter);
+    let stderr = || tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => logger.with(stderr()).init(),
+        LogFormat::Json => logger.with(stderr().json()).init(),
+        #[cfg(target_os = "linux")]
+        LogFormat::Journald => logger.with(tracing_journald::layer()?).init(),
+        #[cfg(not(target_os = "linux"))]
+        LogFormat::Journald => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the systemd journal is only available on Linux",
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Everything sharkmon does with a configuration at startup short of
+/// connecting: parse and validate it, load each meter's register map and
+/// certificates, and resolve each meter's address.
+fn check_config(path: &std::path::Path) -> std::io::Result<()> {
+    use std::net::ToSocketAddrs;
+    let config = config::Config::load(path)?;
+    let mut meters = Vec::new();
+    for m in &config.meters {
+        let context =
+            |e: std::io::Error| std::io::Error::new(e.kind(), format!("meter '{}': {e}", m.name));
+        let meter = Arc::new(meter::Meter::new(m).map_err(context)?);
+        meters.push(meter.clone());
+        let mut paths = Vec::new();
+        for address in std::iter::once(&m.address).chain(&m.failover) {
+            let addrs: Vec<_> = address.to_socket_addrs().map_err(context)?.collect();
+            let ips: Vec<_> = addrs.iter().map(|a| a.ip().to_string()).collect();
+            paths.push(format!("{address} ({})", ips.join(", ")));
+        }
+        let devices: Vec<_> = meter.devices.iter().map(|d| d.name.as_str()).collect();
+        println!(
+            "meter '{}': {}, devices {}",
+            m.name,
+            paths.join(" or "),
+            devices.join(", ")
+        );
+    }
+    meter::check_derived(&meters)?;
+    sink::Sinks::new(&config.sinks)?;
+    for s in &config.sinks {
+        println!("sink '{}': {}", s.name(), s.url);
+    }
+    println!("{}: ok", path.display());
+    Ok(())
+}
+
+/// `sharkmon reset-*`: write one device's reset register over a connection
+/// of its own.
+fn reset_command(kind: ResetKind, args: ResetArgs) -> std::io::Result<()> {
+    let opt = Opt::parse_run(&format!("reset-{kind}"), args.args);
+    init_logging(opt.log.as_deref(), opt.log_format)?;
+    let config = opt.config()?;
+    let meters = config
+        .meters
+        .iter()
+        .map(|m| meter::Meter::new(m).map(Arc::new))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let device = match args.device {
+        Some(name) => name,
+        None => {
+            let mut devices = meters.iter().flat_map(|m| &m.devices);
+            match (devices.next(), devices.next()) {
+                (Some(d), None) => d.name.clone(),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "choose the device to reset with --device",
+                    ))
+                }
+            }
+        }
+    };
+    let (meter, d) = find_device(&meters, &device)?;
+    let register = meter.reset_register(kind)?;
+    if !args.confirm {
+        println!(
+            "would write {} to register {:#06x} of unit {} on {} to reset {kind} readings",
+            register.value, register.address, d.unit, meter.address
+        );
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "not confirmed: run again with --confirm to reset the meter",
+        ));
+    }
+    let runtime = tokio::runtime::Runtime::new()?;
+    let audit = open_audit(&config)?;
+    runtime.block_on(reset_device(&meters, &device, kind, "command line", &audit))?;
+    println!("reset {kind} readings of {device}");
+    Ok(())
+}
+
+/// The meter named `name`, or the only one.
+fn choose_meter<'a>(
+    config: &'a config::Config,
+    name: Option<&str>,
+    purpose: &str,
+) -> std::io::Result<&'a config::MeterConfig> {
+    match name {
+        Some(name) => config
+            .meters
+            .iter()
+            .find(|m| m.name == name)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no meter named '{name}'"),
+                )
+            }),
+        None => match config.meters.as_slice() {
+            [m] => Ok(m),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("choose the meter to {purpose} with --meter"),
+            )),
+        },
+    }
+}
+
+/// `sharkmon bench`: read one meter's blocks back to back for a while, and
+/// report how long the reads took.
+fn bench_command(args: BenchArgs) -> std::io::Result<()> {
+    let opt = Opt::parse_run("bench", args.args);
+    init_logging(opt.log.as_deref(), opt.log_format)?;
+    let config = opt.config()?;
+    let m = choose_meter(&config, args.meter.as_deref(), "measure")?;
+    let meter = meter::Meter::new(m)?;
+    eprintln!(
+        "reading {} for {}...",
+        m.address,
+        humantime::format_duration(args.duration)
+    );
+    let runtime = tokio::runtime::Runtime::new()?;
+    let report = runtime.block_on(meter.bench(args.duration))?;
+    print!("{report}");
+    Ok(())
+}
+
+/// `sharkmon config print`: the effective configuration, as TOML.
+fn print_config(args: Vec<OsString>) -> std::io::Result<()> {
+    let opt = Opt::parse_run("config print", args);
+    let config = opt.config()?;
+    let text = toml::to_string(&config).map_err(std::io::Error::other)?;
+    print!("{text}");
+    Ok(())
+}
+
+/// `sharkmon config schema`: a JSON Schema of the configuration file.
+fn print_schema() -> std::io::Result<()> {
+    let schema = schema::schema::<config::Config>("sharkmon configuration");
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+/// `sharkmon meter-log`: print the records of one meter's own log, oldest
+/// first, one JSON object per line.
+fn meter_log_command(args: MeterLogArgs) -> std::io::Result<()> {
+    let opt = Opt::parse_run("meter-log", args.args);
+    init_logging(opt.log.as_deref(), opt.log_format)?;
+    let config = opt.config()?;
+    let m = choose_meter(&config, args.meter.as_deref(), "read")?;
+    let meter = meter::Meter::new(m)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let logs = runtime.block_on(meter.log_records(args.since.map(|s| s.0)))?;
+    use std::io::Write;
+    let mut out = std::io::stdout().lock();
+    for (device, names, records) in logs {
+        for record in records {
+            let mut line = serde_json::Map::new();
+            let time = record
+                .time
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+            line.insert("time".to_owned(), time.into());
+            line.insert("device".to_owned(), device.clone().into());
+            for (name, value) in names.iter().zip(record.values) {
+                line.insert(name.clone(), value.into());
+            }
+            writeln!(out, "{}", serde_json::Value::Object(line))?;
+        }
+    }
+    Ok(())
+}
+
+/// `sharkmon check`: print one poll's status line and exit with its state,
+/// which is UNKNOWN if sharkmon couldn't get as far as polling.
+fn check_command(args: CheckArgs) -> ! {
+    let opt = Opt::parse_run("check", args.args);
+    let watts = check::Thresholds {
+        warn: args.warn_watts,
+        crit: args.crit_watts,
+    };
+    let result = init_logging(opt.log.as_deref(), opt.log_format).and_then(|()| {
+        let meters = opt
+            .config()?
+            .meters
+            .iter()
+            .map(|m| meter::Meter::new(m).map(Arc::new))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        Ok(runtime.block_on(check::check(&meters, watts, args.timeout)))
+    });
+    let (state, line) = result.unwrap_or_else(|e| (check::State::Unknown, e.to_string()));
+    println!("SHARKMON {state} - {line}");
+    std::process::exit(state as i32)
+}
+
+/// `sharkmon discover`: print a `[[meter]]` table for each meter found.
+fn discover_command(args: DiscoverArgs) -> std::io::Result<()> {
+    init_logging(args.log.as_deref(), None)?;
+    let options = discover::Options {
+        networks: args.networks,
+        port: args.port,
+        unit: args.unit,
+        timeout: args.timeout,
+        multicast: !args.no_multicast,
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+    let found = runtime.block_on(discover::discover(&options))?;
+    if found.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no meters found",
+        ));
+    }
+    eprintln!("found {} meters", found.len());
+    discover::print(&found);
+    Ok(())
+}
+
+fn init_command(args: InitArgs) -> std::io::Result<()> {
+    init_logging(None, None)?;
+    let options = init::Options {
+        port: args.port,
+        unit: args.unit,
+        timeout: args.timeout,
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+    let mut input = std::io::stdin().lock();
+    let mut output = std::io::stderr();
+    runtime.block_on(init::run(&mut input, &mut output, &args.out, &options))
+}
+
+/// The exit code for the result of `main`, having printed its error, if
+/// any, and told systemd about it.
+pub fn report(result: std::io::Result<()>) -> std::process::ExitCode {
+    let Err(e) = result else {
+        return std::process::ExitCode::SUCCESS;
+    };
+    eprintln!("Error: {e}");
+    systemd::failed(&e.to_string());
+    std::process::ExitCode::from(exit::code(&e) as u8)
+}
+
+/// Run sharkmon with the process's command line.
+pub fn main() -> std::io::Result<()> {
+    let mut opt = Opt::parse();
+    match opt.command.take() {
+        Some(Command::Completions { shell }) => {
+            let mut command = Opt::command();
+            clap_complete::generate(shell, &mut command, "sharkmon", &mut std::io::stdout());
+            return Ok(());
+        }
+        Some(Command::CheckConfig { file }) => {
+            return check_config(&file).map_err(|e| exit::tag(Exit::Config, e))
+        }
+        Some(Command::Config {
+            action: ConfigAction::Print { args },
+        }) => return print_config(args),
+        Some(Command::Config {
+            action: ConfigAction::Schema,
+        }) => return print_schema(),
+        Some(Command::Service { action }) => return service::manage(action),
+        Some(Command::Record { out, args }) => {
+            opt = Opt::parse_run("record", args);
+            opt.record = Some(out);
+        }
+        Some(Command::Bench(args)) => return bench_command(args),
+        Some(Command::MeterLog(args)) => return meter_log_command(args),
+        Some(Command::Init(args)) => return init_command(args),
+        Some(Command::Discover(args)) => return discover_command(args),
+        Some(Command::Check(args)) => check_command(args),
+        Some(Command::ResetDemand(args)) => return reset_command(ResetKind::Demand, args),
+        Some(Command::ResetMinmax(args)) => return reset_command(ResetKind::Minmax, args),
+        Some(Command::ResetEnergy(args)) => return reset_command(ResetKind::Energy, args),
+        None => {}
+    }
+
+    init_logging(opt.log.as_deref(), opt.log_format)?;
+
+    if opt.service {
+        service::dispatch(Box::new(move |stop| {
+            serve(opt, async {
+                let _ = stop.await;
+            })
+        }))
+    } else {
+        serve(opt, shutdown_signal())
+    }
+}
+
+/// Ctrl-C, or SIGTERM from a service manager.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            return std::future::pending().await;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Read every meter concurrently and print each device's readings on a line of
+/// its own.
+async fn poll_once(meters: &[Arc<meter::Meter>], printer: &output::Printer) -> std::io::Result<()> {
+    let read = poll_all(meters).await?;
+    for m in &read {
+        for device in &m.devices {
+            printer.print(device);
+        }
+    }
+    let failed = meters.len() - read.len();
+    if failed > 0 {
+        return Err(std::io::Error::other(format!(
+            "{failed} of {} meters could not be read",
+            meters.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Poll every meter once, then print each device's readings in every output
+/// format and check and render each sink, sending nothing.
+async fn dry_run(
+    config: &config::Config,
+    meters: &[Arc<meter::Meter>],
+    tagged: bool,
+    zone: timezone::Zone,
+) -> std::io::Result<()> {
+    use std::fmt::Write;
+    let read = poll_all(meters).await?;
+    let mut failed = meters.len() - read.len();
+    let samples: Vec<sink::Sample> = read
+        .iter()
+        .flat_map(|m| m.devices.iter().map(sink::Sample::new))
+        .collect();
+    let mut out = String::new();
+    for format in output::Format::value_variants() {
+        let name = format.to_possible_value().unwrap();
+        writeln!(out, "== output {}", name.get_name()).unwrap();
+        let printer = output::Printer::new(*format, tagged, zone.clone());
+        for sample in &samples {
+            out.push_str(&printer.format(sample));
+        }
+    }
+    let sinks = sink::Sinks::new(&config.sinks)?;
+    for (name, check, rendered) in sinks.dry_run(&samples).await {
+        match check {
+            Ok(()) => writeln!(out, "== sink {name}").unwrap(),
+            Err(e) => {
+                failed += 1;
+                writeln!(out, "== sink {name}: can't be reached: {e}").unwrap();
+            }
+        }
+        for request in rendered {
+            writeln!(out, "{request}").unwrap();
+        }
+    }
+    print!("{out}");
+    if failed > 0 {
+        return Err(std::io::Error::other(format!(
+            "{failed} of {} meters and sinks failed the dry run",
+            meters.len() + config.sinks.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Read every meter concurrently, returning those that could be read with
+/// their readings processed.
+async fn poll_all(meters: &[Arc<meter::Meter>]) -> std::io::Result<Vec<&Arc<meter::Meter>>> {
+    let polls: Vec<_> = meters
+        .iter()
+        .map(|m| {
+            let m = m.clone();
+            let span = tracing::info_span!("meter", meter = %m.name);
+            tokio::spawn(async move { m.poll_once().await }.instrument(span))
+        })
+        .collect();
+    let mut read = Vec::new();
+    for (m, poll) in meters.iter().zip(polls) {
+        match poll.await.map_err(std::io::Error::other)? {
+            Ok(()) => read.push(m),
+            Err(e) => tracing::error!(meter = %m.name, error = %e, "could not read meter"),
+        }
+    }
+    // Derived readings may use any meter's, so wait for all of them first.
+    for m in &read {
+        for device in &m.devices {
+            m.process(device, meters);
+        }
+    }
+    Ok(read)
+}
+
+/// Run sharkmon until `shutdown` completes.
+fn serve(opt: Opt, shutdown: impl std::future::Future<Output = ()>) -> std::io::Result<()> {
+    tokio::runtime::Runtime::new()?.block_on(run(opt, shutdown))
+}
+
+async fn run(opt: Opt, shutdown: impl std::future::Future<Output = ()>) -> std::io::Result<()> {
+    let config = opt.config()?;
+    let recorder = match &opt.record {
+        Some(path) => Some(Arc::new(recording::Recorder::create(path)?)),
+        None => None,
+    };
+    let replay = match &opt.replay {
+        Some(path) => Some(Arc::new(recording::Recording::load(path)?)),
+        None => None,
+    };
+    #[cfg(feature = "scripting")]
+    let script = match &config.script {
+        Some(path) => Some(Arc::new(
+            script::Script::load(path).map_err(|e| exit::tag(Exit::Config, e))?,
+        )),
+        None => None,
+    };
+    #[cfg(not(feature = "scripting"))]
+    if config.script.is_some() {
+        return Err(exit::tag(
+            Exit::Config,
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "this sharkmon was built without the \"scripting\" feature",
+            ),
+        ));
+    }
+    let leading = match &config.ha {
+        Some(ha) if !opt.once && !opt.dry_run => {
+            Some(ha::start(ha).await.map_err(|e| exit::tag(Exit::Bind, e))?)
+        }
+        _ => None,
+    };
+    let permits = config
+        .max_concurrent_polls
+        .map(|n| Arc::new(tokio::sync::Semaphore::new(n)));
+    let meters = config
+        .meters
+        .iter()
+        .zip(config.gateway_shares())
+        .map(|(m, share)| {
+            let mut meter = meter::Meter::new(m)?;
+            if let Some((index, count)) = share {
+                meter = meter.share_gateway(index, count);
+            }
+            if let Some(permits) = &permits {
+                meter = meter.limit(permits.clone());
+            }
+            if let Some(leading) = &leading {
+                meter = meter.standby(leading.clone());
+            }
+            #[cfg(feature = "scripting")]
+            if let Some(script) = &script {
+                meter = meter.script(script.clone());
+            }
+            if let Some(recorder) = &recorder {
+                meter = meter.record(recorder.clone());
+            }
+            if let Some(recording) = &replay {
+                meter = meter.replay(recording.clone());
+            }
+            Ok(Arc::new(meter))
+        })
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| exit::tag(Exit::Config, e))?;
+    meter::check_derived(&meters).map_err(|e| exit::tag(Exit::Config, e))?;
+    let tagged = meters.iter().map(|m| m.devices.len()).sum::<usize>() > 1;
+    let zone = match &config.timezone {
+        Some(name) => timezone::Zone::new(name).map_err(|e| exit::tag(Exit::Config, e))?,
+        None => timezone::Zone::default(),
+    };
+    let printer = Arc::new(output::Printer::new(opt.output, tagged, zone.clone()));
+    if opt.once {
+        return poll_once(&meters, &printer).await;
+    }
+    if opt.dry_run {
+        return dry_run(&config, &meters, tagged, zone).await;
+    }
+    let sinks = sink::Sinks::new(&config.sinks).map_err(|e| exit::tag(Exit::Config, e))?;
+    let sinks = Arc::new(sinks.start());
+    let recent = config.history.map(|h| {
+        Arc::new(history::History::new(
+            h,
+            &config.history_tiers,
+            zone.clone(),
+        ))
+    });
+    #[cfg(feature = "parquet")]
+    let archive = match &config.archive {
+        Some(a) => Some(Arc::new(archive::Archive::new(a, zone.clone())?)),
+        None => None,
+    };
+    #[cfg(feature = "parquet")]
+    if let Some(archive) = &archive {
+        tokio::spawn(archive.clone().run());
+    }
+    #[cfg(not(feature = "parquet"))]
+    if config.archive.is_some() {
+        return Err(exit::tag(
+            Exit::Config,
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "this sharkmon was built without the \"parquet\" feature, which --archive needs",
+            ),
+        ));
+    }
+    let mut tasks = tasks::Tasks::new(config.server_failure);
+    #[cfg(feature = "dnp3")]
+    let dnp3 = match &config.dnp3 {
+        Some(c) => match outstation::Outstation::start(c, &meters).await {
+            Ok(outstation) => {
+                let outstation = Arc::new(outstation);
+                tokio::spawn(outstation.clone().watch(meters.clone()));
+                Some(outstation)
+            }
+            Err(e) => {
+                tasks.server_failed("DNP3 outstation", exit::tag(Exit::Bind, e))?;
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "dnp3"))]
+    if config.dnp3.is_some() {
+        return Err(exit::tag(
+            Exit::Config,
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "this sharkmon was built without the \"dnp3\" feature",
+            ),
+        ));
+    }
+    #[cfg(feature = "opcua")]
+    let opcua = match &config.opcua {
+        Some(c) => match opcua::Server::start(c, &meters).await {
+            Ok(server) => {
+                let server = Arc::new(server);
+                tokio::spawn(server.clone().watch(meters.clone()));
+                Some(server)
+            }
+            Err(e) => {
+                tasks.server_failed("OPC UA server", exit::tag(Exit::Bind, e))?;
+                None
+            }
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "opcua"))]
+    if config.opcua.is_some() {
+        return Err(exit::tag(
+            Exit::Config,
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "this sharkmon was built without the \"opcua\" feature",
+            ),
+        ));
+    }
+    let alerts = match &config.snmp {
+        Some(snmp) => match snmp::Notifier::start(snmp).await {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                tasks.server_failed("SNMP notifier", exit::tag(Exit::Bind, e))?;
+                None
+            }
+        },
+        None => None,
+    };
+    let alerts =
+        (!config.alerts.is_empty()).then(|| Arc::new(alert::Alerts::new(&config.alerts, alerts)));
+    let events = Arc::new(events::Events::new(&config.transfer));
+    let quality = Arc::new(quality::Quality::new(&config.quality));
+    let baselines = Arc::new(baseline::Baselines::default());
+    let energy = Arc::new(energy::Energy::new(zone.clone()));
+    let state = config.state_file.clone().map(|path| {
+        let state = state::State::new(
+            path,
+            meters.clone(),
+            energy.clone(),
+            baselines.clone(),
+            quality.clone(),
+        );
+        state.restore();
+        Arc::new(state)
+    });
+    if let Some(state) = &state {
+        tokio::spawn(state.clone().run());
+    }
+    // Everything that takes the readings as they are polled consumes them
+    // from the bus, each in a task of its own.
+    let bus = bus::Bus::default();
+    let mut consume = |name: &str, consumer: Box<dyn FnMut(&bus::Reading) + Send>| {
+        tasks.poll(
+            format!("readings consumer '{name}'"),
+            bus.consume(name, consumer),
+        );
+    };
+    if opt.verbose || opt.no_web {
+        consume("stdout", Box::new(move |r| printer.print(&r.device)));
+    }
+    let s = sinks.clone();
+    consume("sinks", Box::new(move |r| s.publish(&r.device)));
+    if let Some(history) = recent.clone() {
+        consume("history", Box::new(move |r| history.record(&r.device)));
+    }
+    #[cfg(feature = "parquet")]
+    if let Some(archive) = archive.clone() {
+        consume("archive", Box::new(move |r| archive.record(&r.device)));
+    }
+    #[cfg(feature = "dnp3")]
+    if let Some(dnp3) = dnp3 {
+        consume("dnp3", Box::new(move |r| dnp3.record(&r.device)));
+    }
+    #[cfg(feature = "opcua")]
+    if let Some(opcua) = opcua {
+        consume("opcua", Box::new(move |r| opcua.record(&r.device)));
+    }
+    if let Some(alerts) = alerts.clone() {
+        consume("alerts", Box::new(move |r| alerts.record(&r.device)));
+    }
+    let e = events.clone();
+    consume("events", Box::new(move |r| e.record(&r.device)));
+    let q = quality.clone();
+    consume("quality", Box::new(move |r| q.record(&r.device)));
+    let b = baselines.clone();
+    consume("baselines", Box::new(move |r| b.record(&r.device)));
+    let e = energy.clone();
+    consume("energy", Box::new(move |r| e.record(&r.device)));
+    let output = meter::Output {
+        bus: bus.clone(),
+        history: recent.clone(),
+        events: Some(events.clone()),
+        meters: meters.as_slice().into(),
+    };
+    for (i, m) in meters.iter().enumerate() {
+        let delay = config.stagger.mul_f64(i as f64 / meters.len() as f64);
+        let (m, output) = (m.clone(), output.clone());
+        tasks.poll(format!("meter '{}'", m.name), async move {
+            tokio::time::sleep(delay).await;
+            meter::supervise(m, output).await
+        });
+    }
+    let audit = Arc::new(open_audit(&config)?);
+    for (m, c) in meters.iter().zip(&config.meters) {
+        if let Some(proxy) = &c.proxy {
+            if let Err(e) = proxy::start(proxy, m.clone(), audit.clone()).await {
+                tasks.server_failed("Modbus/TCP proxy", exit::tag(Exit::Bind, e))?;
+            }
+        }
+    }
+    if let Some(bacnet) = config.bacnet.clone() {
+        tasks.serve("BACnet/IP server", bacnet::serve(bacnet, meters.clone()));
+    }
+    if let Some(timeout) = systemd::watchdog_timeout() {
+        tokio::spawn(systemd::watchdog(meters.clone(), timeout));
+    }
+
+    let watched = meters.clone();
+    let (stop, _) = tokio::sync::watch::channel(false);
+    let mut web: std::pin::Pin<Box<dyn std::future::Future<Output = std::io::Result<()>>>> =
+        Box::pin(std::future::pending());
+    #[cfg(feature = "web")]
+    if opt.no_web {
+        if config.mdns {
+            tracing::warn!("not advertising over mDNS, since the web server is disabled");
+        }
+    } else {
+        let listen = config
+            .web_listen
+            .unwrap_or_else(|| std::net::SocketAddr::from(([0, 0, 0, 0], web::PORT)));
+        if config.mdns {
+            let name = config.mdns_name.clone().unwrap_or_else(mdns::hostname);
+            tasks.serve("mDNS advertisement", mdns::advertise(name, listen.port()));
+        }
+        let state = web::AppState {
+            meters,
+            sinks,
+            history: recent,
+            bus,
+            websockets: Default::default(),
+            events,
+            quality,
+            baselines,
+            energy,
+            zone,
+            api_keys: config.api_keys.clone(),
+            max_requests_in_flight: config.max_requests_in_flight,
+            rate_limit: config
+                .requests_per_second
+                .map(|rate| Arc::new(web::RateLimit::new(rate))),
+            jsonp: config.jsonp,
+            audit,
+            alerts,
+            config_path: opt.config.clone(),
+        };
+        let tls = config
+            .web_tls
+            .as_ref()
+            .map(tls::WebTls::new)
+            .transpose()
+            .map_err(|e| exit::tag(Exit::Config, e))?;
+        #[cfg(feature = "acme")]
+        if let (Some(web_tls), Some(tls)) = (&config.web_tls, &tls) {
+            // Only listening for HTTP-01 challenges can fail here.
+            acme::start(web_tls, tls.clone()).map_err(|e| exit::tag(Exit::Bind, e))?;
+        }
+        #[cfg(not(feature = "acme"))]
+        if config.web_tls.as_ref().is_some_and(|t| t.acme.is_some()) {
+            return Err(exit::tag(
+                Exit::Config,
+                std::io::Error::new(std::io::ErrorKind::Unsupported, "this sharkmon was built without the \"acme\" feature, so can't get certificates itself"),
+            ));
+        }
+        let mut stopped = stop.subscribe();
+        let stopped = async move {
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+        };
+        web = Box::pin(web::serve(state, tls, listen, stopped));
+    }
+    #[cfg(not(feature = "web"))]
+    if config.web_tls.is_some() {
+        return Err(exit::tag(
+            Exit::Config,
+            std::io::Error::new(std::io::ErrorKind::Unsupported, "this sharkmon was built without the \"web\" feature, so has no web server to serve over HTTPS"),
+        ));
+    }
+    #[cfg(not(feature = "web"))]
+    if config.mdns {
+        return Err(exit::tag(
+            Exit::Config,
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "this sharkmon was built without the \"web\" feature, so has nothing to advertise",
+            ),
+        ));
+    }
+    tokio::pin!(shutdown);
+    let mut serving = true;
+    let failure = loop {
+        tokio::select! {
+            () = &mut shutdown => break None,
+            e = tasks.failure() => break Some(e),
+            e = fail_fast(&watched, config.fail_fast) => break Some(e),
+            served = &mut web, if serving => {
+                serving = false;
+                let e = served.err().unwrap_or_else(|| std::io::Error::other("stopped"));
+                if let Err(e) = tasks.server_failed("web server", e) {
+                    break Some(e);
+                }
+            }
+        }
+    };
+    // The web server finishes the requests it is serving.
+    let _ = stop.send(true);
+    if serving {
+        if let Err(e) = web.await {
+            tracing::error!(error = %e, "web server failed while stopping");
+        }
+    }
+    tasks.stop().await;
+    #[cfg(feature = "parquet")]
+    if let Some(archive) = archive {
+        tokio::task::spawn_blocking(move || archive.flush()).await?;
+    }
+    if let Some(state) = state {
+        tokio::task::spawn_blocking(move || state.save()).await??;
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// With --fail-fast, wait for a meter to fail its `attempts` connection
+/// attempts without ever being polled, giving the error to exit with.
+async fn fail_fast(meters: &[Arc<meter::Meter>], attempts: Option<u32>) -> std::io::Error {
+    let Some(attempts) = attempts else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let failed = meters
+            .iter()
+            .find_map(|m| Some((m, m.unreachable(attempts)?)));
+        if let Some((m, e)) = failed {
+            let e = format!(
+                "meter '{}' couldn't be reached in {attempts} attempts: {e}",
+                m.name
+            );
+            let e = std::io::Error::new(std::io::ErrorKind::NotConnected, e);
+            return exit::tag(Exit::Unreachable, e);
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! Multicast DNS: just enough of it to ask for a service, as `sharkmon
+//! discover` does, and to answer for sharkmon's own web API as
+//! `_sharkmon._tcp`, so dashboards and apps on the LAN can find it without
+//! being told its address.
+
+#![cfg_attr(not(feature = "web"), allow(dead_code))]
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+pub const GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+/// The service sharkmon advertises itself as.
+pub const SERVICE: &str = "_sharkmon._tcp.local";
+
+/// The name that lists every advertised service, for browsers.
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+const A: u16 = 1;
+const PTR: u16 = 12;
+const TXT: u16 = 16;
+const SRV: u16 = 33;
+const ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// In a question, asks for a unicast answer; in a record, says it replaces
+/// any cached ones.
+const CLASS_FLAG: u16 = 0x8000;
+
+/// How long others may cache the records.
+const TTL: u32 = 120;
+
+/// A question in a packet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub kind: u16,
+    pub unicast: bool,
+}
+
+/// A resource record in a packet, with its data still encoded.
+#[derive(Debug)]
+pub struct Record {
+    pub kind: u16,
+    /// Where the data starts in the packet, since it may point back into it
+    pub data: usize,
+}
+
+/// The parts of a packet that sharkmon looks at.
+#[derive(Debug, Default)]
+pub struct Packet {
+    pub id: u16,
+    pub response: bool,
+    pub questions: Vec<Question>,
+    pub records: Vec<Record>,
+}
+
+fn u16_at(packet: &[u8], i: usize) -> Option<u16> {
+    packet
+        .get(i..i + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// Read the name at `i`, following compression pointers, and return it with
+/// the offset just past it.
+fn read_name(packet: &[u8], mut i: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Pointers must lead backwards, so a loop of them ends.
+    let mut limit = i;
+    loop {
+        let len = *packet.get(i)? as usize;
+        match len {
+            0 => break,
+            l if l & 0xc0 == 0xc0 => {
+                let target = (u16_at(packet, i)? & 0x3fff) as usize;
+                if target >= limit {
+                    return None;
+                }
+                end.get_or_insert(i + 2);
+                limit = target;
+                i = target;
+            }
+            l => {
+                let label = packet.get(i + 1..i + 1 + l)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                i += 1 + l;
+            }
+        }
+    }
+    Some((labels.join("."), end.unwrap_or(i + 1)))
+}
+
+impl Packet {
+    pub fn parse(packet: &[u8]) -> Option<Packet> {
+        let count = |i| u16_at(packet, i).map(|n| n as usize);
+        let mut parsed = Packet {
+            id: u16_at(packet, 0)?,
+            response: u16_at(packet, 2)? & 0x8000 != 0,
+            ..Default::default()
+        };
+        let records = count(6)? + count(8)? + count(10)?;
+        let mut i = 12;
+        for _ in 0..count(4)? {
+            let (name, next) = read_name(packet, i)?;
+            let class = u16_at(packet, next + 2)?;
+            parsed.questions.push(Question {
+                name,
+                kind: u16_at(packet, next)?,
+                unicast: class & CLASS_FLAG != 0,
+            });
+            i = next + 4;
+        }
+        for _ in 0..records {
+            let (_, next) = read_name(packet, i)?;
+            let len = u16_at(packet, next + 8)? as usize;
+            let data = next + 10;
+            packet.get(data..data + len)?;
+            parsed.records.push(Record {
+                kind: u16_at(packet, next)?,
+                data,
+            });
+            i = data + len;
+        }
+        Some(parsed)
+    }
+}
+
+fn push_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label);
+    }
+    packet.push(0);
+}
+
+/// A query for devices announcing `service`, asking for unicast answers.
+pub fn query(service: &str) -> Vec<u8> {
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    push_name(&mut packet, service);
+    packet.extend_from_slice(&PTR.to_be_bytes());
+    packet.extend_from_slice(&(CLASS_IN | CLASS_FLAG).to_be_bytes());
+    packet
+}
+
+/// The ports in the SRV records of an answer.
+pub fn srv_ports(packet: &[u8]) -> Vec<u16> {
+    let Some(parsed) = Packet::parse(packet) else {
+        return Vec::new();
+    };
+    parsed
+        .records
+        .iter()
+        .filter(|r| r.kind == SRV)
+        // Priority and weight come first.
+        .filter_map(|r| u16_at(packet, r.data + 4))
+        .collect()
+}
+
+/// The records advertising one sharkmon instance.
+#[derive(Debug, Clone)]
+pub struct Advert {
+    /// The instance name, e.g. "garage"
+    pub instance: String,
+    /// The host's own name, without ".local"
+    pub host: String,
+    pub address: Ipv4Addr,
+    pub port: u16,
+    /// `key=value` pairs describing the instance
+    pub txt: Vec<String>,
+}
+
+impl Advert {
+    fn instance_name(&self) -> String {
+        format!("{}.{SERVICE}", self.instance)
+    }
+
+    fn host_name(&self) -> String {
+        format!("{}.local", self.host)
+    }
+
+    /// Whether `question` asks for any of the records.
+    fn answers(&self, question: &Question) -> bool {
+        let name = question.name.to_ascii_lowercase();
+        let wanted = |kind| question.kind == kind || question.kind == ANY;
+        (name == SERVICE && wanted(PTR))
+            || (name == SERVICES && wanted(PTR))
+            || (name == self.instance_name().to_ascii_lowercase() && (wanted(SRV) || wanted(TXT)))
+            || (name == self.host_name().to_ascii_lowercase() && wanted(A))
+    }
+
+    /// A response packet with every record, with `id` as its ID and each
+    /// record's time to live set to `ttl`.
+    pub fn response(&self, id: u16, ttl: u32) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&id.to_be_bytes());
+        // A response, and authoritative
+        packet.extend_from_slice(&[0x84, 0x00, 0, 0, 0, 5, 0, 0, 0, 0]);
+        let mut record = |name: &str, kind: u16, flush: bool, data: &[u8]| {
+            push_name(&mut packet, name);
+            packet.extend_from_slice(&kind.to_be_bytes());
+            let class = if flush {
+                CLASS_IN | CLASS_FLAG
+            } else {
+                CLASS_IN
+            };
+            packet.extend_from_slice(&class.to_be_bytes());
+            packet.extend_from_slice(&ttl.to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(data);
+        };
+        let mut name = Vec::new();
+        push_name(&mut name, &self.instance_name());
+        record(SERVICE, PTR, false, &name);
+        let mut service = Vec::new();
+        push_name(&mut service, SERVICE);
+        record(SERVICES, PTR, false, &service);
+        let mut srv = vec![0, 0, 0, 0];
+        srv.extend_from_slice(&self.port.to_be_bytes());
+        push_name(&mut srv, &self.host_name());
+        record(&self.instance_name(), SRV, true, &srv);
+        let mut txt = Vec::new();
+        for entry in &self.txt {
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            txt.push(entry.len() as u8);
+            txt.extend_from_slice(entry);
+        }
+        record(&self.instance_name(), TXT, true, &txt);
+        record(&self.host_name(), A, true, &self.address.octets());
+        packet
+    }
+}
+
+/// The host's name, as far as it can be found without asking the OS.
+pub fn hostname() -> String {
+    let name = std::env::var("COMPUTERNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_default();
+    let name = name.trim().split('.').next().unwrap_or_default();
+    if name.is_empty() {
+        "sharkmon".to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
+/// The address multicast goes out from, which others can reach us on.
+fn local_address() -> std::io::Result<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(GROUP)?;
+    match socket.local_addr()? {
+        SocketAddr::V4(addr) => Ok(*addr.ip()),
+        SocketAddr::V6(_) => Err(std::io::Error::other("no IPv4 address")),
+    }
+}
+
+fn bind() -> std::io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Share the port with any other responder, such as Avahi.
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, GROUP.port())).into())?;
+    socket.join_multicast_v4(GROUP.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Advertise sharkmon's web API on `port` as `instance`, answering queries
+/// for it until the task is dropped.
+pub async fn advertise(instance: String, port: u16) -> std::io::Result<()> {
+    let socket = bind()?;
+    let advert = Advert {
+        instance,
+        host: hostname(),
+        address: local_address()?,
+        port,
+        txt: vec![
+            format!("version={}", env!("CARGO_PKG_VERSION")),
+            "path=/".to_owned(),
+        ],
+    };
+    info!(
+        instance = %advert.instance,
+        address = %advert.address,
+        port,
+        "advertising over mDNS"
+    );
+    // Announce twice, a second apart, as RFC 6762 asks.
+    for _ in 0..2 {
+        socket.send_to(&advert.response(0, TTL), GROUP).await?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let mut buf = vec![0; 9000];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Some(packet) = Packet::parse(&buf[..len]) else {
+            continue;
+        };
+        let asked: Vec<&Question> = packet
+            .questions
+            .iter()
+            .filter(|q| advert.answers(q))
+            .collect();
+        if packet.response || asked.is_empty() {
+            continue;
+        }
+        debug!(%from, "answering an mDNS query");
+        // Queries from a port other than 5353 are one-shot ones, answered
+        // directly and with their ID.
+        let legacy = from.port() != GROUP.port();
+        let result = if legacy {
+            socket.send_to(&advert.response(packet.id, 10), from).await
+        } else if asked.iter().all(|q| q.unicast) {
+            socket.send_to(&advert.response(0, TTL), from).await
+        } else {
+            socket.send_to(&advert.response(0, TTL), GROUP).await
+        };
+        if let Err(e) = result {
+            warn!(error = %e, "could not answer an mDNS query");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advert() -> Advert {
+        Advert {
+            instance: "garage".to_owned(),
+            host: "pi".to_owned(),
+            address: Ipv4Addr::new(192, 168, 1, 20),
+            port: 8081,
+            txt: vec!["path=/".to_owned()],
+        }
+    }
+
+    #[test]
+    fn queries_parse_back() {
+        let packet = Packet::parse(&query(SERVICE)).unwrap();
+        assert!(!packet.response);
+        assert_eq!(
+            packet.questions,
+            [Question {
+                name: SERVICE.to_owned(),
+                kind: PTR,
+                unicast: true,
+            }]
+        );
+        assert!(advert().answers(&packet.questions[0]));
+        let other = Packet::parse(&query("_http._tcp.local")).unwrap();
+        assert!(!advert().answers(&other.questions[0]));
+    }
+
+    #[test]
+    fn responses_carry_every_record() {
+        let response = advert().response(7, TTL);
+        let packet = Packet::parse(&response).unwrap();
+        assert_eq!(packet.id, 7);
+        assert!(packet.response);
+        let kinds: Vec<u16> = packet.records.iter().map(|r| r.kind).collect();
+        assert_eq!(kinds, [PTR, PTR, SRV, TXT, A]);
+        let srv = &packet.records[2];
+        let target = read_name(&response, srv.data + 6).unwrap().0;
+        assert_eq!(target, "pi.local");
+        assert_eq!(srv_ports(&response), [8081]);
+        let a = &packet.records[4];
+        assert_eq!(&response[a.data..a.data + 4], [192, 168, 1, 20]);
+        // A truncated packet is ignored rather than misread.
+        assert!(Packet::parse(&response[..response.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        let mut packet = query(SERVICE);
+        packet[7] = 1;
+        // An SRV answer for port 5020 whose name points back at the question
+        packet.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 120, 0, 8]);
+        packet.extend_from_slice(&[0, 0, 0, 0, 0x13, 0x9c, 0xc0, 12]);
+        assert_eq!(read_name(&packet, packet.len() - 20).unwrap().0, SERVICE);
+        assert_eq!(srv_ports(&packet), [5020]);
+        // A pointer to itself
+        let mut looped = query(SERVICE);
+        looped[7] = 1;
+        let at = looped.len() as u8;
+        looped.extend_from_slice(&[0xc0, at, 0, 33, 0, 1, 0, 0, 0, 120, 0, 0]);
+        assert!(Packet::parse(&looped).is_none());
+    }
+}
@@ -0,0 +1,2349 @@
+//! Polling of meter connections. Each meter connection runs in its own
+//! supervised task with its own reconnect backoff, so one unreachable meter
+//! doesn't hold up the others.
+
+use crate::client::{FakeMeter, MeterClient, UdpClient};
+use crate::config::{Labels, MeterConfig, MeterSettings, Sign, Total, Transport};
+use crate::{
+    bench, derived, energy, history, meterlog, metrics, recording, registers, sunspec, systemd, tls,
+};
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, debug_span, error, info, info_span, warn, Instrument};
+
+/// Reconnect delays after a connection fails, doubling up to the maximum
+/// while the meter stays unreachable.
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How far a meter's clock may drift before it is set. Meter clocks only
+/// count whole seconds, so anything smaller is noise.
+const MAX_CLOCK_DRIFT: i64 = 2;
+
+/// Failed reads in a row that end a benchmark.
+const BENCH_FAILURES: u32 = 10;
+
+/// How recently the poll loop must have read registers for them to answer
+/// an on-demand read.
+const CACHE_AGE: Duration = Duration::from_secs(1);
+
+/// On-demand reads a second that may go to a meter, beyond those answered
+/// from what the poll loop read.
+const READS_PER_SECOND: usize = 2;
+
+/// How long what a device's option cards said answers `/meter/info` before
+/// they are read again.
+const CARD_AGE: Duration = Duration::from_secs(30);
+
+/// Smoothed readings, one per metric of the register map, in map order.
+#[derive(Debug, Clone, Default)]
+pub struct PowerEwma {
+    initialized: Vec<bool>,
+    names: Arc<[String]>,
+    values: Vec<f32>,
+    /// What the script reported instead of each value, until the value next
+    /// changes. Kept apart so the script's changes aren't smoothed into the
+    /// next reading.
+    scripted: Vec<Option<f32>>,
+    /// Decimal places to serialize readings to, by name
+    precision: Arc<BTreeMap<String, u8>>,
+    /// Which readings are counters, taken as read: smoothing one would have
+    /// it lag, and decaying it while the meter is unreachable would look
+    /// like a reset.
+    counters: Vec<bool>,
+}
+
+impl Serialize for PowerEwma {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.values.len()))?;
+        for (name, value) in self.rounded() {
+            map.serialize_entry(name, &value)?;
+        }
+        map.end()
+    }
+}
+
+fn ewma(a: f32, b: f32, alpha: f32) -> f32 {
+    a * (1.0 - alpha) + b * alpha
+}
+
+impl PowerEwma {
+    fn new(names: Vec<String>) -> PowerEwma {
+        PowerEwma {
+            initialized: vec![false; names.len()],
+            values: vec![0.0; names.len()],
+            scripted: vec![None; names.len()],
+            counters: vec![false; names.len()],
+            names: names.into(),
+            precision: Default::default(),
+        }
+    }
+    /// Readings as they were, e.g. when read back from a buffer.
+    pub fn from_values(names: Vec<String>, values: Vec<f32>) -> PowerEwma {
+        PowerEwma {
+            initialized: vec![true; names.len()],
+            scripted: vec![None; names.len()],
+            counters: vec![false; names.len()],
+            names: names.into(),
+            values,
+            precision: Default::default(),
+        }
+    }
+    /// Round the named readings to so many decimal places when serialized.
+    fn with_precision(mut self, precision: Arc<BTreeMap<String, u8>>) -> PowerEwma {
+        self.precision = precision;
+        self
+    }
+    /// Take the readings of the `counters` as read, without smoothing.
+    fn with_counters(mut self, counters: &[energy::Counter]) -> PowerEwma {
+        for counter in counters {
+            self.counters[counter.index] = true;
+        }
+        self
+    }
+    pub fn names(&self) -> Arc<[String]> {
+        self.names.clone()
+    }
+    /// Only the readings whose place in `keep` is true.
+    pub fn only(&self, keep: &[bool]) -> PowerEwma {
+        fn pick<T: Clone>(items: &[T], keep: &[bool]) -> Vec<T> {
+            items
+                .iter()
+                .zip(keep)
+                .filter(|(_, &keep)| keep)
+                .map(|(item, _)| item.clone())
+                .collect()
+        }
+        PowerEwma {
+            initialized: pick(&self.initialized, keep),
+            names: pick(&self.names, keep).into(),
+            values: pick(&self.values, keep),
+            scripted: pick(&self.scripted, keep),
+            precision: self.precision.clone(),
+            counters: pick(&self.counters, keep),
+        }
+    }
+    pub fn get(&self, name: &str) -> Option<f32> {
+        let i = self.names.iter().position(|n| n == name)?;
+        Some(self.scripted[i].unwrap_or(self.values[i]))
+    }
+    /// Each reading's name and current value, in map order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f32)> {
+        let values = self.values.iter().zip(&self.scripted);
+        self.names
+            .iter()
+            .map(String::as_str)
+            .zip(values.map(|(value, scripted)| scripted.unwrap_or(*value)))
+    }
+    /// Each reading's name and current value, rounded to its precision.
+    pub fn rounded(&self) -> impl Iterator<Item = (&str, f32)> {
+        self.iter()
+            .map(|(name, value)| match self.precision.get(name) {
+                Some(&places) => {
+                    let scale = 10f64.powi(places.into());
+                    (name, ((value as f64 * scale).round() / scale) as f32)
+                }
+                None => (name, value),
+            })
+    }
+    /// Each reading's name and value before the script changed it.
+    #[cfg(feature = "scripting")]
+    pub fn unscripted(&self) -> impl Iterator<Item = (&str, f32)> {
+        self.names
+            .iter()
+            .map(String::as_str)
+            .zip(self.values.iter().copied())
+    }
+    /// Fold in new `values` for the metrics at indices `metrics`, each
+    /// weighted by `alpha`.
+    fn update(&mut self, metrics: &[usize], values: &[f64], alpha: f32) {
+        for (&i, &new) in metrics.iter().zip(values) {
+            if !self.initialized[i] || self.counters[i] {
+                self.values[i] = new as f32;
+                self.initialized[i] = true;
+            } else {
+                self.values[i] = ewma(self.values[i], new as f32, alpha);
+            }
+            self.scripted[i] = None;
+        }
+    }
+    /// Report `value` for the named reading, if there is one, in place of
+    /// its own until it next changes.
+    #[cfg(feature = "scripting")]
+    pub fn script(&mut self, name: &str, value: f32) {
+        if let Some(i) = self.names.iter().position(|n| n == name) {
+            self.scripted[i] = Some(value);
+        }
+    }
+    /// Replace a reading outright, rather than smoothing it.
+    fn set(&mut self, i: usize, value: f32) {
+        self.values[i] = value;
+        self.initialized[i] = true;
+        self.scripted[i] = None;
+    }
+    /// Carry on smoothing from readings saved before a restart.
+    pub fn restore(&mut self, saved: &BTreeMap<String, f32>) {
+        for (i, name) in self.names.iter().enumerate() {
+            if let Some(&value) = saved.get(name) {
+                self.values[i] = value;
+                self.initialized[i] = true;
+            }
+        }
+    }
+    /// Decay every reading towards zero, while the meter is unreachable.
+    fn update_zero(&mut self, alpha: f32) {
+        let all: Vec<usize> = (0..self.values.len())
+            .filter(|&i| !self.counters[i])
+            .collect();
+        self.update(&all, &vec![0.0; all.len()], alpha);
+    }
+}
+
+/// A meter on the connection, addressed by its Modbus unit ID.
+pub struct Device {
+    pub name: String,
+    pub unit: u8,
+    pub readings: Mutex<PowerEwma>,
+    /// How the device adds to `/power/total`
+    pub total: Total,
+    /// The meter's labels
+    pub labels: Arc<Labels>,
+    /// The readings that count energy
+    pub counters: Arc<[energy::Counter]>,
+}
+
+/// The registers to poll on a meter.
+pub enum MeterMap {
+    Fixed(Box<registers::RegisterMap>),
+    /// Discovered from the meter's SunSpec model chain on every connect
+    SunSpec,
+}
+
+/// Where polled readings go besides `/power`: onto the readings bus, for
+/// everything that consumes them.
+#[derive(Clone, Default)]
+pub struct Output {
+    pub bus: crate::bus::Bus,
+    /// The history, filled from the meter's own logs after an outage
+    pub history: Option<Arc<history::History>>,
+    /// Where the meter's limit alarms are reported
+    pub events: Option<Arc<crate::events::Events>>,
+    /// Every meter, whose readings derived readings may use
+    pub meters: Arc<[Arc<Meter>]>,
+}
+
+/// The health of a meter connection, as reported by `/status`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Status {
+    pub connected: bool,
+    pub last_poll: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_time: Option<DateTime<Utc>>,
+    /// Passes since startup that read a poll group
+    pub polls: u64,
+    /// Connections made since startup
+    pub connects: u64,
+    /// Connection failures since startup
+    pub failures: u64,
+    /// Failed register reads since startup, including retried ones
+    pub read_errors: u64,
+    /// Of those, the reads the meter refused, by Modbus exception
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub exceptions: BTreeMap<String, u64>,
+    /// Delay before the next connection attempt, while disconnected
+    pub retry_secs: Option<u64>,
+    /// The address connected to, which may be a failover address
+    pub active_address: Option<String>,
+    /// How far ahead of ours each device's clock was when last checked
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub clock_drift_secs: BTreeMap<String, i64>,
+    /// Each device's firmware version, for register maps that read it
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub firmware: BTreeMap<String, String>,
+    /// How far past each wall-clock boundary of its interval the meter is
+    /// polled, when it is given an offset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_offset_ms: Option<u64>,
+    /// The meter's own limits tripped on each device, when last read
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub limits: BTreeMap<String, Vec<String>>,
+    /// Whether the meter is left to the other instance of a leader/standby
+    /// pair
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub standby: bool,
+    /// Whether polling was paused through the web API
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
+    /// When the poll loop last connected or finished a pass over the groups
+    #[serde(skip)]
+    progress: Option<tokio::time::Instant>,
+}
+
+/// What an option card says about itself, for `/meter/info`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CardInfo {
+    pub slot: u8,
+    /// The card's type code, and its name if the register map knows it
+    pub code: Option<u16>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub installed: bool,
+    /// Why the card's registers couldn't be read, if the meter refused them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The faults the card flags in its status register
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub faults: Vec<String>,
+    /// Whether the card flags any fault
+    pub degraded: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub counters: BTreeMap<String, f64>,
+}
+
+/// An on-demand register read for the poll loop to make.
+struct ReadRequest {
+    unit: u8,
+    block: registers::Block,
+}
+
+/// Registers read, and when.
+type Cached = (tokio::time::Instant, Vec<u16>);
+
+/// Callers waiting for an on-demand read.
+type Waiting = Vec<tokio::sync::oneshot::Sender<std::io::Result<Vec<u16>>>>;
+
+/// A register write waiting for the poll loop, which owns the connection.
+struct WriteRequest {
+    unit: u8,
+    address: u16,
+    values: Vec<u16>,
+    reply: tokio::sync::oneshot::Sender<std::io::Result<()>>,
+}
+
+/// A device's register map for the current connection, and its poll groups.
+type DeviceMap = (registers::RegisterMap, Vec<registers::PollGroup>);
+
+/// One meter connection and the devices polled over it.
+pub struct Meter {
+    pub name: String,
+    pub address: String,
+    /// Other paths to the meter, tried in order when `address` fails
+    pub failover: Vec<String>,
+    transport: Transport,
+    pub labels: Arc<Labels>,
+    tls: Option<tls::MeterTls>,
+    map: MeterMap,
+    read_gap: u16,
+    keepalive: Duration,
+    timeout: Duration,
+    heartbeat: Duration,
+    retries: u32,
+    align: bool,
+    align_jitter: Duration,
+    poll_offset: Option<Duration>,
+    /// This meter's place among the meters at its address, and how many
+    /// there are, when they are staggered
+    gateway_share: Option<(usize, usize)>,
+    adaptive: Option<Duration>,
+    adaptive_threshold: f64,
+    clock_sync: Option<Duration>,
+    /// Whether to fill gaps in the history from the meter's log
+    backfill: bool,
+    /// The firmware version to pick the map's variant by, if not the
+    /// meter's own
+    firmware: Option<String>,
+    writable: Vec<registers::RegisterRange>,
+    /// Each derived reading's index among the readings, and its expression
+    derived: Vec<(usize, derived::Expression)>,
+    /// The units of each reading, in reading order; empty if unknown
+    units: Vec<String>,
+    /// What each reading of the register map is multiplied by, to give it
+    /// in its output units
+    factors: Vec<f64>,
+    pub devices: Vec<Device>,
+    pub status: Mutex<Status>,
+    /// Round-trip times of successful reads
+    pub latency: Mutex<metrics::Histogram>,
+    recorder: Option<Arc<recording::Recorder>>,
+    replay: Option<Arc<recording::Recording>>,
+    fake: Option<FakeMeter>,
+    /// Shared by meters that may not all poll at once
+    permits: Option<Arc<tokio::sync::Semaphore>>,
+    /// Whether this instance leads its leader/standby pair, if it has one
+    leading: Option<tokio::sync::watch::Receiver<bool>>,
+    /// Whether polling is paused, which disconnects from the meter
+    paused: tokio::sync::watch::Sender<bool>,
+    /// The settings that may be changed while running
+    settings: tokio::sync::watch::Sender<MeterSettings>,
+    #[cfg(feature = "scripting")]
+    script: Option<Arc<crate::script::Script>>,
+    writes: tokio::sync::mpsc::Sender<WriteRequest>,
+    /// Held by the poll loop while it is connected
+    write_queue: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<WriteRequest>>,
+    reads: tokio::sync::mpsc::Sender<ReadRequest>,
+    read_queue: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<ReadRequest>>,
+    /// The registers of each unit's blocks as the poll loop last read them,
+    /// and when
+    cache: Mutex<HashMap<(u8, registers::Block), Cached>>,
+    /// When recent on-demand reads went to the meter
+    read_times: Mutex<VecDeque<tokio::time::Instant>>,
+    /// Who is waiting for each on-demand read that hasn't been made yet
+    pending: Mutex<HashMap<(u8, registers::Block), Waiting>>,
+    /// What each unit's option cards last said, and when. Held while they
+    /// are read, so that only one reading of them is made at a time.
+    cards: tokio::sync::Mutex<HashMap<u8, (tokio::time::Instant, Vec<CardInfo>)>>,
+}
+
+/// A meter's entry in `/status`.
+#[derive(Serialize)]
+pub struct MeterStatus {
+    pub name: String,
+    pub address: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failover: Vec<String>,
+    #[serde(skip_serializing_if = "Labels::is_empty")]
+    pub labels: Labels,
+    pub devices: Vec<String>,
+    /// The units of each reading that has them
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub units: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+impl Meter {
+    pub fn new(config: &MeterConfig) -> std::io::Result<Meter> {
+        let tls = match &config.tls {
+            Some(t) => Some(tls::MeterTls::new(
+                &t.ca,
+                t.cert.as_deref(),
+                t.key.as_deref(),
+                t.server_name.clone(),
+            )?),
+            None => None,
+        };
+
+        if config.sunspec && config.clock_sync.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "clock sync isn't available for SunSpec meters",
+            ));
+        }
+        if config.sunspec && config.backfill {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "backfill isn't available for SunSpec meters",
+            ));
+        }
+        if config.sunspec && config.firmware.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "SunSpec meters have no register map to pick a firmware variant of",
+            ));
+        }
+        let (map, names, units, ranges) = if config.sunspec {
+            let names = sunspec::NAMES.map(String::from).to_vec();
+            let units = sunspec::UNITS.map(String::from).to_vec();
+            let ranges = vec![None; names.len()];
+            (MeterMap::SunSpec, names, units, ranges)
+        } else {
+            let mut map = match (&config.register_map, &config.profile) {
+                (Some(path), _) => registers::RegisterMap::load(path)?,
+                (None, Some(profile)) => registers::RegisterMap::profile(profile)?,
+                (None, None) => registers::RegisterMap::profile("shark100")?,
+            };
+            for o in &config.register_formats {
+                map.apply(o)?;
+            }
+            if config.clock_sync.is_some() && map.clock.is_none() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "clock sync needs a [clock] table in the register map",
+                ));
+            }
+            if config.backfill && map.log.is_none() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "backfill needs a [log] table in the register map",
+                ));
+            }
+            if config.firmware.is_some() && map.firmware.is_none() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "firmware needs a [firmware] table in the register map, with its variants",
+                ));
+            }
+            let names = map.names();
+            let units = map.metrics.iter().map(|m| m.units.clone()).collect();
+            let ranges = map.metrics.iter().map(|m| m.register.range()).collect();
+            (MeterMap::Fixed(Box::new(map)), names, units, ranges)
+        };
+
+        let mut names = names;
+        let mut units: Vec<String> = units;
+        let native = names.len();
+        let mut factors = vec![1.0; native];
+        for (reading, to) in &config.output_units {
+            let Some(i) = names.iter().position(|n| n == reading) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("output units for '{reading}', which the register map doesn't have"),
+                ));
+            };
+            let Some(factor) = registers::conversion(&units[i], to) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "can't report reading '{reading}' in {to}, from {}",
+                        Some(units[i].as_str())
+                            .filter(|u| !u.is_empty())
+                            .unwrap_or("unknown units")
+                    ),
+                ));
+            };
+            factors[i] = factor;
+            units[i] = to.clone();
+        }
+        if config.sign == Sign::Export {
+            for (factor, units) in factors.iter_mut().zip(&units) {
+                if ["W", "var"]
+                    .iter()
+                    .any(|base| registers::conversion(units, base).is_some())
+                {
+                    *factor = -*factor;
+                }
+            }
+        }
+        let mut split = Vec::new();
+        for reading in &config.split {
+            let Some(i) = names[..native].iter().position(|n| n == reading) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("can't split '{reading}', which the register map doesn't have"),
+                ));
+            };
+            for (suffix, expression) in [("import", "max({}, 0)"), ("export", "max(-{}, 0)")] {
+                let expression = expression.replace("{}", reading).parse().map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("can't split '{reading}': {e}"),
+                    )
+                })?;
+                split.push((format!("{reading}_{suffix}"), expression, units[i].clone()));
+            }
+        }
+        let configured = config
+            .derived
+            .iter()
+            .map(|(name, expression)| (name.clone(), expression.clone(), String::new()));
+        let mut derived = Vec::new();
+        for (name, expression, derived_units) in split.into_iter().chain(configured) {
+            if names[..native].contains(&name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "derived reading '{name}' has the name of a reading of the register map"
+                    ),
+                ));
+            }
+            let unknown = expression
+                .references()
+                .into_iter()
+                .find(|r| r.device.is_none() && !names[..native].contains(&r.name.to_owned()));
+            if let Some(reference) = unknown {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("derived reading '{name}' uses '{reference}', which the register map doesn't have"),
+                ));
+            }
+            if names.contains(&name) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("derived reading '{name}' has the name of a split reading"),
+                ));
+            }
+            derived.push((names.len(), expression));
+            names.push(name);
+            units.push(derived_units);
+        }
+
+        if let Some(reading) = config.precision.keys().find(|r| !names.contains(r)) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("precision for '{reading}', which isn't a reading"),
+            ));
+        }
+        let precision = Arc::new(config.precision.clone());
+        let counters: Arc<[energy::Counter]> = ranges
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| energy::is_energy(&units[*i]))
+            .map(|(i, range)| energy::Counter {
+                index: i,
+                units: units[i].clone(),
+                range: range.map(|(min, max)| (min * factors[i], max * factors[i])),
+            })
+            .collect();
+
+        let labels = Arc::new(config.labels.clone());
+        let devices = config
+            .units
+            .iter()
+            .zip(config.device_names())
+            .map(|(u, name)| Device {
+                name,
+                unit: u.id,
+                readings: Mutex::new(
+                    PowerEwma::new(names.clone())
+                        .with_precision(precision.clone())
+                        .with_counters(&counters),
+                ),
+                total: u.total,
+                labels: labels.clone(),
+                counters: counters.clone(),
+            })
+            .collect();
+
+        let (writes, write_queue) = tokio::sync::mpsc::channel(16);
+        let (reads, read_queue) = tokio::sync::mpsc::channel(16);
+        Ok(Meter {
+            name: config.name.clone(),
+            address: config.address.clone(),
+            failover: config.failover.clone(),
+            transport: config.transport,
+            labels,
+            tls,
+            map,
+            read_gap: config.read_gap,
+            keepalive: config.keepalive,
+            timeout: config.timeout,
+            heartbeat: config.heartbeat,
+            retries: config.retries,
+            align: config.align,
+            align_jitter: config.align_jitter,
+            poll_offset: config.poll_offset,
+            gateway_share: None,
+            adaptive: config.adaptive,
+            adaptive_threshold: config.adaptive_threshold,
+            clock_sync: config.clock_sync,
+            backfill: config.backfill,
+            firmware: config.firmware.clone(),
+            writable: config.writable.clone(),
+            derived,
+            units,
+            factors,
+            devices,
+            status: Mutex::new(Status::default()),
+            latency: Mutex::new(metrics::Histogram::default()),
+            recorder: None,
+            replay: None,
+            fake: None,
+            permits: None,
+            leading: None,
+            paused: tokio::sync::watch::Sender::new(false),
+            settings: tokio::sync::watch::Sender::new(config.settings()),
+            #[cfg(feature = "scripting")]
+            script: None,
+            writes,
+            write_queue: tokio::sync::Mutex::new(write_queue),
+            reads,
+            read_queue: tokio::sync::Mutex::new(read_queue),
+            cache: Default::default(),
+            read_times: Default::default(),
+            pending: Default::default(),
+            cards: Default::default(),
+        })
+    }
+
+    pub fn status(&self) -> MeterStatus {
+        MeterStatus {
+            name: self.name.clone(),
+            address: self.address.clone(),
+            failover: self.failover.clone(),
+            labels: (*self.labels).clone(),
+            devices: self.devices.iter().map(|d| d.name.clone()).collect(),
+            units: self.reading_units(),
+            status: self.status.lock().unwrap().clone(),
+        }
+    }
+
+    /// The units of each reading that has them, by reading.
+    fn reading_units(&self) -> BTreeMap<String, String> {
+        let Some(device) = self.devices.first() else {
+            return BTreeMap::new();
+        };
+        let names = device.readings.lock().unwrap().names();
+        names
+            .iter()
+            .zip(&self.units)
+            .filter(|(_, units)| !units.is_empty())
+            .map(|(name, units)| (name.clone(), units.clone()))
+            .collect()
+    }
+
+    /// The units of a reading, e.g. "W", if the register map gives them.
+    pub fn units(&self, reading: &str) -> Option<&str> {
+        let names = self.devices.first()?.readings.lock().unwrap().names();
+        let i = names.iter().position(|n| n == reading)?;
+        Some(self.units[i].as_str()).filter(|u| !u.is_empty())
+    }
+
+    pub fn device(&self, name: &str) -> Option<&Device> {
+        self.devices.iter().find(|d| d.name == name)
+    }
+
+    /// The register written to perform reset `kind`, from the register map.
+    pub fn reset_register(&self, kind: registers::ResetKind) -> std::io::Result<registers::Reset> {
+        let map = match &self.map {
+            MeterMap::Fixed(map) => map,
+            MeterMap::SunSpec => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "SunSpec meters have no reset registers",
+                ))
+            }
+        };
+        map.resets.get(&kind).copied().ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "meter '{}' has no {kind} reset in its register map",
+                    self.name
+                ),
+            )
+        })
+    }
+
+    /// Check that `len` registers from `address` may be written through the
+    /// web API.
+    pub fn check_writable(&self, address: u16, len: usize) -> std::io::Result<()> {
+        if len == 0 || len > registers::MAX_WRITE_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("writes must be 1 to {} registers", registers::MAX_WRITE_LEN),
+            ));
+        }
+        if self.writable.iter().any(|r| r.contains(address, len)) {
+            return Ok(());
+        }
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "registers {address:#06x}+{len} of meter '{}' are not writable",
+                self.name
+            ),
+        ))
+    }
+
+    /// Write `values` to the holding registers of the device with the given
+    /// unit ID. While the poll loop is connected the write goes over its
+    /// connection, between reads; otherwise it makes a connection of its own.
+    pub async fn write(&self, unit: u8, address: u16, values: Vec<u16>) -> std::io::Result<()> {
+        if let Ok(_idle) = self.write_queue.try_lock() {
+            let (mut ctx, _) = self.connect().await?;
+            ctx.set_unit(unit);
+            let write = ctx.write(address, &values);
+            let result = self.timed("writing registers", write).await;
+            let _ = ctx.disconnect().await;
+            return result;
+        }
+        let (reply, response) = tokio::sync::oneshot::channel();
+        let request = WriteRequest {
+            unit,
+            address,
+            values,
+            reply,
+        };
+        // The receiver lives as long as the meter, so this only waits for room.
+        let _ = self.writes.send(request).await;
+        // The poll loop may first have to finish a read.
+        match tokio::time::timeout(self.timeout * 2, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(Error::new(
+                ErrorKind::NotConnected,
+                "the meter disconnected before the write",
+            )),
+            Err(_) => Err(Error::new(
+                ErrorKind::TimedOut,
+                "timed out waiting for the poll loop to write",
+            )),
+        }
+    }
+
+    /// Keep what the poll loop read, for on-demand reads.
+    fn cache(&self, unit: u8, data: &registers::BlockData) {
+        let now = tokio::time::Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        for (block, values) in data.blocks() {
+            cache.insert((unit, *block), (now, values.clone()));
+        }
+    }
+
+    /// The registers of `block` from the poll loop's last reads of the unit,
+    /// if it read them all within the last second.
+    fn cached(&self, unit: u8, block: registers::Block) -> Option<Vec<u16>> {
+        let cache = self.cache.lock().unwrap();
+        cache.iter().find_map(|((u, b), (at, values))| {
+            let fresh = *u == unit && b.function == block.function && at.elapsed() < CACHE_AGE;
+            let offset = block.start.checked_sub(b.start)? as usize;
+            let end = offset + block.len as usize;
+            (fresh && end <= values.len()).then(|| values[offset..end].to_vec())
+        })
+    }
+
+    /// Read `block` from the device with the given unit ID on demand, e.g.
+    /// while working out a register map, returning its registers and whether
+    /// they were read in the last second, by the poll loop or another
+    /// on-demand read. Otherwise the read waits for the poll loop, between
+    /// its own reads, and is shared with identical reads made meanwhile.
+    /// Only two a second go to the meter, so interactive use can't crowd out
+    /// polling.
+    pub async fn read_registers(
+        &self,
+        unit: u8,
+        block: registers::Block,
+    ) -> std::io::Result<(Vec<u16>, bool)> {
+        self.read_on_demand(unit, block, true).await
+    }
+
+    /// The option card slots the register map describes.
+    pub fn option_card_slots(&self) -> &[registers::OptionCard] {
+        match &self.map {
+            MeterMap::Fixed(map) => &map.option_cards,
+            MeterMap::SunSpec => &[],
+        }
+    }
+
+    /// Read the identification and health registers of the option card in
+    /// each slot of the device with the given unit ID, as on-demand reads
+    /// within their limit of two a second, or give what they said in the
+    /// last `CARD_AGE`. An empty slot's are skipped, and a slot whose
+    /// registers the meter refuses is reported with the refusal.
+    pub async fn option_cards(&self, unit: u8) -> std::io::Result<Vec<CardInfo>> {
+        let mut cached = self.cards.lock().await;
+        if let Some((at, cards)) = cached.get(&unit) {
+            if at.elapsed() < CARD_AGE {
+                return Ok(cards.clone());
+            }
+        }
+        let cards = self.read_option_cards(unit).await?;
+        cached.insert(unit, (tokio::time::Instant::now(), cards.clone()));
+        Ok(cards)
+    }
+
+    async fn read_option_cards(&self, unit: u8) -> std::io::Result<Vec<CardInfo>> {
+        let mut cards = Vec::new();
+        for slot in self.option_card_slots() {
+            let mut card = CardInfo {
+                slot: slot.slot,
+                code: None,
+                kind: None,
+                installed: false,
+                error: None,
+                faults: Vec::new(),
+                degraded: false,
+                counters: BTreeMap::new(),
+            };
+            match self.read_option_card(unit, slot, &mut card).await {
+                Ok(()) => {}
+                Err(e) if registers::is_exception(&e) => card.error = Some(e.to_string()),
+                Err(e) => return Err(e),
+            }
+            cards.push(card);
+        }
+        Ok(cards)
+    }
+
+    async fn read_option_card(
+        &self,
+        unit: u8,
+        slot: &registers::OptionCard,
+        card: &mut CardInfo,
+    ) -> std::io::Result<()> {
+        let holding = |start, len| registers::Block {
+            function: registers::Function::Holding,
+            start,
+            len,
+        };
+        let id = self.read_paced(unit, holding(slot.id, 1)).await?;
+        let code = id[0];
+        card.code = Some(code);
+        card.kind = slot.type_name(code).map(str::to_owned);
+        card.installed = code != 0 && code != 0xFFFF;
+        if !card.installed {
+            return Ok(());
+        }
+        if let Some(status) = slot.status {
+            let r = self.read_paced(unit, holding(status, 1)).await?;
+            card.faults = slot.faults(r[0]);
+            card.degraded = !card.faults.is_empty();
+        }
+        for counter in &slot.counters {
+            let (address, len) = counter.register.span();
+            let r = self.read_paced(unit, holding(address, len)).await?;
+            let value = counter.register.format.decode(&r) * counter.register.scale;
+            card.counters.insert(counter.name.clone(), value);
+        }
+        Ok(())
+    }
+
+    /// Read `block` on demand, waiting for the limit of two reads a second
+    /// to allow it rather than failing.
+    async fn read_paced(&self, unit: u8, block: registers::Block) -> std::io::Result<Vec<u16>> {
+        loop {
+            match self.read_on_demand(unit, block, true).await {
+                Err(e) if e.kind() == ErrorKind::QuotaExceeded => {
+                    let oldest = self.read_times.lock().unwrap().front().copied();
+                    let free = oldest
+                        .map_or_else(tokio::time::Instant::now, |t| t + Duration::from_secs(1));
+                    tokio::time::sleep_until(free).await;
+                }
+                result => return result.map(|(values, _)| values),
+            }
+        }
+    }
+
+    /// Read `block` for a client of the Modbus/TCP proxy, like
+    /// [`Meter::read_registers`] but without its limit of two reads a second,
+    /// as the proxy keeps its own pace.
+    pub async fn proxy_read(&self, unit: u8, block: registers::Block) -> std::io::Result<Vec<u16>> {
+        let (values, _) = self.read_on_demand(unit, block, false).await?;
+        Ok(values)
+    }
+
+    async fn read_on_demand(
+        &self,
+        unit: u8,
+        block: registers::Block,
+        limited: bool,
+    ) -> std::io::Result<(Vec<u16>, bool)> {
+        if let Some(values) = self.cached(unit, block) {
+            return Ok((values, true));
+        }
+        let key = (unit, block);
+        let (reply, response) = tokio::sync::oneshot::channel();
+        let shared = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get_mut(&key) {
+                Some(waiting) => {
+                    waiting.push(reply);
+                    true
+                }
+                None => {
+                    if limited {
+                        self.limit_reads()?;
+                    }
+                    pending.insert(key, vec![reply]);
+                    false
+                }
+            }
+        };
+        if !shared {
+            if let Ok(_idle) = self.read_queue.try_lock() {
+                let result = match self.connect().await {
+                    Ok((mut ctx, _)) => {
+                        let result = self.read(&mut *ctx, unit, std::slice::from_ref(&block));
+                        let result = result.await;
+                        let _ = ctx.disconnect().await;
+                        result
+                    }
+                    Err(e) => Err(e),
+                };
+                self.answer(key, result);
+            } else {
+                let _ = self.reads.send(ReadRequest { unit, block }).await;
+            }
+        }
+        let result = match tokio::time::timeout(self.timeout * 2, response).await {
+            Ok(Ok(result)) => return result.map(|values| (values, false)),
+            Ok(Err(_)) => Err(Error::new(
+                ErrorKind::NotConnected,
+                "the meter disconnected before the read",
+            )),
+            Err(_) => Err(Error::new(
+                ErrorKind::TimedOut,
+                "timed out waiting for the poll loop to read",
+            )),
+        };
+        // Later reads shouldn't wait on one that may never be made.
+        self.pending.lock().unwrap().remove(&key);
+        result
+    }
+
+    /// Count an on-demand read that goes to the meter, unless there have
+    /// been too many in the last second.
+    fn limit_reads(&self) -> std::io::Result<()> {
+        let now = tokio::time::Instant::now();
+        let mut times = self.read_times.lock().unwrap();
+        while times
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(1))
+        {
+            times.pop_front();
+        }
+        if times.len() >= READS_PER_SECOND {
+            return Err(Error::new(
+                ErrorKind::QuotaExceeded,
+                format!("meter '{}' is already being read on demand", self.name),
+            ));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+
+    /// Answer the on-demand reads waiting for `key` with the registers read.
+    /// What was read is kept for later reads.
+    fn answer(
+        &self,
+        (unit, block): (u8, registers::Block),
+        result: std::io::Result<registers::BlockData>,
+    ) {
+        let result = result.and_then(|data| {
+            self.cache(unit, &data);
+            Ok(data.get(block.function, block.start, block.len)?.to_vec())
+        });
+        let waiting = self.pending.lock().unwrap().remove(&(unit, block));
+        for reply in waiting.into_iter().flatten() {
+            let result = match &result {
+                Ok(values) => Ok(values.clone()),
+                Err(e) => Err(registers::copy_error(e)),
+            };
+            let _ = reply.send(result);
+        }
+    }
+
+    /// Make the reads queued by `read_registers`, starting with `first`. A
+    /// read the meter refuses is only an error for the callers; any other
+    /// failure also drops the connection.
+    async fn queued_reads(
+        &self,
+        ctx: &mut dyn MeterClient,
+        first: ReadRequest,
+        queue: &mut tokio::sync::mpsc::Receiver<ReadRequest>,
+    ) -> std::io::Result<()> {
+        let mut requests = vec![first];
+        while let Ok(request) = queue.try_recv() {
+            requests.push(request);
+        }
+        for ReadRequest { unit, block } in requests {
+            let waiting = self.pending.lock().unwrap().get(&(unit, block)).map(|w| {
+                // Those waiting may all have given up.
+                w.iter().any(|reply| !reply.is_closed())
+            });
+            if waiting != Some(true) {
+                continue;
+            }
+            let result = self.read(ctx, unit, std::slice::from_ref(&block)).await;
+            let lost = match &result {
+                Err(e) if !registers::is_exception(e) => Some(Error::new(e.kind(), e.to_string())),
+                _ => None,
+            };
+            self.answer((unit, block), result);
+            if let Some(e) = lost {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Make a write queued by `write`. A write the meter refuses is only an
+    /// error for the caller; any other failure also drops the connection.
+    async fn queued_write(
+        &self,
+        ctx: &mut dyn MeterClient,
+        request: WriteRequest,
+    ) -> std::io::Result<()> {
+        if request.reply.is_closed() {
+            // The caller gave up waiting; a late write would surprise it.
+            return Ok(());
+        }
+        ctx.set_unit(request.unit);
+        let write = ctx.write(request.address, &request.values);
+        let result = self.timed("writing registers", write).await;
+        let lost = match &result {
+            Err(e) if !registers::is_exception(e) => Some(Error::new(e.kind(), e.to_string())),
+            _ => None,
+        };
+        let _ = request.reply.send(result);
+        lost.map_or(Ok(()), Err)
+    }
+
+    /// Whether the meter is connected but its poll loop hasn't made progress
+    /// for longer than `limit`.
+    pub fn stalled(&self, limit: Duration) -> bool {
+        let status = self.status.lock().unwrap();
+        status.connected && status.progress.is_some_and(|t| t.elapsed() > limit)
+    }
+
+    /// Why the meter couldn't be reached, if it has failed `attempts`
+    /// connection attempts since startup without a single successful poll.
+    pub fn unreachable(&self, attempts: u32) -> Option<String> {
+        let status = self.status.lock().unwrap();
+        (status.polls == 0 && status.failures >= attempts as u64)
+            .then(|| status.last_error.clone().unwrap_or_default())
+    }
+
+    /// Finish a poll of the device: work out its derived readings, then run
+    /// the script's hook.
+    pub fn process(&self, device: &Device, meters: &[Arc<Meter>]) {
+        self.derive(device, meters);
+        #[cfg(feature = "scripting")]
+        if let Some(script) = &self.script {
+            script.on_reading(device);
+        }
+    }
+
+    /// Work out the device's derived readings from its current readings and
+    /// those of the other devices in `meters`. A result that isn't a number,
+    /// such as from dividing by a reading of zero, is reported as zero.
+    fn derive(&self, device: &Device, meters: &[Arc<Meter>]) {
+        if self.derived.is_empty() {
+            return;
+        }
+        let own = device.readings.lock().unwrap().clone();
+        let reading = |r: derived::Reference| {
+            let value = match r.device {
+                None => own.get(r.name),
+                Some(name) => meters
+                    .iter()
+                    .flat_map(|m| &m.devices)
+                    .find(|d| d.name == name)?
+                    .readings
+                    .lock()
+                    .unwrap()
+                    .get(r.name),
+            };
+            value.map(f64::from)
+        };
+        let values: Vec<f32> = self
+            .derived
+            .iter()
+            .map(|(_, e)| {
+                let value = e.eval(&reading).map(|v| v as f32);
+                value.filter(|v| v.is_finite()).unwrap_or(0.0)
+            })
+            .collect();
+        let mut readings = device.readings.lock().unwrap();
+        for ((i, _), value) in self.derived.iter().zip(values) {
+            readings.set(*i, value);
+        }
+    }
+
+    /// Poll the meter forever, reconnecting with backoff when the
+    /// connection fails.
+    async fn run(&self, output: Output) -> ! {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let polls = self.status.lock().unwrap().polls;
+            let Err(e) = self.poll_connection(&output).await;
+            let alpha = self.settings.borrow().ewma_alpha;
+            for device in &self.devices {
+                device.readings.lock().unwrap().update_zero(alpha);
+            }
+            {
+                let mut status = self.status.lock().unwrap();
+                if status.polls > polls {
+                    backoff = MIN_BACKOFF;
+                }
+                status.connected = false;
+                status.active_address = None;
+                status.last_error = Some(e.to_string());
+                status.last_error_time = Some(Utc::now());
+                status.failures += 1;
+                status.retry_secs = Some(backoff.as_secs());
+            }
+            error!(error = %e, retry_secs = backoff.as_secs(), "connection error");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Fail with `TimedOut` if `f` takes longer than the meter's timeout.
+    async fn timed<T>(
+        &self,
+        what: &str,
+        f: impl Future<Output = std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        tokio::time::timeout(self.timeout, f)
+            .await
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, format!("timed out {what}"))))
+    }
+
+    /// Record every register read to `recorder`.
+    pub fn record(mut self, recorder: Arc<recording::Recorder>) -> Meter {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Answer register reads from `recording` instead of connecting.
+    pub fn replay(mut self, recording: Arc<recording::Recording>) -> Meter {
+        self.replay = Some(recording);
+        self
+    }
+
+    /// Talk to `fake` instead of connecting to the meter, e.g. in tests.
+    pub fn fake(mut self, fake: FakeMeter) -> Meter {
+        self.fake = Some(fake);
+        self
+    }
+
+    /// Take one of `permits` for each poll, so only so many meters read at once.
+    pub fn limit(mut self, permits: Arc<tokio::sync::Semaphore>) -> Meter {
+        self.permits = Some(permits);
+        self
+    }
+
+    /// Offset the polls by `index` of `count` shares of the fastest group's
+    /// interval, as meter `index` of the `count` at one address, unless the
+    /// meter has an offset of its own.
+    pub fn share_gateway(mut self, index: usize, count: usize) -> Meter {
+        self.gateway_share = Some((index, count));
+        self
+    }
+
+    /// Poll only while `leading`, and stay disconnected otherwise, so the
+    /// other instance of a leader/standby pair can connect.
+    pub fn standby(mut self, leading: tokio::sync::watch::Receiver<bool>) -> Meter {
+        self.leading = Some(leading);
+        self
+    }
+
+    /// Stop or start polling, returning whether that changed anything. The
+    /// meter is disconnected while paused, so other software can have its
+    /// connection; writes and resets still connect for themselves.
+    pub fn pause(&self, paused: bool) -> bool {
+        self.status.lock().unwrap().paused = paused;
+        self.paused.send_replace(paused) != paused
+    }
+
+    /// The poll interval and smoothing in use.
+    pub fn settings(&self) -> MeterSettings {
+        *self.settings.borrow()
+    }
+
+    /// Change the poll interval and smoothing; a new interval applies from
+    /// the next poll.
+    pub fn set_settings(&self, settings: MeterSettings) {
+        self.settings.send_replace(settings);
+    }
+
+    /// Pass each poll's readings through the script, adding the readings it
+    /// sets to every device.
+    #[cfg(feature = "scripting")]
+    pub fn script(mut self, script: Arc<crate::script::Script>) -> Meter {
+        for device in &mut self.devices {
+            let readings = device.readings.get_mut().unwrap();
+            let mut names = readings.names().to_vec();
+            for name in &script.readings {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+            *readings = PowerEwma::new(names)
+                .with_precision(readings.precision.clone())
+                .with_counters(&device.counters);
+        }
+        self.script = Some(script);
+        self
+    }
+
+    /// Connect to the meter, returning the address used, if it isn't being
+    /// replayed.
+    async fn connect(&self) -> std::io::Result<(Box<dyn MeterClient>, Option<&str>)> {
+        if let Some(recording) = &self.replay {
+            let client = recording::ReplayClient::new(recording, &self.name);
+            return Ok((Box::new(client), None));
+        }
+        let (ctx, address): (Box<dyn MeterClient>, _) = match &self.fake {
+            Some(fake) => (Box::new(fake.connect()?), None),
+            None => {
+                let (ctx, address) = self.connect_network().await?;
+                (ctx, Some(address))
+            }
+        };
+        let ctx = match &self.recorder {
+            Some(recorder) => Box::new(recording::RecordingClient::new(
+                ctx,
+                &self.name,
+                recorder.clone(),
+            )),
+            None => ctx,
+        };
+        Ok((ctx, address))
+    }
+
+    /// Connect over the first path that answers: the meter's address, then
+    /// each failover address in turn.
+    async fn connect_network(&self) -> std::io::Result<(Box<dyn MeterClient>, &str)> {
+        let mut last_error = None;
+        for address in std::iter::once(&self.address).chain(&self.failover) {
+            let connect = self.timed("connecting", self.connect_to(address));
+            match connect
+                .instrument(info_span!("connect", address = %address))
+                .await
+            {
+                Ok(ctx) => {
+                    if *address != self.address {
+                        warn!(address = %address, "connected over a failover address");
+                    }
+                    return Ok((ctx, address));
+                }
+                Err(e) if !self.failover.is_empty() => {
+                    warn!(address = %address, error = %e, "could not connect");
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| Error::other("no addresses to connect to")))
+    }
+
+    async fn connect_to(&self, address: &str) -> std::io::Result<Box<dyn MeterClient>> {
+        use tokio_modbus::prelude::*;
+        if self.transport == Transport::Udp {
+            return Ok(Box::new(UdpClient::connect(address).await?));
+        }
+        let stream = tokio::net::TcpStream::connect(address).await?;
+        if !self.keepalive.is_zero() {
+            let keepalive = socket2::TcpKeepalive::new()
+                .with_time(self.keepalive)
+                .with_interval(self.keepalive);
+            socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+        }
+        let slave = Slave::tcp_device();
+        Ok(Box::new(match &self.tls {
+            Some(tls) => tcp::attach_slave(tls.connect(address, stream).await?, slave),
+            None => tcp::attach_slave(stream, slave),
+        }))
+    }
+
+    /// Read `blocks` from the device with the given unit ID, retrying each
+    /// failed read up to the meter's retry limit before giving up.
+    async fn read(
+        &self,
+        ctx: &mut dyn MeterClient,
+        unit: u8,
+        blocks: &[registers::Block],
+    ) -> std::io::Result<registers::BlockData> {
+        ctx.set_unit(unit);
+        let mut data = registers::BlockData::default();
+        for block in blocks {
+            let mut attempt = 0;
+            loop {
+                let read = registers::read_blocks(ctx, std::slice::from_ref(block));
+                let started = tokio::time::Instant::now();
+                let result = self.timed("reading registers", read).await;
+                let result = result.map_err(|e| match registers::exception_code(&e) {
+                    Some(code) => Error::other(registers::Refused {
+                        code,
+                        unit,
+                        block: *block,
+                    }),
+                    None => e,
+                });
+                match &result {
+                    Ok(_) => self.latency.lock().unwrap().observe(started.elapsed()),
+                    Err(e) => {
+                        let mut status = self.status.lock().unwrap();
+                        status.read_errors += 1;
+                        if let Some(code) = registers::exception_code(e) {
+                            let name = registers::exception_name(code);
+                            *status.exceptions.entry(name).or_default() += 1;
+                        }
+                    }
+                }
+                match result {
+                    Ok(block_data) => {
+                        data.append(block_data);
+                        break;
+                    }
+                    Err(e) if attempt < self.retries => {
+                        attempt += 1;
+                        warn!(
+                            error = %e,
+                            unit,
+                            start = block.start,
+                            attempt,
+                            retries = self.retries,
+                            "read failed, retrying"
+                        );
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Connect, and work out the register map and poll groups of each device.
+    async fn open(&self) -> std::io::Result<(Box<dyn MeterClient>, Vec<DeviceMap>)> {
+        let (mut ctx, address) = self.connect().await?;
+        self.status.lock().unwrap().active_address = address.map(str::to_owned);
+        let mut maps = Vec::with_capacity(self.devices.len());
+        for device in &self.devices {
+            ctx.set_unit(device.unit);
+            let map = match &self.map {
+                MeterMap::Fixed(map) => self.firmware_map(&mut *ctx, device, map).await,
+                MeterMap::SunSpec => {
+                    self.timed("discovering SunSpec models", sunspec::discover(&mut *ctx))
+                        .await?
+                }
+            };
+            log_map(device, &map);
+            let groups = map.poll_groups(self.read_gap);
+            maps.push((map, groups));
+        }
+        Ok((ctx, maps))
+    }
+
+    /// The register map for the firmware the device runs, which is read from
+    /// it unless configured. A device that won't say is read with the map as
+    /// it is.
+    async fn firmware_map(
+        &self,
+        ctx: &mut dyn MeterClient,
+        device: &Device,
+        map: &registers::RegisterMap,
+    ) -> registers::RegisterMap {
+        let Some(firmware) = &map.firmware else {
+            return map.clone();
+        };
+        let version = match &self.firmware {
+            Some(version) => version.clone(),
+            None => {
+                let block = firmware.block();
+                let read = registers::read_blocks(ctx, std::slice::from_ref(&block));
+                let read = self.timed("reading the firmware version", read).await;
+                match read.and_then(|data| firmware.decode(&data)) {
+                    Ok(version) if !version.is_empty() => version,
+                    Ok(_) => return map.clone(),
+                    Err(e) => {
+                        warn!(device = %device.name, error = %e, "could not read the firmware version");
+                        return map.clone();
+                    }
+                }
+            }
+        };
+        let previous = self
+            .status
+            .lock()
+            .unwrap()
+            .firmware
+            .insert(device.name.clone(), version.clone());
+        if previous.is_some_and(|p| p != version) {
+            info!(device = %device.name, firmware = %version, "the firmware has changed");
+        }
+        match map.variant(&version) {
+            Some(variant) => {
+                info!(device = %device.name, firmware = %version, "using the register map's variant for this firmware");
+                variant
+            }
+            None => map.clone(),
+        }
+    }
+
+    /// Read one group from the device and fold it into its readings.
+    async fn poll_group(
+        &self,
+        ctx: &mut dyn MeterClient,
+        device: &Device,
+        map: &registers::RegisterMap,
+        group: &registers::PollGroup,
+    ) -> std::io::Result<Vec<f64>> {
+        let span = debug_span!("poll", device = %device.name, interval = ?group.interval);
+        let update = self
+            .read(ctx, device.unit, &group.blocks)
+            .instrument(span)
+            .await
+            .and_then(|data| {
+                self.cache(device.unit, &data);
+                map.decode(group, &data)
+            });
+        match update {
+            Ok(mut values) => {
+                for (&i, value) in group.metrics.iter().zip(&mut values) {
+                    *value *= self.factors[i];
+                }
+                let alpha = self.settings.borrow().ewma_alpha;
+                let mut readings = device.readings.lock().unwrap();
+                readings.update(&group.metrics, &values, alpha);
+                Ok(values)
+            }
+            Err(e) => {
+                error!(device = %device.name, error = %e, "poll failed");
+                Err(e)
+            }
+        }
+    }
+
+    /// Read the device's clock, note its drift, and set it if it is too far
+    /// off. A meter refusing the clock registers doesn't drop the connection.
+    async fn sync_clock(
+        &self,
+        ctx: &mut dyn MeterClient,
+        device: &Device,
+        clock: &registers::Clock,
+    ) -> std::io::Result<()> {
+        let block = clock.block();
+        let data = match self
+            .read(ctx, device.unit, std::slice::from_ref(&block))
+            .await
+        {
+            Ok(data) => data,
+            Err(e) if registers::is_exception(&e) => {
+                warn!(device = %device.name, error = %e, "could not read the meter clock");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let drift = match clock.decode(&data) {
+            Ok(time) => {
+                let drift = (time - Utc::now().trunc_subsecs(0)).num_seconds();
+                let mut status = self.status.lock().unwrap();
+                status.clock_drift_secs.insert(device.name.clone(), drift);
+                Some(drift)
+            }
+            Err(e) => {
+                warn!(device = %device.name, error = %e, "could not decode the meter clock");
+                None
+            }
+        };
+        if drift.is_some_and(|d| d.abs() <= MAX_CLOCK_DRIFT) {
+            return Ok(());
+        }
+        let address = clock.write_address.unwrap_or(clock.address);
+        let values = clock.encode(Utc::now());
+        let write = ctx.write(address, &values);
+        match self.timed("setting the clock", write).await {
+            Ok(()) => info!(device = %device.name, drift_secs = drift, "set the meter clock"),
+            Err(e) if registers::is_exception(&e) => {
+                warn!(device = %device.name, error = %e, "meter refused the clock setting")
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Read which of the meter's limits are tripped on the device, and return
+    /// whether to keep reading them: a meter refusing the limits register,
+    /// as one without the alarm engine may, isn't asked again until the
+    /// next connection, rather than dropping this one.
+    async fn read_limits(
+        &self,
+        ctx: &mut dyn MeterClient,
+        device: &Device,
+        limits: &registers::Limits,
+        output: &Output,
+    ) -> std::io::Result<bool> {
+        let block = limits.block();
+        let data = match self
+            .read(ctx, device.unit, std::slice::from_ref(&block))
+            .await
+        {
+            Ok(data) => data,
+            Err(e) if registers::is_exception(&e) => {
+                warn!(device = %device.name, error = %e, "could not read the meter's limits");
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        let value = data.get(block.function, block.start, 1)?[0];
+        let tripped = limits.tripped(value);
+        if let Some(events) = &output.events {
+            events.limits(&device.name, &tripped);
+        }
+        let mut status = self.status.lock().unwrap();
+        status.limits.insert(device.name.clone(), tripped);
+        Ok(true)
+    }
+
+    /// Add the records each device logged after `since`, while the meter
+    /// couldn't be polled, to the history. A meter refusing to give its log
+    /// doesn't drop the connection.
+    async fn backfill(
+        &self,
+        ctx: &mut dyn MeterClient,
+        maps: &[DeviceMap],
+        history: &history::History,
+        since: DateTime<Utc>,
+    ) -> std::io::Result<()> {
+        for (device, (map, _)) in self.devices.iter().zip(maps) {
+            let Some(log) = &map.log else {
+                continue;
+            };
+            ctx.set_unit(device.unit);
+            match self.read_log(ctx, map, log, Some(since)).await {
+                Ok((names, records)) => {
+                    let added = history.backfill(device, &names, &records);
+                    if added > 0 {
+                        info!(device = %device.name, records = added, "filled the history from the meter's log");
+                    }
+                }
+                Err(e)
+                    if registers::is_exception(&e)
+                        || matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::ResourceBusy) =>
+                {
+                    warn!(device = %device.name, error = %e, "could not read the meter's log")
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The records of the device's log after `since`, with the names of the
+    /// readings in them, converted to the meter's output units.
+    async fn read_log(
+        &self,
+        ctx: &mut dyn MeterClient,
+        map: &registers::RegisterMap,
+        log: &registers::Log,
+        since: Option<DateTime<Utc>>,
+    ) -> std::io::Result<(Vec<String>, Vec<meterlog::Record>)> {
+        let metrics = log
+            .metrics(map)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let mut records = meterlog::read(ctx, log, map, since, self.timeout).await?;
+        for record in &mut records {
+            for (value, &i) in record.values.iter_mut().zip(&metrics) {
+                *value *= self.factors[i];
+            }
+        }
+        let names = metrics
+            .iter()
+            .map(|&i| map.metrics[i].name.clone())
+            .collect();
+        Ok((names, records))
+    }
+
+    /// Connect and read the log of every device that has one, for `sharkmon
+    /// meter-log`: each device's name, the readings logged, and the records
+    /// after `since`.
+    pub async fn log_records(
+        &self,
+        since: Option<DateTime<Utc>>,
+    ) -> std::io::Result<Vec<(String, Vec<String>, Vec<meterlog::Record>)>> {
+        let MeterMap::Fixed(map) = &self.map else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "meter '{}' is read through SunSpec, which has no log",
+                    self.name
+                ),
+            ));
+        };
+        let Some(log) = &map.log else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("meter '{}': the register map has no [log] table", self.name),
+            ));
+        };
+        let (mut ctx, _) = self.connect().await?;
+        let mut logs = Vec::new();
+        for device in &self.devices {
+            ctx.set_unit(device.unit);
+            let (names, records) = self.read_log(&mut *ctx, map, log, since).await?;
+            logs.push((device.name.clone(), names, records));
+        }
+        let _ = ctx.disconnect().await;
+        Ok(logs)
+    }
+
+    /// Connect and read every group of every device once, for `--once`.
+    pub async fn poll_once(&self) -> std::io::Result<()> {
+        let (mut ctx, maps) = self.open().await?;
+        for (device, (map, groups)) in self.devices.iter().zip(&maps) {
+            for group in groups {
+                self.poll_group(&mut *ctx, device, map, group).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read every block of every device in turn for `duration`, timing each
+    /// read, for `sharkmon bench`. Reads aren't retried; a failed one is
+    /// counted, and too many in a row end the run.
+    pub async fn bench(&self, duration: Duration) -> std::io::Result<bench::Report> {
+        let (mut ctx, maps) = self.open().await?;
+        let mut report = bench::Report {
+            meter: self.name.clone(),
+            ..Default::default()
+        };
+        for (device, (_, groups)) in self.devices.iter().zip(&maps) {
+            for group in groups {
+                for block in &group.blocks {
+                    let times =
+                        bench::BlockTimes::new(&device.name, device.unit, group.interval, *block);
+                    report.blocks.push(times);
+                }
+            }
+        }
+        let start = tokio::time::Instant::now();
+        let mut failures = 0;
+        while start.elapsed() < duration {
+            for times in &mut report.blocks {
+                ctx.set_unit(times.unit);
+                let read = registers::read_blocks(&mut *ctx, std::slice::from_ref(&times.block));
+                let started = tokio::time::Instant::now();
+                match self.timed("reading registers", read).await {
+                    Ok(_) => {
+                        times.latencies.push(started.elapsed());
+                        failures = 0;
+                    }
+                    Err(e) => {
+                        warn!(error = %e, unit = times.unit, start = times.block.start, "read failed");
+                        times.errors += 1;
+                        failures += 1;
+                        if failures >= BENCH_FAILURES {
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        }
+        report.elapsed = start.elapsed();
+        let _ = ctx.disconnect().await;
+        Ok(report)
+    }
+
+    async fn poll_connection(&self, output: &Output) -> std::io::Result<Infallible> {
+        let (mut ctx, maps) = self.open().await?;
+        let last_poll = self.status.lock().unwrap().last_poll;
+        if let (true, Some(history), Some(since)) = (self.backfill, &output.history, last_poll) {
+            self.backfill(&mut *ctx, &maps, history, since).await?;
+        }
+        let mut writes = self.write_queue.lock().await;
+        let mut reads = self.read_queue.lock().await;
+        {
+            let mut status = self.status.lock().unwrap();
+            status.connected = true;
+            status.connects += 1;
+            status.retry_secs = None;
+            status.progress = Some(tokio::time::Instant::now());
+        }
+
+        let heartbeat = (!self.heartbeat.is_zero()).then_some(self.heartbeat);
+        // The heartbeat reads the first register of the first block polled.
+        let heartbeat_read = self
+            .devices
+            .iter()
+            .zip(&maps)
+            .find_map(|(device, (_, groups))| {
+                let block = groups.first()?.blocks.first()?;
+                Some((device.unit, registers::Block { len: 1, ..*block }))
+            });
+        let mut last_read = tokio::time::Instant::now();
+        let start = tokio::time::Instant::now();
+        let mut clock_due = start;
+        let mut limits_due: Vec<Option<tokio::time::Instant>> = maps
+            .iter()
+            .map(|(map, _)| map.limits.as_ref().map(|_| start))
+            .collect();
+        // Every group is polled straight away; aligned groups then fall onto
+        // their boundaries. With an offset, the groups are first polled at
+        // the fastest one's next offset boundary instead, and then stay on
+        // their own.
+        let mut settings = self.settings.subscribe();
+        let mut poll_interval = settings.borrow_and_update().poll_interval;
+        let mut schedules: Vec<Vec<Schedule>> = maps
+            .iter()
+            .map(|(_, groups)| {
+                groups
+                    .iter()
+                    .map(|g| Schedule {
+                        due: start,
+                        interval: interval(g, groups, poll_interval),
+                        last: Vec::new(),
+                    })
+                    .collect()
+            })
+            .collect();
+        let fastest = schedules.iter().flatten().map(|s| s.interval).min();
+        let offset = self.poll_offset.or_else(|| {
+            let (index, count) = self.gateway_share?;
+            Some(fastest?.mul_f64(index as f64 / count as f64))
+        });
+        if let (Some(offset), Some(fastest)) = (offset, fastest) {
+            let first = align(start + fastest / 2, fastest, offset);
+            schedules.iter_mut().flatten().for_each(|s| s.due = first);
+            let mut status = self.status.lock().unwrap();
+            status.poll_offset_ms = Some(offset.as_millis() as u64);
+        }
+        let phase = offset.or(self.align.then_some(Duration::ZERO));
+
+        loop {
+            let permit = match &self.permits {
+                Some(permits) => permits.acquire().await.ok(),
+                None => None,
+            };
+            let now = tokio::time::Instant::now();
+            let mut polled = false;
+            for ((device, (map, groups)), schedules) in
+                self.devices.iter().zip(&maps).zip(&mut schedules)
+            {
+                let mut updated = false;
+                for (group, schedule) in groups.iter().zip(schedules.iter_mut()) {
+                    if now < schedule.due {
+                        continue;
+                    }
+                    let values = self.poll_group(&mut *ctx, device, map, group).await?;
+                    let base = interval(group, groups, poll_interval);
+                    match self.adaptive {
+                        Some(max) => {
+                            schedule.adapt(device, base, values, max, self.adaptive_threshold)
+                        }
+                        None => schedule.interval = base,
+                    }
+                    while schedule.due <= now {
+                        schedule.due += schedule.interval;
+                        if let Some(offset) = phase {
+                            schedule.due = align(schedule.due, schedule.interval, offset);
+                        }
+                    }
+                    updated = true;
+                    last_read = now;
+                }
+                if updated {
+                    self.process(device, &output.meters);
+                    output.bus.publish(device);
+                }
+                polled |= updated;
+            }
+            if let Some(clock_sync) = self.clock_sync.filter(|_| now >= clock_due) {
+                clock_due = now + clock_sync;
+                for (device, (map, _)) in self.devices.iter().zip(&maps) {
+                    if let Some(clock) = &map.clock {
+                        self.sync_clock(&mut *ctx, device, clock).await?;
+                    }
+                }
+            }
+            for ((device, (map, _)), due) in self.devices.iter().zip(&maps).zip(&mut limits_due) {
+                if let Some(limits) = map
+                    .limits
+                    .as_ref()
+                    .filter(|_| due.is_some_and(|d| now >= d))
+                {
+                    let keep = self.read_limits(&mut *ctx, device, limits, output).await?;
+                    *due = keep.then(|| now + limits.interval);
+                }
+            }
+            if let (Some(heartbeat), Some((unit, block))) = (heartbeat, &heartbeat_read) {
+                if now.duration_since(last_read) >= heartbeat {
+                    let read = self.read(&mut *ctx, *unit, std::slice::from_ref(block));
+                    if let Err(e) = read.await {
+                        error!(error = %e, "heartbeat failed");
+                        return Err(e);
+                    }
+                    last_read = now;
+                }
+            }
+            {
+                // A pass that only kept the connection alive or checked the
+                // clock or limits has no new readings to date.
+                let mut status = self.status.lock().unwrap();
+                if polled {
+                    status.polls += 1;
+                    status.last_poll = Some(Utc::now());
+                }
+                status.progress = Some(tokio::time::Instant::now());
+            }
+            drop(permit);
+            systemd::ready();
+
+            // Sleep until a group, the heartbeat, the clock check or a limits
+            // read is due,
+            // handling writes and on-demand reads meanwhile.
+            let mut wake = schedules
+                .iter()
+                .flatten()
+                .map(|s| s.due)
+                .chain(heartbeat.map(|h| last_read + h))
+                .chain(self.clock_sync.map(|_| clock_due))
+                .chain(limits_due.iter().flatten().copied())
+                .min()
+                .unwrap_or(now + Duration::from_secs(1));
+            if self.align {
+                wake += random_below(self.align_jitter);
+            }
+            let sleep = tokio::time::sleep_until(wake);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    Some(request) = writes.recv() => self.queued_write(&mut *ctx, request).await?,
+                    Some(request) = reads.recv() => {
+                        self.queued_reads(&mut *ctx, request, &mut reads).await?
+                    }
+                    Ok(()) = settings.changed() => {
+                        // Poll sooner if the new interval is shorter.
+                        poll_interval = settings.borrow_and_update().poll_interval;
+                        let now = tokio::time::Instant::now();
+                        for ((_, groups), schedules) in maps.iter().zip(&mut schedules) {
+                            for (group, schedule) in groups.iter().zip(schedules.iter_mut()) {
+                                schedule.interval = interval(group, groups, poll_interval);
+                                schedule.due = schedule.due.min(now + schedule.interval);
+                            }
+                        }
+                        if let Some(due) = schedules.iter().flatten().map(|s| s.due).min() {
+                            sleep.as_mut().reset(due.min(wake));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// When a poll group is next due, and how often it is being polled.
+struct Schedule {
+    due: tokio::time::Instant,
+    interval: Duration,
+    /// The values from the last poll
+    last: Vec<f64>,
+}
+
+impl Schedule {
+    /// Poll the group every `interval` while any of its values moves by
+    /// more than `threshold` (a fraction) between polls, and back off towards
+    /// `max` while they hold steady.
+    fn adapt(
+        &mut self,
+        device: &Device,
+        base: Duration,
+        values: Vec<f64>,
+        max: Duration,
+        threshold: f64,
+    ) {
+        let changing = self.last.len() != values.len()
+            || self
+                .last
+                .iter()
+                .zip(&values)
+                .any(|(old, new)| (new - old).abs() > threshold * old.abs().max(new.abs()));
+        let interval = if changing {
+            base
+        } else {
+            (self.interval * 2).min(max.max(base))
+        };
+        if interval != self.interval {
+            debug!(device = %device.name, interval = ?interval, changing, "adjusting poll interval");
+            self.interval = interval;
+        }
+        self.last = values;
+    }
+}
+
+/// How often to poll `group` of `groups`: every `poll_interval`, when set,
+/// for the groups polled most often, and at the interval of the register map
+/// otherwise.
+fn interval(
+    group: &registers::PollGroup,
+    groups: &[registers::PollGroup],
+    poll_interval: Option<Duration>,
+) -> Duration {
+    let fastest = groups.iter().map(|g| g.interval).min();
+    poll_interval
+        .filter(|_| Some(group.interval) == fastest)
+        .unwrap_or(group.interval)
+}
+
+/// Move `due` to the nearest multiple of `interval` since the Unix epoch,
+/// plus `offset`, so aligned polls track the wall clock even as it is slewed.
+fn align(due: tokio::time::Instant, interval: Duration, offset: Duration) -> tokio::time::Instant {
+    let (now, wall) = (tokio::time::Instant::now(), SystemTime::now());
+    let Ok(epoch) = wall.duration_since(UNIX_EPOCH) else {
+        return due;
+    };
+    let at = if due >= now {
+        epoch.as_nanos() + due.duration_since(now).as_nanos()
+    } else {
+        epoch
+            .as_nanos()
+            .saturating_sub(now.duration_since(due).as_nanos())
+    };
+    let step = interval.as_nanos().max(1);
+    let offset = offset.as_nanos() % step;
+    let aligned = (at.saturating_sub(offset) + step / 2) / step * step + offset;
+    if aligned >= at {
+        due + Duration::from_nanos((aligned - at) as u64)
+    } else {
+        due.checked_sub(Duration::from_nanos((at - aligned) as u64))
+            .unwrap_or(due)
+    }
+}
+
+/// A random duration shorter than `max`, or zero.
+fn random_below(max: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    // Each RandomState is freshly keyed, which is random enough for jitter.
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    Duration::from_nanos(random % max.as_nanos() as u64)
+}
+
+/// Check that the other devices' readings used by derived readings exist.
+pub fn check_derived(meters: &[Arc<Meter>]) -> std::io::Result<()> {
+    let devices = || meters.iter().flat_map(|m| &m.devices);
+    for m in meters {
+        for (_, expression) in &m.derived {
+            for reference in expression.references() {
+                let Some(name) = reference.device else {
+                    continue;
+                };
+                let found = devices()
+                    .find(|d| d.name == name)
+                    .is_some_and(|d| d.readings.lock().unwrap().get(reference.name).is_some());
+                if !found {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "meter '{}': derived readings use '{reference}', which no device has",
+                            m.name
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the meter's poll loop in its own task, restarting it if it panics.
+pub async fn supervise(meter: Arc<Meter>, output: Output) -> ! {
+    loop {
+        if let Some(mut leading) = meter.leading.clone() {
+            meter.status.lock().unwrap().standby = !*leading.borrow();
+            let _ = leading.wait_for(|leading| *leading).await;
+            meter.status.lock().unwrap().standby = false;
+        }
+        let mut paused = meter.paused.subscribe();
+        if *paused.borrow() {
+            info!(meter = %meter.name, "polling paused");
+            let _ = paused.wait_for(|paused| !*paused).await;
+            info!(meter = %meter.name, "polling resumed");
+        }
+        let m = meter.clone();
+        let span = info_span!("meter", meter = %meter.name);
+        let output = output.clone();
+        let mut task = tokio::spawn(async move { m.run(output).await }.instrument(span));
+        let standby = async {
+            match meter.leading.clone() {
+                Some(mut leading) => {
+                    let _ = leading.wait_for(|leading| !*leading).await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = &mut task => {
+                if let Err(e) = result {
+                    error!(meter = %meter.name, error = %e, "poll task failed, restarting");
+                }
+            }
+            _ = standby => {
+                // Dropping the poll loop closes the connection.
+                task.abort();
+                info!(meter = %meter.name, "disconnecting to stand by");
+                meter.status.lock().unwrap().active_address = None;
+            }
+            _ = paused.wait_for(|paused| *paused) => {
+                task.abort();
+                info!(meter = %meter.name, "disconnecting to pause polling");
+                meter.status.lock().unwrap().active_address = None;
+            }
+        }
+        meter.status.lock().unwrap().connected = false;
+        tokio::time::sleep(MIN_BACKOFF).await;
+    }
+}
+
+fn log_map(device: &Device, map: &registers::RegisterMap) {
+    for m in &map.metrics {
+        let r = &m.register;
+        info!(
+            device = %device.name,
+            unit = device.unit,
+            group = %m.group,
+            function = ?map.function_of(m),
+            "polling {} from {:#06x} as {} * {} {}",
+            m.name, r.address, r.format, r.scale, m.units
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// A meter described by a `[[meter]]` table with `extra` added.
+    fn meter(extra: &str) -> std::io::Result<Meter> {
+        let text =
+            format!("[[meter]]\nname = \"main\"\naddress = \"fake:502\"\nretries = 1\n{extra}");
+        let config: Config = toml::from_str(&text).unwrap();
+        Meter::new(&config.meters[0])
+    }
+
+    fn reading(meter: &Meter, name: &str) -> Option<f32> {
+        meter.devices[0].readings.lock().unwrap().get(name)
+    }
+
+    #[test]
+    fn ewma_starts_at_the_first_reading_and_then_smooths() {
+        let mut readings = PowerEwma::new(vec!["watts".to_owned(), "volts".to_owned()]);
+        readings.update(&[0], &[100.0], 0.2);
+        assert_eq!(readings.get("watts"), Some(100.0));
+        assert_eq!(readings.get("volts"), Some(0.0));
+        readings.update(&[0], &[200.0], 0.2);
+        assert_eq!(readings.get("watts"), Some(120.0));
+        assert_eq!(readings.get("amps"), None);
+    }
+
+    #[test]
+    fn readings_decay_while_the_meter_is_unreachable() {
+        let mut readings = PowerEwma::new(vec!["watts".to_owned()]);
+        readings.update(&[0], &[1000.0], 0.2);
+        readings.update_zero(0.2);
+        assert_eq!(readings.get("watts"), Some(800.0));
+    }
+
+    #[test]
+    fn energy_counters_are_taken_as_read() {
+        let counter = energy::Counter {
+            index: 1,
+            units: "kWh".to_owned(),
+            range: None,
+        };
+        let names = vec!["watts".to_owned(), "kwh".to_owned()];
+        let mut readings = PowerEwma::new(names).with_counters(&[counter]);
+        readings.update(&[0, 1], &[1000.0, 5000.0], 0.2);
+        readings.update(&[0, 1], &[0.0, 10.0], 0.2);
+        assert_eq!(readings.get("kwh"), Some(10.0));
+        readings.update_zero(0.2);
+        assert_eq!(readings.get("watts"), Some(640.0));
+        assert_eq!(readings.get("kwh"), Some(10.0));
+    }
+
+    #[test]
+    fn readings_serialize_in_map_order() {
+        let readings = PowerEwma::from_values(vec!["b".to_owned(), "a".to_owned()], vec![1.0, 2.0]);
+        let json = serde_json::to_string(&readings).unwrap();
+        assert_eq!(json, r#"{"b":1.0,"a":2.0}"#);
+    }
+
+    #[test]
+    fn readings_serialize_to_their_precision() {
+        let names = vec![
+            "watts".to_owned(),
+            "frequency".to_owned(),
+            "volts".to_owned(),
+        ];
+        let precision = [("watts".to_owned(), 1), ("frequency".to_owned(), 3)];
+        let readings = PowerEwma::from_values(names, vec![1234.5678, 59.99998, 240.123])
+            .with_precision(Arc::new(precision.into_iter().collect()));
+        let json = serde_json::to_string(&readings).unwrap();
+        assert_eq!(json, r#"{"watts":1234.6,"frequency":60.0,"volts":240.123}"#);
+        assert_eq!(readings.get("frequency"), Some(59.99998));
+        assert!(meter("precision = { amps = 1 }").is_err());
+    }
+
+    #[tokio::test]
+    async fn picks_the_register_map_for_the_firmware_on_each_connect() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.toml");
+        std::fs::write(
+            &path,
+            "[firmware]\naddress = 0x0011\nlen = 2\n\
+             [[firmware.variant]]\nversions = [\"20*\"]\nregisters = { watts = 0x0500 }\n\
+             [[metric]]\nname = \"watts\"\naddress = 0x0383\n",
+        )
+        .unwrap();
+        let fake = FakeMeter::new();
+        fake.set_float(1, 0x0383, 100.0);
+        fake.set_float(1, 0x0500, 200.0);
+        // "1001", then an update to "2002", which moved the register.
+        fake.set(1, registers::Function::Holding, 0x0011, &[0x3130, 0x3031]);
+        let extra = format!(
+            "ewma_alpha = 1.0\nregister_map = {:?}",
+            path.display().to_string()
+        );
+        let updated = meter(&extra).unwrap().fake(fake.clone());
+        updated.poll_once().await.unwrap();
+        assert_eq!(reading(&updated, "watts"), Some(100.0));
+        fake.set(1, registers::Function::Holding, 0x0011, &[0x3230, 0x3032]);
+        updated.poll_once().await.unwrap();
+        assert_eq!(reading(&updated, "watts"), Some(200.0));
+        assert_eq!(updated.status.lock().unwrap().firmware["main"], "2002");
+
+        // The configured version wins over the meter's.
+        let pinned = meter(&format!("{extra}\nfirmware = \"1000\"")).unwrap();
+        let pinned = pinned.fake(fake.clone());
+        pinned.poll_once().await.unwrap();
+        assert_eq!(reading(&pinned, "watts"), Some(100.0));
+        // The bundled profiles have no variants to pick.
+        assert!(meter("firmware = \"1000\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn polls_a_fake_meter() {
+        let fake = FakeMeter::shark100(1500.0, 240.5, 60.0);
+        let meter = meter("").unwrap().fake(fake.clone());
+        meter.poll_once().await.unwrap();
+        assert_eq!(reading(&meter, "watts"), Some(1500.0));
+        assert_eq!(reading(&meter, "volts"), Some(240.5));
+        assert_eq!(reading(&meter, "frequency"), Some(60.0));
+        assert_eq!(fake.connects(), 1);
+        // Volts and frequency are read in one block.
+        assert_eq!(fake.reads(), 2);
+    }
+
+    #[tokio::test]
+    async fn applies_register_format_overrides() {
+        let fake = FakeMeter::shark100(0.0, 240.0, 60.0);
+        fake.set(1, registers::Function::Holding, 0x0383, &[0, 15000]);
+        let meter = meter(r#"register_formats = ["watts=int32:0.1"]"#)
+            .unwrap()
+            .fake(fake);
+        meter.poll_once().await.unwrap();
+        assert_eq!(reading(&meter, "watts"), Some(1500.0));
+    }
+
+    #[tokio::test]
+    async fn reports_readings_in_their_output_units() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let kilowatts = meter(r#"output_units = { watts = "kW" }"#)
+            .unwrap()
+            .fake(fake);
+        kilowatts.poll_once().await.unwrap();
+        assert_eq!(reading(&kilowatts, "watts"), Some(1.5));
+        assert_eq!(kilowatts.units("watts"), Some("kW"));
+        assert_eq!(kilowatts.status().units["volts"], "V");
+        assert!(meter(r#"output_units = { watts = "kV" }"#).is_err());
+        assert!(meter(r#"output_units = { amps = "mA" }"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_refused_read_is_retried_then_reported() {
+        let fake = FakeMeter::new();
+        let meter = meter("").unwrap().fake(fake);
+        let e = meter.poll_once().await.unwrap_err();
+        assert!(registers::is_exception(&e), "{e}");
+        let start = "unit 1 refused a read of 2 holding registers at 0x0383: illegal data address";
+        assert!(e.to_string().starts_with(start), "{e}");
+        let status = meter.status.lock().unwrap();
+        assert_eq!(status.read_errors, 2);
+        assert_eq!(status.exceptions["illegal-data-address"], 2);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_meter_fails_to_connect() {
+        let fake = FakeMeter::shark100(1.0, 2.0, 3.0);
+        fake.fail(Some(ErrorKind::ConnectionRefused));
+        let meter = meter("").unwrap().fake(fake.clone());
+        let e = meter.poll_once().await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(reading(&meter, "watts"), Some(0.0));
+        fake.fail(None);
+        meter.poll_once().await.unwrap();
+        assert_eq!(reading(&meter, "watts"), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn writes_over_a_connection_of_their_own_when_idle() {
+        let fake = FakeMeter::new();
+        let meter = meter("writable = [\"0x1000-0x1001\"]")
+            .unwrap()
+            .fake(fake.clone());
+        meter.check_writable(0x1000, 2).unwrap();
+        meter.write(1, 0x1000, vec![7, 8]).await.unwrap();
+        assert_eq!(fake.get(1, registers::Function::Holding, 0x1001), Some(8));
+    }
+
+    #[tokio::test]
+    async fn benchmarks_every_block() {
+        let fake = FakeMeter::shark100(1.0, 2.0, 3.0);
+        let meter = meter("").unwrap().fake(fake.clone());
+        let report = meter.bench(Duration::from_millis(20)).await.unwrap();
+        assert_eq!(report.blocks.len(), 2);
+        assert!(report.blocks.iter().all(|b| !b.latencies.is_empty()));
+        assert_eq!(report.reads(), fake.reads());
+        assert!(report.safe_interval().is_some());
+
+        fake.fail(Some(ErrorKind::TimedOut));
+        assert!(meter.bench(Duration::from_millis(20)).await.is_err());
+    }
+
+    #[test]
+    fn only_writable_ranges_may_be_written() {
+        let meter = meter("writable = [\"0x1000-0x1001\"]").unwrap();
+        let kind = |address, len| meter.check_writable(address, len).unwrap_err().kind();
+        assert_eq!(kind(0x1001, 2), ErrorKind::PermissionDenied);
+        assert_eq!(kind(0x2000, 1), ErrorKind::PermissionDenied);
+        assert_eq!(kind(0x1000, 0), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn reset_registers_come_from_the_profile() {
+        let meter = meter("").unwrap();
+        let reset = meter.reset_register(registers::ResetKind::Energy).unwrap();
+        assert_eq!(reset.address, 0x4e20);
+        let e = meter
+            .reset_register(registers::ResetKind::Demand)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn works_out_derived_readings() {
+        let fake = FakeMeter::shark100(2400.0, 240.0, 60.0);
+        let meter = meter("derived = { amps = \"watts / volts\", idle = \"watts / 0\" }")
+            .unwrap()
+            .fake(fake);
+        meter.poll_once().await.unwrap();
+        let meter = Arc::new(meter);
+        meter.process(&meter.devices[0], std::slice::from_ref(&meter));
+        assert_eq!(reading(&meter, "amps"), Some(10.0));
+        // Not a number, so reported as zero
+        assert_eq!(reading(&meter, "idle"), Some(0.0));
+    }
+
+    #[test]
+    fn derived_readings_must_use_known_readings() {
+        let e = meter("derived = { amps = \"watts / current\" }")
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        let e = meter("derived = { watts = \"volts\" }").err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        let meter = Arc::new(meter("derived = { net = \"watts - solar.watts\" }").unwrap());
+        assert!(check_derived(&[meter]).is_err());
+    }
+
+    #[tokio::test]
+    async fn splits_export_positive_power_into_import_and_export() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let solar = meter("sign = \"export\"\nsplit = [\"watts\"]")
+            .unwrap()
+            .fake(fake);
+        solar.poll_once().await.unwrap();
+        let solar = Arc::new(solar);
+        solar.process(&solar.devices[0], std::slice::from_ref(&solar));
+        assert_eq!(reading(&solar, "watts"), Some(-1500.0));
+        assert_eq!(reading(&solar, "volts"), Some(240.0));
+        assert_eq!(reading(&solar, "watts_import"), Some(0.0));
+        assert_eq!(reading(&solar, "watts_export"), Some(1500.0));
+        assert_eq!(solar.units("watts_export"), Some("W"));
+        assert!(meter("split = [\"amps\"]").is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Records from a log of readings the meter keeps itself, such as the
+//! Shark 200's historical logs, read back through its log retrieval
+//! registers. `sharkmon meter-log` prints them, and with `backfill` the poll
+//! loop adds those logged while the meter couldn't be polled to the history.
+//!
+//! A retrieval session engages the log by writing its number, then moves a
+//! window over its records, newest first: it writes the offset of the
+//! window's first record, waits for the meter to say the window is ready, and
+//! reads it. The session ends by disengaging the log, even if reading it
+//! failed, so the meter doesn't keep it locked for its session timeout.
+
+use crate::client::MeterClient;
+use crate::registers::{self, ClockLayout, Function, Log, RegisterMap};
+use chrono::{DateTime, Utc};
+use std::future::Future;
+use std::io::{self, Error, ErrorKind};
+use std::time::Duration;
+
+/// Registers read from the log's status block.
+const STATUS_LEN: u16 = 12;
+
+/// Registers in the retrieval window.
+const WINDOW_LEN: u16 = 125;
+
+/// The window status while the meter is still filling the window.
+const NOT_READY: u16 = 0xFF;
+
+/// How many times to look for the window being ready before giving up.
+const READY_CHECKS: u32 = 20;
+
+/// A record of the log: when it was logged, and the value of each of the
+/// log's readings, scaled as the register map says.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub time: DateTime<Utc>,
+    pub values: Vec<f64>,
+}
+
+/// What the log's status block says about it.
+struct Status {
+    used: u32,
+    /// Registers in each record
+    record_len: u16,
+    last: Option<DateTime<Utc>>,
+}
+
+/// The records logged after `since`, or all of them, oldest first. Each
+/// request must be answered within `timeout`.
+pub async fn read(
+    ctx: &mut dyn MeterClient,
+    log: &Log,
+    map: &RegisterMap,
+    since: Option<DateTime<Utc>>,
+    timeout: Duration,
+) -> io::Result<Vec<Record>> {
+    let metrics = log
+        .metrics(map)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let status = status(ctx, log, timeout).await?;
+    let values_len: u16 = metrics
+        .iter()
+        .map(|&i| map.metrics[i].register.format.len())
+        .sum();
+    if status.record_len < 3 + values_len || status.record_len > WINDOW_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "log {} records are {} registers long, which doesn't fit a timestamp and {values_len} registers of readings",
+                log.number, status.record_len,
+            ),
+        ));
+    }
+    let newer = |t: DateTime<Utc>| since.is_none_or(|since| t > since);
+    if status.used == 0 || status.last.is_some_and(|last| !newer(last)) {
+        return Ok(Vec::new());
+    }
+    let per_window = (WINDOW_LEN / status.record_len).min(255);
+    let engage = [(log.number as u16) << 8 | 0x80, per_window << 8];
+    timed(timeout, ctx.write(log.session, &engage)).await?;
+    let mut records = Vec::new();
+    let result = async {
+        let mut end = status.used;
+        while end > 0 {
+            let start = end.saturating_sub(per_window as u32);
+            let count = (end - start) as u16;
+            let window = window(ctx, log, start, count * status.record_len, timeout).await?;
+            let mut older = false;
+            for r in window.chunks(status.record_len as usize) {
+                match record(r, log, map, &metrics) {
+                    Some(record) if newer(record.time) => records.push(record),
+                    Some(_) => older = true,
+                    // Unset records, e.g. after the log was cleared, have no
+                    // valid time.
+                    None => {}
+                }
+            }
+            if older {
+                break;
+            }
+            end = start;
+        }
+        Ok(())
+    }
+    .await;
+    let disengage = timed(timeout, ctx.write(log.session, &[0])).await;
+    result.and(disengage)?;
+    records.sort_by_key(|r| r.time);
+    Ok(records)
+}
+
+async fn status(ctx: &mut dyn MeterClient, log: &Log, timeout: Duration) -> io::Result<Status> {
+    let r = timed(timeout, ctx.read(Function::Holding, log.status, STATUS_LEN)).await?;
+    if r.len() != STATUS_LEN as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "short read of the log status block",
+        ));
+    }
+    let bytes = r[4];
+    if !bytes.is_multiple_of(2) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("log {} records are an odd {bytes} bytes long", log.number),
+        ));
+    }
+    if r[5] != 0 {
+        return Err(Error::new(
+            ErrorKind::ResourceBusy,
+            format!(
+                "log {} isn't available, perhaps being read by another client",
+                log.number
+            ),
+        ));
+    }
+    Ok(Status {
+        used: (r[2] as u32) << 16 | r[3] as u32,
+        record_len: bytes / 2,
+        last: registers::decode_time(ClockLayout::Packed, &r[9..12], log.utc),
+    })
+}
+
+/// The `len` registers of the window whose first record is `start`.
+async fn window(
+    ctx: &mut dyn MeterClient,
+    log: &Log,
+    start: u32,
+    len: u16,
+    timeout: Duration,
+) -> io::Result<Vec<u16>> {
+    let offset = log.session + 2;
+    let position = [(start >> 16) as u16 & 0xFF, start as u16];
+    timed(timeout, ctx.write(offset, &position)).await?;
+    for _ in 0..READY_CHECKS {
+        let status = timed(timeout, ctx.read(Function::Holding, offset, 1)).await?;
+        if status.first().is_some_and(|s| s >> 8 != NOT_READY) {
+            let window = timed(timeout, ctx.read(Function::Holding, offset + 2, len)).await?;
+            if window.len() != len as usize {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "short read of the log window",
+                ));
+            }
+            return Ok(window);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Err(Error::new(
+        ErrorKind::TimedOut,
+        format!("the meter didn't fill the window of log {}", log.number),
+    ))
+}
+
+/// The record in the registers `r`, unless its timestamp isn't valid.
+fn record(r: &[u16], log: &Log, map: &RegisterMap, metrics: &[usize]) -> Option<Record> {
+    let time = registers::decode_time(ClockLayout::Packed, &r[..3], log.utc)?;
+    let mut at = 3;
+    let values = metrics
+        .iter()
+        .map(|&i| {
+            let register = &map.metrics[i].register;
+            let len = register.format.len() as usize;
+            let value = register.format.decode(&r[at..at + len]) * register.scale;
+            at += len;
+            value
+        })
+        .collect();
+    Some(Record { time, values })
+}
+
+async fn timed<T>(timeout: Duration, f: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    tokio::time::timeout(timeout, f).await.unwrap_or_else(|_| {
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            "timed out reading the meter's log",
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use chrono::TimeZone;
+
+    /// A meter keeping a log of watts, as it answers the retrieval
+    /// registers of the shark200 profile.
+    struct LoggingMeter {
+        log: Log,
+        records: Vec<(DateTime<Utc>, f32)>,
+        engaged: bool,
+        start: u32,
+        reads: usize,
+    }
+
+    fn packed(t: DateTime<Utc>) -> [u16; 3] {
+        use chrono::{Datelike, Timelike};
+        let pack = |hi: u32, lo: u32| ((hi as u16) << 8) | lo as u16;
+        [
+            pack(t.year() as u32 - 2000, t.month()),
+            pack(t.day(), t.hour()),
+            pack(t.minute(), t.second()),
+        ]
+    }
+
+    #[async_trait]
+    impl MeterClient for LoggingMeter {
+        fn set_unit(&mut self, _: u8) {}
+
+        async fn read(&mut self, _: Function, start: u16, len: u16) -> io::Result<Vec<u16>> {
+            self.reads += 1;
+            if start == self.log.status {
+                let used = self.records.len() as u32;
+                let mut r = vec![0, 100, (used >> 16) as u16, used as u16, 10, 0];
+                r.extend(packed(self.records[0].0));
+                r.extend(packed(self.records.last().unwrap().0));
+                return Ok(r);
+            }
+            assert!(self.engaged, "the log was read without engaging it");
+            if start == self.log.session + 2 {
+                return Ok(vec![0]);
+            }
+            assert_eq!(start, self.log.session + 4);
+            let mut r = Vec::new();
+            for (time, watts) in &self.records[self.start as usize..] {
+                r.extend(packed(*time));
+                let bits = watts.to_bits();
+                r.extend([(bits >> 16) as u16, bits as u16]);
+            }
+            r.truncate(len as usize);
+            Ok(r)
+        }
+
+        async fn write(&mut self, address: u16, values: &[u16]) -> io::Result<()> {
+            if address == self.log.session {
+                self.engaged = values[0] == (self.log.number as u16) << 8 | 0x80;
+            } else {
+                self.start = (values[0] as u32) << 16 | values[1] as u32;
+            }
+            Ok(())
+        }
+
+        async fn disconnect(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_the_records_since_a_time() {
+        let map = RegisterMap::parse(
+            "[log]\nnumber = 2\nstatus = 0xC757\nsession = 0xC34D\nreadings = [\"watts\"]\n\
+             [[metric]]\nname = \"watts\"\naddress = 0x0383\n\
+             [[metric]]\nname = \"volts\"\naddress = 0x03ED\n",
+        )
+        .unwrap();
+        let log = map.log.clone().unwrap();
+        let first = Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap();
+        let records = (0..100)
+            .map(|i| (first + chrono::Duration::minutes(15 * i), i as f32))
+            .collect();
+        let mut meter = LoggingMeter {
+            log: log.clone(),
+            records,
+            engaged: false,
+            start: 0,
+            reads: 0,
+        };
+        let timeout = Duration::from_secs(1);
+
+        let all = read(&mut meter, &log, &map, None, timeout).await.unwrap();
+        assert_eq!(all.len(), 100);
+        assert_eq!(all[0].time, first);
+        assert_eq!(all[99].values, [99.0]);
+        assert!(!meter.engaged);
+
+        // Only the windows back to `since` are read.
+        meter.reads = 0;
+        let since = first + chrono::Duration::minutes(15 * 95);
+        let recent = read(&mut meter, &log, &map, Some(since), timeout)
+            .await
+            .unwrap();
+        let watts: Vec<_> = recent.iter().map(|r| r.values[0]).collect();
+        assert_eq!(watts, [96.0, 97.0, 98.0, 99.0]);
+        assert_eq!(meter.reads, 3);
+
+        let last = meter.records[99].0;
+        let none = read(&mut meter, &log, &map, Some(last), timeout)
+            .await
+            .unwrap();
+        assert!(none.is_empty());
+    }
+}
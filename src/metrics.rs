@@ -0,0 +1,389 @@
+//! `/metrics` in the Prometheus text exposition format: each device's readings,
+//! and the health of each meter connection, including a histogram of Modbus
+//! round-trip times so a degrading gateway shows up before reads fail. The
+//! health of sharkmon itself (memory, CPU, file descriptors and tokio tasks)
+//! is reported alongside, so one scrape covers both. Meters keep their
+//! histograms without the web server, but nothing renders them.
+#![cfg_attr(not(feature = "web"), allow(dead_code))]
+
+use crate::bus::Bus;
+use crate::config::Labels;
+use crate::meter::{Meter, Status};
+use crate::quality::Quality;
+use crate::sink::{CircuitState, SinkStatus, Sinks};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets, in seconds. An RS-485 gateway answers
+/// a short read in tens of milliseconds; the top buckets cover the timeout.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A cumulative histogram of durations.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Observations at or below each of `LATENCY_BUCKETS`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&mut self.buckets) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Quote a label value.
+fn label(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The meter's own labels, to follow the others on each of its series.
+fn extra_labels(labels: &Labels) -> String {
+    let mut out = String::new();
+    for (name, value) in labels {
+        write!(out, ",{name}={}", label(value)).unwrap();
+    }
+    out
+}
+
+/// A metric's value for each meter or sink, from its status.
+type Value<T> = fn(&T) -> u64;
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+/// The text of `/metrics` for `meters`.
+pub fn render(meters: &[Arc<Meter>], sinks: &Sinks, bus: &Bus, quality: &Quality) -> String {
+    let mut out = String::new();
+    let statuses: Vec<_> = meters.iter().map(|m| m.status()).collect();
+
+    family(
+        &mut out,
+        "sharkmon_reading",
+        "gauge",
+        "Smoothed meter reading, in the units of its units label, if it has one.",
+    );
+    for m in meters {
+        let extra = extra_labels(&m.labels);
+        for device in &m.devices {
+            let readings = device.readings.lock().unwrap().clone();
+            for (name, value) in readings.iter() {
+                let units = m
+                    .units(name)
+                    .map(|u| format!(",units={}", label(u)))
+                    .unwrap_or_default();
+                writeln!(
+                    out,
+                    "sharkmon_reading{{meter={},device={},reading={}{units}{extra}}} {value}",
+                    label(&m.name),
+                    label(&device.name),
+                    label(name)
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    let counters: [(&str, &str, &str, Value<Status>); 5] = [
+        (
+            "sharkmon_connected",
+            "gauge",
+            "Whether the meter is connected.",
+            |s| s.connected as u64,
+        ),
+        (
+            "sharkmon_polls_total",
+            "counter",
+            "Completed poll cycles.",
+            |s| s.polls,
+        ),
+        (
+            "sharkmon_connects_total",
+            "counter",
+            "Connections made to the meter, including reconnects.",
+            |s| s.connects,
+        ),
+        (
+            "sharkmon_connection_failures_total",
+            "counter",
+            "Connections that failed or were dropped after an error.",
+            |s| s.failures,
+        ),
+        (
+            "sharkmon_read_errors_total",
+            "counter",
+            "Register reads that failed, including ones that were retried.",
+            |s| s.read_errors,
+        ),
+    ];
+    for (name, kind, help, value) in counters {
+        family(&mut out, name, kind, help);
+        for s in &statuses {
+            writeln!(
+                out,
+                "{name}{{meter={}{}}} {}",
+                label(&s.name),
+                extra_labels(&s.labels),
+                value(&s.status)
+            )
+            .unwrap();
+        }
+    }
+
+    family(
+        &mut out,
+        "sharkmon_modbus_exceptions_total",
+        "counter",
+        "Register reads the meter refused, by Modbus exception.",
+    );
+    for s in &statuses {
+        for (exception, count) in &s.status.exceptions {
+            writeln!(
+                out,
+                "sharkmon_modbus_exceptions_total{{meter={},exception={}{}}} {count}",
+                label(&s.name),
+                label(exception),
+                extra_labels(&s.labels)
+            )
+            .unwrap();
+        }
+    }
+
+    let name = "sharkmon_modbus_request_duration_seconds";
+    family(
+        &mut out,
+        name,
+        "histogram",
+        "Round-trip time of successful register reads.",
+    );
+    for m in meters {
+        let latency = m.latency.lock().unwrap().clone();
+        let meter = format!("{}{}", label(&m.name), extra_labels(&m.labels));
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+            writeln!(out, "{name}_bucket{{meter={meter},le=\"{bound}\"}} {count}").unwrap();
+        }
+        writeln!(
+            out,
+            "{name}_bucket{{meter={meter},le=\"+Inf\"}} {}",
+            latency.count
+        )
+        .unwrap();
+        writeln!(out, "{name}_sum{{meter={meter}}} {}", latency.sum).unwrap();
+        writeln!(out, "{name}_count{{meter={meter}}} {}", latency.count).unwrap();
+    }
+
+    let sinks = sinks.status();
+    if !sinks.is_empty() {
+        let families: [(&str, &str, &str, Value<SinkStatus>); 8] = [
+            (
+                "sharkmon_sink_sent_total",
+                "counter",
+                "Samples delivered.",
+                |s| s.sent,
+            ),
+            (
+                "sharkmon_sink_dropped_total",
+                "counter",
+                "Samples lost to failed requests, a full queue or an open circuit.",
+                |s| s.dropped,
+            ),
+            (
+                "sharkmon_sink_buffered",
+                "gauge",
+                "Samples waiting in the sink's disk buffer.",
+                |s| s.buffered,
+            ),
+            (
+                "sharkmon_sink_queued",
+                "gauge",
+                "Samples waiting in the sink's queue, with a batch being collected.",
+                |s| s.queued,
+            ),
+            (
+                "sharkmon_sink_queue_depth",
+                "gauge",
+                "Samples the sink's queue holds, beyond which new samples are dropped.",
+                |s| s.queue_depth,
+            ),
+            (
+                "sharkmon_sink_suppressed_total",
+                "counter",
+                "Samples not sent because no reading moved past its deadband.",
+                |s| s.suppressed,
+            ),
+            (
+                "sharkmon_sink_failures_total",
+                "counter",
+                "Failed requests.",
+                |s| s.failures,
+            ),
+            (
+                "sharkmon_sink_circuit_open",
+                "gauge",
+                "Whether the sink's circuit breaker is holding back samples.",
+                |s| (s.circuit == CircuitState::Open) as u64,
+            ),
+        ];
+        for (name, kind, help, value) in families {
+            family(&mut out, name, kind, help);
+            for s in &sinks {
+                writeln!(out, "{name}{{sink={}}} {}", label(&s.name), value(s)).unwrap();
+            }
+        }
+    }
+
+    let missed = bus.missed();
+    if !missed.is_empty() {
+        let name = "sharkmon_readings_missed_total";
+        family(
+            &mut out,
+            name,
+            "counter",
+            "Readings a consumer of the readings bus skipped after falling behind.",
+        );
+        for (consumer, n) in missed {
+            writeln!(out, "{name}{{consumer={}}} {n}", label(&consumer)).unwrap();
+        }
+    }
+
+    let scores = quality.current();
+    if !scores.is_empty() {
+        let name = "sharkmon_power_quality_score";
+        family(
+            &mut out,
+            name,
+            "gauge",
+            "Power quality of the hour so far, from 0 (bad) to 100 (perfect).",
+        );
+        for (device, hour) in scores {
+            writeln!(out, "{name}{{device={}}} {}", label(&device), hour.score).unwrap();
+        }
+    }
+
+    render_runtime(&mut out);
+    #[cfg(target_os = "linux")]
+    render_process(&mut out);
+    out
+}
+
+pub fn scalar(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    family(out, name, kind, help);
+    writeln!(out, "{name} {value}").unwrap();
+}
+
+/// The tokio runtime's workers and tasks, which should stay steady; a count
+/// that keeps growing is a leak.
+fn render_runtime(out: &mut String) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    let metrics = runtime.metrics();
+    scalar(
+        out,
+        "sharkmon_tokio_workers",
+        "gauge",
+        "Worker threads of the tokio runtime.",
+        metrics.num_workers(),
+    );
+    scalar(
+        out,
+        "sharkmon_tokio_alive_tasks",
+        "gauge",
+        "Tasks that have been spawned and not finished.",
+        metrics.num_alive_tasks(),
+    );
+    scalar(
+        out,
+        "sharkmon_tokio_global_queue_depth",
+        "gauge",
+        "Tasks waiting in the runtime's global queue.",
+        metrics.global_queue_depth(),
+    );
+}
+
+/// The standard process metrics, from `/proc`.
+#[cfg(target_os = "linux")]
+fn render_process(out: &mut String) {
+    // /proc reports CPU time in USER_HZ ticks, which Linux fixes at 100.
+    const TICKS_PER_SEC: f64 = 100.0;
+
+    if let Ok(stat) = std::fs::read_to_string("/proc/self/stat") {
+        // The command name is in parentheses and may contain spaces, so count
+        // fields from the closing one: state is field 3, utime 14 and stime 15.
+        let fields: Vec<&str> = match stat.rsplit_once(')') {
+            Some((_, rest)) => rest.split_whitespace().collect(),
+            None => Vec::new(),
+        };
+        let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+        if let (Some(utime), Some(stime)) = (field(14), field(15)) {
+            scalar(
+                out,
+                "process_cpu_seconds_total",
+                "counter",
+                "User and system CPU time spent, in seconds.",
+                (utime + stime) as f64 / TICKS_PER_SEC,
+            );
+        }
+    }
+
+    if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+        let kb = |key: &str| {
+            status
+                .lines()
+                .find_map(|l| l.strip_prefix(key))
+                .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        };
+        if let Some(rss) = kb("VmRSS:") {
+            scalar(
+                out,
+                "process_resident_memory_bytes",
+                "gauge",
+                "Resident memory size in bytes.",
+                rss * 1024,
+            );
+        }
+        if let Some(vsize) = kb("VmSize:") {
+            scalar(
+                out,
+                "process_virtual_memory_bytes",
+                "gauge",
+                "Virtual memory size in bytes.",
+                vsize * 1024,
+            );
+        }
+    }
+
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        scalar(
+            out,
+            "process_open_fds",
+            "gauge",
+            "Open file descriptors.",
+            fds.count(),
+        );
+    }
+}
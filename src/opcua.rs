@@ -0,0 +1,255 @@
+//! An OPC UA server, for historians and SCADA systems that would rather
+//! subscribe to OPC UA than scrape the REST API. The address space is a
+//! `sharkmon` folder under Objects holding a folder per device, each with a
+//! Double variable per reading and its engineering units. Node ids are
+//! strings in the `urn:sharkmon` namespace, e.g. `ns=2;s=main.watts`, so
+//! they stay the same across restarts. Variables are updated as their meter
+//! is polled, and go to UncertainLastUsableValue while it is disconnected.
+
+use crate::config::OpcUaConfig;
+use crate::meter::{Device, Meter};
+use ::opcua::server::address_space::VariableBuilder;
+use ::opcua::server::diagnostics::NamespaceMetadata;
+use ::opcua::server::node_manager::memory::{simple_node_manager, SimpleNodeManager};
+use ::opcua::server::{ServerBuilder, ServerHandle};
+use ::opcua::types::{
+    DataTypeId, DataValue, EUInformation, ExtensionObject, LocalizedText, NodeId, ObjectId,
+    StatusCode, VariableTypeId,
+};
+use std::io::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const NAMESPACE: &str = "urn:sharkmon";
+
+/// How often meters are checked for lost communications.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The UNECE code of readings in `units`, which OPC UA engineering units
+/// are identified by.
+fn unece(units: &str) -> Option<&'static str> {
+    Some(match units {
+        "W" => "WTT",
+        "kW" => "KWT",
+        "MW" => "MAW",
+        "V" => "VLT",
+        "kV" => "KVT",
+        "A" => "AMP",
+        "Hz" => "HTZ",
+        "Wh" => "WHR",
+        "kWh" => "KWH",
+        "MWh" => "MWH",
+        "VA" => "D46",
+        "kVA" => "KVA",
+        "var" | "VAR" => "D44",
+        "kvar" => "KVR",
+        "°C" => "CEL",
+        "°F" => "FAH",
+        "%" => "P1",
+        _ => return None,
+    })
+}
+
+/// An EUInformation for `units`, if they have a UNECE code.
+fn engineering_units(units: &str) -> Option<EUInformation> {
+    let code = unece(units)?;
+    Some(EUInformation {
+        namespace_uri: "http://www.opcfoundation.org/UA/units/un/cefact".into(),
+        // The code's letters, one per byte.
+        unit_id: code.bytes().fold(0, |id, b| id << 8 | b as i32),
+        display_name: LocalizedText::from(units),
+        description: LocalizedText::null(),
+    })
+}
+
+/// The OPC UA server, with the variables it updates.
+pub struct Server {
+    handle: ServerHandle,
+    manager: Arc<SimpleNodeManager>,
+    namespace: u16,
+}
+
+impl Server {
+    /// Build the address space and start listening for clients.
+    pub async fn start(config: &OpcUaConfig, meters: &[Arc<Meter>]) -> std::io::Result<Server> {
+        let other = |e: String| Error::other(format!("OPC UA: {e}"));
+        let listener = tokio::net::TcpListener::bind(config.listen).await?;
+        let address = listener.local_addr()?;
+        let (server, handle) = ServerBuilder::new_anonymous(&config.name)
+            .application_uri(format!("{NAMESPACE}:{}", config.name))
+            .product_uri(NAMESPACE)
+            .host(address.ip().to_string())
+            .port(address.port())
+            .create_sample_keypair(true)
+            .pki_dir(&config.pki_dir)
+            .with_node_manager(simple_node_manager(
+                NamespaceMetadata {
+                    namespace_uri: NAMESPACE.to_owned(),
+                    ..Default::default()
+                },
+                "sharkmon",
+            ))
+            .build()
+            .map_err(other)?;
+        let manager = handle
+            .node_managers()
+            .get_of_type::<SimpleNodeManager>()
+            .ok_or_else(|| other("no node manager".to_owned()))?;
+        let namespace = handle
+            .get_namespace_index(NAMESPACE)
+            .ok_or_else(|| other("no namespace".to_owned()))?;
+
+        {
+            let mut space = manager.address_space().write();
+            let root = NodeId::new(namespace, "sharkmon");
+            space.add_folder(
+                &root,
+                "sharkmon",
+                "sharkmon",
+                &ObjectId::ObjectsFolder.into(),
+            );
+            for meter in meters {
+                for device in &meter.devices {
+                    let folder = NodeId::new(namespace, device.name.as_str());
+                    space.add_folder(&folder, device.name.as_str(), device.name.as_str(), &root);
+                    let names = device.readings.lock().unwrap().names();
+                    for reading in names.iter() {
+                        let id = NodeId::new(namespace, format!("{}.{reading}", device.name));
+                        VariableBuilder::new(&id, reading.as_str(), reading.as_str())
+                            .data_type(DataTypeId::Double)
+                            .value(0.0f64)
+                            .has_type_definition(VariableTypeId::BaseDataVariableType)
+                            .organized_by(folder.clone())
+                            .insert(&mut *space);
+                        let Some(units) = meter.units(reading).and_then(engineering_units) else {
+                            continue;
+                        };
+                        let units_id =
+                            NodeId::new(namespace, format!("{}.{reading}.units", device.name));
+                        VariableBuilder::new(&units_id, "EngineeringUnits", "EngineeringUnits")
+                            .data_type(DataTypeId::EUInformation)
+                            .value(ExtensionObject::from_message(units))
+                            .has_type_definition(VariableTypeId::PropertyType)
+                            .property_of(id)
+                            .insert(&mut *space);
+                    }
+                }
+            }
+        }
+
+        tokio::spawn(async move {
+            if let Err(e) = server.run_with(listener).await {
+                tracing::error!(error = %e, "OPC UA server failed");
+            }
+        });
+        tracing::info!(listen = %address, "serving OPC UA");
+        Ok(Server {
+            handle,
+            manager,
+            namespace,
+        })
+    }
+
+    /// Set the values of the device's variables, each with `status`.
+    fn set(&self, device: &Device, status: StatusCode) {
+        let readings = device.readings.lock().unwrap().clone();
+        let values: Vec<_> = readings
+            .iter()
+            .map(|(reading, value)| {
+                let id = NodeId::new(self.namespace, format!("{}.{reading}", device.name));
+                (id, DataValue::new_now_status(value as f64, status))
+            })
+            .collect();
+        let values = values.iter().map(|(id, value)| (id, None, value.clone()));
+        if let Err(e) = self.manager.set_values(self.handle.subscriptions(), values) {
+            tracing::debug!(device = device.name, error = %e, "couldn't update OPC UA variables");
+        }
+    }
+
+    /// Update the device's variables from its readings.
+    pub fn record(&self, device: &Device) {
+        self.set(device, StatusCode::Good);
+    }
+
+    /// Mark the variables of disconnected meters as uncertain, until their
+    /// next poll.
+    pub async fn watch(self: Arc<Self>, meters: Vec<Arc<Meter>>) {
+        let mut lost = vec![false; meters.len()];
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (m, meter) in meters.iter().enumerate() {
+                let connected = meter.status.lock().unwrap().connected;
+                if !connected && !lost[m] {
+                    for device in &meter.devices {
+                        self.set(device, StatusCode::UncertainLastUsableValue);
+                    }
+                }
+                lost[m] = !connected;
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.handle.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+    use ::opcua::types::{AttributeId, DataEncoding, NumericRange, TimestampsToReturn, Variant};
+
+    #[test]
+    fn identifies_units() {
+        let watts = engineering_units("W").unwrap();
+        assert_eq!(watts.unit_id, 5723220);
+        assert_eq!(engineering_units("kWh").unwrap().unit_id, 4937544);
+        assert!(engineering_units("furlongs").is_none());
+    }
+
+    #[tokio::test]
+    async fn updates_variables_as_meters_are_polled() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = format!(
+            "[opcua]\nlisten = \"127.0.0.1:0\"\npki_dir = {:?}\n\
+             [[meter]]\nname = \"main\"\naddress = \"fake:502\"\n",
+            dir.path()
+        );
+        let config: crate::config::Config = toml::from_str(&text).unwrap();
+        let meter = Meter::new(&config.meters[0])
+            .unwrap()
+            .fake(FakeMeter::shark100(1500.0, 240.0, 60.0));
+        meter.poll_once().await.unwrap();
+        let meters = [Arc::new(meter)];
+        let server = Server::start(config.opcua.as_ref().unwrap(), &meters)
+            .await
+            .unwrap();
+        server.record(&meters[0].devices[0]);
+        assert_eq!(server.namespace, 2);
+
+        let space = server.manager.address_space().read();
+        let read = |id: &str| {
+            let node = space.find(&NodeId::new(server.namespace, id)).unwrap();
+            node.as_node()
+                .get_attribute(
+                    TimestampsToReturn::Neither,
+                    AttributeId::Value,
+                    &NumericRange::None,
+                    &DataEncoding::Binary,
+                )
+                .unwrap()
+        };
+        let watts = read("main.watts");
+        assert_eq!(watts.value, Some(Variant::Double(1500.0)));
+        assert_eq!(watts.status, Some(StatusCode::Good));
+        let Some(Variant::ExtensionObject(units)) = read("main.watts.units").value else {
+            panic!("watts have no engineering units");
+        };
+        let units = units.inner_as::<EUInformation>().unwrap();
+        assert_eq!(units.display_name, LocalizedText::from("W"));
+    }
+}
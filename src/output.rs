@@ -0,0 +1,166 @@
+//! Readings printed to stdout, one line per device update, in a choice of
+//! formats for piping into other tools.
+
+use crate::config::Labels;
+use crate::meter::{Device, PowerEwma};
+use crate::sink::Sample;
+use crate::timezone::Zone;
+use chrono::SecondsFormat;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum Format {
+    /// One JSON object per line
+    #[default]
+    JsonLines,
+    /// Comma-separated values, with a header line whenever the columns change
+    Csv,
+    /// InfluxDB line protocol
+    Influx,
+    /// Aligned text for reading in a terminal
+    Pretty,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<&'a str>,
+    #[serde(skip_serializing_if = "Labels::is_empty")]
+    labels: &'a Labels,
+    #[serde(flatten)]
+    readings: &'a PowerEwma,
+}
+
+pub struct Printer {
+    format: Format,
+    /// Name the device in JSON and pretty output, when there is more than one
+    tagged: bool,
+    /// The CSV columns last printed
+    header: Mutex<Vec<String>>,
+    /// The timezone of CSV and pretty timestamps
+    zone: Zone,
+}
+
+impl Printer {
+    pub fn new(format: Format, tagged: bool, zone: Zone) -> Printer {
+        Printer {
+            format,
+            tagged,
+            header: Mutex::new(Vec::new()),
+            zone,
+        }
+    }
+
+    /// Print the device's current readings.
+    pub fn print(&self, device: &Device) {
+        let text = self.format(&Sample::new(device));
+        std::io::stdout()
+            .lock()
+            .write_all(text.as_bytes())
+            .expect("Could not write to stdout");
+    }
+
+    /// The sample as it is printed, ending in a newline.
+    pub fn format(&self, sample: &Sample) -> String {
+        let (device, readings) = (&sample.device, &sample.readings);
+        let time = self.zone.local(sample.time);
+        let mut out = String::new();
+        match self.format {
+            Format::JsonLines => out = json_line(sample, self.tagged),
+            Format::Csv => {
+                let columns: Vec<String> = readings.iter().map(|(n, _)| n.to_owned()).collect();
+                let mut header = self.header.lock().unwrap();
+                if *header != columns {
+                    writeln!(out, "time,device,{}", columns.join(",")).unwrap();
+                    *header = columns;
+                }
+                write!(
+                    out,
+                    "{},{}",
+                    time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    csv_field(device)
+                )
+                .unwrap();
+                for (_, value) in readings.rounded() {
+                    write!(out, ",{value}").unwrap();
+                }
+            }
+            Format::Influx => out = influx_line(sample),
+            Format::Pretty => {
+                write!(out, "{}", time.format("%Y-%m-%d %H:%M:%S")).unwrap();
+                if self.tagged {
+                    write!(out, "  {device:<12}").unwrap();
+                }
+                for (name, value) in readings.iter() {
+                    write!(out, "  {name} {value:>10.3}").unwrap();
+                }
+            }
+        }
+        out.push('\n');
+        out
+    }
+}
+
+/// The sample as a JSON object, without a trailing newline. The device is
+/// named if `tagged`; the meter's labels, if it has any, are always included.
+pub fn json_line(sample: &Sample, tagged: bool) -> String {
+    let line = JsonLine {
+        time: sample.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        device: tagged.then_some(&sample.device),
+        labels: &sample.labels,
+        readings: &sample.readings,
+    };
+    serde_json::to_string(&line).unwrap()
+}
+
+/// The sample in InfluxDB line protocol, without a trailing newline. The
+/// meter's labels become tags alongside the device.
+pub fn influx_line(sample: &Sample) -> String {
+    let tags = format!(",device={}", influx_escape(&sample.device));
+    line_protocol("sharkmon", tags, sample)
+}
+
+/// The sample in line protocol for Grafana Live, which makes a channel of
+/// each measurement: here the device, with the meter's labels as tags.
+#[cfg(feature = "http-sinks")]
+pub fn grafana_line(sample: &Sample) -> String {
+    line_protocol(&influx_escape(&sample.device), String::new(), sample)
+}
+
+fn line_protocol(measurement: &str, mut tags: String, sample: &Sample) -> String {
+    for (name, value) in sample.labels.iter() {
+        write!(tags, ",{}={}", influx_escape(name), influx_escape(value)).unwrap();
+    }
+    let fields: Vec<String> = sample
+        .readings
+        .rounded()
+        .map(|(name, value)| format!("{}={value}", influx_escape(name)))
+        .collect();
+    let nanos = sample.time.timestamp_nanos_opt().unwrap_or_default();
+    format!("{measurement}{tags} {} {nanos}", fields.join(","))
+}
+
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Escape a measurement tag value or field key for the line protocol.
+fn influx_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
@@ -0,0 +1,246 @@
+//! A DNP3 outstation, for SCADA masters that poll DNP3 rather than HTTP.
+//! Each configured reading is an analog input point (group 30, with change
+//! events in group 32 as class 1), updated as its meter is polled and
+//! flagged COMM_LOST while the meter is disconnected.
+
+use crate::config::Dnp3Config;
+use crate::meter::{Device, Meter};
+use ::dnp3::app::measurement::{AnalogInput, Flags, Time};
+use ::dnp3::app::NullListener;
+use ::dnp3::link::{EndpointAddress, LinkErrorMode};
+use ::dnp3::outstation::database::{
+    Add, AnalogInputConfig, EventBufferConfig, EventClass, Update, UpdateFlags, UpdateFlagsType,
+    UpdateOptions,
+};
+use ::dnp3::outstation::{
+    DefaultControlHandler, OutstationApplication, OutstationConfig, OutstationHandle,
+    OutstationInformation,
+};
+use ::dnp3::tcp::{AddressFilter, Server, ServerHandle};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Events kept for masters that haven't collected them yet.
+const EVENT_BUFFER: u16 = 1000;
+
+/// How often meters are checked for lost communications.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An analog point: a reading of a device.
+#[derive(Debug, PartialEq)]
+struct Point {
+    meter: usize,
+    device: String,
+    reading: String,
+}
+
+/// The points for `names`, each "device.reading", or just the reading when
+/// there is one device; every reading of every device by default.
+fn points(names: &[String], meters: &[Arc<Meter>]) -> std::io::Result<Vec<Point>> {
+    let devices: Vec<(usize, &Device)> = meters
+        .iter()
+        .enumerate()
+        .flat_map(|(i, m)| m.devices.iter().map(move |d| (i, d)))
+        .collect();
+    let point = |meter: usize, device: &Device, reading: &str| Point {
+        meter,
+        device: device.name.clone(),
+        reading: reading.to_owned(),
+    };
+    if names.is_empty() {
+        let mut points = Vec::new();
+        for (meter, device) in &devices {
+            for name in device.readings.lock().unwrap().names().iter() {
+                points.push(point(*meter, device, name));
+            }
+        }
+        return Ok(points);
+    }
+    names
+        .iter()
+        .map(|name| {
+            let (device, reading) = match name.split_once('.') {
+                Some((device, reading)) => (Some(device), reading),
+                None => (None, name.as_str()),
+            };
+            let found = devices.iter().find(|(_, d)| match device {
+                Some(device) => d.name == device,
+                None => devices.len() == 1,
+            });
+            match found {
+                Some((meter, d)) if d.readings.lock().unwrap().get(reading).is_some() => {
+                    Ok(point(*meter, d, reading))
+                }
+                _ => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("dnp3: no device has a reading '{name}'"),
+                )),
+            }
+        })
+        .collect()
+}
+
+fn now() -> Time {
+    Time::synchronized(chrono::Utc::now().timestamp_millis() as u64)
+}
+
+/// The outstation's application, which takes no writes or controls.
+struct ReadOnly;
+
+impl OutstationApplication for ReadOnly {}
+
+impl OutstationInformation for ReadOnly {}
+
+/// The outstation, with its database of points.
+pub struct Outstation {
+    handle: Mutex<OutstationHandle>,
+    points: Vec<Point>,
+    /// Keeps the server running
+    _server: ServerHandle,
+}
+
+impl Outstation {
+    /// Set up the points and start listening for masters.
+    pub async fn start(config: &Dnp3Config, meters: &[Arc<Meter>]) -> std::io::Result<Outstation> {
+        let invalid = |e: String| Error::new(ErrorKind::InvalidInput, format!("dnp3: {e}"));
+        let points = points(&config.points, meters)?;
+        if points.len() > u16::MAX as usize {
+            return Err(invalid(format!("too many points ({})", points.len())));
+        }
+        let address =
+            EndpointAddress::try_new(config.address).map_err(|e| invalid(e.to_string()))?;
+        let master = EndpointAddress::try_new(config.master).map_err(|e| invalid(e.to_string()))?;
+        let outstation_config =
+            OutstationConfig::new(address, master, EventBufferConfig::all_types(EVENT_BUFFER));
+
+        let mut server = Server::new_tcp_server(LinkErrorMode::Close, config.listen);
+        let handle = server
+            .add_outstation(
+                outstation_config,
+                Box::new(ReadOnly),
+                Box::new(ReadOnly),
+                DefaultControlHandler::create(),
+                NullListener::create(),
+                AddressFilter::Any,
+            )
+            .map_err(|e| invalid(e.to_string()))?;
+        handle.transaction(|db| {
+            for i in 0..points.len() {
+                let config = AnalogInputConfig {
+                    deadband: config.deadband,
+                    ..Default::default()
+                };
+                db.add(i as u16, Some(EventClass::Class1), config);
+            }
+        });
+        let server = server.bind().await?;
+        tracing::info!(listen = %config.listen, points = points.len(), "serving DNP3");
+        Ok(Outstation {
+            handle: Mutex::new(handle),
+            points,
+            _server: server,
+        })
+    }
+
+    /// Update the device's points from its readings.
+    pub fn record(&self, device: &Device) {
+        let readings = device.readings.lock().unwrap();
+        let time = now();
+        self.handle.lock().unwrap().transaction(|db| {
+            for (i, point) in self.points.iter().enumerate() {
+                if point.device != device.name {
+                    continue;
+                }
+                if let Some(value) = readings.get(&point.reading) {
+                    let value = AnalogInput::new(value as f64, Flags::ONLINE, time);
+                    db.update(i as u16, &value, UpdateOptions::detect_event());
+                }
+            }
+        });
+    }
+
+    /// Flag the points of disconnected meters as COMM_LOST, until their
+    /// next poll.
+    pub async fn watch(self: Arc<Self>, meters: Vec<Arc<Meter>>) {
+        let mut lost = vec![false; meters.len()];
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (m, meter) in meters.iter().enumerate() {
+                let connected = meter.status.lock().unwrap().connected;
+                if connected || lost[m] {
+                    lost[m] = !connected;
+                    continue;
+                }
+                lost[m] = true;
+                let time = now();
+                self.handle.lock().unwrap().transaction(|db| {
+                    for (i, point) in self.points.iter().enumerate() {
+                        if point.meter == m {
+                            db.update_flags(
+                                i as u16,
+                                UpdateFlagsType::AnalogInput,
+                                Flags::COMM_LOST,
+                                Some(time),
+                                UpdateOptions::detect_event(),
+                            );
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+    use ::dnp3::outstation::database::Get;
+
+    async fn meter() -> Arc<Meter> {
+        let config: crate::config::Config =
+            toml::from_str("[[meter]]\nname = \"main\"\naddress = \"fake:502\"\n").unwrap();
+        let meter = Meter::new(&config.meters[0])
+            .unwrap()
+            .fake(FakeMeter::shark100(1500.0, 240.0, 60.0));
+        meter.poll_once().await.unwrap();
+        Arc::new(meter)
+    }
+
+    #[tokio::test]
+    async fn maps_readings_to_points() {
+        let meters = [meter().await];
+        let all = points(&[], &meters).unwrap();
+        let names: Vec<_> = all.iter().map(|p| p.reading.as_str()).collect();
+        assert_eq!(names, ["watts", "volts", "frequency"]);
+
+        let names = ["main.frequency".to_owned(), "watts".to_owned()];
+        let some = points(&names, &meters).unwrap();
+        assert_eq!(some[0].reading, "frequency");
+        assert_eq!(some[1].reading, "watts");
+        let e = points(&["solar.watts".to_owned()], &meters).unwrap_err();
+        assert!(e.to_string().contains("solar.watts"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn records_polled_readings() {
+        let meters = [meter().await];
+        let config: crate::config::Config = toml::from_str(
+            "[dnp3]\nlisten = \"127.0.0.1:0\"\npoints = [\"volts\", \"watts\"]\n\
+             [[meter]]\nname = \"main\"\naddress = \"fake:502\"\n",
+        )
+        .unwrap();
+        let outstation = Outstation::start(config.dnp3.as_ref().unwrap(), &meters)
+            .await
+            .unwrap();
+        outstation.record(&meters[0].devices[0]);
+        let (volts, watts) = outstation.handle.lock().unwrap().transaction(|db| {
+            let get = |i| Get::<AnalogInput>::get(db, i).unwrap();
+            (get(0), get(1))
+        });
+        assert_eq!((volts.value, volts.flags), (240.0, Flags::ONLINE));
+        assert_eq!(watts.value, 1500.0);
+    }
+}
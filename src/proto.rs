@@ -0,0 +1,29 @@
+//! The Protobuf messages of `proto/sharkmon.proto`, served at `/power.pb`.
+
+use crate::meter::Device;
+use chrono::Utc;
+
+include!(concat!(env!("OUT_DIR"), "/sharkmon.v1.rs"));
+
+impl Reading {
+    /// The device's current readings.
+    pub fn new(device: &Device) -> Reading {
+        let readings = device.readings.lock().unwrap().clone();
+        let mut reading = Reading {
+            device: device.name.clone(),
+            time_unix_ms: Utc::now().timestamp_millis(),
+            ..Default::default()
+        };
+        for (name, value) in readings.iter() {
+            match name {
+                "watts" => reading.watts = Some(value),
+                "volts" => reading.volts = Some(value),
+                "frequency" => reading.frequency = Some(value),
+                _ => {
+                    reading.other.insert(name.to_owned(), value);
+                }
+            }
+        }
+        reading
+    }
+}
@@ -0,0 +1,307 @@
+//! A Modbus/TCP server that passes other clients' requests through to a
+//! meter, for software such as a utility's own logger that needs the meter's
+//! registers when the meter allows only one client, or is only reachable
+//! through sharkmon.
+//!
+//! Requests don't get a connection of their own: reads are on-demand reads
+//! over the poll loop's connection, between its reads, answered from what it
+//! read in the last second when they can be, and writes go the same way as
+//! writes through the web API, recorded in the audit log. Only the function
+//! codes and registers the configuration allows are passed on, and requests
+//! from all the clients together are spaced out to `requests_per_second`;
+//! anything else is refused with a Modbus exception, as a gateway would.
+
+use crate::audit;
+use crate::config::ProxyConfig;
+use crate::meter::{Device, Meter};
+use crate::registers::{self, Block, Function};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tokio_modbus::ExceptionCode;
+use tracing::{debug, info, warn};
+
+/// Clients served at once; more are disconnected as soon as they connect.
+const MAX_CLIENTS: usize = 16;
+
+/// The longest a request waits for its turn before being refused as busy.
+const MAX_WAIT: Duration = Duration::from_secs(2);
+
+/// The most registers one request may read, as on a meter.
+const MAX_READ_LEN: u16 = 125;
+
+struct Proxy {
+    config: ProxyConfig,
+    meter: Arc<Meter>,
+    audit: Arc<audit::Audit>,
+    /// When the next request may go to the meter
+    next: Mutex<Instant>,
+    clients: Arc<Semaphore>,
+}
+
+/// Listen on the proxy's address and serve its clients until sharkmon
+/// exits, returning the address listened on.
+pub async fn start(
+    config: &ProxyConfig,
+    meter: Arc<Meter>,
+    audit: Arc<audit::Audit>,
+) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(config.listen).await.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("meter '{}': proxy on {}: {e}", meter.name, config.listen),
+        )
+    })?;
+    let addr = listener.local_addr()?;
+    info!(meter = %meter.name, %addr, "serving Modbus/TCP proxy");
+    let proxy = Arc::new(Proxy {
+        config: config.clone(),
+        meter,
+        audit,
+        next: Mutex::new(Instant::now()),
+        clients: Arc::new(Semaphore::new(MAX_CLIENTS)),
+    });
+    tokio::spawn(async move {
+        loop {
+            let (stream, client) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = %e, "Modbus/TCP proxy couldn't accept a connection");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let Ok(permit) = proxy.clients.clone().try_acquire_owned() else {
+                debug!(%client, "too many Modbus/TCP proxy clients");
+                continue;
+            };
+            let proxy = proxy.clone();
+            tokio::spawn(async move {
+                if let Err(e) = proxy.serve(stream, client).await {
+                    debug!(error = %e, %client, "Modbus/TCP proxy client disconnected");
+                }
+                drop(permit);
+            });
+        }
+    });
+    Ok(addr)
+}
+
+impl Proxy {
+    /// Answer one client's requests, in turn, until it hangs up or sends
+    /// something that isn't Modbus/TCP.
+    async fn serve(&self, mut stream: TcpStream, client: SocketAddr) -> io::Result<()> {
+        loop {
+            let mut header = [0; 7];
+            stream.read_exact(&mut header).await?;
+            let protocol = u16::from_be_bytes([header[2], header[3]]);
+            let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if protocol != 0 || !(2..=254).contains(&len) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "not a Modbus/TCP request",
+                ));
+            }
+            let mut pdu = vec![0; len - 1];
+            stream.read_exact(&mut pdu).await?;
+            let reply = match self.answer(header[6], &pdu, client).await {
+                Ok(reply) => reply,
+                Err(code) => vec![pdu[0] | 0x80, code.into()],
+            };
+            let mut frame = header[..4].to_vec();
+            frame.extend(((reply.len() + 1) as u16).to_be_bytes());
+            frame.push(header[6]);
+            frame.extend(reply);
+            stream.write_all(&frame).await?;
+        }
+    }
+
+    /// The reply to the request `pdu` for `unit`.
+    async fn answer(
+        &self,
+        unit: u8,
+        pdu: &[u8],
+        client: SocketAddr,
+    ) -> Result<Vec<u8>, ExceptionCode> {
+        let function = pdu[0];
+        if !self.config.functions.contains(&function) {
+            return Err(ExceptionCode::IllegalFunction);
+        }
+        let device = self.device(unit)?;
+        let word = |i: usize| u16::from_be_bytes([pdu[i], pdu[i + 1]]);
+        match function {
+            3 | 4 => {
+                if pdu.len() != 5 {
+                    return Err(ExceptionCode::IllegalDataValue);
+                }
+                let (start, len) = (word(1), word(3));
+                if !(1..=MAX_READ_LEN).contains(&len) {
+                    return Err(ExceptionCode::IllegalDataValue);
+                }
+                if !self.readable(start, len) {
+                    return Err(ExceptionCode::IllegalDataAddress);
+                }
+                let block = Block {
+                    function: if function == 3 {
+                        Function::Holding
+                    } else {
+                        Function::Input
+                    },
+                    start,
+                    len,
+                };
+                self.pace()?.await;
+                let values = self
+                    .meter
+                    .proxy_read(device.unit, block)
+                    .await
+                    .map_err(|e| refusal(&e))?;
+                let mut reply = vec![function, (values.len() * 2) as u8];
+                reply.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+                Ok(reply)
+            }
+            6 | 16 => {
+                let (address, values) = match function {
+                    6 if pdu.len() == 5 => (word(1), vec![word(3)]),
+                    16 if pdu.len() >= 6
+                        && pdu.len() == 6 + pdu[5] as usize
+                        && pdu[5].is_multiple_of(2) =>
+                    {
+                        let values: Vec<u16> = (6..pdu.len()).step_by(2).map(word).collect();
+                        if values.len() != word(3) as usize {
+                            return Err(ExceptionCode::IllegalDataValue);
+                        }
+                        (word(1), values)
+                    }
+                    _ => return Err(ExceptionCode::IllegalDataValue),
+                };
+                if self.meter.check_writable(address, values.len()).is_err() {
+                    return Err(ExceptionCode::IllegalDataAddress);
+                }
+                self.pace()?.await;
+                let details = serde_json::json!({"address": address, "values": values});
+                let result = self.meter.write(device.unit, address, values).await;
+                self.audit.record(
+                    audit::Entry::new(
+                        &format!("Modbus/TCP proxy ({})", client.ip()),
+                        "modbus-write",
+                    )
+                    .meter(&self.meter.name)
+                    .device(&device.name)
+                    .details(details)
+                    .result(&result),
+                );
+                result.map_err(|e| refusal(&e))?;
+                // Both write replies repeat the start of the request.
+                Ok(pdu[..5].to_vec())
+            }
+            _ => Err(ExceptionCode::IllegalFunction),
+        }
+    }
+
+    /// The device a request for `unit` is for. With a single device behind
+    /// the meter connection, unit 0 and 255, which clients often use to mean
+    /// whatever is at the address, are taken to be it.
+    fn device(&self, unit: u8) -> Result<&Device, ExceptionCode> {
+        let devices = &self.meter.devices;
+        match devices.iter().find(|d| d.unit == unit) {
+            Some(device) => Ok(device),
+            None if devices.len() == 1 && (unit == 0 || unit == 255) => Ok(&devices[0]),
+            None => Err(ExceptionCode::GatewayPathUnavailable),
+        }
+    }
+
+    fn readable(&self, start: u16, len: u16) -> bool {
+        let readable = &self.config.readable;
+        readable.is_empty() || readable.iter().any(|r| r.contains(start, len as usize))
+    }
+
+    /// Take the next turn to send the meter a request, waited for by the
+    /// returned future, or refuse the request as busy if the turn is too far
+    /// off.
+    fn pace(&self) -> Result<tokio::time::Sleep, ExceptionCode> {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        let at = (*next).max(now);
+        if at - now > MAX_WAIT {
+            return Err(ExceptionCode::ServerDeviceBusy);
+        }
+        *next = at + Duration::from_secs_f64(1.0 / self.config.requests_per_second);
+        Ok(tokio::time::sleep_until(at))
+    }
+}
+
+/// The exception a failed request is answered with: the meter's own if it
+/// refused the request, and otherwise the ones gateways use for a device that
+/// can't be reached.
+fn refusal(e: &io::Error) -> ExceptionCode {
+    if let Some(code) = registers::exception_code(e) {
+        return code;
+    }
+    match e.kind() {
+        ErrorKind::TimedOut => ExceptionCode::GatewayTargetDevice,
+        _ => ExceptionCode::GatewayPathUnavailable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+    use crate::config::Config;
+    use tokio_modbus::client::{Reader, Writer};
+    use tokio_modbus::prelude::SlaveContext;
+    use tokio_modbus::Slave;
+
+    async fn proxy(extra: &str, fake: &FakeMeter) -> SocketAddr {
+        let text = format!(
+            "[[meter]]\nname = \"main\"\naddress = \"fake:502\"\n{extra}\n\
+             [meter.proxy]\nlisten = \"127.0.0.1:0\"\nfunctions = [3, 4, 16]\n\
+             readable = [\"0x0383-0x0386\"]\nrequests_per_second = 1000.0\n"
+        );
+        let config: Config = toml::from_str(&text).unwrap();
+        config.validate().unwrap();
+        let meter = Arc::new(Meter::new(&config.meters[0]).unwrap().fake(fake.clone()));
+        let proxy = config.meters[0].proxy.as_ref().unwrap();
+        start(proxy, meter, Default::default()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn passes_allowed_requests_through() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let addr = proxy("writable = [\"0x1000-0x1001\"]", &fake).await;
+        let mut client = tokio_modbus::client::tcp::connect_slave(addr, Slave(1))
+            .await
+            .unwrap();
+
+        let watts = 1500f32.to_bits();
+        let read = client.read_holding_registers(0x0383, 2).await.unwrap();
+        assert_eq!(read, Ok(vec![(watts >> 16) as u16, watts as u16]));
+
+        // Outside `readable`, and a function code that isn't allowed.
+        let read = client.read_holding_registers(0x03ED, 2).await.unwrap();
+        assert_eq!(read, Err(ExceptionCode::IllegalDataAddress));
+        let write = client.write_single_register(0x1000, 7).await.unwrap();
+        assert_eq!(write, Err(ExceptionCode::IllegalFunction));
+
+        // Only writes within `writable` reach the meter.
+        let write = client
+            .write_multiple_registers(0x1000, &[1, 2])
+            .await
+            .unwrap();
+        assert_eq!(write, Ok(()));
+        assert_eq!(fake.get(1, Function::Holding, 0x1001), Some(2));
+        let write = client.write_multiple_registers(0x2000, &[1]).await.unwrap();
+        assert_eq!(write, Err(ExceptionCode::IllegalDataAddress));
+
+        // A unit ID with no device behind it.
+        client.set_slave(Slave(9));
+        let read = client.read_holding_registers(0x0383, 2).await.unwrap();
+        assert_eq!(read, Err(ExceptionCode::GatewayPathUnavailable));
+    }
+}
@@ -0,0 +1,262 @@
+//! Power quality scores: each device's hour summed up as one number, from 100
+//! for a steady supply at nominal voltage and frequency down to 0, for those
+//! who want to know whether the power is healthy without reading voltage
+//! charts. The score weighs how far the voltage strays from nominal (5% is
+//! as bad as it gets), how far the frequency does (0.5 Hz), and the voltage's
+//! total harmonic distortion (8%), if the meter reads it.
+
+use crate::config::QualityConfig;
+use crate::meter::Device;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+/// Hours of scores kept for each device.
+const HOURS_KEPT: usize = 7 * 24;
+
+/// Deviations at which each part of the score bottoms out.
+const VOLTAGE_LIMIT: f64 = 5.0;
+const FREQUENCY_LIMIT: f64 = 0.5;
+const THD_LIMIT: f64 = 8.0;
+
+/// How much each part counts towards the score.
+const VOLTAGE_WEIGHT: f64 = 0.5;
+const FREQUENCY_WEIGHT: f64 = 0.3;
+const THD_WEIGHT: f64 = 0.2;
+
+/// Standard nominal voltages, one of which a supply is assumed to be.
+const NOMINAL_VOLTS: [f64; 17] = [
+    100.0, 110.0, 115.0, 120.0, 127.0, 200.0, 208.0, 220.0, 230.0, 240.0, 277.0, 347.0, 380.0,
+    400.0, 415.0, 480.0, 600.0,
+];
+
+/// The nominal frequency of a supply running at `frequency`: 50 or 60 Hz.
+pub fn nominal_frequency(frequency: f32) -> f32 {
+    if frequency < 55.0 {
+        50.0
+    } else {
+        60.0
+    }
+}
+
+/// One device's power quality over an hour.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hour {
+    pub start: DateTime<Utc>,
+    /// Whether the hour is over, rather than still being scored
+    pub complete: bool,
+    /// From 0 (bad) to 100 (perfect)
+    pub score: f64,
+    pub samples: u64,
+    /// The mean and largest deviation of the voltage from nominal, in %
+    pub voltage_deviation: f64,
+    pub max_voltage_deviation: f64,
+    /// The mean deviation of the frequency from nominal, in Hz
+    pub frequency_deviation: f64,
+    /// The mean total harmonic distortion, in %, if the meter reads it
+    pub thd: Option<f64>,
+    pub nominal_volts: f64,
+    pub nominal_frequency: f64,
+}
+
+/// Sums over the samples of an hour.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Sums {
+    samples: u64,
+    voltage: f64,
+    max_voltage: f64,
+    frequency: f64,
+    thd: f64,
+    thd_samples: u64,
+}
+
+/// A device's scores.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Scores {
+    nominal_volts: f64,
+    nominal_frequency: f64,
+    start: DateTime<Utc>,
+    sums: Sums,
+    hours: VecDeque<Hour>,
+}
+
+impl Scores {
+    fn hour(&self, complete: bool) -> Hour {
+        let sums = &self.sums;
+        let n = sums.samples.max(1) as f64;
+        let (voltage, frequency) = (sums.voltage / n, sums.frequency / n);
+        let thd = (sums.thd_samples > 0).then(|| sums.thd / sums.thd_samples as f64);
+        let mut parts = vec![
+            (VOLTAGE_WEIGHT, voltage / VOLTAGE_LIMIT),
+            (FREQUENCY_WEIGHT, frequency / FREQUENCY_LIMIT),
+        ];
+        if let Some(thd) = thd {
+            parts.push((THD_WEIGHT, thd / THD_LIMIT));
+        }
+        let weights: f64 = parts.iter().map(|(w, _)| w).sum();
+        let penalty: f64 = parts.iter().map(|(w, p)| w * p.min(1.0)).sum();
+        let score = 100.0 * (1.0 - penalty / weights);
+        Hour {
+            start: self.start,
+            complete,
+            // One decimal place is plenty for a health number.
+            score: (score * 10.0).round() / 10.0,
+            samples: sums.samples,
+            voltage_deviation: voltage,
+            max_voltage_deviation: sums.max_voltage,
+            frequency_deviation: frequency,
+            thd,
+            nominal_volts: self.nominal_volts,
+            nominal_frequency: self.nominal_frequency,
+        }
+    }
+}
+
+/// Each device's scores.
+pub type Saved = BTreeMap<String, Scores>;
+
+pub struct Quality {
+    config: QualityConfig,
+    devices: Mutex<Saved>,
+}
+
+fn hour_of(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(TimeDelta::hours(1)).unwrap_or(time)
+}
+
+impl Quality {
+    pub fn new(config: &QualityConfig) -> Quality {
+        Quality {
+            config: config.clone(),
+            devices: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add the device's latest readings to its score for this hour.
+    pub fn record(&self, device: &Device) {
+        let (volts, frequency, thd) = {
+            let readings = device.readings.lock().unwrap();
+            let get = |name: &str| readings.get(name);
+            match (get(&self.config.volts), get(&self.config.frequency)) {
+                (Some(volts), Some(frequency)) => (volts, frequency, get(&self.config.thd)),
+                _ => return,
+            }
+        };
+        self.observe(&device.name, Utc::now(), volts, frequency, thd);
+    }
+
+    fn observe(
+        &self,
+        device: &str,
+        time: DateTime<Utc>,
+        volts: f32,
+        frequency: f32,
+        thd: Option<f32>,
+    ) {
+        let mut devices = self.devices.lock().unwrap();
+        let start = hour_of(time);
+        let scores = devices.entry(device.to_owned()).or_insert_with(|| Scores {
+            nominal_volts: self.config.nominal_volts.unwrap_or_else(|| {
+                let volts = volts as f64;
+                let distance = |v: &&f64| ((*v - volts).abs() * 100.0) as u64;
+                *NOMINAL_VOLTS.iter().min_by_key(distance).unwrap()
+            }),
+            nominal_frequency: self
+                .config
+                .nominal_frequency
+                .unwrap_or(nominal_frequency(frequency) as f64),
+            start,
+            sums: Sums::default(),
+            hours: VecDeque::new(),
+        });
+        if start != scores.start {
+            if scores.sums.samples > 0 {
+                if scores.hours.len() == HOURS_KEPT {
+                    scores.hours.pop_front();
+                }
+                let hour = scores.hour(true);
+                scores.hours.push_back(hour);
+            }
+            scores.start = start;
+            scores.sums = Sums::default();
+        }
+        let voltage = 100.0 * (volts as f64 - scores.nominal_volts).abs() / scores.nominal_volts;
+        let sums = &mut scores.sums;
+        sums.samples += 1;
+        sums.voltage += voltage;
+        sums.max_voltage = sums.max_voltage.max(voltage);
+        sums.frequency += (frequency as f64 - scores.nominal_frequency).abs();
+        if let Some(thd) = thd {
+            sums.thd += thd as f64;
+            sums.thd_samples += 1;
+        }
+    }
+
+    /// The scores, to be saved across a restart.
+    pub fn save(&self) -> Saved {
+        self.devices.lock().unwrap().clone()
+    }
+
+    /// Carry on from saved scores.
+    pub fn restore(&self, saved: Saved) {
+        *self.devices.lock().unwrap() = saved;
+    }
+
+    /// The device's scored hours, oldest first, ending with the current
+    /// one; None if it hasn't been scored.
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    pub fn hours(&self, device: &str) -> Option<Vec<Hour>> {
+        let devices = self.devices.lock().unwrap();
+        let scores = devices.get(device)?;
+        let mut hours: Vec<Hour> = scores.hours.iter().cloned().collect();
+        hours.push(scores.hour(false));
+        Some(hours)
+    }
+
+    /// Each scored device's current hour so far.
+    pub fn current(&self) -> Vec<(String, Hour)> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .map(|(device, scores)| (device.clone(), scores.hour(false)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_each_hour() {
+        let quality = Quality::new(&QualityConfig::default());
+        let hour = hour_of(Utc::now()) - TimeDelta::hours(2);
+        let at = |minutes| hour + TimeDelta::minutes(minutes);
+        // A perfect hour, then one 2.5% and 0.25 Hz off.
+        quality.observe("main", at(0), 240.0, 60.0, None);
+        quality.observe("main", at(30), 240.0, 60.0, None);
+        quality.observe("main", at(60), 246.0, 60.25, None);
+        quality.observe("main", at(90), 234.0, 59.75, None);
+        quality.observe("main", at(120), 240.0, 60.0, Some(4.0));
+
+        let hours = quality.hours("main").unwrap();
+        assert_eq!(hours.len(), 3);
+        assert_eq!((hours[0].start, hours[0].samples), (at(0), 2));
+        assert_eq!(hours[0].score, 100.0);
+        assert!(hours[0].complete);
+        assert_eq!(hours[0].nominal_volts, 240.0);
+        assert_eq!(hours[1].voltage_deviation, 2.5);
+        assert_eq!(hours[1].score, 50.0);
+        // The current hour, where half the THD limit costs a tenth.
+        assert!(!hours[2].complete);
+        assert_eq!(hours[2].thd, Some(4.0));
+        assert_eq!(hours[2].score, 90.0);
+        assert!(quality.hours("solar").is_none());
+
+        let quality = Quality::new(&QualityConfig::default());
+        quality.observe("main", at(0), 229.0, 49.9, None);
+        let hour = quality.hours("main").unwrap().remove(0);
+        assert_eq!((hour.nominal_volts, hour.nominal_frequency), (230.0, 50.0));
+    }
+}
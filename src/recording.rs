@@ -0,0 +1,216 @@
+//! Recording a meter's raw register responses, and replaying them in place of
+//! the meter. A recording is a JSON lines file with one read per line:
+//!
+//! ```json
+//! {"time":"2024-01-01T00:00:00Z","meter":"main","unit":1,"function":"holding","start":899,"len":2,"data":[17562,20480]}
+//! ```
+//!
+//! Reads the meter answered with an exception have `"exception": <code>`
+//! instead of `data`.
+
+use crate::client::{self, MeterClient};
+use crate::registers::Function;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio_modbus::{ExceptionCode, Slave};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub meter: String,
+    pub unit: u8,
+    pub function: Function,
+    pub start: u16,
+    pub len: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Vec<u16>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exception: Option<u8>,
+}
+
+/// Appends entries to a recording file.
+pub struct Recorder {
+    out: Mutex<BufWriter<std::fs::File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> std::io::Result<Recorder> {
+        Ok(Recorder {
+            out: Mutex::new(BufWriter::new(std::fs::File::create(path)?)),
+        })
+    }
+
+    fn record(&self, entry: &Entry) -> std::io::Result<()> {
+        let mut out = self.out.lock().unwrap();
+        serde_json::to_writer(&mut *out, entry)?;
+        // Flush every line, so a recording stopped with ^C is complete.
+        out.write_all(b"\n")?;
+        out.flush()
+    }
+}
+
+/// A meter connection that records every register read it answers.
+pub struct RecordingClient {
+    inner: Box<dyn MeterClient>,
+    meter: String,
+    unit: u8,
+    recorder: Arc<Recorder>,
+}
+
+impl RecordingClient {
+    pub fn new(
+        inner: Box<dyn MeterClient>,
+        meter: &str,
+        recorder: Arc<Recorder>,
+    ) -> RecordingClient {
+        RecordingClient {
+            inner,
+            meter: meter.to_owned(),
+            unit: Slave::tcp_device().0,
+            recorder,
+        }
+    }
+}
+
+#[async_trait]
+impl MeterClient for RecordingClient {
+    fn set_unit(&mut self, unit: u8) {
+        self.unit = unit;
+        self.inner.set_unit(unit);
+    }
+
+    async fn read(
+        &mut self,
+        function: Function,
+        start: u16,
+        len: u16,
+    ) -> std::io::Result<Vec<u16>> {
+        let response = self.inner.read(function, start, len).await;
+        let (data, exception) = match &response {
+            Ok(data) => (Some(data.clone()), None),
+            Err(e) => {
+                let code = e.get_ref().and_then(|e| e.downcast_ref::<ExceptionCode>());
+                (None, code.map(|code| u8::from(*code)))
+            }
+        };
+        if data.is_some() || exception.is_some() {
+            let entry = Entry {
+                time: Utc::now(),
+                meter: self.meter.clone(),
+                unit: self.unit,
+                function,
+                start,
+                len,
+                data,
+                exception,
+            };
+            if let Err(e) = self.recorder.record(&entry) {
+                tracing::error!(error = %e, "could not write to the recording");
+            }
+        }
+        response
+    }
+
+    async fn write(&mut self, address: u16, values: &[u16]) -> std::io::Result<()> {
+        self.inner.write(address, values).await
+    }
+
+    async fn disconnect(&mut self) -> std::io::Result<()> {
+        self.inner.disconnect().await
+    }
+}
+
+/// A recording loaded for replay, grouped by meter.
+pub struct Recording {
+    meters: HashMap<String, Vec<Entry>>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> std::io::Result<Recording> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut meters: HashMap<String, Vec<Entry>> = HashMap::new();
+        for (i, line) in file.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}:{}: {e}", path.display(), i + 1),
+                )
+            })?;
+            meters.entry(entry.meter.clone()).or_default().push(entry);
+        }
+        Ok(Recording { meters })
+    }
+}
+
+type ReadKey = (u8, Function, u16, u16);
+
+/// Answers each register read with the next recorded response to the same
+/// read, from the start of the recording, failing once they run out.
+pub struct ReplayClient {
+    responses: HashMap<ReadKey, VecDeque<Entry>>,
+    unit: u8,
+}
+
+impl ReplayClient {
+    pub fn new(recording: &Recording, meter: &str) -> ReplayClient {
+        let mut responses: HashMap<ReadKey, VecDeque<Entry>> = HashMap::new();
+        for e in recording.meters.get(meter).into_iter().flatten() {
+            let key = (e.unit, e.function, e.start, e.len);
+            responses.entry(key).or_default().push_back(e.clone());
+        }
+        ReplayClient {
+            responses,
+            unit: Slave::tcp_device().0,
+        }
+    }
+}
+
+#[async_trait]
+impl MeterClient for ReplayClient {
+    fn set_unit(&mut self, unit: u8) {
+        self.unit = unit;
+    }
+
+    async fn read(
+        &mut self,
+        function: Function,
+        start: u16,
+        len: u16,
+    ) -> std::io::Result<Vec<u16>> {
+        let key = (self.unit, function, start, len);
+        let Some(entry) = self.responses.get_mut(&key).and_then(|q| q.pop_front()) else {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "end of recording for unit {} {function:?} {start:#06x}+{len}",
+                    self.unit
+                ),
+            ));
+        };
+        match (entry.data, entry.exception) {
+            (_, Some(code)) => Err(client::exception(ExceptionCode::new(code))),
+            (Some(data), None) => Ok(data),
+            (None, None) => Err(client::exception(ExceptionCode::ServerDeviceFailure)),
+        }
+    }
+
+    async fn write(&mut self, _address: u16, _values: &[u16]) -> std::io::Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "only register reads can be replayed",
+        ))
+    }
+
+    async fn disconnect(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
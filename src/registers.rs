@@ -0,0 +1,1395 @@
+//! Meter register definitions and decoding, and the grouping of registers
+//! into block reads. Each Modbus request costs a full round trip (and over an
+//! RS-485 gateway, a slow one), so registers that are close together are
+//! fetched with a single read and decoded from the block.
+
+use crate::client::MeterClient;
+use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_modbus::ExceptionCode;
+
+/// How a value is encoded in the meter's registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// IEEE 754 single precision float, high word first (the Shark's format)
+    #[default]
+    FloatBe,
+    /// IEEE 754 single precision float, low word first
+    FloatSwapped,
+    Int16,
+    Uint16,
+    /// Signed 32 bit integer, high word first
+    Int32,
+    /// Unsigned 32 bit integer, high word first
+    Uint32,
+    /// Signed 32 bit integer, low word first
+    Int32Swapped,
+    /// Unsigned 32 bit integer, low word first
+    Uint32Swapped,
+}
+
+const FORMAT_NAMES: [(Format, &str); 8] = [
+    (Format::FloatBe, "float-be"),
+    (Format::FloatSwapped, "float-swapped"),
+    (Format::Int16, "int16"),
+    (Format::Uint16, "uint16"),
+    (Format::Int32, "int32"),
+    (Format::Uint32, "uint32"),
+    (Format::Int32Swapped, "int32-swapped"),
+    (Format::Uint32Swapped, "uint32-swapped"),
+];
+
+impl Format {
+    /// The number of 16-bit registers a value occupies.
+    #[allow(clippy::len_without_is_empty)] // never zero
+    pub fn len(self) -> u16 {
+        match self {
+            Format::Int16 | Format::Uint16 => 1,
+            _ => 2,
+        }
+    }
+
+    /// Decode a value from exactly `self.len()` registers.
+    pub fn decode(self, r: &[u16]) -> f64 {
+        let be32 = || (r[0] as u32) << 16 | r[1] as u32;
+        let le32 = || (r[1] as u32) << 16 | r[0] as u32;
+        match self {
+            Format::FloatBe => f32::from_bits(be32()) as f64,
+            Format::FloatSwapped => f32::from_bits(le32()) as f64,
+            Format::Int16 => r[0] as i16 as f64,
+            Format::Uint16 => r[0] as f64,
+            Format::Int32 => be32() as i32 as f64,
+            Format::Uint32 => be32() as f64,
+            Format::Int32Swapped => le32() as i32 as f64,
+            Format::Uint32Swapped => le32() as f64,
+        }
+    }
+
+    /// The smallest and largest values an integer format holds, where a
+    /// counter in it rolls over; None for floats.
+    pub fn range(self) -> Option<(f64, f64)> {
+        match self {
+            Format::FloatBe | Format::FloatSwapped => None,
+            Format::Int16 => Some((i16::MIN as f64, i16::MAX as f64)),
+            Format::Uint16 => Some((0.0, u16::MAX as f64)),
+            Format::Int32 | Format::Int32Swapped => Some((i32::MIN as f64, i32::MAX as f64)),
+            Format::Uint32 | Format::Uint32Swapped => Some((0.0, u32::MAX as f64)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name) = FORMAT_NAMES.iter().find(|(fmt, _)| fmt == self).unwrap();
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        FORMAT_NAMES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(fmt, _)| *fmt)
+            .ok_or_else(|| {
+                let names: Vec<&str> = FORMAT_NAMES.iter().map(|(_, name)| *name).collect();
+                format!(
+                    "unknown register format '{s}', expected one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// A single value read from the meter: `scale * decoded registers`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Register {
+    pub address: u16,
+    #[serde(rename = "type", default)]
+    pub format: Format,
+    #[serde(default = "unit_scale")]
+    pub scale: f64,
+}
+
+fn unit_scale() -> f64 {
+    1.0
+}
+
+impl Register {
+    pub fn span(&self) -> (u16, u16) {
+        (self.address, self.format.len())
+    }
+
+    /// The scaled range of the register's format, if it has one.
+    pub fn range(&self) -> Option<(f64, f64)> {
+        let (min, max) = self.format.range()?;
+        let (a, b) = (min * self.scale, max * self.scale);
+        Some((a.min(b), a.max(b)))
+    }
+
+    /// The scaled value of the register, read from the register table
+    /// `function`, which must be a finite number that a reading can hold, so
+    /// a garbled response isn't taken as a reading.
+    pub fn decode(&self, function: Function, data: &BlockData) -> std::io::Result<f64> {
+        let (address, len) = self.span();
+        let value = self.format.decode(data.get(function, address, len)?) * self.scale;
+        if !(value as f32).is_finite() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("register {address:#06x} holds {value}, not a reading"),
+            ));
+        }
+        Ok(value)
+    }
+}
+
+/// A named reading and where to find it on the meter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Metric {
+    pub name: String,
+    #[serde(flatten)]
+    pub register: Register,
+    /// Units of the scaled value, e.g. "W"
+    #[serde(default)]
+    pub units: String,
+    /// The polling group, which sets how often the metric is read
+    #[serde(default = "fast_group")]
+    pub group: String,
+    /// The register table the metric is read from, if not the map's
+    #[serde(default)]
+    pub function: Option<Function>,
+}
+
+fn fast_group() -> String {
+    "fast".to_owned()
+}
+
+/// Polling groups every map has, unless it redefines their intervals.
+const DEFAULT_GROUPS: [(&str, Duration); 3] = [
+    ("fast", Duration::from_secs(1)),
+    ("medium", Duration::from_secs(30)),
+    ("slow", Duration::from_secs(300)),
+];
+
+/// Metrics that are read together on their own schedule.
+#[derive(Debug, Clone)]
+pub struct PollGroup {
+    pub interval: Duration,
+    /// Indices into the map's metrics
+    pub metrics: Vec<usize>,
+    pub blocks: Vec<Block>,
+}
+
+/// The accumulated values a meter can be told to clear.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetKind {
+    /// Peak demand
+    Demand,
+    /// Recorded minimum and maximum readings
+    Minmax,
+    /// Energy totals
+    Energy,
+}
+
+impl fmt::Display for ResetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResetKind::Demand => "demand",
+            ResetKind::Minmax => "minmax",
+            ResetKind::Energy => "energy",
+        })
+    }
+}
+
+/// A holding register that clears accumulated values when written.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reset {
+    pub address: u16,
+    /// The value to write; meters with password protection expect the
+    /// password here
+    #[serde(default)]
+    pub value: u16,
+}
+
+/// How a meter lays out its date and time in consecutive holding registers.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClockLayout {
+    /// Six registers: year, month, day, hour, minute and second
+    #[default]
+    Registers,
+    /// Three registers of two bytes each: year since 2000 and month, day and
+    /// hour, minute and second
+    Packed,
+}
+
+/// The time in the registers `r`, laid out as `layout` and in UTC or else
+/// local time, if it is a valid one.
+pub fn decode_time(layout: ClockLayout, r: &[u16], utc: bool) -> Option<DateTime<Utc>> {
+    let (hi, lo) = (|w: u16| (w >> 8) as u32, |w: u16| (w & 0xff) as u32);
+    let (year, fields) = match layout {
+        ClockLayout::Registers => (r[0] as i32, [r[1], r[2], r[3], r[4], r[5]].map(u32::from)),
+        ClockLayout::Packed => (
+            2000 + hi(r[0]) as i32,
+            [lo(r[0]), hi(r[1]), lo(r[1]), hi(r[2]), lo(r[2])],
+        ),
+    };
+    let [month, day, hour, minute, second] = fields;
+    let time = NaiveDate::from_ymd_opt(year, month, day)
+        .and_then(|d| d.and_hms_opt(hour, minute, second))?;
+    if utc {
+        Some(time.and_utc())
+    } else {
+        time.and_local_timezone(Local)
+            .earliest()
+            .map(|t| t.to_utc())
+    }
+}
+
+/// A log of readings the meter keeps itself, read back through a retrieval
+/// window as on the Shark 200. Each record is a packed timestamp followed by
+/// the registers of `readings`, in the order the log was programmed with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Log {
+    /// The log's number, e.g. 2 for the Shark's first historical log
+    pub number: u8,
+    /// The log's status block: its capacity and records used (32 bits
+    /// each), record size in bytes, availability, and the timestamps of its
+    /// first and last records
+    pub status: u16,
+    /// The register engaging the log, followed by the records per window,
+    /// the window's status and first record, and the window itself
+    pub session: u16,
+    /// Readings in each record; every metric of the map, in order, if empty
+    #[serde(default)]
+    pub readings: Vec<String>,
+    /// Whether the timestamps are UTC rather than local time
+    #[serde(default)]
+    pub utc: bool,
+}
+
+impl Log {
+    /// The index into the map's metrics of each reading in a record.
+    pub fn metrics(&self, map: &RegisterMap) -> Result<Vec<usize>, String> {
+        if self.readings.is_empty() {
+            return Ok((0..map.metrics.len()).collect());
+        }
+        self.readings
+            .iter()
+            .map(|name| {
+                map.metrics
+                    .iter()
+                    .position(|m| m.name == *name)
+                    .ok_or_else(|| format!("the log has unknown reading '{name}'"))
+            })
+            .collect()
+    }
+}
+
+/// A register of bit flags from the meter's own alarm engine, each set while
+/// one of its limits is tripped, such as the Shark's limits status register.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub address: u16,
+    /// The name of the limit each bit stands for, from bit 0 up; bits named
+    /// "" are ignored
+    pub names: Vec<String>,
+    /// How often to read the register
+    #[serde(with = "humantime_serde", default = "default_limits_interval")]
+    pub interval: Duration,
+}
+
+fn default_limits_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl Limits {
+    pub fn block(&self) -> Block {
+        Block {
+            function: Function::Holding,
+            start: self.address,
+            len: 1,
+        }
+    }
+
+    /// The names of the limits tripped in the register's `value`.
+    pub fn tripped(&self, value: u16) -> Vec<String> {
+        flags(&self.names, value)
+    }
+}
+
+/// Where the meter gives its firmware version, and the registers that
+/// firmware revisions moved. The version is read on every connect, so a
+/// meter whose firmware is updated is read by the right registers once it
+/// comes back.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Firmware {
+    /// The first holding register of the version, which is ASCII text, two
+    /// characters to a register
+    pub address: u16,
+    pub len: u16,
+    #[serde(rename = "variant", default)]
+    pub variants: Vec<Variant>,
+}
+
+/// Where some firmware versions keep metrics that aren't where the rest of
+/// the map says.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variant {
+    /// Each a version, or the start of several followed by `*`, e.g. "10*"
+    pub versions: Vec<String>,
+    /// The address each moved metric is read from in these versions, e.g.
+    /// `registers = { frequency = 0x0403 }`
+    pub registers: BTreeMap<String, u16>,
+}
+
+impl Firmware {
+    pub fn block(&self) -> Block {
+        Block {
+            function: Function::Holding,
+            start: self.address,
+            len: self.len,
+        }
+    }
+
+    pub fn decode(&self, data: &BlockData) -> std::io::Result<String> {
+        Ok(ascii(data.get(
+            Function::Holding,
+            self.address,
+            self.len,
+        )?))
+    }
+}
+
+impl Variant {
+    pub fn matches(&self, version: &str) -> bool {
+        self.versions.iter().any(|v| match v.strip_suffix('*') {
+            Some(start) => version.starts_with(start),
+            None => v == version,
+        })
+    }
+}
+
+/// Registers holding ASCII text, two characters each, high byte first.
+pub fn ascii(registers: &[u16]) -> String {
+    let bytes: Vec<u8> = registers.iter().flat_map(|r| r.to_be_bytes()).collect();
+    let text: String = bytes
+        .iter()
+        .map(|&b| match b {
+            0x20..=0x7e => b as char,
+            _ => ' ',
+        })
+        .collect();
+    text.trim().to_owned()
+}
+
+/// The names of the bits set in `value`, from bit 0 up, skipping those named
+/// "".
+fn flags(names: &[String], value: u16) -> Vec<String> {
+    names
+        .iter()
+        .enumerate()
+        .filter(|(bit, name)| value & (1 << bit) != 0 && !name.is_empty())
+        .map(|(_, name)| name.clone())
+        .collect()
+}
+
+/// An option card the meter may have in one of its slots, such as the
+/// Shark's INP100 Ethernet card: the register identifying it, and those
+/// telling how well it is communicating, read for `/meter/info`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OptionCard {
+    pub slot: u8,
+    /// The register holding the type code of the card in the slot, 0 or
+    /// 0xFFFF if there is none
+    pub id: u16,
+    /// The names of the type codes known
+    #[serde(default)]
+    pub types: Vec<CardType>,
+    /// A register of bit flags the card sets while something is wrong
+    pub status: Option<u16>,
+    /// The name of the fault each bit of `status` stands for, from bit 0 up;
+    /// bits named "" are ignored
+    #[serde(default)]
+    pub faults: Vec<String>,
+    /// Counts of the card's traffic and errors
+    #[serde(default)]
+    pub counters: Vec<Counter>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CardType {
+    pub code: u16,
+    pub name: String,
+}
+
+/// A named value an option card keeps, e.g. of the frames it has received.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Counter {
+    pub name: String,
+    #[serde(flatten)]
+    pub register: Register,
+}
+
+impl OptionCard {
+    /// The name of the card with type code `code`, if it is a known one.
+    pub fn type_name(&self, code: u16) -> Option<&str> {
+        let card = self.types.iter().find(|t| t.code == code)?;
+        Some(card.name.as_str())
+    }
+
+    /// The names of the faults flagged in the status register's `value`.
+    pub fn faults(&self, value: u16) -> Vec<String> {
+        flags(&self.faults, value)
+    }
+}
+
+/// The registers holding a meter's real-time clock.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Clock {
+    pub address: u16,
+    /// Where the time is written to set the clock, if not `address`
+    pub write_address: Option<u16>,
+    #[serde(default)]
+    pub layout: ClockLayout,
+    /// Whether the meter keeps UTC rather than local time
+    #[serde(default)]
+    pub utc: bool,
+}
+
+impl Clock {
+    pub fn block(&self) -> Block {
+        let len = match self.layout {
+            ClockLayout::Registers => 6,
+            ClockLayout::Packed => 3,
+        };
+        Block {
+            function: Function::Holding,
+            start: self.address,
+            len,
+        }
+    }
+
+    pub fn decode(&self, data: &BlockData) -> std::io::Result<DateTime<Utc>> {
+        let block = self.block();
+        let r = data.get(block.function, block.start, block.len)?;
+        decode_time(self.layout, r, self.utc).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("meter clock reads an invalid time {r:?}"),
+            )
+        })
+    }
+
+    /// The registers to write to set the clock to `time`.
+    pub fn encode(&self, time: DateTime<Utc>) -> Vec<u16> {
+        let t = if self.utc {
+            time.naive_utc()
+        } else {
+            time.with_timezone(&Local).naive_local()
+        };
+        let field = |v: u32| v as u16;
+        match self.layout {
+            ClockLayout::Registers => vec![
+                t.year() as u16,
+                field(t.month()),
+                field(t.day()),
+                field(t.hour()),
+                field(t.minute()),
+                field(t.second()),
+            ],
+            ClockLayout::Packed => {
+                let pack = |hi: u32, lo: u32| ((hi as u16) << 8) | lo as u16;
+                vec![
+                    pack((t.year() - 2000) as u32, t.month()),
+                    pack(t.day(), t.hour()),
+                    pack(t.minute(), t.second()),
+                ]
+            }
+        }
+    }
+}
+
+/// The full set of readings polled from a meter, in output order. Maps can be
+/// loaded from a TOML file with one `[[metric]]` table per reading:
+///
+/// ```toml
+/// [[metric]]
+/// name = "watts"
+/// address = 0x0383
+/// type = "float-be"
+/// scale = 1.0
+/// units = "W"
+/// group = "fast"
+/// ```
+///
+/// Metrics in the "fast" group (the default) are read every second, "medium"
+/// every 30 seconds and "slow" every 5 minutes. A `[groups]` table can change
+/// those intervals or add groups, e.g. `energy = "1m"`.
+///
+/// Metrics are read from the holding registers unless the map has
+/// `function = "input"`, and a metric can say `function = "holding"` or
+/// `function = "input"` for itself, so a map can mix the two.
+///
+/// A `[resets]` table gives the registers written by the reset commands, e.g.
+/// `energy = { address = 0x4E20 }`, and a `[clock]` table the meter's
+/// real-time clock, e.g. `address = 0x1000` and `layout = "packed"`. A `[log]`
+/// table describes a log of readings kept by the meter, for `sharkmon
+/// meter-log` and to fill gaps in the history, and a `[limits]` table the
+/// register flagging which of the meter's own limits are tripped. Each
+/// `[[option_card]]` describes the registers of a slot for an option card.
+///
+/// A `[firmware]` table gives where the meter reports its firmware version,
+/// and each `[[firmware.variant]]` the registers moved in some versions, e.g.
+/// `versions = ["10*"]` and `registers = { frequency = 0x0403 }`. The meter's
+/// version picks the first variant that lists it, or none.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegisterMap {
+    /// The register table holding the metrics that don't name their own
+    #[serde(default)]
+    pub function: Function,
+    /// Polling intervals by group name, on top of `DEFAULT_GROUPS`
+    #[serde(default)]
+    pub groups: BTreeMap<String, humantime_serde::Serde<Duration>>,
+    /// Registers that reset the meter's accumulated values
+    #[serde(default)]
+    pub resets: BTreeMap<ResetKind, Reset>,
+    pub clock: Option<Clock>,
+    pub log: Option<Log>,
+    pub limits: Option<Limits>,
+    pub firmware: Option<Firmware>,
+    #[serde(rename = "option_card", default)]
+    pub option_cards: Vec<OptionCard>,
+    #[serde(rename = "metric")]
+    pub metrics: Vec<Metric>,
+}
+
+/// Register maps for the supported meter models, selectable with `--profile`.
+const PROFILES: [(&str, &str); 4] = [
+    ("shark100", include_str!("../profiles/shark100.toml")),
+    ("shark200", include_str!("../profiles/shark200.toml")),
+    ("sdm630", include_str!("../profiles/sdm630.toml")),
+    ("wattnode", include_str!("../profiles/wattnode.toml")),
+];
+
+pub const PROFILE_NAMES: [&str; PROFILES.len()] = {
+    let mut names = [""; PROFILES.len()];
+    let mut i = 0;
+    while i < PROFILES.len() {
+        names[i] = PROFILES[i].0;
+        i += 1;
+    }
+    names
+};
+
+impl RegisterMap {
+    /// The built-in register map for a meter model in `PROFILE_NAMES`.
+    pub fn profile(name: &str) -> std::io::Result<RegisterMap> {
+        let (_, text) = PROFILES.iter().find(|(n, _)| *n == name).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown meter profile '{name}'"),
+            )
+        })?;
+        RegisterMap::parse(text)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("profile {name}: {e}")))
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<RegisterMap> {
+        let text = std::fs::read_to_string(path)?;
+        RegisterMap::parse(&text)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {e}", path.display())))
+    }
+
+    /// A register map from the text of a register map file.
+    pub fn parse(text: &str) -> Result<RegisterMap, String> {
+        let map: RegisterMap = toml::from_str(text).map_err(|e| e.to_string())?;
+        map.validate()?;
+        Ok(map)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.metrics.is_empty() {
+            return Err("register map has no metrics".to_owned());
+        }
+        let mut names = HashSet::new();
+        for m in &self.metrics {
+            if !names.insert(m.name.as_str()) {
+                return Err(format!("metric '{}' is defined more than once", m.name));
+            }
+            if m.register.address as u32 + m.register.format.len() as u32 > 0x10000 {
+                return Err(format!("metric '{}' extends past register 0xffff", m.name));
+            }
+            if !m.register.scale.is_finite() {
+                return Err(format!(
+                    "metric '{}' has a scale that isn't a number",
+                    m.name
+                ));
+            }
+            if self.interval(&m.group).is_none() {
+                return Err(format!(
+                    "metric '{}' is in unknown group '{}'",
+                    m.name, m.group
+                ));
+            }
+        }
+        if let Some(block) = self.clock.map(|c| c.block()) {
+            if block.start as u32 + block.len as u32 > 0x10000 {
+                return Err("the clock extends past register 0xffff".to_owned());
+            }
+        }
+        if let Some(log) = &self.log {
+            log.metrics(self)?;
+        }
+        if let Some(limits) = &self.limits {
+            if limits.names.len() > 16 {
+                return Err("the limits register has only 16 bits to name".to_owned());
+            }
+            if limits.interval.is_zero() {
+                return Err("the limits register has a zero interval".to_owned());
+            }
+        }
+        if let Some(firmware) = &self.firmware {
+            if firmware.len == 0 || firmware.address as u32 + firmware.len as u32 > 0x10000 {
+                return Err("the firmware version needs registers within 0x0000-0xffff".to_owned());
+            }
+            for variant in &firmware.variants {
+                if variant.versions.is_empty() {
+                    return Err("a firmware variant lists no versions".to_owned());
+                }
+                for (name, &address) in &variant.registers {
+                    let Some(m) = self.metrics.iter().find(|m| &m.name == name) else {
+                        return Err(format!("a firmware variant moves unknown metric '{name}'"));
+                    };
+                    if address as u32 + m.register.format.len() as u32 > 0x10000 {
+                        return Err(format!(
+                            "a firmware variant moves metric '{name}' past register 0xffff"
+                        ));
+                    }
+                }
+            }
+        }
+        for (i, card) in self.option_cards.iter().enumerate() {
+            if self.option_cards[..i].iter().any(|c| c.slot == card.slot) {
+                return Err(format!("option card slot {} is listed twice", card.slot));
+            }
+            if card.faults.len() > 16 {
+                return Err(format!(
+                    "the status register of option card slot {} has only 16 bits to name",
+                    card.slot
+                ));
+            }
+            let past_end = card.counters.iter().any(|c| {
+                let (address, len) = c.register.span();
+                address as u32 + len as u32 > 0x10000
+            });
+            if past_end {
+                return Err(format!(
+                    "a counter of option card slot {} runs past the end of the address space",
+                    card.slot
+                ));
+            }
+        }
+        if let Some((name, _)) = self.groups.iter().find(|(_, i)| i.is_zero()) {
+            return Err(format!("group '{name}' has a zero interval"));
+        }
+        Ok(())
+    }
+
+    /// The polling interval of `group`.
+    pub fn interval(&self, group: &str) -> Option<Duration> {
+        match self.groups.get(group) {
+            Some(interval) => Some(**interval),
+            None => DEFAULT_GROUPS
+                .iter()
+                .find(|(name, _)| *name == group)
+                .map(|(_, interval)| *interval),
+        }
+    }
+
+    /// The map for a meter running firmware `version`, with the registers
+    /// of the first variant listing it moved; None if none do.
+    pub fn variant(&self, version: &str) -> Option<RegisterMap> {
+        let firmware = self.firmware.as_ref()?;
+        let variant = firmware.variants.iter().find(|v| v.matches(version))?;
+        let mut map = self.clone();
+        for metric in &mut map.metrics {
+            if let Some(&address) = variant.registers.get(&metric.name) {
+                metric.register.address = address;
+            }
+        }
+        Some(map)
+    }
+
+    /// The register table `metric` is read from.
+    pub fn function_of(&self, metric: &Metric) -> Function {
+        metric.function.unwrap_or(self.function)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.metrics.iter().map(|m| m.name.clone()).collect()
+    }
+
+    pub fn apply(&mut self, o: &FormatOverride) -> std::io::Result<()> {
+        let metric = self
+            .metrics
+            .iter_mut()
+            .find(|m| m.name == o.name)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("register format for unknown reading '{}'", o.name),
+                )
+            })?;
+        metric.register.format = o.format;
+        metric.register.scale = o.scale;
+        Ok(())
+    }
+
+    /// Split the metrics into their polling groups, fastest first, and plan
+    /// the block reads for each group, holding registers before input
+    /// registers.
+    pub fn poll_groups(&self, read_gap: u16) -> Vec<PollGroup> {
+        let mut by_interval: BTreeMap<Duration, Vec<usize>> = BTreeMap::new();
+        for (i, m) in self.metrics.iter().enumerate() {
+            // Groups were checked by validate(), but SunSpec maps are built
+            // in code and only use the default group.
+            let interval = self.interval(&m.group).unwrap_or(DEFAULT_GROUPS[0].1);
+            by_interval.entry(interval).or_default().push(i);
+        }
+        by_interval
+            .into_iter()
+            .map(|(interval, metrics)| {
+                let mut spans: BTreeMap<Function, Vec<(u16, u16)>> = BTreeMap::new();
+                for &i in &metrics {
+                    let m = &self.metrics[i];
+                    spans
+                        .entry(self.function_of(m))
+                        .or_default()
+                        .push(m.register.span());
+                }
+                let blocks = spans
+                    .into_iter()
+                    .flat_map(|(function, spans)| plan_blocks(function, spans, read_gap))
+                    .collect();
+                PollGroup {
+                    interval,
+                    blocks,
+                    metrics,
+                }
+            })
+            .collect()
+    }
+
+    /// Decode the metrics of `group`, in group order.
+    pub fn decode(&self, group: &PollGroup, data: &BlockData) -> std::io::Result<Vec<f64>> {
+        group
+            .metrics
+            .iter()
+            .map(|&i| {
+                let m = &self.metrics[i];
+                m.register.decode(self.function_of(m), data)
+            })
+            .collect()
+    }
+}
+
+/// The SI prefixes readings can be converted between.
+const PREFIXES: [(&str, f64); 4] = [("k", 1e3), ("M", 1e6), ("G", 1e9), ("m", 1e-3)];
+
+/// The factor converting a value in units `from` to units `to`, e.g. 0.001
+/// from "W" to "kW", if they differ only in their prefixes.
+pub fn conversion(from: &str, to: &str) -> Option<f64> {
+    if from.is_empty() || to.is_empty() {
+        return None;
+    }
+    let (from, to) = (unit_readings(from), unit_readings(to));
+    from.iter().find_map(|(f, base)| {
+        let (t, _) = to.iter().find(|(_, b)| b == base)?;
+        Some(f / t)
+    })
+}
+
+/// Each way of reading `units`: as they are, or as a prefix and a unit.
+fn unit_readings(units: &str) -> Vec<(f64, &str)> {
+    let prefixed = PREFIXES.iter().filter_map(|(prefix, factor)| {
+        let base = units.strip_prefix(prefix).filter(|b| !b.is_empty())?;
+        Some((*factor, base))
+    });
+    std::iter::once((1.0, units)).chain(prefixed).collect()
+}
+
+/// A command-line override of a register's format, written as
+/// `NAME=FORMAT[:SCALE]`, e.g. `watts=int32:0.1`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FormatOverride {
+    pub name: String,
+    pub format: Format,
+    pub scale: f64,
+}
+
+impl TryFrom<String> for FormatOverride {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<FormatOverride, String> {
+        s.parse()
+    }
+}
+
+impl FromStr for FormatOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FormatOverride, String> {
+        let (name, spec) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=FORMAT[:SCALE], got '{s}'"))?;
+        let (format, scale) = match spec.split_once(':') {
+            Some((format, scale)) => (
+                format,
+                scale
+                    .parse()
+                    .ok()
+                    .filter(|scale: &f64| scale.is_finite())
+                    .ok_or_else(|| format!("invalid scale '{scale}'"))?,
+            ),
+            None => (spec, 1.0),
+        };
+        Ok(FormatOverride {
+            name: name.to_owned(),
+            format: format.parse()?,
+            scale,
+        })
+    }
+}
+
+impl fmt::Display for FormatOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}:{}", self.name, self.format, self.scale)
+    }
+}
+
+impl Serialize for FormatOverride {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The most registers a single read request may return.
+pub const MAX_BLOCK_LEN: u16 = 125;
+
+/// The most registers a single write request may carry.
+pub const MAX_WRITE_LEN: usize = 123;
+
+/// An inclusive range of holding registers, written `START[-END]` in decimal
+/// or with a 0x prefix in hex, e.g. `0x1000-0x1005`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RegisterRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl RegisterRange {
+    /// Whether the `len` registers from `address` all lie in the range.
+    pub fn contains(&self, address: u16, len: usize) -> bool {
+        address >= self.start && address as usize + len <= self.end as usize + 1
+    }
+}
+
+pub fn parse_address(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("invalid register address '{s}': {e}"))
+}
+
+impl TryFrom<String> for RegisterRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<RegisterRange, String> {
+        s.parse()
+    }
+}
+
+impl FromStr for RegisterRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RegisterRange, String> {
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (parse_address(start)?, parse_address(end)?),
+            None => {
+                let address = parse_address(s)?;
+                (address, address)
+            }
+        };
+        if end < start {
+            return Err(format!("register range '{s}' ends before it starts"));
+        }
+        Ok(RegisterRange { start, end })
+    }
+}
+
+impl fmt::Display for RegisterRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}-{:#06x}", self.start, self.end)
+    }
+}
+
+impl Serialize for RegisterRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Which Modbus register table a value lives in.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Function {
+    /// Holding registers, read with function code 0x03
+    #[default]
+    Holding,
+    /// Input registers, read with function code 0x04
+    Input,
+}
+
+/// A contiguous run of registers fetched with one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub function: Function,
+    pub start: u16,
+    pub len: u16,
+}
+
+/// Group the register spans `(address, len)` into as few blocks as possible,
+/// merging spans separated by at most `max_gap` unused registers.
+pub fn plan_blocks(
+    function: Function,
+    spans: impl IntoIterator<Item = (u16, u16)>,
+    max_gap: u16,
+) -> Vec<Block> {
+    let mut spans: Vec<(u16, u16)> = spans.into_iter().collect();
+    spans.sort_unstable();
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for (address, len) in spans {
+        let end = address as u32 + len as u32;
+        if let Some(last) = blocks.last_mut() {
+            let last_end = last.start as u32 + last.len as u32;
+            let merged_len = end.max(last_end) - last.start as u32;
+            if address as u32 <= last_end + max_gap as u32 && merged_len <= MAX_BLOCK_LEN as u32 {
+                last.len = merged_len as u16;
+                continue;
+            }
+        }
+        blocks.push(Block {
+            function,
+            start: address,
+            len,
+        });
+    }
+    blocks
+}
+
+/// The register contents returned for a set of blocks.
+#[derive(Debug, Default)]
+pub struct BlockData {
+    blocks: Vec<(Block, Vec<u16>)>,
+}
+
+impl BlockData {
+    /// Add the blocks read in `other`.
+    pub fn append(&mut self, mut other: BlockData) {
+        self.blocks.append(&mut other.blocks);
+    }
+
+    /// Add the registers read for `block`, which a misbehaving gateway may
+    /// have returned too few or too many of.
+    pub fn push(&mut self, block: Block, data: Vec<u16>) -> std::io::Result<()> {
+        if data.len() != block.len as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "read of {} registers at {:#06x} returned {}",
+                    block.len,
+                    block.start,
+                    data.len()
+                ),
+            ));
+        }
+        self.blocks.push((block, data));
+        Ok(())
+    }
+
+    /// Each block read, with its registers.
+    pub fn blocks(&self) -> &[(Block, Vec<u16>)] {
+        &self.blocks
+    }
+
+    /// The `len` registers of the register table `function` starting at
+    /// `address`, which must lie within one of the blocks that were read.
+    pub fn get(&self, function: Function, address: u16, len: u16) -> std::io::Result<&[u16]> {
+        self.blocks
+            .iter()
+            .find(|(b, _)| {
+                b.function == function
+                    && address >= b.start
+                    && address as u32 + len as u32 <= b.start as u32 + b.len as u32
+            })
+            .and_then(|(b, data)| {
+                data.get((address - b.start) as usize..(address - b.start + len) as usize)
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("register {address:#06x} missing from block read"),
+                )
+            })
+    }
+}
+
+/// Read every block from the meter.
+pub async fn read_blocks(
+    ctx: &mut dyn MeterClient,
+    blocks: &[Block],
+) -> std::io::Result<BlockData> {
+    let mut out = BlockData::default();
+    for block in blocks {
+        let data = ctx.read(block.function, block.start, block.len).await?;
+        out.push(*block, data)?;
+    }
+    Ok(out)
+}
+
+/// Whether `e` is the meter refusing a request with a Modbus exception, rather
+/// than a failure of the connection.
+pub fn is_exception(e: &Error) -> bool {
+    exception_code(e).is_some()
+}
+
+/// The Modbus exception the meter refused a request with, if it did.
+pub fn exception_code(e: &Error) -> Option<ExceptionCode> {
+    let inner = e.get_ref()?;
+    match inner.downcast_ref::<Refused>() {
+        Some(refused) => Some(refused.code),
+        None => inner.downcast_ref::<ExceptionCode>().copied(),
+    }
+}
+
+/// A copy of `e` for another caller, still telling which exception the meter
+/// refused the request with, if it did.
+pub fn copy_error(e: &Error) -> Error {
+    let inner = e.get_ref();
+    if let Some(refused) = inner.and_then(|i| i.downcast_ref::<Refused>()) {
+        return Error::new(e.kind(), refused.clone());
+    }
+    if let Some(code) = inner.and_then(|i| i.downcast_ref::<ExceptionCode>()) {
+        return Error::new(e.kind(), *code);
+    }
+    Error::new(e.kind(), e.to_string())
+}
+
+/// The name `/status` and `/metrics` count `code` under, e.g.
+/// "illegal-data-address".
+pub fn exception_name(code: ExceptionCode) -> String {
+    use ExceptionCode::*;
+    match code {
+        IllegalFunction => "illegal-function",
+        IllegalDataAddress => "illegal-data-address",
+        IllegalDataValue => "illegal-data-value",
+        ServerDeviceFailure => "server-device-failure",
+        Acknowledge => "acknowledge",
+        ServerDeviceBusy => "server-device-busy",
+        MemoryParityError => "memory-parity-error",
+        GatewayPathUnavailable => "gateway-path-unavailable",
+        GatewayTargetDevice => "gateway-target-device-failed-to-respond",
+        Custom(code) => return format!("exception-{code}"),
+    }
+    .to_owned()
+}
+
+/// A read the meter refused with a Modbus exception, saying which unit and
+/// registers, and what the exception most likely means: a wrong unit ID
+/// is reported by the gateway, a wrong register map by the meter itself.
+#[derive(Debug, Clone)]
+pub struct Refused {
+    pub code: ExceptionCode,
+    pub unit: u8,
+    pub block: Block,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.block.function {
+            Function::Holding => "holding",
+            Function::Input => "input",
+        };
+        write!(
+            f,
+            "unit {} refused a read of {} {kind} registers at {:#06x}: {}",
+            self.unit,
+            self.block.len,
+            self.block.start,
+            exception_name(self.code).replace('-', " ")
+        )?;
+        use ExceptionCode::*;
+        let hint = match self.code {
+            IllegalFunction => "it can't read these registers with this function code",
+            IllegalDataAddress => "it has no such registers; check the register map or profile",
+            IllegalDataValue => "it won't read that many at once there; try a smaller read_gap",
+            ServerDeviceBusy => "it is busy",
+            GatewayPathUnavailable => "the gateway has no path to the unit; check its setup",
+            GatewayTargetDevice => "nothing answered the gateway; check the unit ID and wiring",
+            _ => return Ok(()),
+        };
+        write!(f, " ({hint})")
+    }
+}
+
+impl std::error::Error for Refused {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_each_format() {
+        let bits = 3.5f32.to_bits();
+        let (high, low) = ((bits >> 16) as u16, bits as u16);
+        assert_eq!(Format::FloatBe.decode(&[high, low]), 3.5);
+        assert_eq!(Format::FloatSwapped.decode(&[low, high]), 3.5);
+        assert_eq!(Format::Int16.decode(&[0xffff]), -1.0);
+        assert_eq!(Format::Uint16.decode(&[0xffff]), 65535.0);
+        assert_eq!(Format::Int32.decode(&[0xffff, 0xfffe]), -2.0);
+        assert_eq!(Format::Uint32.decode(&[0x0001, 0x0000]), 65536.0);
+        assert_eq!(Format::Int32Swapped.decode(&[0xfffe, 0xffff]), -2.0);
+        assert_eq!(Format::Uint32Swapped.decode(&[0x0000, 0x0001]), 65536.0);
+    }
+
+    #[test]
+    fn format_names_round_trip() {
+        for (format, name) in FORMAT_NAMES {
+            assert_eq!(name.parse::<Format>(), Ok(format));
+            assert_eq!(format.to_string(), name);
+        }
+        assert!("float".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn parses_format_overrides() {
+        let o: FormatOverride = "watts=int32:0.1".parse().unwrap();
+        assert_eq!(
+            (o.name.as_str(), o.format, o.scale),
+            ("watts", Format::Int32, 0.1)
+        );
+        let o: FormatOverride = "volts=uint16".parse().unwrap();
+        assert_eq!(o.scale, 1.0);
+        assert!("watts".parse::<FormatOverride>().is_err());
+        assert!("watts=int32:x".parse::<FormatOverride>().is_err());
+        assert!("watts=int32:inf".parse::<FormatOverride>().is_err());
+    }
+
+    #[test]
+    fn converts_between_prefixes() {
+        assert_eq!(conversion("W", "kW"), Some(0.001));
+        assert_eq!(conversion("kWh", "Wh"), Some(1000.0));
+        assert_eq!(conversion("kWh", "MWh"), Some(0.001));
+        assert_eq!(conversion("V", "V"), Some(1.0));
+        assert_eq!(conversion("var", "kvar"), Some(0.001));
+        assert_eq!(conversion("W", "kV"), None);
+        assert_eq!(conversion("", "kW"), None);
+    }
+
+    #[test]
+    fn parses_register_ranges() {
+        let r: RegisterRange = "0x1000-0x1005".parse().unwrap();
+        assert_eq!((r.start, r.end), (0x1000, 0x1005));
+        assert!(r.contains(0x1000, 6));
+        assert!(!r.contains(0x1000, 7));
+        assert!(!r.contains(0x0fff, 1));
+        let r: RegisterRange = "4096".parse().unwrap();
+        assert!(r.contains(4096, 1));
+        assert!("0x1005-0x1000".parse::<RegisterRange>().is_err());
+    }
+
+    #[test]
+    fn merges_nearby_spans_into_blocks() {
+        let spans = [(100, 2), (104, 2), (200, 2)];
+        let blocks = plan_blocks(Function::Holding, spans, 2);
+        let found: Vec<_> = blocks.iter().map(|b| (b.start, b.len)).collect();
+        assert_eq!(found, [(100, 6), (200, 2)]);
+        let blocks = plan_blocks(Function::Holding, spans, 0);
+        assert_eq!(blocks.len(), 3);
+    }
+
+    #[test]
+    fn blocks_stay_within_the_read_limit() {
+        let spans = (0..100).map(|i| (i * 2, 2));
+        let blocks = plan_blocks(Function::Input, spans, 32);
+        assert!(blocks.iter().all(|b| b.len <= MAX_BLOCK_LEN));
+        assert_eq!(blocks.iter().map(|b| b.len).sum::<u16>(), 200);
+    }
+
+    #[test]
+    fn every_profile_loads() {
+        for name in PROFILE_NAMES {
+            let map = RegisterMap::profile(name).unwrap();
+            assert!(!map.poll_groups(32).is_empty(), "{name}");
+        }
+        assert!(RegisterMap::profile("shark9000").is_err());
+    }
+
+    #[test]
+    fn decodes_a_poll_group_from_blocks() {
+        let map = RegisterMap::profile("shark100").unwrap();
+        let groups = map.poll_groups(32);
+        let mut data = BlockData::default();
+        for block in &groups[0].blocks {
+            let mut registers = vec![0; block.len as usize];
+            // 230.0 as a float-be at every even offset
+            for pair in registers.chunks_mut(2) {
+                pair.copy_from_slice(&[0x4366, 0x0000][..pair.len()]);
+            }
+            data.push(*block, registers).unwrap();
+        }
+        let values = map.decode(&groups[0], &data).unwrap();
+        assert_eq!(values.len(), groups[0].metrics.len());
+        assert!(values.iter().all(|v| *v == 230.0));
+    }
+
+    #[test]
+    fn names_the_tripped_limits() {
+        let map = RegisterMap::profile("shark200").unwrap();
+        let limits = map.limits.unwrap();
+        assert_eq!(limits.block().start, 0x1195);
+        assert_eq!(limits.tripped(0), Vec::<String>::new());
+        assert_eq!(limits.tripped(0x0201), ["limit1_below", "limit2_above"]);
+
+        let metric = "[[metric]]\nname = \"a\"\naddress = 3\n";
+        let unnamed = format!("[limits]\naddress = 1\nnames = [\"\", \"b\"]\n{metric}");
+        let limits = RegisterMap::parse(&unnamed).unwrap().limits.unwrap();
+        assert_eq!(limits.tripped(0xffff), ["b"]);
+        let too_many = format!("[limits]\naddress = 1\nnames = {:?}\n{metric}", ["a"; 17]);
+        assert!(RegisterMap::parse(&too_many).is_err());
+    }
+
+    #[test]
+    fn rejects_maps_with_duplicate_or_ungrouped_metrics() {
+        let duplicate =
+            "[[metric]]\nname = \"a\"\naddress = 1\n[[metric]]\nname = \"a\"\naddress = 3\n";
+        assert!(RegisterMap::parse(duplicate).is_err());
+        let ungrouped = "[[metric]]\nname = \"a\"\naddress = 1\ngroup = \"hourly\"\n";
+        assert!(RegisterMap::parse(ungrouped).is_err());
+        let past_end = "[[metric]]\nname = \"a\"\naddress = 0xffff\n";
+        assert!(RegisterMap::parse(past_end).is_err());
+        let unscaled = "[[metric]]\nname = \"a\"\naddress = 1\nscale = nan\n";
+        assert!(RegisterMap::parse(unscaled).is_err());
+    }
+
+    #[test]
+    fn firmware_variants_move_registers() {
+        let metrics =
+            "[[metric]]\nname = \"a\"\naddress = 3\n[[metric]]\nname = \"b\"\naddress = 5\n";
+        let variants = "[firmware]\naddress = 0x11\nlen = 2\n\
+             [[firmware.variant]]\nversions = [\"1001\", \"12*\"]\nregisters = { a = 7 }\n\
+             [[firmware.variant]]\nversions = [\"1*\"]\nregisters = { b = 9 }\n";
+        let map = RegisterMap::parse(&format!("{variants}{metrics}")).unwrap();
+        let address = |map: &RegisterMap| {
+            map.metrics
+                .iter()
+                .map(|m| m.register.address)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(address(&map.variant("1001").unwrap()), [7, 5]);
+        assert_eq!(address(&map.variant("1234").unwrap()), [7, 5]);
+        assert_eq!(address(&map.variant("1100").unwrap()), [3, 9]);
+        assert!(map.variant("2000").is_none());
+
+        let mut data = BlockData::default();
+        let firmware = map.firmware.unwrap();
+        data.push(firmware.block(), vec![0x3130, 0x3031]).unwrap();
+        assert_eq!(firmware.decode(&data).unwrap(), "1001");
+
+        let unknown = variants.replace("{ a = 7 }", "{ c = 7 }");
+        assert!(RegisterMap::parse(&format!("{unknown}{metrics}")).is_err());
+        let past_end = variants.replace("{ a = 7 }", "{ a = 0xffff }");
+        assert!(RegisterMap::parse(&format!("{past_end}{metrics}")).is_err());
+    }
+
+    #[test]
+    fn missing_registers_are_an_error() {
+        let data = BlockData::default();
+        assert_eq!(
+            data.get(Function::Holding, 0, 1).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn garbled_responses_are_not_readings() {
+        let block = Block {
+            function: Function::Holding,
+            start: 0x100,
+            len: 2,
+        };
+        let mut data = BlockData::default();
+        assert!(data.push(block, vec![0; 3]).is_err());
+        data.push(block, vec![0x7fc0, 0x0000]).unwrap();
+        let float = Register {
+            address: 0x100,
+            format: Format::FloatBe,
+            scale: 1.0,
+        };
+        assert_eq!(
+            float.decode(Function::Holding, &data).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        let huge = Register {
+            format: Format::Uint32,
+            scale: 1e36,
+            ..float
+        };
+        assert!(huge.decode(Function::Holding, &data).is_err());
+    }
+
+    #[test]
+    fn metrics_can_be_read_from_either_register_table() {
+        let text = "[[metric]]\nname = \"a\"\naddress = 0x10\ntype = \"uint16\"\n\
+                    [[metric]]\nname = \"b\"\naddress = 0x10\ntype = \"uint16\"\n\
+                    function = \"input\"\n";
+        let map = RegisterMap::parse(text).unwrap();
+        let groups = map.poll_groups(32);
+        let functions: Vec<_> = groups[0].blocks.iter().map(|b| b.function).collect();
+        assert_eq!(functions, [Function::Holding, Function::Input]);
+        let mut data = BlockData::default();
+        data.push(groups[0].blocks[0], vec![1]).unwrap();
+        data.push(groups[0].blocks[1], vec![2]).unwrap();
+        assert_eq!(map.decode(&groups[0], &data).unwrap(), [1.0, 2.0]);
+    }
+}
@@ -0,0 +1,406 @@
+//! A JSON Schema for the configuration file, for editors to check and
+//! complete it with, printed by `sharkmon config schema`.
+//!
+//! The schema is traced from the configuration's own `Deserialize` impls
+//! rather than kept by hand, so it can't fall behind them: deserializing is
+//! run against a probe that follows a path of keys down to one setting, and
+//! answers with whatever that setting asks for next, such as a string or a
+//! struct with certain fields. What a setting does with its value after
+//! that, such as parsing a duration from the string, isn't traced, so the
+//! schema says which keys there are and what shape their values take, not
+//! which values are valid, and nothing is marked as required.
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The JSON Schema that values of `T` are read by.
+pub fn schema<T: DeserializeOwned>(title: &str) -> Value {
+    let mut tracer = Tracer::<T> {
+        defs: BTreeMap::new(),
+        ty: std::marker::PhantomData,
+    };
+    let mut root = tracer.trace(&mut Vec::new());
+    // The outermost struct is the schema itself, rather than a reference to
+    // it.
+    let name = root["$ref"]
+        .as_str()
+        .and_then(|r| r.strip_prefix("#/$defs/"));
+    if let Some(def) = name.and_then(|name| tracer.defs.remove(name)) {
+        root = def.unwrap_or_default();
+    }
+    let mut schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": title,
+    });
+    if let Value::Object(root) = root {
+        schema.as_object_mut().unwrap().extend(root);
+    }
+    let defs: Map<String, Value> = tracer
+        .defs
+        .into_iter()
+        .map(|(name, def)| (name.to_owned(), def.unwrap_or_default()))
+        .collect();
+    schema["$defs"] = Value::Object(defs);
+    schema
+}
+
+/// One step down from a value to one within it.
+#[derive(Clone, Copy)]
+enum Step {
+    Field(&'static str),
+    /// Any element of a sequence
+    Item,
+    /// Any value of a map
+    Value,
+}
+
+/// What the value at the end of a path asked for.
+enum Shape {
+    Leaf(Value),
+    Seq,
+    Map,
+    Struct {
+        name: &'static str,
+        fields: &'static [&'static str],
+        /// Whether other keys are refused
+        closed: bool,
+    },
+}
+
+struct Tracer<T> {
+    /// Each struct's schema, None while its fields are still being traced
+    defs: BTreeMap<&'static str, Option<Value>>,
+    ty: std::marker::PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Tracer<T> {
+    fn trace(&mut self, path: &mut Vec<Step>) -> Value {
+        let found = RefCell::new(None);
+        let _ = T::deserialize(Probe {
+            rest: path,
+            found: &found,
+        });
+        let mut child = |step, tracer: &mut Self| {
+            path.push(step);
+            let schema = tracer.trace(path);
+            path.pop();
+            schema
+        };
+        match found.into_inner() {
+            // Never reached, because reading what was before it failed.
+            None => json!({}),
+            Some(Shape::Leaf(schema)) => schema,
+            Some(Shape::Seq) => json!({"type": "array", "items": child(Step::Item, self)}),
+            Some(Shape::Map) => {
+                json!({"type": "object", "additionalProperties": child(Step::Value, self)})
+            }
+            Some(Shape::Struct {
+                name,
+                fields,
+                closed,
+            }) => {
+                let reference = json!({"$ref": format!("#/$defs/{name}")});
+                if self.defs.contains_key(name) {
+                    return reference;
+                }
+                self.defs.insert(name, None);
+                let properties: Map<String, Value> = fields
+                    .iter()
+                    .map(|&field| (field.to_owned(), child(Step::Field(field), self)))
+                    .collect();
+                let mut def = json!({"type": "object", "properties": properties});
+                if closed {
+                    def["additionalProperties"] = false.into();
+                }
+                self.defs.insert(name, Some(def));
+                reference
+            }
+        }
+    }
+}
+
+/// Why the probe stopped deserializing.
+#[derive(Debug)]
+enum Stop {
+    /// It found what the end of its path asked for
+    Found,
+    /// A struct refused a key that isn't one of its fields
+    Unknown,
+    Failed(String),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Found => f.write_str("found"),
+            Stop::Unknown => f.write_str("unknown field"),
+            Stop::Failed(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for Stop {}
+
+impl de::Error for Stop {
+    fn custom<E: fmt::Display>(e: E) -> Stop {
+        Stop::Failed(e.to_string())
+    }
+
+    fn unknown_field(_: &str, _: &'static [&'static str]) -> Stop {
+        Stop::Unknown
+    }
+}
+
+/// A key no struct has, to find out whether one refuses others.
+const UNKNOWN: &str = "\0unknown";
+
+/// A deserializer that follows `rest` down to one value, and records what
+/// that value asks for in `found`.
+#[derive(Clone, Copy)]
+struct Probe<'a> {
+    rest: &'a [Step],
+    found: &'a RefCell<Option<Shape>>,
+}
+
+impl Probe<'_> {
+    fn found<V>(self, shape: Shape) -> Result<V, Stop> {
+        *self.found.borrow_mut() = Some(shape);
+        Err(Stop::Found)
+    }
+
+    fn leaf<V>(self, schema: Value) -> Result<V, Stop> {
+        self.found(Shape::Leaf(schema))
+    }
+
+    fn integer<V>(self, min: i128, max: u128) -> Result<V, Stop> {
+        let mut schema = json!({"type": "integer"});
+        if let Ok(min) = i64::try_from(min) {
+            schema["minimum"] = min.into();
+        }
+        // TOML's integers are 64-bit and signed.
+        if let Ok(max) = i64::try_from(max) {
+            schema["maximum"] = max.into();
+        }
+        self.leaf(schema)
+    }
+}
+
+fn off_path<V>() -> Result<V, Stop> {
+    Err(Stop::Failed("the value isn't the shape it was".to_owned()))
+}
+
+macro_rules! leaves {
+    ($($method:ident => $schema:expr,)*) => {
+        $(fn $method<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Stop> {
+            if !self.rest.is_empty() {
+                return off_path();
+            }
+            self.leaf($schema)
+        })*
+    };
+}
+
+macro_rules! integers {
+    ($($method:ident: $ty:ty,)*) => {
+        $(fn $method<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Stop> {
+            if !self.rest.is_empty() {
+                return off_path();
+            }
+            self.integer(<$ty>::MIN as i128, <$ty>::MAX as u128)
+        })*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Probe<'_> {
+    type Error = Stop;
+
+    leaves! {
+        deserialize_any => json!({}),
+        deserialize_ignored_any => json!({}),
+        deserialize_bool => json!({"type": "boolean"}),
+        deserialize_f32 => json!({"type": "number"}),
+        deserialize_f64 => json!({"type": "number"}),
+        deserialize_char => json!({"type": "string", "minLength": 1, "maxLength": 1}),
+        deserialize_str => json!({"type": "string"}),
+        deserialize_string => json!({"type": "string"}),
+        deserialize_identifier => json!({"type": "string"}),
+        deserialize_bytes => json!({"type": "string"}),
+        deserialize_byte_buf => json!({"type": "string"}),
+        deserialize_unit => json!({"type": "null"}),
+    }
+
+    integers! {
+        deserialize_i8: i8,
+        deserialize_i16: i16,
+        deserialize_i32: i32,
+        deserialize_i64: i64,
+        deserialize_i128: i128,
+        deserialize_u8: u8,
+        deserialize_u16: u16,
+        deserialize_u32: u32,
+        deserialize_u64: u64,
+        deserialize_u128: u128,
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Stop> {
+        self.deserialize_unit(visitor)
+    }
+
+    // TOML has no null, so an optional setting is one that may be left out.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Stop> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Stop> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Stop> {
+        match self.rest.split_first() {
+            None => self.found(Shape::Seq),
+            Some((Step::Item, rest)) => visitor.visit_seq(One(Some(Probe { rest, ..self }))),
+            Some(_) => off_path(),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Stop> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: usize,
+        visitor: V,
+    ) -> Result<V::Value, Stop> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Stop> {
+        match self.rest.split_first() {
+            None => self.found(Shape::Map),
+            Some((Step::Value, rest)) => visitor.visit_map(Entry {
+                key: Some("name"),
+                value: Probe { rest, ..self },
+            }),
+            Some(_) => off_path(),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Stop> {
+        match self.rest.split_first() {
+            None => {
+                // Offer a key that isn't a field, for the struct to refuse or
+                // ignore.
+                let offered = visitor.visit_map(Entry {
+                    key: Some(UNKNOWN),
+                    value: self,
+                });
+                let closed = matches!(offered, Err(Stop::Unknown));
+                self.found(Shape::Struct {
+                    name,
+                    fields,
+                    closed,
+                })
+            }
+            Some((Step::Field(field), rest)) => visitor.visit_map(Entry {
+                key: Some(field),
+                value: Probe { rest, ..self },
+            }),
+            Some(_) => off_path(),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        variants: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Stop> {
+        if !self.rest.is_empty() {
+            return off_path();
+        }
+        self.leaf(json!({"enum": variants}))
+    }
+}
+
+/// A sequence of the one element at the next step of the path.
+struct One<'a>(Option<Probe<'a>>);
+
+impl<'de> de::SeqAccess<'de> for One<'_> {
+    type Error = Stop;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Stop> {
+        match self.0.take() {
+            Some(probe) => seed.deserialize(probe).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A map of the one key at the next step of the path.
+struct Entry<'a> {
+    key: Option<&'static str>,
+    value: Probe<'a>,
+}
+
+impl<'de> de::MapAccess<'de> for Entry<'_> {
+    type Error = Stop;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Stop> {
+        match self.key.take() {
+            Some(key) => seed.deserialize(key.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, Stop> {
+        seed.deserialize(self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn traces_the_configuration() {
+        let schema = schema::<Config>("sharkmon configuration");
+        assert_eq!(schema["additionalProperties"], false);
+        let meter = &schema["properties"]["meter"];
+        assert_eq!(meter["type"], "array");
+        assert_eq!(meter["items"]["$ref"], "#/$defs/MeterConfig");
+        let meter = &schema["$defs"]["MeterConfig"]["properties"];
+        assert_eq!(meter["timeout"]["type"], "string");
+        assert_eq!(meter["read_gap"]["maximum"], u16::MAX);
+        assert_eq!(meter["sign"]["enum"], json!(["import", "export"]));
+        assert_eq!(meter["labels"]["additionalProperties"]["type"], "string");
+        // Structs are defined once, in $defs, and referred to.
+        let unit = &meter["unit"]["items"]["$ref"];
+        assert_eq!(unit, "#/$defs/UnitConfig");
+        assert!(schema["$defs"]["UnitConfig"]["properties"]["id"].is_object());
+    }
+}
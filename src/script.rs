@@ -0,0 +1,124 @@
+//! Rhai scripts run on every poll, for site-specific logic that doesn't
+//! belong in sharkmon itself. The script defines `on_reading`, which is
+//! given each device's name and readings after it is polled and returns the
+//! readings to report:
+//!
+//! ```rhai
+//! add_reading("import_kw");
+//!
+//! fn on_reading(device, r) {
+//!     r.import_kw = max(r.watts, 0.0) / 1000.0;
+//!     if r.watts > 20000.0 {
+//!         post("http://alerts.local/hook", `{"device": "${device}"}`);
+//!     }
+//!     r
+//! }
+//! ```
+//!
+//! Top-level statements run once, when the script is loaded; that is where
+//! `add_reading` names the extra readings it sets. `print` and `debug` write
+//! to the log, and `post(url, body)` sends an HTTP request in the background.
+
+use crate::meter::Device;
+use rhai::{CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
+
+/// Operations a script may take on one call, so a runaway loop can't stall
+/// polling.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    /// The readings the script adds to every device
+    pub readings: Vec<String>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> std::io::Result<Script> {
+        let invalid =
+            |e: String| Error::new(ErrorKind::InvalidData, format!("{}: {e}", path.display()));
+        let added = Arc::new(Mutex::new(Vec::new()));
+        let client = reqwest::Client::new();
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.on_print(|s| info!(target: "sharkmon::script", "{s}"));
+        engine.on_debug(|s, _, position| debug!(target: "sharkmon::script", %position, "{s}"));
+        let names = added.clone();
+        engine.register_fn("add_reading", move |name: &str| {
+            names.lock().unwrap().push(name.to_owned());
+        });
+        engine.register_fn("post", move |url: &str, body: &str| {
+            let request = client.post(url).body(body.to_owned());
+            let url = url.to_owned();
+            tokio::spawn(async move {
+                let result = request.send().await.and_then(|r| r.error_for_status());
+                if let Err(e) = result {
+                    warn!(target: "sharkmon::script", url, error = %e, "post failed");
+                }
+            });
+        });
+
+        let source = std::fs::read_to_string(path)?;
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| invalid(e.to_string()))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == "on_reading" && f.params.len() == 2)
+        {
+            return Err(invalid(
+                "the script doesn't define on_reading(device, readings)".to_owned(),
+            ));
+        }
+        engine.run_ast(&ast).map_err(|e| invalid(e.to_string()))?;
+        let readings = std::mem::take(&mut *added.lock().unwrap());
+        Ok(Script {
+            engine,
+            ast,
+            readings,
+        })
+    }
+
+    /// Pass the device's readings through `on_reading`. Values it returns
+    /// for the device's readings are reported in their place; anything else
+    /// is ignored.
+    pub fn on_reading(&self, device: &Device) {
+        let readings = device.readings.lock().unwrap().clone();
+        let map: Map = readings
+            .unscripted()
+            .map(|(name, value)| (name.into(), Dynamic::from_float(value.into())))
+            .collect();
+        let options = CallFnOptions::new().eval_ast(false);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            &self.ast,
+            "on_reading",
+            (device.name.clone(), map),
+        );
+        let returned = match result {
+            Ok(value) => value.try_cast::<Map>(),
+            Err(e) => {
+                warn!(device = %device.name, error = %e, "on_reading failed");
+                return;
+            }
+        };
+        let Some(returned) = returned else {
+            return;
+        };
+        let mut readings = device.readings.lock().unwrap();
+        for (name, value) in returned {
+            let value = value
+                .as_float()
+                .or_else(|_| value.as_int().map(|i| i as f64));
+            if let Ok(value) = value {
+                readings.script(&name, value as f32);
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Running as a native Windows service. `sharkmon service install -- <options>`
+//! registers a service that starts sharkmon with `--service` and the given
+//! options, and `sharkmon service uninstall` removes it.
+
+use clap::Subcommand;
+use std::ffi::OsString;
+
+#[derive(Subcommand)]
+pub enum Action {
+    /// Register sharkmon as a Windows service, started at boot with the
+    /// options after "--", e.g. install -- --config C:\sharkmon\sharkmon.toml
+    Install {
+        #[clap(last = true)]
+        args: Vec<OsString>,
+    },
+    /// Stop and remove the Windows service
+    Uninstall,
+}
+
+/// Runs sharkmon until the receiver fires, when the service is stopped.
+pub type App = Box<dyn FnOnce(tokio::sync::oneshot::Receiver<()>) -> std::io::Result<()> + Send>;
+
+#[cfg(windows)]
+pub use windows::{dispatch, manage};
+
+#[cfg(not(windows))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Windows services are only available on Windows",
+    )
+}
+
+#[cfg(not(windows))]
+pub fn manage(_action: Action) -> std::io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(windows))]
+pub fn dispatch(_app: App) -> std::io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::{Action, App};
+    use std::ffi::OsString;
+    use std::io::Error;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    const SERVICE_NAME: &str = "sharkmon";
+
+    /// The service control manager calls `service_main` with no way to pass
+    /// it state, so the app waits here.
+    static APP: Mutex<Option<App>> = Mutex::new(None);
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Hand the process over to the service control manager, which runs `app`
+    /// on its own thread until the service is stopped.
+    pub fn dispatch(app: App) -> std::io::Result<()> {
+        // Services start in the system directory; resolve relative paths
+        // next to the executable instead.
+        if let Some(dir) = std::env::current_exe()?.parent() {
+            std::env::set_current_dir(dir)?;
+        }
+        *APP.lock().unwrap() = Some(app);
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(Error::other)
+    }
+
+    fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: match state {
+                ServiceState::Running => ServiceControlAccept::STOP,
+                _ => ServiceControlAccept::empty(),
+            },
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            tracing::error!(error = %e, "service failed");
+        }
+    }
+
+    fn run_service() -> windows_service::Result<()> {
+        let app = APP.lock().unwrap().take().expect("service started twice");
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+        let stop_tx = Mutex::new(Some(stop_tx));
+        let handler = move |control| match control {
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            ServiceControl::Stop => {
+                if let Some(tx) = stop_tx.lock().unwrap().take() {
+                    let _ = tx.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let handle = service_control_handler::register(SERVICE_NAME, handler)?;
+        handle.set_service_status(status(ServiceState::Running, 0))?;
+        let exit_code = match app(stop_rx) {
+            Ok(()) => 0,
+            Err(e) => {
+                tracing::error!(error = %e, "sharkmon exited with an error");
+                1
+            }
+        };
+        handle.set_service_status(status(ServiceState::Stopped, exit_code))
+    }
+
+    pub fn manage(action: Action) -> std::io::Result<()> {
+        match action {
+            Action::Install { args } => install(args),
+            Action::Uninstall => uninstall(),
+        }
+        .map_err(Error::other)
+    }
+
+    fn install(args: Vec<OsString>) -> windows_service::Result<()> {
+        let access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
+        let manager = ServiceManager::local_computer(None::<&str>, access)?;
+        let mut launch_arguments = vec![OsString::from("--service")];
+        launch_arguments.extend(args);
+        let info = ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: "Sharkmon power meter monitor".into(),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe().map_err(windows_service::Error::Winapi)?,
+            launch_arguments,
+            dependencies: vec![],
+            // LocalSystem
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+        service.set_description("Polls Modbus power meters and serves their readings")?;
+        println!("Installed the {SERVICE_NAME} service");
+        Ok(())
+    }
+
+    fn uninstall() -> windows_service::Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
+        let service = manager.open_service(SERVICE_NAME, access)?;
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop()?;
+        }
+        service.delete()?;
+        println!("Removed the {SERVICE_NAME} service");
+        Ok(())
+    }
+}
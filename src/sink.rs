@@ -0,0 +1,1352 @@
+//! Sinks: exporters such as webhooks or InfluxDB that every sample is sent
+//! to as it is polled. Each sink has its own task and a bounded queue, so a
+//! slow or dead sink can neither stall the poll loops nor use unbounded
+//! memory. Samples leave the queue in batches, so that a sink which can take
+//! several in one request needn't make a request for each. A sink that keeps
+//! failing trips a circuit breaker: its samples are held back for a
+//! cooling-off period, after which one sample tests whether it has
+//! recovered. Held back and failed samples are dropped, or with a buffer
+//! file kept on disk, up to a size limit, and sent once the sink recovers.
+//!
+//! A sink with deadbands is only sent the readings that have moved past
+//! their band since it was last sent them, or that have gone unsent for its
+//! `max_interval`, so that steady loads don't fill a database with the same
+//! values. A sample with no reading left to send isn't sent at all.
+//!
+//! The built-in sinks post JSON or InfluxDB lines to an HTTP endpoint, or
+//! set openHAB items or Domoticz devices to each reading. A
+//! crate embedding sharkmon can add its own by implementing [`Sink`] and
+//! registering a constructor for it before calling `sharkmon::main`:
+//!
+//! ```no_run
+//! use sharkmon::sink::{Sample, Sink};
+//!
+//! struct Stdout;
+//!
+//! #[async_trait::async_trait]
+//! impl Sink for Stdout {
+//!     async fn handle(&self, sample: &Sample) -> std::io::Result<()> {
+//!         println!("{} {:?}", sample.device, sample.readings.get("watts"));
+//!         Ok(())
+//!     }
+//! }
+//!
+//! fn main() -> std::io::Result<()> {
+//!     sharkmon::sink::register("stdout", |_config| Ok(Box::new(Stdout)));
+//!     sharkmon::main()
+//! }
+//! ```
+//!
+//! after which `[[sink]]` tables with `format = "stdout"` make one, and see
+//! their `options` table through the [`SinkConfig`].
+
+use crate::config::{Labels, SinkConfig, SinkFormat};
+use crate::meter::{Device, PowerEwma};
+#[cfg(feature = "http-sinks")]
+use crate::output;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "http-sinks")]
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Consecutive failures that open the circuit.
+const TRIP_FAILURES: u32 = 5;
+
+/// How long an open circuit drops samples before testing the sink again,
+/// doubling while the sink stays down.
+const MIN_COOLDOWN: Duration = Duration::from_secs(10);
+const MAX_COOLDOWN: Duration = Duration::from_secs(300);
+
+/// A device's readings at the time they were polled.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub time: DateTime<Utc>,
+    pub device: String,
+    pub labels: Arc<Labels>,
+    pub readings: PowerEwma,
+}
+
+impl Sample {
+    /// The device's current readings.
+    pub fn new(device: &Device) -> Sample {
+        Sample {
+            time: Utc::now(),
+            device: device.name.clone(),
+            labels: device.labels.clone(),
+            readings: device.readings.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// A sample as kept in a disk buffer, with its readings in order.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    time: DateTime<Utc>,
+    device: String,
+    #[serde(default, skip_serializing_if = "Labels::is_empty")]
+    labels: Labels,
+    names: Vec<String>,
+    values: Vec<f32>,
+}
+
+impl Serialize for Sample {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (names, values) = self
+            .readings
+            .rounded()
+            .map(|(name, value)| (name.to_owned(), value))
+            .unzip();
+        Stored {
+            time: self.time,
+            device: self.device.clone(),
+            labels: (*self.labels).clone(),
+            names,
+            values,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Sample {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Sample, D::Error> {
+        let stored = Stored::deserialize(deserializer)?;
+        if stored.names.len() != stored.values.len() {
+            return Err(serde::de::Error::custom(
+                "names and values differ in length",
+            ));
+        }
+        Ok(Sample {
+            time: stored.time,
+            device: stored.device,
+            labels: Arc::new(stored.labels),
+            readings: PowerEwma::from_values(stored.names, stored.values),
+        })
+    }
+}
+
+/// An exporter that every sample is sent to. Samples arrive one at a time,
+/// in order, from the sink's own task; an error counts towards tripping its
+/// circuit breaker and, if the sink has a buffer, keeps the sample to send
+/// again later.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    async fn handle(&self, sample: &Sample) -> std::io::Result<()>;
+
+    /// Send several samples, oldest first, for a sink whose `batch_size` is
+    /// more than one. Sinks that can send them in one request override this;
+    /// by default each is handled in turn. If it fails, the whole batch
+    /// counts as undelivered.
+    async fn handle_batch(&self, samples: &[Sample]) -> std::io::Result<()> {
+        for sample in samples {
+            self.handle(sample).await?;
+        }
+        Ok(())
+    }
+
+    /// Check that the sink can be reached, e.g. that its host name resolves,
+    /// without sending it anything, for `--dry-run`.
+    async fn check(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// What the sink would send for `sample`, for `--dry-run`, if it can
+    /// say.
+    fn render(&self, _sample: &Sample) -> Option<String> {
+        None
+    }
+}
+
+/// Makes a sink from its `[[sink]]` table.
+pub type Factory = Box<dyn Fn(&SinkConfig) -> std::io::Result<Box<dyn Sink>> + Send + Sync>;
+
+/// Constructors of the sinks registered by crates embedding sharkmon.
+static FACTORIES: Mutex<BTreeMap<String, Factory>> = Mutex::new(BTreeMap::new());
+
+/// Make sinks whose format is `format` with `factory`. Registering a format
+/// again replaces its factory; the built-in formats can't be replaced.
+pub fn register(
+    format: &str,
+    factory: impl Fn(&SinkConfig) -> std::io::Result<Box<dyn Sink>> + Send + Sync + 'static,
+) {
+    FACTORIES
+        .lock()
+        .unwrap()
+        .insert(format.to_owned(), Box::new(factory));
+}
+
+/// Whether sinks of `format` have been registered.
+pub fn is_registered(format: &str) -> bool {
+    FACTORIES.lock().unwrap().contains_key(format)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Samples are being sent
+    #[default]
+    Closed,
+    /// The sink kept failing, and samples are being dropped
+    Open,
+    /// The next sample tests whether the sink has recovered
+    HalfOpen,
+}
+
+/// The health of a sink, as reported by `/status`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SinkStatus {
+    pub name: String,
+    pub circuit: CircuitState,
+    /// Samples delivered since startup
+    pub sent: u64,
+    /// Samples lost to failed requests, a full queue or an open circuit
+    /// when they couldn't be buffered
+    pub dropped: u64,
+    /// Samples waiting in the sink's disk buffer
+    pub buffered: u64,
+    /// Samples waiting in the sink's queue, with a batch being collected
+    pub queued: u64,
+    /// Samples the queue holds
+    pub queue_depth: u64,
+    /// Samples not sent because no reading moved past its deadband
+    pub suppressed: u64,
+    /// Failed requests since startup
+    pub failures: u64,
+    pub last_error: Option<String>,
+    pub last_error_time: Option<DateTime<Utc>>,
+}
+
+/// A sink, with its queue, circuit breaker and buffer.
+struct Runner {
+    config: SinkConfig,
+    sink: Box<dyn Sink>,
+    status: Mutex<SinkStatus>,
+    /// Samples taken from the queue into a batch that is still collecting
+    collecting: AtomicU64,
+    /// When each reading with a deadband was last sent, by device and
+    /// reading
+    sent: Mutex<HashMap<(String, String), Sent>>,
+}
+
+/// A reading as a sink was last sent it.
+struct Sent {
+    value: f32,
+    time: DateTime<Utc>,
+}
+
+/// Every configured sink.
+#[derive(Default)]
+pub struct Sinks {
+    sinks: Vec<(Arc<Runner>, Option<mpsc::Sender<Sample>>)>,
+}
+
+impl Sinks {
+    /// Make each configured sink: the built-in HTTP sinks, or one of a
+    /// registered format.
+    pub fn new(configs: &[SinkConfig]) -> std::io::Result<Sinks> {
+        let mut sinks = Vec::with_capacity(configs.len());
+        for config in configs {
+            let context = |e: Error| Error::new(e.kind(), format!("sink '{}': {e}", config.name()));
+            let sink: Box<dyn Sink> = match &config.format {
+                #[cfg(feature = "http-sinks")]
+                SinkFormat::Json
+                | SinkFormat::Influx
+                | SinkFormat::Openhab
+                | SinkFormat::Domoticz
+                | SinkFormat::Grafana => Box::new(HttpSink::new(config).map_err(context)?),
+                #[cfg(not(feature = "http-sinks"))]
+                SinkFormat::Json
+                | SinkFormat::Influx
+                | SinkFormat::Openhab
+                | SinkFormat::Domoticz
+                | SinkFormat::Grafana => {
+                    return Err(context(Error::new(
+                        ErrorKind::Unsupported,
+                        "this sharkmon was built without the \"http-sinks\" feature",
+                    )))
+                }
+                SinkFormat::Other(format) => {
+                    let factories = FACTORIES.lock().unwrap();
+                    let factory = factories.get(format).ok_or_else(|| {
+                        context(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("unknown sink format '{format}'"),
+                        ))
+                    })?;
+                    factory(config).map_err(context)?
+                }
+            };
+            let runner = Runner {
+                config: config.clone(),
+                sink,
+                status: Mutex::new(SinkStatus {
+                    name: config.name().to_owned(),
+                    queue_depth: config.queue_depth as u64,
+                    ..Default::default()
+                }),
+                collecting: AtomicU64::new(0),
+                sent: Mutex::default(),
+            };
+            sinks.push((Arc::new(runner), None));
+        }
+        Ok(Sinks { sinks })
+    }
+
+    /// Start each sink's task.
+    pub fn start(mut self) -> Sinks {
+        for (sink, queue) in &mut self.sinks {
+            let (tx, rx) = mpsc::channel(sink.config.queue_depth.max(1));
+            tokio::spawn(sink.clone().run(rx));
+            *queue = Some(tx);
+        }
+        self
+    }
+
+    /// Check each sink and render what it would be sent for `samples`,
+    /// without sending anything: its name, whether it can be reached, and
+    /// the rendered samples.
+    pub async fn dry_run(
+        &self,
+        samples: &[Sample],
+    ) -> Vec<(String, std::io::Result<()>, Vec<String>)> {
+        let mut results = Vec::with_capacity(self.sinks.len());
+        for (runner, _) in &self.sinks {
+            let rendered = samples
+                .iter()
+                .filter_map(|s| runner.sink.render(s))
+                .collect();
+            let check = runner.sink.check().await;
+            results.push((runner.config.name().to_owned(), check, rendered));
+        }
+        results
+    }
+
+    /// Queue the device's current readings for every sink, without waiting.
+    pub fn publish(&self, device: &Device) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let sample = Sample::new(device);
+        for (sink, queue) in &self.sinks {
+            let Some(queue) = queue else { continue };
+            let Some(sample) = sink.filter(&sample) else {
+                continue;
+            };
+            match queue.try_reserve() {
+                Ok(permit) => {
+                    sink.accepted(&sample);
+                    permit.send(sample);
+                }
+                Err(_) => sink.status.lock().unwrap().dropped += 1,
+            }
+        }
+    }
+
+    pub fn status(&self) -> Vec<SinkStatus> {
+        self.sinks
+            .iter()
+            .map(|(sink, queue)| {
+                let mut status = sink.status.lock().unwrap().clone();
+                let waiting = queue
+                    .as_ref()
+                    .map_or(0, |queue| queue.max_capacity() - queue.capacity());
+                status.queued = waiting as u64 + sink.collecting.load(Ordering::Relaxed);
+                status
+            })
+            .collect()
+    }
+}
+
+/// The built-in sinks: each sample posted to an HTTP endpoint, as JSON or
+/// as an InfluxDB line, or to a Grafana Live stream, or each reading sent to
+/// a home automation server.
+#[cfg(feature = "http-sinks")]
+struct HttpSink {
+    config: SinkConfig,
+    client: reqwest::Client,
+    headers: HeaderMap,
+    openhab: Openhab,
+    domoticz: Domoticz,
+    /// Where a grafana sink pushes, `<url>/api/live/push/<stream>`
+    grafana: String,
+}
+
+/// Which openHAB item each reading sets, from the sink's options:
+/// `items = { watts = "House_Power" }` names the items to set, and without it
+/// every reading sets an item named `prefix` (by default "sharkmon_"), the
+/// device and the reading, e.g. `sharkmon_main_watts`.
+#[cfg(feature = "http-sinks")]
+#[derive(Debug, Default)]
+struct Openhab {
+    prefix: String,
+    items: BTreeMap<String, String>,
+}
+
+/// Which Domoticz device each reading sets, from the sink's options:
+/// `idx = { watts = 12, "solar.watts" = 13 }`, where a reading named with
+/// its device takes precedence. Readings without one aren't sent.
+#[cfg(feature = "http-sinks")]
+#[derive(Debug, Default)]
+struct Domoticz {
+    idx: BTreeMap<String, u64>,
+}
+
+#[cfg(feature = "http-sinks")]
+/// The option `key` as a table of values that `value` accepts.
+fn option_table<T>(
+    config: &SinkConfig,
+    key: &str,
+    value: impl Fn(&toml::Value) -> Option<T>,
+) -> std::io::Result<BTreeMap<String, T>> {
+    let invalid = |e: String| Error::new(ErrorKind::InvalidInput, e);
+    let Some(option) = config.options.get(key) else {
+        return Ok(BTreeMap::new());
+    };
+    let table = option
+        .as_table()
+        .ok_or_else(|| invalid(format!("option '{key}' must be a table")))?;
+    table
+        .iter()
+        .map(|(name, v)| match value(v) {
+            Some(v) => Ok((name.clone(), v)),
+            None => Err(invalid(format!(
+                "option '{key}': invalid value for '{name}'"
+            ))),
+        })
+        .collect()
+}
+
+#[cfg(feature = "http-sinks")]
+impl Openhab {
+    fn new(config: &SinkConfig) -> std::io::Result<Openhab> {
+        let prefix = match config.options.get("prefix") {
+            None => "sharkmon_".to_owned(),
+            Some(toml::Value::String(prefix)) => prefix.clone(),
+            Some(_) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "option 'prefix' must be a string",
+                ))
+            }
+        };
+        let items = option_table(config, "items", |v| v.as_str().map(str::to_owned))?;
+        Ok(Openhab { prefix, items })
+    }
+
+    /// The item a reading of `device` sets, if any.
+    fn item(&self, device: &str, reading: &str) -> Option<String> {
+        if !self.items.is_empty() {
+            return self
+                .items
+                .get(&format!("{device}.{reading}"))
+                .or_else(|| self.items.get(reading))
+                .cloned();
+        }
+        // Item names are letters, digits and underscores.
+        let name = format!("{}{device}_{reading}", self.prefix);
+        Some(
+            name.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(feature = "http-sinks")]
+impl Domoticz {
+    fn new(config: &SinkConfig) -> std::io::Result<Domoticz> {
+        let idx = option_table(config, "idx", |v| {
+            v.as_integer().and_then(|i| u64::try_from(i).ok())
+        })?;
+        if idx.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a domoticz sink needs an 'idx' option giving each reading's device",
+            ));
+        }
+        Ok(Domoticz { idx })
+    }
+
+    /// The Domoticz device a reading of `device` sets, if any.
+    fn idx(&self, device: &str, reading: &str) -> Option<u64> {
+        self.idx
+            .get(&format!("{device}.{reading}"))
+            .or_else(|| self.idx.get(reading))
+            .copied()
+    }
+}
+
+/// Grafana Live's push endpoint for the stream named by the sink's `stream`
+/// option, by default "sharkmon"; each device is then the channel
+/// `stream/<stream>/<device>`.
+#[cfg(feature = "http-sinks")]
+fn grafana_push_url(config: &SinkConfig) -> std::io::Result<String> {
+    let stream = match config.options.get("stream") {
+        None => "sharkmon",
+        Some(toml::Value::String(stream)) => stream.as_str(),
+        Some(_) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "option 'stream' must be a string",
+            ))
+        }
+    };
+    let base = config.url.trim_end_matches('/');
+    Ok(format!("{base}/api/live/push/{stream}"))
+}
+
+#[cfg(feature = "http-sinks")]
+impl HttpSink {
+    /// Check the sink's URL and headers and build its HTTP client.
+    fn new(config: &SinkConfig) -> std::io::Result<HttpSink> {
+        let invalid = |e: String| Error::new(ErrorKind::InvalidInput, e);
+        let url = reqwest::Url::parse(&config.url).map_err(|e| invalid(e.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(invalid(format!(
+                "unsupported URL scheme '{}'",
+                url.scheme()
+            )));
+        }
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = HeaderName::try_from(name).map_err(|e| invalid(e.to_string()))?;
+            let mut value =
+                HeaderValue::try_from(value.expose()).map_err(|e| invalid(e.to_string()))?;
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(HttpSink {
+            config: config.clone(),
+            client,
+            headers,
+            openhab: match config.format {
+                SinkFormat::Openhab => Openhab::new(config)?,
+                _ => Openhab::default(),
+            },
+            domoticz: match config.format {
+                SinkFormat::Domoticz => Domoticz::new(config)?,
+                _ => Domoticz::default(),
+            },
+            grafana: match config.format {
+                SinkFormat::Grafana => grafana_push_url(config)?,
+                _ => String::new(),
+            },
+        })
+    }
+
+    /// Where a sample is posted to, as what, and the body posted, for the
+    /// sinks that post each sample in one request.
+    fn post(&self, sample: &Sample) -> (&str, &'static str, String) {
+        match self.config.format {
+            SinkFormat::Influx => (
+                &self.config.url,
+                "text/plain; charset=utf-8",
+                output::influx_line(sample),
+            ),
+            SinkFormat::Grafana => (
+                &self.grafana,
+                "text/plain; charset=utf-8",
+                output::grafana_line(sample),
+            ),
+            _ => (
+                &self.config.url,
+                "application/json",
+                output::json_line(sample, true),
+            ),
+        }
+    }
+
+    /// Post several samples in one request: InfluxDB or Grafana lines one
+    /// per line, or JSON as an array.
+    async fn post_batch(&self, samples: &[Sample]) -> std::io::Result<()> {
+        let (url, content_type, _) = self.post(&samples[0]);
+        let lines = samples.iter().map(|sample| self.post(sample).2);
+        let body = match self.config.format {
+            SinkFormat::Influx | SinkFormat::Grafana => lines.collect::<Vec<_>>().join("\n"),
+            _ => format!("[{}]", lines.collect::<Vec<_>>().join(",")),
+        };
+        self.client
+            .post(url)
+            .header(CONTENT_TYPE, content_type)
+            .headers(self.headers.clone())
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    /// Set each reading's openHAB item, with `PUT /rest/items/<item>/state`.
+    async fn send_openhab(&self, sample: &Sample) -> std::io::Result<()> {
+        let base = self.config.url.trim_end_matches('/');
+        for (reading, value) in sample.readings.iter() {
+            let Some(item) = self.openhab.item(&sample.device, reading) else {
+                continue;
+            };
+            self.client
+                .put(format!("{base}/rest/items/{item}/state"))
+                .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                .headers(self.headers.clone())
+                .body(value.to_string())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(Error::other)?;
+        }
+        Ok(())
+    }
+
+    /// Set each reading's Domoticz device, with its `udevice` command.
+    async fn send_domoticz(&self, sample: &Sample) -> std::io::Result<()> {
+        let base = self.config.url.trim_end_matches('/');
+        for (reading, value) in sample.readings.iter() {
+            let Some(idx) = self.domoticz.idx(&sample.device, reading) else {
+                continue;
+            };
+            let response: serde_json::Value = self
+                .client
+                .get(format!("{base}/json.htm"))
+                .query(&[
+                    ("type", "command"),
+                    ("param", "udevice"),
+                    ("idx", &idx.to_string()),
+                    ("nvalue", "0"),
+                    ("svalue", &value.to_string()),
+                ])
+                .headers(self.headers.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(Error::other)?
+                .text()
+                .await
+                .map_err(Error::other)
+                .and_then(|body| serde_json::from_str(&body).map_err(Error::other))?;
+            // Domoticz reports its errors in the body, with a 200.
+            if response["status"] != "OK" {
+                return Err(Error::other(format!(
+                    "domoticz device {idx}: {}",
+                    response["message"].as_str().unwrap_or("update failed")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "http-sinks")]
+#[async_trait]
+impl Sink for HttpSink {
+    async fn handle(&self, sample: &Sample) -> std::io::Result<()> {
+        let (url, content_type, body) = match self.config.format {
+            SinkFormat::Openhab => return self.send_openhab(sample).await,
+            SinkFormat::Domoticz => return self.send_domoticz(sample).await,
+            _ => self.post(sample),
+        };
+        self.client
+            .post(url)
+            .header(CONTENT_TYPE, content_type)
+            .headers(self.headers.clone())
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::other)?;
+        Ok(())
+    }
+
+    async fn handle_batch(&self, samples: &[Sample]) -> std::io::Result<()> {
+        match (&self.config.format, samples) {
+            (_, []) => Ok(()),
+            // These make a request for each reading anyway.
+            (SinkFormat::Openhab | SinkFormat::Domoticz, _) | (_, [_]) => {
+                for sample in samples {
+                    self.handle(sample).await?;
+                }
+                Ok(())
+            }
+            _ => self.post_batch(samples).await,
+        }
+    }
+
+    async fn check(&self) -> std::io::Result<()> {
+        let url = reqwest::Url::parse(&self.config.url)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        let host = url.host_str().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = url.port_or_known_default().unwrap_or(80);
+        let mut addrs = tokio::net::lookup_host((host, port)).await?;
+        if addrs.next().is_none() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{host} has no addresses"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn render(&self, sample: &Sample) -> Option<String> {
+        let base = self.config.url.trim_end_matches('/');
+        let readings = sample.readings.iter();
+        let requests: Vec<String> = match self.config.format {
+            SinkFormat::Openhab => readings
+                .filter_map(|(reading, value)| {
+                    let item = self.openhab.item(&sample.device, reading)?;
+                    Some(format!("PUT {base}/rest/items/{item}/state {value}"))
+                })
+                .collect(),
+            SinkFormat::Domoticz => readings
+                .filter_map(|(reading, value)| {
+                    let idx = self.domoticz.idx(&sample.device, reading)?;
+                    Some(format!(
+                        "GET {base}/json.htm?type=command&param=udevice&idx={idx}&nvalue=0&svalue={value}"
+                    ))
+                })
+                .collect(),
+            _ => {
+                let (url, content_type, body) = self.post(sample);
+                vec![format!("POST {url} ({content_type})\n{body}")]
+            }
+        };
+        Some(requests.join("\n"))
+    }
+}
+
+/// Samples a sink couldn't take, kept in a file one JSON object per line
+/// until it recovers, up to a size limit.
+struct DiskBuffer {
+    path: PathBuf,
+    max_bytes: u64,
+    bytes: u64,
+    lines: u64,
+}
+
+impl DiskBuffer {
+    /// Open the buffer, keeping anything left from an earlier run.
+    fn open(path: &Path, max_bytes: u64) -> std::io::Result<DiskBuffer> {
+        let (bytes, lines) = match std::fs::read_to_string(path) {
+            Ok(text) => (text.len() as u64, text.lines().count() as u64),
+            Err(e) if e.kind() == ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e),
+        };
+        Ok(DiskBuffer {
+            path: path.to_owned(),
+            max_bytes,
+            bytes,
+            lines,
+        })
+    }
+
+    /// Append a sample, unless the buffer is full.
+    fn push(&mut self, sample: &Sample) -> std::io::Result<bool> {
+        let line = serde_json::to_string(sample)?;
+        let len = line.len() as u64 + 1;
+        if self.bytes + len > self.max_bytes {
+            return Ok(false);
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{line}")?;
+        self.bytes += len;
+        self.lines += 1;
+        Ok(true)
+    }
+
+    fn read(&self) -> std::io::Result<Vec<String>> {
+        let text = std::fs::read_to_string(&self.path)?;
+        Ok(text.lines().map(str::to_owned).collect())
+    }
+
+    /// Replace the contents with `lines`.
+    fn rewrite(&mut self, lines: &[String]) -> std::io::Result<()> {
+        let mut text = String::new();
+        for line in lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+        std::fs::write(&self.path, &text)?;
+        self.bytes = text.len() as u64;
+        self.lines = lines.len() as u64;
+        Ok(())
+    }
+}
+
+impl Runner {
+    /// The part of `sample` its deadbands let through: the readings that
+    /// have moved past their band, or are due to be sent again, and those
+    /// without one. None if that leaves nothing.
+    fn filter(&self, sample: &Sample) -> Option<Sample> {
+        if self.config.deadband.is_empty() {
+            return Some(sample.clone());
+        }
+        let sent = self.sent.lock().unwrap();
+        let keep: Vec<bool> = sample
+            .readings
+            .iter()
+            .map(|(name, value)| {
+                let Some(band) = self.config.deadband(name) else {
+                    return true;
+                };
+                let key = (sample.device.clone(), name.to_owned());
+                match sent.get(&key) {
+                    None => true,
+                    Some(last) => {
+                        band.exceeded(last.value, value)
+                            || self.config.max_interval.is_some_and(|interval| {
+                                (sample.time - last.time).to_std().unwrap_or_default() >= interval
+                            })
+                    }
+                }
+            })
+            .collect();
+        if !keep.contains(&true) {
+            self.status.lock().unwrap().suppressed += 1;
+            return None;
+        }
+        if !keep.contains(&false) {
+            return Some(sample.clone());
+        }
+        Some(Sample {
+            readings: sample.readings.only(&keep),
+            ..sample.clone()
+        })
+    }
+
+    /// Measure the deadbands from the readings of `sample`, now that it is
+    /// on its way to the sink.
+    fn accepted(&self, sample: &Sample) {
+        if self.config.deadband.is_empty() {
+            return;
+        }
+        let mut sent = self.sent.lock().unwrap();
+        for (name, value) in sample.readings.iter() {
+            if self.config.deadband(name).is_some() {
+                let key = (sample.device.clone(), name.to_owned());
+                let time = sample.time;
+                sent.insert(key, Sent { value, time });
+            }
+        }
+    }
+
+    /// Forget the readings of a sample that was dropped, unless newer ones
+    /// were sent since, so that they go with the next sample.
+    fn forget(&self, sample: &Sample) {
+        if self.config.deadband.is_empty() {
+            return;
+        }
+        let mut sent = self.sent.lock().unwrap();
+        for (name, _) in sample.readings.iter() {
+            let key = (sample.device.clone(), name.to_owned());
+            if sent.get(&key).is_some_and(|last| last.time == sample.time) {
+                sent.remove(&key);
+            }
+        }
+    }
+
+    async fn send(&self, samples: &[Sample]) -> std::io::Result<()> {
+        self.sink.handle_batch(samples).await?;
+        self.status.lock().unwrap().sent += samples.len() as u64;
+        Ok(())
+    }
+
+    /// Keep undelivered samples in the buffer, or count them as dropped.
+    fn keep(&self, buffer: &mut Option<DiskBuffer>, samples: &[Sample]) {
+        let mut dropped = 0;
+        for sample in samples {
+            let kept = match buffer {
+                Some(buffer) => buffer.push(sample).unwrap_or_else(|e| {
+                    warn!(sink = %self.config.name(), error = %e, "could not write to the buffer");
+                    false
+                }),
+                None => false,
+            };
+            if !kept {
+                self.forget(sample);
+                dropped += 1;
+            }
+        }
+        let mut status = self.status.lock().unwrap();
+        status.dropped += dropped;
+        status.buffered = buffer.as_ref().map_or(0, |b| b.lines);
+    }
+
+    /// Send the buffered samples in order, in batches, keeping whatever
+    /// isn't delivered. Lines that can't be read back, e.g. from an older
+    /// version, are dropped.
+    async fn replay(&self, buffer: &mut DiskBuffer) -> std::io::Result<()> {
+        let mut samples = Vec::new();
+        for line in buffer.read()? {
+            match serde_json::from_str::<Sample>(&line) {
+                Ok(sample) => samples.push(sample),
+                Err(e) => {
+                    warn!(sink = %self.config.name(), error = %e, "dropping an unreadable buffered sample");
+                    self.status.lock().unwrap().dropped += 1;
+                }
+            }
+        }
+        let mut sent = 0;
+        let mut result = Ok(());
+        for batch in samples.chunks(self.config.batch_size.max(1)) {
+            if let Err(e) = self.send(batch).await {
+                result = Err(e);
+                break;
+            }
+            sent += batch.len();
+        }
+        if sent > 0 {
+            info!(sink = %self.config.name(), samples = sent, "sent buffered samples");
+        }
+        let left = samples[sent..]
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        buffer.rewrite(&left)?;
+        self.status.lock().unwrap().buffered = buffer.lines;
+        result
+    }
+
+    /// Deliver a batch, behind any samples that are already buffered.
+    async fn deliver(
+        &self,
+        buffer: &mut Option<DiskBuffer>,
+        batch: &[Sample],
+    ) -> std::io::Result<()> {
+        if buffer.as_ref().is_none_or(|b| b.lines == 0) {
+            let result = self.send(batch).await;
+            if result.is_err() {
+                self.keep(buffer, batch);
+            }
+            return result;
+        }
+        self.keep(buffer, batch);
+        match buffer {
+            Some(b) => self.replay(b).await,
+            None => Ok(()),
+        }
+    }
+
+    /// The next batch to send: the next sample and those after it, up to the
+    /// batch size, that arrive within the flush interval, or without one
+    /// those already waiting. None once the queue has closed.
+    async fn batch(&self, queue: &mut mpsc::Receiver<Sample>) -> Option<Vec<Sample>> {
+        let size = self.config.batch_size.max(1);
+        let mut batch = Vec::with_capacity(size);
+        if queue.recv_many(&mut batch, size).await == 0 {
+            return None;
+        }
+        if let Some(interval) = self.config.flush_interval {
+            let until = tokio::time::Instant::now() + interval;
+            while batch.len() < size {
+                self.collecting.store(batch.len() as u64, Ordering::Relaxed);
+                let limit = size - batch.len();
+                let more = queue.recv_many(&mut batch, limit);
+                match tokio::time::timeout_at(until, more).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+            self.collecting.store(0, Ordering::Relaxed);
+        }
+        Some(batch)
+    }
+
+    fn set_circuit(&self, circuit: CircuitState) {
+        self.status.lock().unwrap().circuit = circuit;
+    }
+
+    /// Send queued samples in batches until the queue closes, tripping the circuit
+    /// breaker when the sink keeps failing.
+    async fn run(self: Arc<Self>, mut queue: mpsc::Receiver<Sample>) {
+        let name = self.config.name().to_owned();
+        let mut buffer = match &self.config.buffer {
+            Some(path) => match DiskBuffer::open(path, self.config.buffer_max_bytes) {
+                Ok(buffer) => Some(buffer),
+                Err(e) => {
+                    error!(sink = %name, error = %e, "could not open the buffer, samples will be dropped");
+                    None
+                }
+            },
+            None => None,
+        };
+        self.status.lock().unwrap().buffered = buffer.as_ref().map_or(0, |b| b.lines);
+        let mut failures = 0;
+        let mut cooldown = MIN_COOLDOWN;
+        while let Some(batch) = self.batch(&mut queue).await {
+            match self.deliver(&mut buffer, &batch).await {
+                Ok(()) => {
+                    let mut status = self.status.lock().unwrap();
+                    if status.circuit != CircuitState::Closed {
+                        info!(sink = %name, "sink recovered, closing the circuit");
+                    }
+                    status.circuit = CircuitState::Closed;
+                    failures = 0;
+                    cooldown = MIN_COOLDOWN;
+                    continue;
+                }
+                Err(e) => {
+                    warn!(sink = %name, error = %e, samples = batch.len(), "could not send samples");
+                    let mut status = self.status.lock().unwrap();
+                    status.failures += 1;
+                    status.last_error = Some(e.to_string());
+                    status.last_error_time = Some(Utc::now());
+                    failures += 1;
+                }
+            }
+            if failures < TRIP_FAILURES {
+                continue;
+            }
+            warn!(
+                sink = %name,
+                cooldown_secs = cooldown.as_secs(),
+                buffered = buffer.is_some(),
+                "sink keeps failing, holding back its samples for a while"
+            );
+            self.set_circuit(CircuitState::Open);
+            let until = tokio::time::Instant::now() + cooldown;
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(until) => break,
+                    sample = queue.recv() => match sample {
+                        Some(sample) => self.keep(&mut buffer, std::slice::from_ref(&sample)),
+                        None => return,
+                    },
+                }
+            }
+            // One more failure opens the circuit again, for longer.
+            self.set_circuit(CircuitState::HalfOpen);
+            failures = TRIP_FAILURES - 1;
+            cooldown = (cooldown * 2).min(MAX_COOLDOWN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Total;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn config(text: &str) -> SinkConfig {
+        toml::from_str(text).unwrap()
+    }
+
+    fn device(watts: f32) -> Device {
+        Device {
+            name: "main".to_owned(),
+            unit: 1,
+            readings: Mutex::new(PowerEwma::from_values(
+                vec!["watts".to_owned()],
+                vec![watts],
+            )),
+            total: Total::Add,
+            labels: Arc::new(Labels::from([("site".to_owned(), "home".to_owned())])),
+            counters: Arc::new([]),
+        }
+    }
+
+    /// Collects what it is sent, failing while `down` is set.
+    struct Collect {
+        samples: mpsc::UnboundedSender<Sample>,
+        down: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Sink for Collect {
+        async fn handle(&self, sample: &Sample) -> std::io::Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(Error::other("down"));
+            }
+            self.samples.send(sample.clone()).unwrap();
+            Ok(())
+        }
+    }
+
+    /// Register a collecting sink under `format`.
+    fn collect(format: &str) -> (mpsc::UnboundedReceiver<Sample>, Arc<AtomicBool>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let down = Arc::new(AtomicBool::new(false));
+        let sink_down = down.clone();
+        register(format, move |_| {
+            Ok(Box::new(Collect {
+                samples: tx.clone(),
+                down: sink_down.clone(),
+            }))
+        });
+        (rx, down)
+    }
+
+    /// Records how many samples it is sent at a time.
+    struct Batches(mpsc::UnboundedSender<usize>);
+
+    #[async_trait]
+    impl Sink for Batches {
+        async fn handle(&self, sample: &Sample) -> std::io::Result<()> {
+            self.handle_batch(std::slice::from_ref(sample)).await
+        }
+
+        async fn handle_batch(&self, samples: &[Sample]) -> std::io::Result<()> {
+            self.0.send(samples.len()).unwrap();
+            Ok(())
+        }
+    }
+
+    /// Wait for the sink's task to catch up.
+    async fn settle(sinks: &Sinks, done: impl Fn(&SinkStatus) -> bool) {
+        while !done(&sinks.status()[0]) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[test]
+    fn samples_round_trip_through_json() {
+        let sample = Sample::new(&device(1500.0));
+        let line = serde_json::to_string(&sample).unwrap();
+        let read: Sample = serde_json::from_str(&line).unwrap();
+        assert_eq!(read.device, "main");
+        assert_eq!(read.labels.get("site").map(String::as_str), Some("home"));
+        assert_eq!(read.readings.get("watts"), Some(1500.0));
+        assert_eq!(read.time, sample.time);
+
+        let bad =
+            r#"{"time":"2024-01-01T00:00:00Z","device":"main","names":["watts"],"values":[]}"#;
+        assert!(serde_json::from_str::<Sample>(bad).is_err());
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        let Err(e) = Sinks::new(&[config("name = \"x\"\nformat = \"carrier-pigeon\"")]) else {
+            panic!("an unknown format was accepted");
+        };
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("sink 'x'"), "{e}");
+    }
+
+    #[cfg(feature = "http-sinks")]
+    #[test]
+    fn names_home_automation_devices() {
+        let openhab = Openhab::new(&config("format = \"openhab\"")).unwrap();
+        assert_eq!(
+            openhab.item("main-1", "watts").as_deref(),
+            Some("sharkmon_main_1_watts")
+        );
+        let text = "format = \"openhab\"\n\
+                    [options.items]\n\
+                    watts = \"House_Power\"\n\
+                    \"solar.watts\" = \"Solar_Power\"\n";
+        let openhab = Openhab::new(&config(text)).unwrap();
+        assert_eq!(
+            openhab.item("main", "watts").as_deref(),
+            Some("House_Power")
+        );
+        assert_eq!(
+            openhab.item("solar", "watts").as_deref(),
+            Some("Solar_Power")
+        );
+        assert_eq!(openhab.item("main", "volts"), None);
+
+        let text =
+            "format = \"domoticz\"\noptions = { idx = { watts = 12, \"solar.watts\" = 13 } }";
+        let domoticz = Domoticz::new(&config(text)).unwrap();
+        assert_eq!(domoticz.idx("main", "watts"), Some(12));
+        assert_eq!(domoticz.idx("solar", "watts"), Some(13));
+        assert_eq!(domoticz.idx("main", "volts"), None);
+        let e = Domoticz::new(&config("format = \"domoticz\"")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        let e = Domoticz::new(&config("options = { idx = { watts = \"12\" } }")).unwrap_err();
+        assert!(e.to_string().contains("'watts'"), "{e}");
+    }
+
+    #[cfg(feature = "http-sinks")]
+    #[test]
+    fn pushes_to_grafana_live_streams() {
+        let grafana = config("url = \"http://grafana:3000/\"\nformat = \"grafana\"");
+        assert_eq!(
+            grafana_push_url(&grafana).unwrap(),
+            "http://grafana:3000/api/live/push/sharkmon"
+        );
+        let grafana = config(
+            "url = \"http://grafana:3000\"\nformat = \"grafana\"\noptions = { stream = \"house\" }",
+        );
+        assert_eq!(
+            grafana_push_url(&grafana).unwrap(),
+            "http://grafana:3000/api/live/push/house"
+        );
+        let line = output::grafana_line(&Sample::new(&device(1500.0)));
+        assert!(line.starts_with("main,site=home watts=1500 "), "{line}");
+    }
+
+    #[cfg(feature = "http-sinks")]
+    #[tokio::test]
+    async fn dry_runs_render_without_sending() {
+        let sinks = Sinks::new(&[
+            config("url = \"http://127.0.0.1:1/write\"\nformat = \"influx\""),
+            config("url = \"http://127.0.0.1:1\"\nformat = \"domoticz\"\noptions = { idx = { watts = 12 } }"),
+        ])
+        .unwrap();
+        let sample = Sample::new(&device(1500.0));
+        let results = sinks.dry_run(std::slice::from_ref(&sample)).await;
+        let (_, check, rendered) = &results[0];
+        assert!(check.is_ok());
+        assert!(
+            rendered[0].starts_with("POST http://127.0.0.1:1/write (text/plain; charset=utf-8)\nsharkmon,device=main,site=home watts=1500 "),
+            "{}",
+            rendered[0]
+        );
+        let (_, _, rendered) = &results[1];
+        assert_eq!(
+            rendered[0],
+            "GET http://127.0.0.1:1/json.htm?type=command&param=udevice&idx=12&nvalue=0&svalue=1500"
+        );
+        assert_eq!(sinks.status()[0].sent, 0);
+    }
+
+    #[tokio::test]
+    async fn registered_sinks_get_published_samples() {
+        let (mut samples, _) = collect("test-published");
+        assert!(is_registered("test-published"));
+        let sinks = Sinks::new(&[config("format = \"test-published\"")])
+            .unwrap()
+            .start();
+        sinks.publish(&device(100.0));
+        sinks.publish(&device(200.0));
+        assert_eq!(
+            samples.recv().await.unwrap().readings.get("watts"),
+            Some(100.0)
+        );
+        assert_eq!(
+            samples.recv().await.unwrap().readings.get("watts"),
+            Some(200.0)
+        );
+        settle(&sinks, |status| status.sent == 2).await;
+    }
+
+    #[tokio::test]
+    async fn samples_are_sent_in_batches() {
+        let (tx, mut batches) = mpsc::unbounded_channel();
+        register("test-batches", move |_| Ok(Box::new(Batches(tx.clone()))));
+        let text =
+            "format = \"test-batches\"\nbatch_size = 3\nflush_interval = \"1h\"\nqueue_depth = 8";
+        let sinks = Sinks::new(&[config(text)]).unwrap().start();
+        for watts in 0..5 {
+            sinks.publish(&device(watts as f32));
+        }
+        assert_eq!(batches.recv().await, Some(3));
+        // The rest wait for the flush interval, or for the queue to close.
+        settle(&sinks, |status| status.queued == 2).await;
+        assert_eq!(sinks.status()[0].queue_depth, 8);
+        drop(sinks);
+        assert_eq!(batches.recv().await, Some(2));
+    }
+
+    #[test]
+    fn deadbands_hold_back_readings_that_barely_move() {
+        let _ = collect("test-deadband");
+        let text = "format = \"test-deadband\"\ndeadband = { watts = 10, \"*\" = \"1%\" }\nmax_interval = \"1m\"";
+        let sinks = Sinks::new(&[config(text)]).unwrap();
+        let runner = &sinks.sinks[0].0;
+        let start = Utc::now();
+        let sample = |secs: i64, watts: f32, volts: f32| Sample {
+            time: start + chrono::Duration::seconds(secs),
+            device: "main".to_owned(),
+            labels: Default::default(),
+            readings: PowerEwma::from_values(
+                vec!["watts".to_owned(), "volts".to_owned()],
+                vec![watts, volts],
+            ),
+        };
+        let sent = |sample: Sample| {
+            runner.filter(&sample).map(|sample| {
+                runner.accepted(&sample);
+                let names: Vec<_> = sample.readings.iter().map(|(name, _)| name).collect();
+                names.join(",")
+            })
+        };
+        assert_eq!(
+            sent(sample(0, 100.0, 240.0)).as_deref(),
+            Some("watts,volts")
+        );
+        assert_eq!(sent(sample(1, 105.0, 241.0)), None);
+        assert_eq!(sent(sample(2, 111.0, 241.0)).as_deref(), Some("watts"));
+        // Volts last went a minute ago, watts only just.
+        assert_eq!(sent(sample(61, 111.0, 241.0)).as_deref(), Some("volts"));
+        assert_eq!(sent(sample(62, f32::NAN, 241.0)).as_deref(), Some("watts"));
+        assert_eq!(sinks.status()[0].suppressed, 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_samples_dont_count_as_sent_for_deadbands() {
+        let (mut samples, down) = collect("test-deadband-dropped");
+        let text = "format = \"test-deadband-dropped\"\ndeadband = { watts = 10 }";
+        let mut sinks = Sinks::new(&[config(text)]).unwrap();
+        // A queue nothing takes from, to fill.
+        let (tx, mut queue) = mpsc::channel(1);
+        sinks.sinks[0].1 = Some(tx);
+        sinks.publish(&device(100.0));
+        sinks.publish(&device(200.0));
+        assert_eq!(sinks.status()[0].dropped, 1);
+        assert_eq!(
+            queue.recv().await.unwrap().readings.get("watts"),
+            Some(100.0)
+        );
+        sinks.publish(&device(200.0));
+        assert_eq!(
+            queue.recv().await.unwrap().readings.get("watts"),
+            Some(200.0)
+        );
+
+        // Nor do samples lost to a failed request.
+        let sinks = Sinks::new(&[config(text)]).unwrap().start();
+        down.store(true, Ordering::SeqCst);
+        sinks.publish(&device(300.0));
+        settle(&sinks, |status| status.dropped == 1).await;
+        down.store(false, Ordering::SeqCst);
+        sinks.publish(&device(300.0));
+        assert_eq!(
+            samples.recv().await.unwrap().readings.get("watts"),
+            Some(300.0)
+        );
+        assert_eq!(sinks.status()[0].suppressed, 0);
+    }
+
+    #[tokio::test]
+    async fn failed_samples_are_buffered_and_sent_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffer = dir.path().join("buffer.jsonl");
+        let (mut samples, down) = collect("test-buffered");
+        let text = format!("format = \"test-buffered\"\nbuffer = {:?}", buffer);
+        let sinks = Sinks::new(&[config(&text)]).unwrap().start();
+
+        down.store(true, Ordering::SeqCst);
+        sinks.publish(&device(1.0));
+        sinks.publish(&device(2.0));
+        settle(&sinks, |status| status.failures == 2).await;
+        assert_eq!(sinks.status()[0].buffered, 2);
+        assert_eq!(sinks.status()[0].dropped, 0);
+
+        down.store(false, Ordering::SeqCst);
+        sinks.publish(&device(3.0));
+        for watts in [1.0, 2.0, 3.0] {
+            assert_eq!(
+                samples.recv().await.unwrap().readings.get("watts"),
+                Some(watts)
+            );
+        }
+        settle(&sinks, |status| status.sent == 3 && status.buffered == 0).await;
+        assert_eq!(std::fs::read_to_string(&buffer).unwrap(), "");
+    }
+}
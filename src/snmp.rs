@@ -0,0 +1,366 @@
+//! SNMP notifications, for network operations tools that collect traps. Each
+//! alert that fires or clears is sent to every receiver as an SNMPv2c trap,
+//! or as an inform, which the receiver acknowledges and is sent again until
+//! it does.
+//!
+//! Notifications are `<oid>.0.1` (firing) and `<oid>.0.2` (cleared), with
+//! the alert's name, device, reading, value and limit as the strings
+//! `<oid>.1.1` to `<oid>.1.5`. The default `<oid>` is under Net-SNMP's
+//! experimental arc; sites with their own enterprise number can set another.
+
+use crate::alert::Event;
+use crate::config::SnmpConfig;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+pub const DEFAULT_OID: &str = "1.3.6.1.4.1.8072.9999.9999.1";
+
+/// SNMP's well-known port for notifications.
+const TRAP_PORT: u16 = 162;
+
+const INFORM_RETRIES: usize = 3;
+
+/// Notifications waiting to be sent, beyond which new ones are dropped.
+const QUEUE_LEN: usize = 100;
+
+// The version field's value for SNMPv2c
+const VERSION_2C: i64 = 1;
+
+// BER tags
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const TIMETICKS: u8 = 0x43;
+const RESPONSE: u8 = 0xa2;
+const INFORM: u8 = 0xa6;
+const TRAP: u8 = 0xa7;
+
+const SYS_UP_TIME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+const SNMP_TRAP_OID: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+
+/// A dotted object identifier, e.g. "1.3.6.1.4.1.8072".
+pub fn parse_oid(s: &str) -> Result<Vec<u32>, String> {
+    let invalid = || format!("invalid SNMP OID '{s}'");
+    let arcs = s
+        .trim_start_matches('.')
+        .split('.')
+        .map(|arc| arc.parse().map_err(|_| invalid()))
+        .collect::<Result<Vec<u32>, _>>()?;
+    match arcs[..] {
+        [first, second, ..] if first < 2 && second < 40 || first == 2 => Ok(arcs),
+        _ => Err(invalid()),
+    }
+}
+
+enum Value<'a> {
+    Integer(i64),
+    Text(&'a str),
+    Oid(&'a [u32]),
+    TimeTicks(u32),
+}
+
+/// Append a BER tag, length and contents.
+fn tlv(out: &mut Vec<u8>, tag: u8, contents: &[u8]) {
+    out.push(tag);
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(contents);
+}
+
+/// The shortest two's complement encoding of `n`.
+fn integer(n: i64) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let mut skip = 0;
+    while skip < 7
+        && (bytes[skip] == 0 && bytes[skip + 1] < 0x80
+            || bytes[skip] == 0xff && bytes[skip + 1] >= 0x80)
+    {
+        skip += 1;
+    }
+    bytes[skip..].to_vec()
+}
+
+fn oid(arcs: &[u32]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let (first, rest) = match arcs {
+        [a, b, rest @ ..] => (a * 40 + b, rest),
+        _ => (0, arcs),
+    };
+    for &arc in std::iter::once(&first).chain(rest) {
+        let groups = (1..5).rev().filter(|&g| arc >> (7 * g) != 0);
+        for g in groups {
+            out.push(0x80 | (arc >> (7 * g)) as u8 & 0x7f);
+        }
+        out.push(arc as u8 & 0x7f);
+    }
+    out
+}
+
+fn value(out: &mut Vec<u8>, v: &Value) {
+    match v {
+        Value::Integer(n) => tlv(out, INTEGER, &integer(*n)),
+        Value::Text(s) => tlv(out, OCTET_STRING, s.as_bytes()),
+        Value::Oid(arcs) => tlv(out, OBJECT_IDENTIFIER, &oid(arcs)),
+        Value::TimeTicks(t) => tlv(out, TIMETICKS, &integer(*t as i64)),
+    }
+}
+
+/// An SNMPv2c message holding a PDU of type `pdu`.
+fn message(community: &str, pdu: u8, request_id: i32, varbinds: &[(Vec<u32>, Value)]) -> Vec<u8> {
+    let mut list = Vec::new();
+    for (name, v) in varbinds {
+        let mut varbind = Vec::new();
+        tlv(&mut varbind, OBJECT_IDENTIFIER, &oid(name));
+        value(&mut varbind, v);
+        tlv(&mut list, SEQUENCE, &varbind);
+    }
+    let mut body = Vec::new();
+    value(&mut body, &Value::Integer(request_id as i64));
+    value(&mut body, &Value::Integer(0)); // error-status
+    value(&mut body, &Value::Integer(0)); // error-index
+    tlv(&mut body, SEQUENCE, &list);
+    let mut contents = Vec::new();
+    value(&mut contents, &Value::Integer(VERSION_2C));
+    value(&mut contents, &Value::Text(community));
+    tlv(&mut contents, pdu, &body);
+    let mut out = Vec::new();
+    tlv(&mut out, SEQUENCE, &contents);
+    out
+}
+
+/// Split a BER element off the front of `data`, as its tag and contents.
+fn element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&first, mut data) = data.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n > 4 || data.len() < n {
+            return None;
+        }
+        let len = data[..n].iter().fold(0, |len, &b| len << 8 | b as usize);
+        data = &data[n..];
+        len
+    };
+    (data.len() >= len).then(|| (tag, &data[..len], &data[len..]))
+}
+
+/// The request id of a Response PDU, which acknowledges an inform.
+fn response_id(packet: &[u8]) -> Option<i32> {
+    let (SEQUENCE, contents, _) = element(packet)? else {
+        return None;
+    };
+    let (_, _, rest) = element(contents)?; // version
+    let (_, _, rest) = element(rest)?; // community
+    let (RESPONSE, pdu, _) = element(rest)? else {
+        return None;
+    };
+    let (INTEGER, id, _) = element(pdu)? else {
+        return None;
+    };
+    if id.is_empty() || id.len() > 4 {
+        return None;
+    }
+    let sign = if id[0] >= 0x80 { -1 } else { 0 };
+    Some(id.iter().fold(sign, |n, &b| n << 8 | b as i32))
+}
+
+/// Sends each alert's events to the receivers, from a task of its own.
+pub struct Notifier {
+    queue: mpsc::Sender<Event>,
+}
+
+impl Notifier {
+    pub async fn start(config: &SnmpConfig) -> std::io::Result<Notifier> {
+        let oid = parse_oid(&config.oid).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+        let (queue, events) = mpsc::channel(QUEUE_LEN);
+        let sender = Sender {
+            socket,
+            config: config.clone(),
+            oid,
+            started: Instant::now(),
+            request_id: 0,
+        };
+        tokio::spawn(sender.run(events));
+        Ok(Notifier { queue })
+    }
+
+    /// Queue a notification of `event`, without waiting.
+    pub fn notify(&self, event: Event) {
+        if self.queue.try_send(event).is_err() {
+            tracing::warn!("too many SNMP notifications queued; dropping one");
+        }
+    }
+}
+
+struct Sender {
+    socket: UdpSocket,
+    config: SnmpConfig,
+    oid: Vec<u32>,
+    started: Instant,
+    request_id: i32,
+}
+
+impl Sender {
+    async fn run(mut self, mut events: mpsc::Receiver<Event>) {
+        while let Some(event) = events.recv().await {
+            for receiver in self.config.receivers.clone() {
+                if let Err(e) = self.send(&receiver, &event).await {
+                    tracing::warn!(receiver, alert = event.alert, error = %e, "couldn't send SNMP notification");
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, receiver: &str, event: &Event) -> std::io::Result<()> {
+        let address = match receiver.contains(':') {
+            true => receiver.to_owned(),
+            false => format!("{receiver}:{TRAP_PORT}"),
+        };
+        let address = tokio::net::lookup_host(address)
+            .await?
+            .find(|a| a.is_ipv4())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no IPv4 address"))?;
+        self.request_id = self.request_id.wrapping_add(1) & i32::MAX;
+        let arc = |tail: &[u32]| [&self.oid[..], tail].concat();
+        let notification = arc(&[0, if event.firing { 1 } else { 2 }]);
+        let (value, limit) = (event.value.to_string(), event.limit.to_string());
+        // Hundredths of a second, wrapping as SNMP's TimeTicks do.
+        let uptime = (self.started.elapsed().as_millis() / 10) as u32;
+        let varbinds = [
+            (SYS_UP_TIME.to_vec(), Value::TimeTicks(uptime)),
+            (SNMP_TRAP_OID.to_vec(), Value::Oid(&notification)),
+            (arc(&[1, 1]), Value::Text(&event.alert)),
+            (arc(&[1, 2]), Value::Text(&event.device)),
+            (arc(&[1, 3]), Value::Text(&event.reading)),
+            (arc(&[1, 4]), Value::Text(&value)),
+            (arc(&[1, 5]), Value::Text(&limit)),
+        ];
+        let pdu = if self.config.inform { INFORM } else { TRAP };
+        let packet = message(
+            self.config.community.expose(),
+            pdu,
+            self.request_id,
+            &varbinds,
+        );
+        if !self.config.inform {
+            self.socket.send_to(&packet, address).await?;
+            return Ok(());
+        }
+        let mut buf = [0; 1500];
+        for _ in 0..INFORM_RETRIES {
+            self.socket.send_to(&packet, address).await?;
+            let deadline = tokio::time::Instant::now() + self.config.timeout;
+            while let Ok(received) =
+                tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await
+            {
+                let (len, from) = received?;
+                if from == address && response_id(&buf[..len]) == Some(self.request_id) {
+                    return Ok(());
+                }
+            }
+        }
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("inform not acknowledged after {INFORM_RETRIES} tries"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn encodes_ber() {
+        assert_eq!(integer(0), [0]);
+        assert_eq!(integer(127), [0x7f]);
+        assert_eq!(integer(128), [0, 0x80]);
+        assert_eq!(integer(-1), [0xff]);
+        assert_eq!(integer(-129), [0xff, 0x7f]);
+        assert_eq!(
+            oid(&parse_oid("1.3.6.1.4.1.8072").unwrap()),
+            [0x2b, 6, 1, 4, 1, 0xbf, 0x08]
+        );
+        let mut long = Vec::new();
+        tlv(&mut long, OCTET_STRING, &[0; 200]);
+        assert_eq!(long[..3], [OCTET_STRING, 0x81, 200]);
+        assert!(parse_oid("1.3.six").is_err());
+        assert!(parse_oid("7.1").is_err());
+
+        let packet = message("public", RESPONSE, 300, &[]);
+        assert_eq!(
+            packet,
+            [
+                0x30, 0x19, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', 0xa2,
+                0x0c, 0x02, 0x02, 0x01, 0x2c, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x00
+            ]
+        );
+        assert_eq!(response_id(&packet), Some(300));
+        assert_eq!(response_id(&message("public", TRAP, 300, &[])), None);
+    }
+
+    #[tokio::test]
+    async fn informs_are_sent_until_acknowledged() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config: crate::config::Config = toml::from_str(&format!(
+            "[snmp]\nreceivers = [\"{}\"]\ninform = true\ntimeout = \"100ms\"\n\
+             [[meter]]\nname = \"main\"\naddress = \"fake:502\"\n",
+            receiver.local_addr().unwrap()
+        ))
+        .unwrap();
+        let notifier = Notifier::start(config.snmp.as_ref().unwrap())
+            .await
+            .unwrap();
+        notifier.notify(Event {
+            alert: "overload".to_owned(),
+            device: "main".to_owned(),
+            reading: "watts".to_owned(),
+            value: 16000.0,
+            limit: 15000.0,
+            firing: true,
+        });
+
+        let mut buf = [0; 1500];
+        // Ignore the first, so that it is sent again.
+        receiver.recv_from(&mut buf).await.unwrap();
+        let (len, from) = receiver.recv_from(&mut buf).await.unwrap();
+        let packet = &buf[..len];
+        let (_, contents, _) = element(packet).unwrap();
+        let (_, _, rest) = element(contents).unwrap();
+        let (_, community, rest) = element(rest).unwrap();
+        assert_eq!(community, b"public");
+        let (pdu, body, _) = element(rest).unwrap();
+        assert_eq!(pdu, INFORM);
+        let mut firing = oid(&parse_oid(DEFAULT_OID).unwrap());
+        firing.extend([0, 1]);
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(&firing));
+        assert!(contains(b"overload") && contains(b"16000"));
+
+        // Acknowledge it by echoing it back as a response.
+        let mut response = packet.to_vec();
+        let at = packet.len() - rest.len();
+        response[at] = RESPONSE;
+        receiver.send_to(&response, from).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), receiver.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+}
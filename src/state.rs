@@ -0,0 +1,166 @@
+//! State worked out from the readings, saved to a file every minute and when
+//! sharkmon stops, and restored when it starts, so a restart doesn't wipe
+//! the day's energy totals or start the smoothing, baselines and quality
+//! scores from nothing. Saved with `--state-file`; nothing is kept otherwise.
+
+use crate::{baseline, energy, meter, quality};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often the state is saved while sharkmon runs.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default, Serialize, Deserialize)]
+struct Saved {
+    saved: Option<DateTime<Utc>>,
+    /// Each device's smoothed readings, by name
+    #[serde(default)]
+    readings: BTreeMap<String, BTreeMap<String, f32>>,
+    #[serde(default)]
+    energy: energy::Saved,
+    #[serde(default)]
+    baselines: baseline::Saved,
+    #[serde(default)]
+    quality: quality::Saved,
+}
+
+pub struct State {
+    path: PathBuf,
+    meters: Vec<Arc<meter::Meter>>,
+    energy: Arc<energy::Energy>,
+    baselines: Arc<baseline::Baselines>,
+    quality: Arc<quality::Quality>,
+}
+
+impl State {
+    pub fn new(
+        path: PathBuf,
+        meters: Vec<Arc<meter::Meter>>,
+        energy: Arc<energy::Energy>,
+        baselines: Arc<baseline::Baselines>,
+        quality: Arc<quality::Quality>,
+    ) -> State {
+        State {
+            path,
+            meters,
+            energy,
+            baselines,
+            quality,
+        }
+    }
+
+    fn devices(&self) -> impl Iterator<Item = &meter::Device> {
+        self.meters.iter().flat_map(|m| &m.devices)
+    }
+
+    /// Carry on from the saved state, if there is any. A file that can't be
+    /// read is reported and left to be overwritten, rather than stopping
+    /// the meters being monitored.
+    pub fn restore(&self) {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!(path = %self.path.display(), error = %e, "could not read the saved state");
+                return;
+            }
+        };
+        let saved: Saved = match serde_json::from_str(&text) {
+            Ok(saved) => saved,
+            Err(e) => {
+                warn!(path = %self.path.display(), error = %e, "ignoring unreadable saved state");
+                return;
+            }
+        };
+        for device in self.devices() {
+            if let Some(readings) = saved.readings.get(&device.name) {
+                device.readings.lock().unwrap().restore(readings);
+            }
+        }
+        self.energy.restore(saved.energy);
+        self.baselines.restore(saved.baselines);
+        self.quality.restore(saved.quality);
+        info!(path = %self.path.display(), saved = ?saved.saved, "restored the saved state");
+    }
+
+    /// Write the state, replacing the file only once it is all written.
+    pub fn save(&self) -> std::io::Result<()> {
+        let readings = self
+            .devices()
+            .map(|d| {
+                let readings = d.readings.lock().unwrap();
+                let values = readings.iter().map(|(n, v)| (n.to_owned(), v)).collect();
+                (d.name.clone(), values)
+            })
+            .collect();
+        let saved = Saved {
+            saved: Some(Utc::now()),
+            readings,
+            energy: self.energy.save(),
+            baselines: self.baselines.save(),
+            quality: self.quality.save(),
+        };
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, serde_json::to_vec(&saved)?)?;
+        std::fs::rename(&temporary, &self.path)
+    }
+
+    /// Save the state every minute, forever.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let state = self.clone();
+            let saved = tokio::task::spawn_blocking(move || state.save()).await;
+            if let Ok(Err(e)) = saved {
+                warn!(path = %self.path.display(), error = %e, "could not save the state");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn restores_what_it_saved() {
+        let config: Config =
+            toml::from_str("[[meter]]\nname = \"main\"\naddress = \"fake:502\"\n").unwrap();
+        let state = |meter| {
+            let path = std::env::temp_dir().join(format!("sharkmon-state-{}", std::process::id()));
+            State::new(
+                path,
+                vec![Arc::new(meter)],
+                Arc::new(energy::Energy::new(Default::default())),
+                Default::default(),
+                Arc::new(quality::Quality::new(&Default::default())),
+            )
+        };
+        let meter = meter::Meter::new(&config.meters[0])
+            .unwrap()
+            .fake(FakeMeter::shark100(1500.0, 240.0, 60.0));
+        meter.poll_once().await.unwrap();
+        let before = state(meter);
+        before.quality.record(&before.meters[0].devices[0]);
+        before.baselines.record(&before.meters[0].devices[0]);
+        before.save().unwrap();
+
+        let after = state(meter::Meter::new(&config.meters[0]).unwrap());
+        after.restore();
+        std::fs::remove_file(&after.path).unwrap();
+        let readings = after.meters[0].devices[0].readings.lock().unwrap().clone();
+        assert_eq!(readings.get("watts"), Some(1500.0));
+        assert_eq!(after.quality.hours("main").unwrap()[0].samples, 1);
+        assert_eq!(after.baselines.save().len(), 1);
+    }
+}
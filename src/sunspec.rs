@@ -0,0 +1,149 @@
+//! SunSpec device discovery. SunSpec inverters and meters describe their own
+//! registers as a chain of "models" following a "SunS" marker; sharkmon walks
+//! the chain on connect and maps the first AC meter or inverter model it finds
+//! onto its usual watts/volts/frequency readings.
+
+use crate::client::MeterClient;
+use crate::registers::{Format, Function, Metric, Register, RegisterMap};
+use std::io::{Error, ErrorKind};
+
+/// The readings produced from any SunSpec model.
+pub const NAMES: [&str; 3] = ["watts", "volts", "frequency"];
+pub const UNITS: [&str; 3] = ["W", "V", "Hz"];
+
+/// Where the "SunS" marker may start, in the order SunSpec recommends probing.
+const BASE_ADDRESSES: [u16; 3] = [40000, 50000, 0];
+const MARKER: [u16; 2] = [0x5375, 0x6e53];
+const END_OF_CHAIN: u16 = 0xffff;
+
+/// Register offsets of one reading within a model body, with its scale
+/// factor register for the integer models.
+struct Field {
+    offset: u16,
+    scale_offset: Option<u16>,
+    format: Format,
+}
+
+const fn int_field(offset: u16, scale_offset: u16, format: Format) -> Field {
+    Field {
+        offset,
+        scale_offset: Some(scale_offset),
+        format,
+    }
+}
+
+const fn float_field(offset: u16) -> Field {
+    Field {
+        offset,
+        scale_offset: None,
+        format: Format::FloatBe,
+    }
+}
+
+/// The watts, volts and frequency fields of a supported model.
+fn model_fields(id: u16) -> Option<[Field; 3]> {
+    use Format::*;
+    Some(match id {
+        // Inverters (single, split and three phase), integer + scale factor
+        101..=103 => [
+            int_field(12, 13, Int16),
+            int_field(8, 11, Uint16),
+            int_field(14, 15, Uint16),
+        ],
+        // Inverters, float
+        111..=113 => [float_field(20), float_field(14), float_field(22)],
+        // AC meters, integer + scale factor
+        201..=204 => [
+            int_field(16, 20, Int16),
+            int_field(5, 13, Int16),
+            int_field(14, 15, Int16),
+        ],
+        // AC meters, float
+        211..=214 => [float_field(26), float_field(8), float_field(24)],
+        _ => return None,
+    })
+}
+
+async fn read(ctx: &mut dyn MeterClient, address: u16, len: u16) -> std::io::Result<Vec<u16>> {
+    ctx.read(Function::Holding, address, len).await
+}
+
+/// Find the SunSpec model chain on the device and build a register map for
+/// the first supported meter or inverter model in it.
+pub async fn discover(ctx: &mut dyn MeterClient) -> std::io::Result<RegisterMap> {
+    let mut base = None;
+    for address in BASE_ADDRESSES {
+        // Devices answer probes of unused addresses with an exception.
+        if let Ok(marker) = read(ctx, address, 2).await {
+            if marker == MARKER {
+                base = Some(address);
+                break;
+            }
+        }
+    }
+    let mut address =
+        base.ok_or_else(|| Error::new(ErrorKind::NotFound, "no SunSpec marker found"))? + 2;
+
+    let mut seen = Vec::new();
+    loop {
+        let header = read(ctx, address, 2).await?;
+        let (id, len) = (header[0], header[1]);
+        if id == END_OF_CHAIN {
+            break;
+        }
+        let body = address
+            .checked_add(2)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "SunSpec model chain overflows"))?;
+        if let Some(fields) = model_fields(id) {
+            tracing::info!("using SunSpec model {id} at {body}");
+            let needed = fields
+                .iter()
+                .map(|f| f.scale_offset.unwrap_or(0).max(f.offset + 1) + 1)
+                .max()
+                .unwrap_or(0);
+            let data = read(ctx, body, needed.min(len)).await?;
+            let metrics = NAMES
+                .iter()
+                .zip(UNITS)
+                .zip(fields)
+                .map(|((name, units), field)| {
+                    let scale = match field.scale_offset.and_then(|o| data.get(o as usize)) {
+                        // 0x8000 marks an unimplemented scale factor
+                        Some(&sf) if sf != 0x8000 => 10f64.powi(sf as i16 as i32),
+                        _ => 1.0,
+                    };
+                    Metric {
+                        name: name.to_string(),
+                        register: Register {
+                            address: body + field.offset,
+                            format: field.format,
+                            scale,
+                        },
+                        units: units.to_owned(),
+                        group: "fast".to_owned(),
+                        function: None,
+                    }
+                })
+                .collect();
+            return Ok(RegisterMap {
+                function: Function::Holding,
+                groups: Default::default(),
+                resets: Default::default(),
+                clock: None,
+                log: None,
+                limits: None,
+                firmware: None,
+                option_cards: Vec::new(),
+                metrics,
+            });
+        }
+        seen.push(id);
+        address = body
+            .checked_add(len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "SunSpec model chain overflows"))?;
+    }
+    Err(Error::new(
+        ErrorKind::NotFound,
+        format!("no supported SunSpec meter or inverter model (found {seen:?})"),
+    ))
+}
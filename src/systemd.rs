@@ -0,0 +1,54 @@
+//! systemd integration for running as a `Type=notify` service: readiness is
+//! reported once the first poll succeeds, and the watchdog is fed while every
+//! meter's poll loop is making progress. Outside systemd these do nothing.
+
+use crate::meter::Meter;
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+fn notify(state: sd_notify::NotifyState) {
+    if let Err(e) = sd_notify::notify(&[state]) {
+        tracing::warn!(error = %e, "could not notify systemd");
+    }
+}
+
+/// Tell systemd the service has started. Only the first call has any effect.
+pub fn ready() {
+    static READY: Once = Once::new();
+    READY.call_once(|| {
+        #[cfg(target_os = "linux")]
+        notify(sd_notify::NotifyState::Ready);
+    });
+}
+
+/// Tell systemd why the service is exiting, for `systemctl status`.
+pub fn failed(error: &str) {
+    #[cfg(target_os = "linux")]
+    notify(sd_notify::NotifyState::Status(error));
+    #[cfg(not(target_os = "linux"))]
+    let _ = error;
+}
+
+/// The watchdog timeout systemd expects us to keep within, if it is enabled.
+pub fn watchdog_timeout() -> Option<Duration> {
+    #[cfg(target_os = "linux")]
+    return sd_notify::watchdog_enabled();
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// Feed the watchdog at half its timeout, unless a connected meter has stopped
+/// polling, in which case systemd will restart the process.
+pub async fn watchdog(meters: Vec<Arc<Meter>>, timeout: Duration) -> ! {
+    let mut interval = tokio::time::interval(timeout / 2);
+    loop {
+        interval.tick().await;
+        if let Some(m) = meters.iter().find(|m| m.stalled(timeout)) {
+            tracing::error!(meter = %m.name, "poll loop stalled, not feeding the watchdog");
+            continue;
+        }
+        #[cfg(target_os = "linux")]
+        notify(sd_notify::NotifyState::Watchdog);
+    }
+}
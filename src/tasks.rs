@@ -0,0 +1,123 @@
+//! The poll loops and servers of a run, joined so that one failing ends the
+//! run with its error rather than leaving the rest going without it. A poll
+//! loop, or a consumer of the readings bus, only ends if it panics, which
+//! always ends the run; a server failing, to start or afterwards, ends it
+//! unless `server_failure` says to continue.
+
+use crate::config::ServerFailure;
+use crate::exit;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use tokio::task::{Id, JoinSet};
+use tracing::{error, warn};
+
+pub struct Tasks {
+    set: JoinSet<io::Result<()>>,
+    /// Each task's name, and whether it is a server
+    names: HashMap<Id, (String, bool)>,
+    server_failure: ServerFailure,
+}
+
+impl Tasks {
+    pub fn new(server_failure: ServerFailure) -> Tasks {
+        Tasks {
+            set: JoinSet::new(),
+            names: HashMap::new(),
+            server_failure,
+        }
+    }
+
+    /// Run a meter's poll loop, or another task the run can't go on
+    /// without, such as a consumer of the readings bus.
+    pub fn poll(
+        &mut self,
+        name: String,
+        task: impl Future<Output = io::Result<()>> + Send + 'static,
+    ) {
+        let id = self.set.spawn(task).id();
+        self.names.insert(id, (name, false));
+    }
+
+    /// Run a server, called `name` in its errors.
+    pub fn serve(
+        &mut self,
+        name: &str,
+        task: impl Future<Output = io::Result<()>> + Send + 'static,
+    ) {
+        let id = self.set.spawn(task).id();
+        self.names.insert(id, (name.to_owned(), true));
+    }
+
+    /// What the server `name` failing with `e` means for the run: its end,
+    /// or only a message if servers may fail.
+    pub fn server_failed(&self, name: &str, e: io::Error) -> io::Result<()> {
+        match self.server_failure {
+            ServerFailure::Exit => Err(exit::context(name, e)),
+            ServerFailure::Continue => {
+                error!(error = %e, "{name} failed; polling continues without it");
+                Ok(())
+            }
+        }
+    }
+
+    /// Wait for a task to end in a way that ends the run, giving the error
+    /// to exit with.
+    pub async fn failure(&mut self) -> io::Error {
+        loop {
+            let Some(joined) = self.set.join_next_with_id().await else {
+                return std::future::pending().await;
+            };
+            let (id, result) = match joined {
+                Ok((id, result)) => (id, result),
+                Err(e) => (e.id(), Err(io::Error::other(e))),
+            };
+            let Some((name, server)) = self.names.remove(&id) else {
+                continue;
+            };
+            match result {
+                Err(e) if server => match self.server_failed(&name, e) {
+                    Ok(()) => continue,
+                    Err(e) => return e,
+                },
+                Ok(()) if server => warn!("{name} stopped"),
+                Err(e) => return exit::context(&name, e),
+                Ok(()) => return io::Error::other(format!("{name} stopped")),
+            }
+        }
+    }
+
+    /// Stop every task.
+    pub async fn stop(mut self) {
+        self.set.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn failing() -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "address in use"))
+    }
+
+    #[tokio::test]
+    async fn failures_end_the_run_as_configured() {
+        let mut tasks = Tasks::new(ServerFailure::Exit);
+        tasks.serve("BACnet/IP server", failing());
+        let e = tasks.failure().await;
+        assert_eq!(e.to_string(), "BACnet/IP server: address in use");
+
+        // A server may fail, but a poll loop ending still ends the run.
+        let mut tasks = Tasks::new(ServerFailure::Continue);
+        tasks.serve("BACnet/IP server", failing());
+        tasks.poll("meter 'main'".to_owned(), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            panic!("poll loop panicked")
+        });
+        let e = tasks.failure().await;
+        assert!(e.to_string().starts_with("meter 'main': "), "{e}");
+        tasks.stop().await;
+    }
+}
@@ -0,0 +1,107 @@
+//! The timezone of human-facing output: CSV and console timestamps, the
+//! dashboard, and the boundaries of daily summaries, such as history steps
+//! and archive partitions, so that a day's energy runs from local midnight.
+//! UTC unless `--timezone` names an IANA zone, e.g. "America/New_York".
+
+use chrono::{DateTime, FixedOffset, Utc};
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use std::io::{Error, ErrorKind};
+
+#[derive(Debug, Clone)]
+pub struct Zone {
+    name: String,
+    tz: TimeZone,
+}
+
+impl Default for Zone {
+    fn default() -> Zone {
+        Zone {
+            name: "UTC".to_owned(),
+            tz: TimeZone::UTC,
+        }
+    }
+}
+
+impl Zone {
+    /// The IANA zone `name`, from the system's timezone database.
+    pub fn new(name: &str) -> std::io::Result<Zone> {
+        let tz = TimeZone::get(name).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown timezone '{name}': {e}"),
+            )
+        })?;
+        Ok(Zone {
+            name: name.to_owned(),
+            tz,
+        })
+    }
+
+    #[cfg_attr(not(feature = "web"), allow(dead_code))]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The zone's offset from UTC at `millis` since the epoch, in seconds.
+    fn offset(&self, millis: i64) -> i32 {
+        Timestamp::from_millisecond(millis).map_or(0, |t| self.tz.to_offset(t).seconds())
+    }
+
+    /// `time` as the zone's local time.
+    pub fn local(&self, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+        let offset = self.offset(time.timestamp_millis());
+        let offset = FixedOffset::east_opt(offset).unwrap_or(FixedOffset::east_opt(0).unwrap());
+        time.with_timezone(&offset)
+    }
+
+    /// The start of the step `millis` since the epoch falls in, for steps of
+    /// `step` milliseconds counted from local midnight, e.g. the local day.
+    pub fn step_start(&self, millis: i64, step: i64) -> i64 {
+        let local = millis + self.offset(millis) as i64 * 1000;
+        let start = local.div_euclid(step) * step;
+        // The local start as UTC: a day that begins in a DST change begins
+        // when the clocks say it does.
+        let utc = Timestamp::from_millisecond(start).ok().and_then(|t| {
+            let civil = t.to_zoned(TimeZone::UTC).datetime();
+            let zoned = self.tz.to_ambiguous_timestamp(civil).compatible().ok()?;
+            Some(zoned.as_millisecond())
+        });
+        utc.unwrap_or(start - self.offset(millis) as i64 * 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_start_at_local_midnight() {
+        let utc = Zone::default();
+        let day = 24 * 60 * 60 * 1000;
+        let time = DateTime::parse_from_rfc3339("2024-03-10T15:00:00Z").unwrap();
+        let millis = time.timestamp_millis();
+        let midnight = |s| DateTime::parse_from_rfc3339(s).unwrap().timestamp_millis();
+        assert_eq!(
+            utc.step_start(millis, day),
+            midnight("2024-03-10T00:00:00Z")
+        );
+        assert_eq!(
+            utc.local(time.to_utc()).to_rfc3339(),
+            "2024-03-10T15:00:00+00:00"
+        );
+
+        // US clocks went forward that morning, so local midnight was still EST.
+        let new_york = Zone::new("America/New_York").unwrap();
+        let start = new_york.step_start(millis, day);
+        assert_eq!(start, midnight("2024-03-10T00:00:00-05:00"));
+        let later = midnight("2024-03-11T03:00:00Z");
+        assert_eq!(
+            new_york.step_start(later, day),
+            midnight("2024-03-10T00:00:00-05:00")
+        );
+        let local = new_york.local(time.to_utc());
+        assert_eq!(local.to_rfc3339(), "2024-03-10T11:00:00-04:00");
+        assert!(Zone::new("Mars/Olympus_Mons").is_err());
+    }
+}
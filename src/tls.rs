@@ -0,0 +1,216 @@
+//! TLS client support for secure Modbus (Modbus/TCP Security, a.k.a. MBAPS,
+//! conventionally on port 802). The meter connection is an ordinary Modbus
+//! TCP session carried inside a TLS 1.2+ stream. Also the web server's
+//! HTTPS, with its optional client certificates.
+
+#[cfg(feature = "web")]
+use crate::config::{Role, WebTlsConfig};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+#[cfg(feature = "web")]
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// A ready-to-use TLS client configuration for talking to the meter.
+#[derive(Clone)]
+pub struct MeterTls {
+    connector: TlsConnector,
+    server_name: Option<String>,
+}
+
+fn invalid_data<E: std::fmt::Display>(what: &Path) -> impl FnOnce(E) -> Error + '_ {
+    move |e| Error::new(ErrorKind::InvalidData, format!("{}: {e}", what.display()))
+}
+
+/// The CA certificates in the PEM file `ca`.
+fn roots(ca: &Path) -> std::io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for c in CertificateDer::pem_file_iter(ca).map_err(invalid_data(ca))? {
+        roots
+            .add(c.map_err(invalid_data(ca))?)
+            .map_err(invalid_data(ca))?;
+    }
+    if roots.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: no CA certificates found", ca.display()),
+        ));
+    }
+    Ok(roots)
+}
+
+/// The certificate chain in the PEM file `cert`.
+fn chain(cert: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(cert)
+        .map_err(invalid_data(cert))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid_data(cert))
+}
+
+impl MeterTls {
+    /// Build a client configuration that trusts the certificates in `ca`, and
+    /// optionally presents the client certificate chain in `cert` signed by
+    /// `key`. Secure Modbus devices generally require a client certificate.
+    pub fn new(
+        ca: &Path,
+        cert: Option<&Path>,
+        key: Option<&Path>,
+        server_name: Option<String>,
+    ) -> std::io::Result<MeterTls> {
+        let roots = roots(ca)?;
+        let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+            .with_root_certificates(roots);
+
+        let config = match (cert, key) {
+            (Some(cert), Some(key)) => {
+                let key_der = PrivateKeyDer::from_pem_file(key).map_err(invalid_data(key))?;
+                builder
+                    .with_client_auth_cert(chain(cert)?, key_der)
+                    .map_err(invalid_data(cert))?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "a TLS client certificate and key must be given together",
+                ))
+            }
+        };
+
+        Ok(MeterTls {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// Perform the TLS handshake over an established TCP connection to `meter`.
+    /// The certificate is verified against the configured server name, or the
+    /// host part of `meter` if none was given.
+    pub async fn connect(
+        &self,
+        meter: &str,
+        stream: TcpStream,
+    ) -> std::io::Result<TlsStream<TcpStream>> {
+        let host = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => meter
+                .rsplit_once(':')
+                .map_or(meter, |(host, _port)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']'),
+        };
+        let server_name = ServerName::try_from(host.to_owned())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, format!("{host}: {e}")))?;
+        self.connector.connect(server_name, stream).await
+    }
+}
+
+/// The web server's HTTPS configuration, and the access each client
+/// certificate gives.
+#[cfg(feature = "web")]
+#[derive(Clone)]
+pub struct WebTls {
+    pub acceptor: tokio_rustls::TlsAcceptor,
+    certificate: Arc<Certificate>,
+    access: std::collections::BTreeMap<String, Role>,
+}
+
+/// The certificate the web server presents, which can be replaced while it
+/// runs; handshakes fail while there is none.
+#[cfg(feature = "web")]
+#[derive(Debug, Default)]
+struct Certificate(std::sync::RwLock<Option<Arc<CertifiedKey>>>);
+
+#[cfg(feature = "web")]
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+#[cfg(feature = "web")]
+impl WebTls {
+    /// HTTPS with the certificate in `config`. With ACME, that may not have
+    /// been obtained yet.
+    pub fn new(config: &WebTlsConfig) -> std::io::Result<WebTls> {
+        let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let builder = match &config.client_ca {
+            Some(ca) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots(ca)?), provider)
+                        .build()
+                        .map_err(invalid_data(ca))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let certificate = Arc::new(Certificate::default());
+        let tls = WebTls {
+            acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(
+                builder.with_cert_resolver(certificate.clone()),
+            )),
+            certificate,
+            access: config.access.clone(),
+        };
+        match tls.reload(config) {
+            Err(e) if config.acme.is_some() && !config.cert.exists() => {
+                tracing::debug!(error = %e, "no certificate yet");
+            }
+            result => result?,
+        }
+        Ok(tls)
+    }
+
+    /// Present the certificate now in `config`'s files.
+    pub fn reload(&self, config: &WebTlsConfig) -> std::io::Result<()> {
+        let key = PrivateKeyDer::from_pem_file(&config.key).map_err(invalid_data(&config.key))?;
+        let provider = tokio_rustls::rustls::crypto::ring::default_provider();
+        let certified = CertifiedKey::from_der(chain(&config.cert)?, key, &provider)
+            .map_err(invalid_data(&config.cert))?;
+        *self.certificate.0.write().unwrap() = Some(Arc::new(certified));
+        Ok(())
+    }
+
+    /// The name and access of the certificate a client verified with, if
+    /// it did.
+    pub fn client(
+        &self,
+        stream: &tokio_rustls::server::TlsStream<TcpStream>,
+    ) -> Option<(String, Role)> {
+        let certificate = stream.get_ref().1.peer_certificates()?.first()?;
+        let name = common_name(certificate)?;
+        let access = self.access.get(&name).copied().unwrap_or_default();
+        Some((name, access))
+    }
+}
+
+/// The common name (CN) in a DER certificate's subject.
+#[cfg(feature = "web")]
+fn common_name(certificate: &[u8]) -> Option<String> {
+    use x509_cert::der::{oid::db::rfc4519::CN, Decode};
+    let certificate = x509_cert::Certificate::from_der(certificate).ok()?;
+    let subject = &certificate.tbs_certificate.subject;
+    let name = subject
+        .0
+        .iter()
+        .flat_map(|rdn| rdn.0.iter())
+        .find(|attribute| attribute.oid == CN)?;
+    // UTF8String, PrintableString and IA5String all hold the text as is.
+    String::from_utf8(name.value.value().to_vec()).ok()
+}
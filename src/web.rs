@@ -0,0 +1,2211 @@
+//! The web server: the dashboard, the current readings and history in JSON
+//! and other formats, live streams, `/status` and `/metrics`, and the
+//! endpoints that change meter state.
+
+use crate::exit::{self, Exit};
+use crate::registers::{self, ResetKind};
+use crate::{
+    alert, audit, baseline, bus, config, energy, events, export, fanout, history, homeassistant,
+    meter, metrics, output, proto, quality, sink, timezone, tls,
+};
+use axum::{
+    extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// The web page, with the devices filled in by `dashboard`.
+const DASHBOARD: &str = include_str!("../assets/dashboard.html");
+const DASHBOARD_JS: &str = include_str!("../assets/dashboard.js");
+
+/// Readings that add up across devices in `/power/total`.
+const TOTALS: [&str; 2] = ["watts", "kwh"];
+
+/// The port the web server listens on, unless given another address.
+pub const PORT: u16 = 8081;
+
+/// How long `/power/next` waits for a poll, unless told otherwise.
+const DEFAULT_LONG_POLL: Duration = Duration::from_secs(30);
+
+/// The longest `/power/next` can be told to wait.
+const MAX_LONG_POLL: Duration = Duration::from_secs(300);
+
+/// How long an HTTPS client has to finish the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest JSONP callback name accepted.
+const MAX_CALLBACK_LEN: usize = 64;
+
+/// Clients whose allowances are kept before those that have refilled are
+/// forgotten.
+const MAX_RATE_LIMITED_CLIENTS: usize = 1024;
+
+/// Everything the web handlers need.
+pub struct AppState {
+    pub meters: Vec<Arc<meter::Meter>>,
+    pub sinks: Arc<sink::Sinks>,
+    pub history: Option<Arc<history::History>>,
+    /// Every reading as it is polled, for the streams
+    pub bus: bus::Bus,
+    /// How WebSocket clients are keeping up
+    pub websockets: Arc<fanout::Stats>,
+    /// Generator transfers and other events seen in the readings
+    pub events: Arc<events::Events>,
+    /// Each device's hourly power quality scores
+    pub quality: Arc<quality::Quality>,
+    /// Each device's watts yesterday and last week
+    pub baselines: Arc<baseline::Baselines>,
+    /// Each device's daily energy, from its energy counters
+    pub energy: Arc<energy::Energy>,
+    /// The timezone the dashboard and CSV exports show times in
+    pub zone: timezone::Zone,
+    pub api_keys: Vec<config::ApiKey>,
+    /// How many requests are served at once, if that is limited
+    pub max_requests_in_flight: Option<usize>,
+    /// Each client's allowance of requests, if that is limited
+    pub rate_limit: Option<Arc<RateLimit>>,
+    /// Whether `/power` answers `?callback=` with JSONP
+    pub jsonp: bool,
+    /// Where changes made through the API are recorded
+    pub audit: Arc<audit::Audit>,
+    pub alerts: Option<Arc<alert::Alerts>>,
+    /// The configuration file that changes made through `/api/v1/config`
+    /// are saved to, if there is one
+    pub config_path: Option<std::path::PathBuf>,
+}
+
+impl AppState {
+    fn devices(&self) -> impl Iterator<Item = &meter::Device> {
+        self.meters.iter().flat_map(|m| &m.devices)
+    }
+
+    /// When the meter with the device last finished a poll.
+    fn polled(&self, device: &str) -> Option<DateTime<Utc>> {
+        let (meter, _) = crate::find_device(&self.meters, device).ok()?;
+        let polled = meter.status.lock().unwrap().last_poll;
+        polled
+    }
+
+    /// The device's readings, with its watts yesterday and last week.
+    fn power(&self, device: &meter::Device) -> Power {
+        let readings = device.readings.lock().unwrap().clone();
+        let baseline = self.baselines.compare(&device.name, readings.get("watts"));
+        Power { readings, baseline }
+    }
+
+    /// The API key the request carries as a bearer token, if it is one.
+    fn api_key(&self, headers: &HeaderMap) -> Option<&config::ApiKey> {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))?;
+        self.api_keys
+            .iter()
+            .find(|key| constant_time_eq(key.key.expose().as_bytes(), token.as_bytes()))
+    }
+
+    /// Who the client is, as the audit log names them, if their client
+    /// certificate or API key has the `needed` role, or else the response
+    /// refusing the request. Refusals are logged to the audit log.
+    fn authorize(
+        &self,
+        headers: &HeaderMap,
+        client: SocketAddr,
+        certificate: Option<&ClientCertificate>,
+        needed: config::Role,
+        action: &str,
+    ) -> Result<String, Box<axum::response::Response>> {
+        let named = |name: Option<&str>| match name {
+            Some(name) => format!("{name} ({})", client.ip()),
+            None => client.ip().to_string(),
+        };
+        let certificate_name = certificate.map(|c| c.name.as_str());
+        if certificate.is_some_and(|c| c.role >= needed) {
+            return Ok(named(certificate_name));
+        }
+        if self.api_keys.is_empty() {
+            let message = "no API keys are configured, so this endpoint is disabled";
+            return Err(Box::new((StatusCode::FORBIDDEN, message).into_response()));
+        }
+        let Some(key) = self.api_key(headers) else {
+            tracing::warn!(
+                target: "sharkmon::audit",
+                client = named(certificate_name),
+                action,
+                "rejected a request without a valid API key"
+            );
+            let refusal = (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                "a valid API key is required",
+            );
+            return Err(Box::new(refusal.into_response()));
+        };
+        let client = named(key.name.as_deref().or(certificate_name));
+        if key.role < needed {
+            tracing::warn!(
+                target: "sharkmon::audit",
+                client,
+                action,
+                role = %key.role,
+                "rejected a request from a key without the role for it"
+            );
+            let message = format!("this needs an {needed}'s API key, not a {}'s", key.role);
+            return Err(Box::new((StatusCode::FORBIDDEN, message).into_response()));
+        }
+        Ok(client)
+    }
+}
+
+/// The client certificate an HTTPS request came with, if it came with one.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    /// Its common name (CN)
+    pub name: String,
+    pub role: config::Role,
+}
+
+/// Compare without returning early at the first difference, so response times
+/// don't reveal how much of a key was guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Each client address's allowance of requests: a bucket holding up to a
+/// second's worth, which refills at `per_second`, so a dashboard loading its
+/// page and assets isn't held up but one polling at 100 Hz is.
+pub struct RateLimit {
+    per_second: f64,
+    clients: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64) -> RateLimit {
+        RateLimit {
+            per_second,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a request from the client's allowance, or else say how long
+    /// until there is one.
+    fn take(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let burst = self.per_second.max(1.0);
+        let refilled = |tokens: f64, at: Instant| {
+            (tokens + now.duration_since(at).as_secs_f64() * self.per_second).min(burst)
+        };
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_RATE_LIMITED_CLIENTS && !clients.contains_key(&client) {
+            // Forgetting a full bucket loses nothing.
+            clients.retain(|_, (tokens, at)| refilled(*tokens, *at) < burst);
+        }
+        let (tokens, at) = clients.entry(client).or_insert((burst, now));
+        *tokens = refilled(*tokens, *at);
+        *at = now;
+        if *tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - *tokens) / self.per_second));
+        }
+        *tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/// Refuse a request beyond the client's allowance with 429, saying when to
+/// try again.
+async fn rate_limit<B>(
+    State(state): State<Arc<AppState>>,
+    request: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> axum::response::Response {
+    let client = request.extensions().get::<ConnectInfo<SocketAddr>>();
+    if let (Some(limit), Some(ConnectInfo(client))) = (&state.rate_limit, client) {
+        if let Err(wait) = limit.take(client.ip()) {
+            let retry = wait.as_secs_f64().ceil().max(1.0).to_string();
+            let message = "too many requests from this address";
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry)],
+                message,
+            )
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// The response to a request beyond `max_requests_in_flight`.
+async fn overloaded(_: tower::BoxError) -> (StatusCode, &'static str) {
+    let message = "too many requests are being served; try again shortly";
+    (StatusCode::SERVICE_UNAVAILABLE, message)
+}
+
+/// `GET /`: the dashboard, told which devices there are, whether history is
+/// kept, and the timezone to show times in.
+async fn dashboard(State(state): State<Arc<AppState>>) -> axum::response::Html<String> {
+    let devices: Vec<&str> = state.devices().map(|d| d.name.as_str()).collect();
+    let config = serde_json::json!({
+        "devices": devices,
+        "history": state.history.is_some(),
+        "timezone": state.zone.name(),
+    });
+    // Keep device names from closing the script element.
+    let config = config.to_string().replace('<', "\\u003c");
+    axum::response::Html(DASHBOARD.replace("{{config}}", &config))
+}
+
+async fn dashboard_js() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/javascript; charset=utf-8")],
+        DASHBOARD_JS,
+    )
+}
+
+/// A device's readings as `/power` shows them.
+#[derive(Serialize)]
+struct Power {
+    #[serde(flatten)]
+    readings: meter::PowerEwma,
+    #[serde(flatten)]
+    baseline: baseline::Comparison,
+}
+
+async fn power(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<JsonpQuery>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let device = state.devices().next().expect("no devices configured");
+    if let Some(callback) = &query.callback {
+        return jsonp(&state, callback, &state.power(device));
+    }
+    // Before the readings, so they are never older than the tag says.
+    let polled = state.polled(&device.name);
+    negotiate_polled(&headers, polled, &state.power(device))
+}
+
+/// `GET /power/total`: the power and energy of every device added up, with
+/// those marked to subtract taken away and those excluded left out.
+async fn power_total(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let polled = state
+        .meters
+        .iter()
+        .filter_map(|m| m.status.lock().unwrap().last_poll)
+        .max();
+    let mut totals: std::collections::BTreeMap<&str, f32> = Default::default();
+    for device in state.devices() {
+        let sign = match device.total {
+            config::Total::Add => 1.0,
+            config::Total::Subtract => -1.0,
+            config::Total::Exclude => continue,
+        };
+        let readings = device.readings.lock().unwrap();
+        for (name, value) in readings.iter() {
+            if let Some(total) = TOTALS.iter().find(|t| **t == name) {
+                *totals.entry(total).or_default() += sign * value;
+            }
+        }
+    }
+    negotiate_polled(&headers, polled, &totals)
+}
+
+#[derive(Deserialize)]
+struct NextQuery {
+    /// The first device if not given
+    device: Option<String>,
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<std::time::Duration>,
+}
+
+/// `GET /power/next?device=&timeout=`: a device's readings, as `/power`
+/// gives them, once it is next polled, for clients that can't take a
+/// WebSocket; 204 No Content if it isn't polled within the timeout.
+async fn power_next(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<NextQuery>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let device = match &query.device {
+        Some(name) => state.devices().find(|d| &d.name == name),
+        None => state.devices().next(),
+    };
+    let Some(device) = device else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let timeout = query.timeout.unwrap_or(DEFAULT_LONG_POLL);
+    if timeout > MAX_LONG_POLL {
+        let message = format!(
+            "the timeout can be at most {}",
+            humantime::format_duration(MAX_LONG_POLL)
+        );
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    let mut readings = state.bus.subscribe();
+    let next = async {
+        loop {
+            match readings.recv().await {
+                Ok(reading) if reading.device.name == device.name => return true,
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return false,
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, next).await {
+        // Untagged: the poll isn't finished, so its time isn't known yet.
+        Ok(true) => negotiate(&headers, &state.power(device)),
+        _ => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+async fn device_power(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<JsonpQuery>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let Some(device) = state.devices().find(|d| d.name == name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Some(callback) = &query.callback {
+        return jsonp(&state, callback, &state.power(device));
+    }
+    let polled = state.polled(&device.name);
+    negotiate_polled(&headers, polled, &state.power(device))
+}
+
+/// `GET /power.pb`: every device's readings as a `sharkmon.v1.Readings`
+/// Protobuf message.
+async fn power_protobuf(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    use prost::Message;
+    let readings = proto::Readings {
+        readings: state.devices().map(proto::Reading::new).collect(),
+    };
+    (
+        [(header::CONTENT_TYPE, "application/x-protobuf")],
+        readings.encode_to_vec(),
+    )
+}
+
+#[derive(Deserialize)]
+struct JsonpQuery {
+    callback: Option<String>,
+}
+
+/// `value` as a script calling `callback` with it, for clients that load
+/// the readings with a `<script>` element, if `--jsonp` allows it. The name
+/// must be a plain JavaScript identifier, perhaps with dots, so that the
+/// script can't be made to do anything else.
+fn jsonp<T: Serialize>(state: &AppState, callback: &str, value: &T) -> axum::response::Response {
+    if !state.jsonp {
+        let message = "JSONP is off; start sharkmon with --jsonp to allow ?callback=";
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    let identifier = |part: &str| {
+        part.chars().next().is_some_and(|c| !c.is_ascii_digit())
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+    };
+    if callback.len() > MAX_CALLBACK_LEN || !callback.split('.').all(identifier) {
+        let message = "the callback should be a JavaScript function name";
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    // The comment keeps the body from starting with bytes a plugin could
+    // take for something other than a script.
+    let body = format!("/**/{callback}({});", serde_json::to_string(value).unwrap());
+    (
+        [
+            (header::CONTENT_TYPE, "text/javascript; charset=utf-8"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// `value` as JSON, or as MessagePack or CBOR if the client's Accept header
+/// asks for them, for consumers on slow links.
+fn negotiate<T: Serialize>(headers: &HeaderMap, value: &T) -> axum::response::Response {
+    let (content_type, body) = match media_type(headers) {
+        Some(t @ "application/msgpack") => {
+            (t, rmp_serde::to_vec_named(value).map_err(|e| e.to_string()))
+        }
+        Some(t @ "application/cbor") => {
+            let mut body = Vec::new();
+            let written = ciborium::into_writer(value, &mut body).map_err(|e| e.to_string());
+            (t, written.map(|()| body))
+        }
+        _ => return Json(value).into_response(),
+    };
+    match body {
+        Ok(body) => ([(header::CONTENT_TYPE, content_type)], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+/// Readings polled at `polled`, as `negotiate` encodes them, tagged with
+/// that time, or 304 Not Modified if the client already has them, so one
+/// polling faster than the meter is only sent each poll's readings once.
+fn negotiate_polled<T: Serialize>(
+    headers: &HeaderMap,
+    polled: Option<DateTime<Utc>>,
+    value: &T,
+) -> axum::response::Response {
+    let Some(polled) = polled else {
+        return negotiate(headers, value);
+    };
+    // Each encoding is a different representation, with a tag of its own.
+    let encoding = media_type(headers).map_or("json", |t| t.trim_start_matches("application/"));
+    let etag = format!("\"{:x}-{encoding}\"", polled.timestamp_micros());
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    // A tag given takes precedence over a time, which is only to the second.
+    let unchanged = match header(header::IF_NONE_MATCH) {
+        Some(tags) => tags
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag),
+        None => header(header::IF_MODIFIED_SINCE)
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+            .is_some_and(|since| polled.timestamp() <= since.timestamp()),
+    };
+    let mut response = if unchanged {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        negotiate(headers, value)
+    };
+    if response.status().is_success() || unchanged {
+        let modified = polled.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        let headers = response.headers_mut();
+        headers.insert(header::ETAG, etag.parse().unwrap());
+        headers.insert(header::LAST_MODIFIED, modified.parse().unwrap());
+        headers.insert(header::VARY, header::ACCEPT.into());
+    }
+    response
+}
+
+/// The encoding the client prefers, among JSON and those `negotiate` adds.
+fn media_type(headers: &HeaderMap) -> Option<&'static str> {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    // The first of the client's preferences that is available.
+    accept.split(',').find_map(
+        |media| match media.split(';').next().unwrap_or_default().trim() {
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some("application/msgpack")
+            }
+            "application/cbor" => Some("application/cbor"),
+            "application/json" => Some("application/json"),
+            _ => None,
+        },
+    )
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    from: Option<history::QueryTime>,
+    to: Option<history::QueryTime>,
+    #[serde(default, with = "humantime_serde")]
+    step: Option<std::time::Duration>,
+    #[serde(default)]
+    agg: history::Aggregate,
+}
+
+/// `GET /history`: the first device's recent readings.
+async fn history(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HistoryQuery>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let device = state.devices().next().expect("no devices configured");
+    history_response(&state, &device.name, query, &headers)
+}
+
+/// `GET /history/<device>?from=&to=&step=1m&agg=avg|min|max|p95`
+async fn device_history(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<HistoryQuery>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    history_response(&state, &name, query, &headers)
+}
+
+fn history_response(
+    state: &AppState,
+    device: &str,
+    query: HistoryQuery,
+    headers: &HeaderMap,
+) -> axum::response::Response {
+    let Some(history) = &state.history else {
+        let message = "history is off; start sharkmon with --history";
+        return (StatusCode::NOT_FOUND, message).into_response();
+    };
+    if query.step.is_some_and(|s| s.is_zero()) {
+        return (StatusCode::BAD_REQUEST, "step must be longer than zero").into_response();
+    }
+    if state.devices().all(|d| d.name != device) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let from = query.from.map(|t| t.0);
+    let to = query.to.map(|t| t.0);
+    match history.query(device, from, to, query.step, query.agg) {
+        Some(samples) => negotiate(headers, &samples),
+        None => negotiate(headers, &Vec::<()>::new()),
+    }
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+/// `GET /events?type=transfer`: the events seen so far, oldest first.
+async fn list_events(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let kind = query.kind.as_deref();
+    if let Some(kind) = kind.filter(|k| !events::TYPES.contains(k)) {
+        let message = format!(
+            "unknown event type '{kind}'; the types are {}",
+            events::TYPES.join(", ")
+        );
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    negotiate(&headers, &state.events.list(kind))
+}
+
+/// `GET /quality`: each device's power quality score for the hour so far.
+async fn quality(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let current: std::collections::BTreeMap<_, _> = state.quality.current().into_iter().collect();
+    negotiate(&headers, &current)
+}
+
+/// `GET /quality/:device`: the device's hourly scores, up to a week of them,
+/// oldest first and ending with the hour so far.
+async fn device_quality(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    if state.devices().all(|d| d.name != name) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    negotiate(&headers, &state.quality.hours(&name).unwrap_or_default())
+}
+
+/// `GET /energy`: the energy each device's counters counted today and on
+/// earlier days, with the rollovers and resets they've had.
+async fn energy(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    negotiate(&headers, &state.energy.summaries())
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// Every device if not given
+    device: Option<String>,
+    #[serde(default)]
+    format: export::Format,
+    #[serde(flatten)]
+    history: HistoryQuery,
+}
+
+/// `GET /history/export?device=&from=&to=&format=csv|json|parquet`: the stored
+/// readings as a file to download, streamed as it is encoded.
+async fn export_history(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ExportQuery>,
+) -> axum::response::Response {
+    let Some(history) = &state.history else {
+        let message = "history is off; start sharkmon with --history";
+        return (StatusCode::NOT_FOUND, message).into_response();
+    };
+    let q = &query.history;
+    if q.step.is_some_and(|s| s.is_zero()) {
+        return (StatusCode::BAD_REQUEST, "step must be longer than zero").into_response();
+    }
+    let devices = match &query.device {
+        Some(device) if state.devices().all(|d| &d.name != device) => {
+            return StatusCode::NOT_FOUND.into_response()
+        }
+        Some(device) => vec![device.clone()],
+        None => history.devices(),
+    };
+    let (from, to) = (q.from.map(|t| t.0), q.to.map(|t| t.0));
+    let samples = devices
+        .into_iter()
+        .filter_map(|d| Some((d.clone(), history.query(&d, from, to, q.step, q.agg)?)))
+        .collect();
+    let export = export::Export::new(samples).in_zone(state.zone.clone());
+
+    let format = query.format;
+    let (mut sender, body) = axum::body::Body::channel();
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let sent = export.write(format, |chunk| {
+            runtime.block_on(sender.send_data(chunk.into())).is_ok()
+        });
+        if let Err(e) = sent {
+            tracing::error!(error = %e, "could not export history");
+            sender.abort();
+        }
+    });
+    let filename = format!("sharkmon-history.{}", format.extension());
+    (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{filename}\""),
+            ),
+        ],
+        axum::body::boxed(body),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct StreamQuery {
+    /// Every device if not given
+    device: Option<String>,
+}
+
+/// `GET /stream.ndjson?device=`: a response that stays open, with a JSON
+/// object for each sample as it is polled.
+async fn stream_ndjson(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StreamQuery>,
+) -> axum::response::Response {
+    if let Some(device) = &query.device {
+        if state.devices().all(|d| &d.name != device) {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    }
+    let mut readings = state.bus.subscribe();
+    let (mut sender, body) = axum::body::Body::channel();
+    tokio::spawn(async move {
+        loop {
+            let sample = match readings.recv().await {
+                Ok(reading) => reading.sample(),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            };
+            if query.device.as_ref().is_some_and(|d| *d != sample.device) {
+                continue;
+            }
+            let mut line = output::json_line(&sample, true);
+            line.push('\n');
+            if sender.send_data(line.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        axum::body::boxed(body),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct WebSocketQuery {
+    /// Every device if not given
+    device: Option<String>,
+    /// Send only the readings that changed
+    #[serde(default)]
+    delta: bool,
+    /// With `delta`, how far a reading must move to be sent, as a percentage
+    /// of the value last sent; any change by default. Implies `delta`
+    deadband: Option<f32>,
+}
+
+/// `GET /ws?device=&delta=&deadband=`: a WebSocket carrying a JSON message
+/// for each sample as it is polled, or with `delta`, for each that changed.
+async fn websocket(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<WebSocketQuery>,
+    upgrade: WebSocketUpgrade,
+) -> axum::response::Response {
+    if let Some(device) = &query.device {
+        if state.devices().all(|d| &d.name != device) {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    }
+    if query.deadband.is_some_and(|d| d.is_nan() || d < 0.0) {
+        let message = "deadband should be a percentage of at least 0";
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    let delta = (query.delta || query.deadband.is_some())
+        .then(|| Delta::new(query.deadband.unwrap_or_default()));
+    let readings = state.bus.subscribe();
+    let stats = state.websockets.clone();
+    upgrade.on_upgrade(move |socket| send_samples(socket, readings, query.device, delta, stats))
+}
+
+/// The readings each device last sent to a client of `/ws?delta=true`,
+/// whose messages carry only the readings that have moved by more than the
+/// deadband since, which for a steady load is few of them. A device's first
+/// message is in full, with its labels; later ones have just the time and
+/// device besides the readings.
+struct Delta {
+    /// As a fraction of the value last sent
+    deadband: f32,
+    sent: HashMap<String, HashMap<String, f32>>,
+}
+
+impl Delta {
+    fn new(percent: f32) -> Delta {
+        Delta {
+            deadband: percent / 100.0,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// The message for the sample, unless none of its readings changed.
+    fn message(&mut self, sample: &sink::Sample) -> Option<String> {
+        let first = !self.sent.contains_key(&sample.device);
+        let sent = self.sent.entry(sample.device.clone()).or_default();
+        let mut message = serde_json::Map::new();
+        for (name, value) in sample.readings.rounded() {
+            let moved = match sent.get(name) {
+                Some(last) if last.is_nan() || value.is_nan() => last.is_nan() != value.is_nan(),
+                Some(last) => (value - last).abs() > last.abs() * self.deadband,
+                None => true,
+            };
+            if moved {
+                sent.insert(name.to_owned(), value);
+                message.insert(name.to_owned(), value.into());
+            }
+        }
+        if message.is_empty() {
+            return None;
+        }
+        let time = sample
+            .time
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let mut header = serde_json::Map::new();
+        header.insert("time".to_owned(), time.into());
+        header.insert("device".to_owned(), sample.device.clone().into());
+        if first && !sample.labels.is_empty() {
+            header.insert("labels".to_owned(), serde_json::json!(&*sample.labels));
+        }
+        header.extend(message);
+        Some(serde_json::Value::Object(header).to_string())
+    }
+}
+
+/// Queue the client's message for the sample, if it has one. False once the
+/// client has fallen too far behind.
+fn queue_sample(queue: &fanout::Queue, delta: &mut Option<Delta>, sample: &sink::Sample) -> bool {
+    let text = match delta {
+        Some(delta) => {
+            // A dropped message may have carried readings that won't be sent
+            // again until they move, so start over with a full one.
+            if queue.take_lost() {
+                delta.sent.clear();
+            }
+            match delta.message(sample) {
+                Some(text) => text,
+                None => return true,
+            }
+        }
+        None => output::json_line(sample, true),
+    };
+    queue.push(text)
+}
+
+/// Send the client each reading, through its own queue so that a slow client
+/// drops its oldest messages rather than holding anything up, and is
+/// disconnected once it falls too far behind.
+async fn send_samples(
+    mut socket: WebSocket,
+    mut readings: tokio::sync::broadcast::Receiver<bus::Reading>,
+    device: Option<String>,
+    mut delta: Option<Delta>,
+    stats: Arc<fanout::Stats>,
+) {
+    let queue = fanout::Queue::new(&stats);
+    let feed = async {
+        loop {
+            let sample = match readings.recv().await {
+                Ok(reading) => reading.sample(),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                    if !queue.missed(n) {
+                        break;
+                    }
+                    continue;
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+            };
+            if device.as_ref().is_some_and(|d| *d != sample.device) {
+                continue;
+            }
+            if !queue_sample(&queue, &mut delta, &sample) {
+                break;
+            }
+        }
+        // The sending side closes the socket.
+        std::future::pending().await
+    };
+    let send = async {
+        loop {
+            tokio::select! {
+                text = queue.pop() => {
+                    let Some(text) = text else {
+                        tracing::warn!("disconnecting a WebSocket client that fell too far behind");
+                        let close = CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "too far behind".into(),
+                        };
+                        let _ = socket.send(Message::Close(Some(close))).await;
+                        return;
+                    };
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        return;
+                    }
+                }
+                // Nothing is expected from the client, but reading notices it closing.
+                message = socket.recv() => match message {
+                    Some(Ok(_)) => {}
+                    _ => return,
+                },
+            }
+        }
+    };
+    tokio::select! {
+        _ = feed => {}
+        _ = send => {}
+    }
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    meters: Vec<meter::MeterStatus>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sinks: Vec<sink::SinkStatus>,
+}
+
+/// `GET /ha/config`: a `rest:` section for Home Assistant's
+/// `configuration.yaml` with a sensor for every reading, pointing back at
+/// this server by the name the client used for it.
+async fn ha_config(State(state): State<Arc<AppState>>, headers: HeaderMap) -> impl IntoResponse {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map_or_else(|| format!("localhost:{PORT}"), str::to_owned);
+    (
+        [(header::CONTENT_TYPE, "text/yaml; charset=utf-8")],
+        homeassistant::config(&format!("http://{host}"), &state.meters),
+    )
+}
+
+/// `GET /ha/sensors/<device>`: every reading of the device as a Home
+/// Assistant sensor.
+async fn ha_device(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> axum::response::Response {
+    match crate::find_device(&state.meters, &name) {
+        Ok((meter, device)) => Json(homeassistant::sensors(meter, device)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// `GET /ha/sensors/<device>/<reading>`: one reading as a Home Assistant
+/// sensor.
+async fn ha_sensor(
+    State(state): State<Arc<AppState>>,
+    Path((name, reading)): Path<(String, String)>,
+) -> axum::response::Response {
+    let (meter, device) = match crate::find_device(&state.meters, &name) {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+    let value = device.readings.lock().unwrap().get(&reading);
+    match value {
+        Some(value) => {
+            Json(homeassistant::Sensor::new(meter, device, &reading, value)).into_response()
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("no reading named '{reading}'"),
+        )
+            .into_response(),
+    }
+}
+
+async fn status(State(state): State<Arc<AppState>>) -> Json<StatusResponse> {
+    Json(StatusResponse {
+        meters: state.meters.iter().map(|m| m.status()).collect(),
+        sinks: state.sinks.status(),
+    })
+}
+
+#[derive(Deserialize)]
+struct InfoQuery {
+    /// Every device with option card slots if not given
+    device: Option<String>,
+}
+
+/// A device's option cards, or why they couldn't be read.
+#[derive(Serialize)]
+struct DeviceInfo {
+    meter: String,
+    device: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    option_cards: Option<Vec<meter::CardInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// `GET /meter/info?device=main`: what the option cards of each device say
+/// about themselves and their communication health, read from the meter.
+async fn meter_info(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<InfoQuery>,
+) -> axum::response::Response {
+    let devices: Vec<(&meter::Meter, &meter::Device)> = match &query.device {
+        Some(name) => match crate::find_device(&state.meters, name) {
+            Ok(found) => vec![found],
+            Err(e) => return error_response(e),
+        },
+        None => state
+            .meters
+            .iter()
+            .filter(|m| !m.option_card_slots().is_empty())
+            .flat_map(|m| m.devices.iter().map(move |d| (m.as_ref(), d)))
+            .collect(),
+    };
+    let mut info = Vec::new();
+    for (meter, device) in devices {
+        let cards = meter.option_cards(device.unit).await;
+        info.push(DeviceInfo {
+            meter: meter.name.clone(),
+            device: device.name.clone(),
+            error: cards.as_ref().err().map(|e| e.to_string()),
+            option_cards: cards.ok(),
+        });
+    }
+    Json(info).into_response()
+}
+
+#[derive(Deserialize)]
+struct ResetQuery {
+    #[serde(default)]
+    confirm: bool,
+}
+
+/// `POST /reset/<device>/<demand|minmax|energy>?confirm=true`, with an API key.
+async fn reset(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path((device, kind)): Path<(String, ResetKind)>,
+    Query(query): Query<ResetQuery>,
+    certificate: Option<Extension<ClientCertificate>>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let action = format!("reset-{kind}");
+    let operator = config::Role::Operator;
+    let client = match state.authorize(&headers, client, certificate.as_deref(), operator, &action)
+    {
+        Ok(client) => client,
+        Err(refusal) => return *refusal,
+    };
+    if !query.confirm {
+        let message = "add ?confirm=true to reset the meter";
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    match crate::reset_device(&state.meters, &device, kind, &client, &state.audit).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+struct WriteRequest {
+    device: String,
+    address: u16,
+    values: Vec<u16>,
+}
+
+/// `POST /api/v1/modbus/write`, with an operator's API key: write registers of a device
+/// within its meter's `writable` ranges.
+async fn modbus_write(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    certificate: Option<Extension<ClientCertificate>>,
+    headers: HeaderMap,
+    request: Result<Json<WriteRequest>, axum::extract::rejection::JsonRejection>,
+) -> axum::response::Response {
+    let operator = config::Role::Operator;
+    let client = match state.authorize(
+        &headers,
+        client,
+        certificate.as_deref(),
+        operator,
+        "modbus-write",
+    ) {
+        Ok(client) => client,
+        Err(refusal) => return *refusal,
+    };
+    // Only look at the body once the client is known to be allowed to write.
+    let Json(request) = match request {
+        Ok(request) => request,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let (meter, device) = match crate::find_device(&state.meters, &request.device) {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+    if let Err(e) = meter.check_writable(request.address, request.values.len()) {
+        return error_response(e);
+    }
+    let result = meter
+        .write(device.unit, request.address, request.values.clone())
+        .await;
+    let details = serde_json::json!({"address": request.address, "values": request.values});
+    state.audit.record(
+        audit::Entry::new(&client, "modbus-write")
+            .meter(&meter.name)
+            .device(&request.device)
+            .details(details)
+            .result(&result),
+    );
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// `POST /meters/<meter>/pause` or `/resume`, with an operator's API key:
+/// stop polling the meter, disconnecting from it, or start again.
+async fn pause(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(name): Path<String>,
+    certificate: Option<Extension<ClientCertificate>>,
+    headers: HeaderMap,
+    paused: bool,
+) -> axum::response::Response {
+    let action = if paused { "pause" } else { "resume" };
+    let operator = config::Role::Operator;
+    let client = match state.authorize(&headers, client, certificate.as_deref(), operator, action) {
+        Ok(client) => client,
+        Err(refusal) => return *refusal,
+    };
+    let Some(meter) = state.meters.iter().find(|m| m.name == name) else {
+        let message = format!("no meter named '{name}'");
+        return (StatusCode::NOT_FOUND, message).into_response();
+    };
+    // Pausing a paused meter changes nothing, so isn't worth recording.
+    if meter.pause(paused) {
+        state
+            .audit
+            .record(audit::Entry::new(&client, action).meter(&meter.name));
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// `GET /audit?since=&actor=&action=&meter=&limit=`, with an admin's API key:
+/// the latest changes made through the API and the command line, oldest
+/// first.
+async fn audit_log(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Query(query): Query<audit::Query>,
+    certificate: Option<Extension<ClientCertificate>>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let admin = config::Role::Admin;
+    if let Err(refusal) = state.authorize(&headers, client, certificate.as_deref(), admin, "audit")
+    {
+        return *refusal;
+    }
+    Json(state.audit.query(&query)).into_response()
+}
+
+/// The settings `/api/v1/config` gives: each meter's poll interval and
+/// smoothing, and each alert's limits.
+#[derive(Serialize)]
+struct RuntimeConfig {
+    meters: BTreeMap<String, config::MeterSettings>,
+    alerts: BTreeMap<String, AlertLimits>,
+    /// Whether changes are saved to a configuration file, and so outlast a
+    /// restart
+    persisted: bool,
+}
+
+#[derive(Serialize)]
+struct AlertLimits {
+    above: Option<f64>,
+    below: Option<f64>,
+}
+
+impl RuntimeConfig {
+    fn new(state: &AppState) -> RuntimeConfig {
+        let meters = state.meters.iter();
+        let rules = state.alerts.as_ref().map(|a| a.rules()).unwrap_or_default();
+        RuntimeConfig {
+            meters: meters.map(|m| (m.name.clone(), m.settings())).collect(),
+            alerts: rules
+                .into_iter()
+                .map(|r| {
+                    let limits = AlertLimits {
+                        above: r.above,
+                        below: r.below,
+                    };
+                    (r.name, limits)
+                })
+                .collect(),
+            persisted: state.config_path.is_some(),
+        }
+    }
+}
+
+/// The body of `PUT /api/v1/config`: the settings to change, leaving out
+/// those that stay as they are. A null limit removes it, and a null poll
+/// interval goes back to the register map's.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigChange {
+    #[serde(default)]
+    meters: BTreeMap<String, MeterChange>,
+    #[serde(default)]
+    alerts: BTreeMap<String, AlertChange>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeterChange {
+    #[serde(default, deserialize_with = "some_duration")]
+    poll_interval: Option<Option<Duration>>,
+    ewma_alpha: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AlertChange {
+    #[serde(default, deserialize_with = "some")]
+    above: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "some")]
+    below: Option<Option<f64>>,
+}
+
+/// A field that is there, even if null, as opposed to one left out.
+fn some<'de, D: serde::Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+fn some_duration<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<Duration>>, D::Error> {
+    humantime_serde::deserialize(deserializer).map(Some)
+}
+
+/// `GET /api/v1/config`, with an admin's API key: the settings that can be
+/// changed while running.
+async fn get_config(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    certificate: Option<Extension<ClientCertificate>>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let admin = config::Role::Admin;
+    if let Err(refusal) = state.authorize(&headers, client, certificate.as_deref(), admin, "config")
+    {
+        return *refusal;
+    }
+    Json(RuntimeConfig::new(&state)).into_response()
+}
+
+/// `PUT /api/v1/config`, with an admin's API key: change meters' poll
+/// intervals and smoothing and alerts' limits, saving them to the
+/// configuration file if sharkmon has one, and saying in `persisted` whether
+/// it did. Nothing changes unless every change is valid and saved.
+async fn put_config(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    certificate: Option<Extension<ClientCertificate>>,
+    headers: HeaderMap,
+    request: Result<Json<serde_json::Value>, axum::extract::rejection::JsonRejection>,
+) -> axum::response::Response {
+    let admin = config::Role::Admin;
+    let client = match state.authorize(
+        &headers,
+        client,
+        certificate.as_deref(),
+        admin,
+        "config-change",
+    ) {
+        Ok(client) => client,
+        Err(refusal) => return *refusal,
+    };
+    let Json(body) = match request {
+        Ok(request) => request,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let change: ConfigChange = match serde_json::from_value(body.clone()) {
+        Ok(change) => change,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response(),
+    };
+
+    let mut meters = BTreeMap::new();
+    for (name, change) in &change.meters {
+        let Some(meter) = state.meters.iter().find(|m| &m.name == name) else {
+            let message = format!("no meter named '{name}'");
+            return (StatusCode::NOT_FOUND, message).into_response();
+        };
+        let mut settings = meter.settings();
+        if let Some(interval) = change.poll_interval {
+            settings.poll_interval = interval;
+        }
+        if let Some(alpha) = change.ewma_alpha {
+            settings.ewma_alpha = alpha;
+        }
+        if let Err(e) = settings.validate() {
+            let message = format!("meter '{name}': {}", e.message);
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+        meters.insert(name.clone(), settings);
+    }
+    let mut rules = state.alerts.as_ref().map(|a| a.rules()).unwrap_or_default();
+    for (name, change) in &change.alerts {
+        let Some(rule) = rules.iter_mut().find(|r| &r.name == name) else {
+            let message = format!("no alert named '{name}'");
+            return (StatusCode::NOT_FOUND, message).into_response();
+        };
+        if let Some(above) = change.above {
+            rule.above = above;
+        }
+        if let Some(below) = change.below {
+            rule.below = below;
+        }
+        if let Err(e) = rule.check_limits() {
+            let message = format!("alert '{name}' {e}");
+            return (StatusCode::BAD_REQUEST, message).into_response();
+        }
+    }
+
+    if let Some(path) = &state.config_path {
+        let changed: Vec<_> = rules
+            .iter()
+            .filter(|r| change.alerts.contains_key(&r.name))
+            .cloned()
+            .collect();
+        let result = config::Config::save_settings(path, &meters, &changed);
+        if let Err(e) = &result {
+            state.audit.record(
+                audit::Entry::new(&client, "config-change")
+                    .details(body)
+                    .result(&result),
+            );
+            let message = format!("could not save {}: {e}", path.display());
+            return (StatusCode::INTERNAL_SERVER_ERROR, message).into_response();
+        }
+    }
+    for meter in &state.meters {
+        if let Some(settings) = meters.remove(&meter.name) {
+            meter.set_settings(settings);
+        }
+    }
+    if let (Some(alerts), false) = (&state.alerts, change.alerts.is_empty()) {
+        alerts.set_rules(rules);
+    }
+    state
+        .audit
+        .record(audit::Entry::new(&client, "config-change").details(body));
+    Json(RuntimeConfig::new(&state)).into_response()
+}
+
+#[derive(Deserialize)]
+struct ReadQuery {
+    device: String,
+    /// Decimal, or hexadecimal with "0x"
+    address: String,
+    #[serde(default = "one")]
+    count: u16,
+    #[serde(default)]
+    function: registers::Function,
+}
+
+fn one() -> u16 {
+    1
+}
+
+/// The most registers one Modbus request reads.
+const MAX_READ: u16 = 125;
+
+#[derive(Serialize)]
+struct ReadResponse {
+    address: u16,
+    values: Vec<u16>,
+    /// Whether the registers came from the poll loop's own recent reads
+    cached: bool,
+}
+
+/// `GET /api/v1/modbus/read?device=main&address=0x0383&count=2`: read a
+/// device's registers on demand, e.g. to check a register map. `function`
+/// is `holding` (the default) or `input`.
+async fn modbus_read(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ReadQuery>,
+) -> axum::response::Response {
+    let address = match registers::parse_address(&query.address) {
+        Ok(address) => address,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    if query.count == 0 || query.count > MAX_READ {
+        let message = format!("count must be from 1 to {MAX_READ}");
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    if address as u32 + query.count as u32 > 0x10000 {
+        let message = "the registers run past the end of the address space";
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    let (meter, device) = match crate::find_device(&state.meters, &query.device) {
+        Ok(found) => found,
+        Err(e) => return error_response(e),
+    };
+    let block = registers::Block {
+        function: query.function,
+        start: address,
+        len: query.count,
+    };
+    match meter.read_registers(device.unit, block).await {
+        Ok((values, cached)) => Json(ReadResponse {
+            address,
+            values,
+            cached,
+        })
+        .into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// The response to a failed meter request.
+fn error_response(e: std::io::Error) -> axum::response::Response {
+    let status = match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        std::io::ErrorKind::InvalidInput | std::io::ErrorKind::Unsupported => {
+            StatusCode::BAD_REQUEST
+        }
+        std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        std::io::ErrorKind::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+        std::io::ErrorKind::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    (status, e.to_string()).into_response()
+}
+
+async fn prometheus_metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], {
+        let mut text = metrics::render(&state.meters, &state.sinks, &state.bus, &state.quality);
+        state.websockets.render(&mut text);
+        text
+    })
+}
+/// Every endpoint, serving `state`.
+pub fn router(state: AppState) -> Router {
+    let max_in_flight = state.max_requests_in_flight;
+    let state = Arc::new(state);
+    let router = Router::new()
+        .route("/", get(dashboard))
+        .route("/assets/dashboard.js", get(dashboard_js))
+        .route("/ws", get(websocket))
+        .route("/power", get(power))
+        .route("/power/total", get(power_total))
+        .route("/power/next", get(power_next))
+        .route("/power/:device", get(device_power))
+        .route("/power.pb", get(power_protobuf))
+        .route("/history", get(history))
+        .route("/history/export", get(export_history))
+        .route("/history/:device", get(device_history))
+        .route("/events", get(list_events))
+        .route("/quality", get(quality))
+        .route("/quality/:device", get(device_quality))
+        .route("/energy", get(energy))
+        .route("/stream.ndjson", get(stream_ndjson))
+        .route("/status", get(status))
+        .route("/meter/info", get(meter_info))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/ha/config", get(ha_config))
+        .route("/ha/sensors/:device", get(ha_device))
+        .route("/ha/sensors/:device/:reading", get(ha_sensor))
+        .route("/reset/:device/:kind", post(reset))
+        .route("/api/v1/modbus/read", get(modbus_read))
+        .route("/api/v1/modbus/write", post(modbus_write))
+        .route(
+            "/meters/:meter/pause",
+            post(|s, c, p, cert, h| pause(s, c, p, cert, h, true)),
+        )
+        .route(
+            "/meters/:meter/resume",
+            post(|s, c, p, cert, h| pause(s, c, p, cert, h, false)),
+        )
+        .route("/api/v1/config", get(get_config).put(put_config))
+        .route("/audit", get(audit_log));
+    // One limit across every route, refusing rather than queueing requests
+    // beyond it, so a flood can't pile up memory either.
+    let router = match max_in_flight {
+        Some(max) => router.layer(
+            tower::ServiceBuilder::new()
+                .layer(axum::error_handling::HandleErrorLayer::new(overloaded))
+                .load_shed()
+                .layer(tower::limit::GlobalConcurrencyLimitLayer::new(max)),
+        ),
+        None => router,
+    };
+    router
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit,
+        ))
+        .layer(tower_http::trace::TraceLayer::new_for_http())
+        .with_state(state)
+}
+
+/// Serve `state` on `addr` until `shutdown` completes, failing with an
+/// `Exit::Bind` error if `addr` can't be listened on.
+pub async fn serve(
+    state: AppState,
+    tls: Option<tls::WebTls>,
+    addr: SocketAddr,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| {
+        let e = std::io::Error::new(e.kind(), format!("couldn't listen on {addr}: {e}"));
+        exit::tag(Exit::Bind, e)
+    })?;
+    let app = router(state);
+    warn!("sharkmon starting on address {addr}");
+    match tls {
+        Some(tls) => serve_tls(app, tls, listener, shutdown).await,
+        None => axum::Server::from_tcp(listener.into_std()?)
+            .map_err(std::io::Error::other)?
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(shutdown)
+            .await
+            .map_err(std::io::Error::other),
+    }
+}
+
+/// Serve HTTPS on `listener` until `shutdown`, telling the handlers about
+/// each client's certificate.
+async fn serve_tls(
+    app: Router,
+    tls: tls::WebTls,
+    listener: tokio::net::TcpListener,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> std::io::Result<()> {
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, client) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(error = %e, "could not accept a connection");
+                    continue;
+                }
+            },
+            () = &mut shutdown => return Ok(()),
+        };
+        let (app, tls) = (app.clone(), tls.clone());
+        tokio::spawn(async move {
+            let handshake =
+                tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.acceptor.accept(stream));
+            let stream = match handshake.await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!(%client, error = %e, "TLS handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    debug!(%client, "TLS handshake timed out");
+                    return;
+                }
+            };
+            let certificate = tls
+                .client(&stream)
+                .map(|(name, role)| ClientCertificate { name, role });
+            let app =
+                tower::ServiceExt::map_request(app, move |mut request: axum::http::Request<_>| {
+                    request.extensions_mut().insert(ConnectInfo(client));
+                    if let Some(certificate) = &certificate {
+                        request.extensions_mut().insert(certificate.clone());
+                    }
+                    request
+                });
+            let served = hyper::server::conn::Http::new()
+                .serve_connection(stream, app)
+                .with_upgrades()
+                .await;
+            if let Err(e) = served {
+                debug!(%client, error = %e, "HTTPS connection failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::FakeMeter;
+    use crate::registers::Function;
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use tower::ServiceExt;
+
+    /// A meter named `name` polled once from `fake`.
+    async fn meter(name: &str, extra: &str, fake: &FakeMeter) -> Arc<meter::Meter> {
+        let text = format!("[[meter]]\nname = \"{name}\"\naddress = \"fake:502\"\n{extra}");
+        let config: config::Config = toml::from_str(&text).unwrap();
+        let meter = meter::Meter::new(&config.meters[0])
+            .unwrap()
+            .fake(fake.clone());
+        meter.poll_once().await.unwrap();
+        Arc::new(meter)
+    }
+
+    fn state(meters: Vec<Arc<meter::Meter>>, api_keys: &[&str]) -> AppState {
+        AppState {
+            meters,
+            sinks: Default::default(),
+            history: None,
+            bus: Default::default(),
+            websockets: Default::default(),
+            events: Arc::new(events::Events::new(&Default::default())),
+            quality: Arc::new(quality::Quality::new(&Default::default())),
+            baselines: Default::default(),
+            energy: Arc::new(energy::Energy::new(Default::default())),
+            zone: Default::default(),
+            api_keys: api_keys.iter().map(|key| key.parse().unwrap()).collect(),
+            max_requests_in_flight: None,
+            rate_limit: None,
+            jsonp: false,
+            audit: Default::default(),
+            alerts: None,
+            config_path: None,
+        }
+    }
+
+    /// Send `request` as though from localhost, returning the status and body.
+    async fn send(state: AppState, mut request: Request<Body>) -> (StatusCode, Vec<u8>) {
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+        request.extensions_mut().insert(ConnectInfo(client));
+        let response = router(state).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn post(uri: &str, key: Option<&str>, body: &str) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(key) = key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {key}"));
+        }
+        request.body(Body::from(body.to_owned())).unwrap()
+    }
+
+    fn json(body: &[u8]) -> serde_json::Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_the_readings() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let (status, body) = send(state(meters.clone(), &[]), get("/power")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body)["watts"], 1500.0);
+        assert_eq!(json(&body)["volts"], 240.0);
+
+        let (status, body) = send(state(meters.clone(), &[]), get("/power/main")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body)["frequency"], 60.0);
+        let (status, _) = send(state(meters, &[]), get("/power/garage")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn totals_subtract_devices_marked_so() {
+        let main = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let solar = FakeMeter::shark100(500.0, 240.0, 60.0);
+        let meters = vec![
+            meter("main", "", &main).await,
+            meter("solar", "unit = [{ id = 1, total = \"subtract\" }]", &solar).await,
+        ];
+        let (status, body) = send(state(meters, &[]), get("/power/total")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body)["watts"], 1000.0);
+    }
+
+    #[tokio::test]
+    async fn serves_home_assistant_sensors() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let (status, body) = send(state(meters.clone(), &[]), get("/ha/sensors/main/watts")).await;
+        assert_eq!(status, StatusCode::OK);
+        let sensor = json(&body);
+        assert_eq!(sensor["state"], 1500.0);
+        assert_eq!(sensor["unit_of_measurement"], "W");
+        assert_eq!(sensor["device_class"], "power");
+        assert_eq!(sensor["state_class"], "measurement");
+        let (status, body) = send(state(meters.clone(), &[]), get("/ha/sensors/main")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body)["volts"]["device_class"], "voltage");
+        let (status, _) = send(state(meters.clone(), &[]), get("/ha/sensors/main/amps")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let request = Request::get("/ha/config")
+            .header(header::HOST, "pi.local:8081")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(state(meters, &[]), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let yaml = String::from_utf8(body).unwrap();
+        assert!(
+            yaml.contains("http://pi.local:8081/ha/sensors/main"),
+            "{yaml}"
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_events_by_type() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let (status, body) = send(state(meters.clone(), &[]), get("/events?type=transfer")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body), serde_json::json!([]));
+        let (status, body) = send(state(meters, &[]), get("/events?type=eclipse")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(String::from_utf8(body).unwrap().contains("transfer"));
+    }
+
+    #[tokio::test]
+    async fn reads_option_cards_for_meter_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let map = dir.path().join("map.toml");
+        std::fs::write(
+            &map,
+            "[[metric]]\nname = \"watts\"\naddress = 0x0383\n\
+             [[option_card]]\nslot = 1\nid = 0x7530\nstatus = 0x7531\n\
+             types = [{ code = 0x0105, name = \"INP100 Ethernet\" }]\n\
+             faults = [\"link_down\", \"rx_errors\"]\n\
+             counters = [{ name = \"frames\", address = 0x7540, type = \"uint32\" }]\n\
+             [[option_card]]\nslot = 2\nid = 0x7918\n",
+        )
+        .unwrap();
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        fake.set(1, Function::Holding, 0x7530, &[0x0105, 0b10]);
+        fake.set(1, Function::Holding, 0x7540, &[1, 2]);
+        let extra = format!("register_map = {:?}\n", map.display().to_string());
+        let meters = vec![meter("main", &extra, &fake).await];
+
+        let (status, body) = send(state(meters.clone(), &[]), get("/meter/info")).await;
+        assert_eq!(status, StatusCode::OK);
+        let info = json(&body);
+        assert_eq!(info[0]["device"], "main");
+        let cards = &info[0]["option_cards"];
+        assert_eq!(cards[0]["type"], "INP100 Ethernet");
+        assert_eq!(cards[0]["faults"], serde_json::json!(["rx_errors"]));
+        assert_eq!(cards[0]["degraded"], true);
+        assert_eq!(cards[0]["counters"]["frames"], 65538.0);
+        assert_eq!(cards[1]["installed"], false);
+        assert!(cards[1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("illegal data address"));
+
+        // Later requests are answered from what the cards said, rather than
+        // reading them again.
+        fake.set(1, Function::Holding, 0x7530, &[0x0105, 0]);
+        let (_, body) = send(state(meters, &[]), get("/meter/info")).await;
+        assert_eq!(json(&body)[0]["option_cards"][0]["degraded"], true);
+    }
+
+    #[tokio::test]
+    async fn serves_quality_scores() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let state = state(meters.clone(), &[]);
+        state.quality.record(&meters[0].devices[0]);
+        let quality = state.quality.clone();
+        let (status, body) = send(state, get("/quality")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body)["main"]["score"], 100.0);
+        let state = AppState {
+            quality,
+            ..self::state(meters.clone(), &[])
+        };
+        let (_, body) = send(state, get("/quality/main")).await;
+        assert_eq!(json(&body)[0]["samples"], 1);
+        let (status, _) = send(self::state(meters, &[]), get("/quality/solar")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn negotiates_msgpack() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let request = Request::get("/power")
+            .header(header::ACCEPT, "text/html, application/msgpack;q=0.9")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(state(meters, &[]), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let readings: std::collections::BTreeMap<String, f32> =
+            rmp_serde::from_slice(&body).unwrap();
+        assert_eq!(readings["watts"], 1500.0);
+    }
+
+    #[tokio::test]
+    async fn changing_meter_state_needs_an_api_key() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let reset = "/reset/main/energy?confirm=true";
+        let (status, _) = send(state(meters.clone(), &[]), post(reset, None, "")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = send(state(meters, &["secret"]), post(reset, Some("guess"), "")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(fake.get(1, Function::Holding, 0x4e20), None);
+    }
+
+    #[tokio::test]
+    async fn writes_registers_with_an_api_key() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "writable = [\"0x1000-0x1001\"]", &fake).await];
+        let write = |address| {
+            let body = format!(r#"{{"device": "main", "address": {address}, "values": [7, 8]}}"#);
+            post("/api/v1/modbus/write", Some("secret"), &body)
+        };
+        let (status, _) = send(state(meters.clone(), &["secret"]), write(0x1000)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(fake.get(1, Function::Holding, 0x1001), Some(8));
+        let (status, _) = send(state(meters, &["secret"]), write(0x2000)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn reads_registers_on_demand() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        fake.set(1, Function::Holding, 0x2000, &[1, 2, 3]);
+        fake.set(1, Function::Holding, 0x3000, &[4]);
+        let meters = vec![meter("main", "", &fake).await];
+        let read = |query: &str| get(&format!("/api/v1/modbus/read?device=main&{query}"));
+        // The poll loop has just read the watts.
+        let reads = fake.reads();
+        let (status, body) = send(state(meters.clone(), &[]), read("address=0x0383&count=2")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body)["cached"], true);
+        assert_eq!(fake.reads(), reads);
+
+        let (_, body) = send(state(meters.clone(), &[]), read("address=8192&count=3")).await;
+        let body = json(&body);
+        assert_eq!(
+            (body["values"].clone(), body["cached"].clone()),
+            (serde_json::json!([1, 2, 3]), false.into())
+        );
+        // That read is kept for the next.
+        let (_, body) = send(state(meters.clone(), &[]), read("address=0x2001")).await;
+        assert_eq!(json(&body)["values"], serde_json::json!([2]));
+        assert_eq!(json(&body)["cached"], true);
+        let (status, _) = send(state(meters.clone(), &[]), read("address=0x3000")).await;
+        assert_eq!(status, StatusCode::OK);
+        // Only two a second go to the meter.
+        let (status, _) = send(state(meters.clone(), &[]), read("address=0x3001")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = send(state(meters, &[]), read("address=0x2000&count=200")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn limits_each_clients_request_rate() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let limit = Arc::new(RateLimit::new(2.0));
+        let limited = || AppState {
+            rate_limit: Some(limit.clone()),
+            max_requests_in_flight: Some(4),
+            ..state(meters.clone(), &[])
+        };
+        assert_eq!(send(limited(), get("/power")).await.0, StatusCode::OK);
+        assert_eq!(send(limited(), get("/status")).await.0, StatusCode::OK);
+        let (status, _) = send(limited(), get("/power")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // Other clients have allowances of their own.
+        assert!(limit.take("10.0.0.2".parse().unwrap()).is_ok());
+        let wait = limit.take("127.0.0.1".parse().unwrap()).unwrap_err();
+        assert!(wait <= Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn heartbeats_leave_readings_unmodified() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let extra = "poll_interval = \"1h\"\nheartbeat = \"10ms\"\n";
+        let main = meter("main", extra, &fake).await;
+        let output = meter::Output {
+            bus: Default::default(),
+            history: None,
+            events: None,
+            meters: Arc::from([main.clone()]),
+        };
+        let task = tokio::spawn(meter::supervise(main.clone(), output));
+        while main.status.lock().unwrap().polls == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let meters = vec![main.clone()];
+        let request = |etag: Option<&str>| {
+            let mut request = Request::get("/power/main");
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            router(state(meters.clone(), &[])).oneshot(request.body(Body::empty()).unwrap())
+        };
+        let response = request(None).await.unwrap();
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+
+        // Many heartbeats, but no poll group read.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = request(Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(main.status.lock().unwrap().polls, 1);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn answers_unchanged_readings_with_not_modified() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let polled = |at| meters[0].status.lock().unwrap().last_poll = Some(at);
+        polled(Utc::now() - chrono::TimeDelta::seconds(1));
+        let request = |header: Option<(header::HeaderName, &str)>| {
+            let mut request = Request::get("/power/main");
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            let request = request.body(Body::empty()).unwrap();
+            router(state(meters.clone(), &[])).oneshot(request)
+        };
+        let response = request(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let modified = response.headers()[header::LAST_MODIFIED].to_str().unwrap();
+        let modified = modified.to_owned();
+
+        let response = request(Some((header::IF_NONE_MATCH, &etag))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        let response = request(Some((header::IF_MODIFIED_SINCE, &modified)));
+        assert_eq!(response.await.unwrap().status(), StatusCode::NOT_MODIFIED);
+        let response = request(Some((header::ACCEPT, "application/cbor")))
+            .await
+            .unwrap();
+        assert_ne!(response.headers()[header::ETAG], etag.as_str());
+
+        // The next poll's readings are new.
+        polled(Utc::now());
+        let response = request(Some((header::IF_NONE_MATCH, &etag))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn sends_only_readings_that_moved_beyond_the_deadband() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let main = meter("main", "", &fake).await;
+        let mut sample = sink::Sample::new(&main.devices[0]);
+        let mut delta = Delta::new(1.0);
+        let first = json(delta.message(&sample).unwrap().as_bytes());
+        assert_eq!(
+            (first["watts"].clone(), first["volts"].clone()),
+            (1500.0.into(), 240.0.into())
+        );
+        assert_eq!(delta.message(&sample), None);
+
+        let watts = |watts| [("watts".to_owned(), watts)].into_iter().collect();
+        sample.readings.restore(&watts(1510.0));
+        assert_eq!(delta.message(&sample), None);
+        // Measured from the value last sent, not the last polled.
+        sample.readings.restore(&watts(1520.0));
+        let message = json(delta.message(&sample).unwrap().as_bytes());
+        assert_eq!(message["device"], "main");
+        assert_eq!(message["watts"], 1520.0);
+        assert!(message.get("volts").is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_a_full_message_after_dropping_changes() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let main = meter("main", "", &fake).await;
+        let mut sample = sink::Sample::new(&main.devices[0]);
+        let mut delta = Some(Delta::new(1.0));
+        let stats = fanout::Stats::default();
+        let queue = fanout::Queue::new(&stats);
+        // Only watts moves, so only the first message, which is dropped to
+        // make room, has the volts.
+        for i in 0..fanout::QUEUE_LEN + 2 {
+            let watts = 1500.0 + 100.0 * i as f32;
+            sample
+                .readings
+                .restore(&[("watts".to_owned(), watts)].into());
+            assert!(queue_sample(&queue, &mut delta, &sample));
+        }
+        let mut last = None;
+        for _ in 0..fanout::QUEUE_LEN {
+            last = queue.pop().await;
+        }
+        let last = json(last.unwrap().as_bytes());
+        assert_eq!(last["volts"], 240.0);
+        assert_eq!(
+            last["watts"],
+            1500.0 + 100.0 * (fanout::QUEUE_LEN + 1) as f32
+        );
+    }
+
+    #[tokio::test]
+    async fn long_polls_for_the_next_reading() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let bus = bus::Bus::default();
+        let waiting = AppState {
+            bus: bus.clone(),
+            ..state(meters.clone(), &[])
+        };
+        let next = tokio::spawn(send(waiting, get("/power/next?timeout=10s")));
+        while bus.receivers() == 0 {
+            tokio::task::yield_now().await;
+        }
+        bus.publish(&meters[0].devices[0]);
+        let (status, body) = next.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json(&body)["watts"], 1500.0);
+
+        let (status, _) = send(state(meters.clone(), &[]), get("/power/next?timeout=10ms")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(state(meters, &[]), get("/power/next?timeout=1h")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn wraps_readings_in_a_callback_only_when_allowed() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let allowed = || AppState {
+            jsonp: true,
+            ..state(meters.clone(), &[])
+        };
+        let (status, body) = send(allowed(), get("/power/main?callback=kiosk.show")).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with("/**/kiosk.show({") && body.ends_with("});"));
+        assert!(body.contains(r#""watts":1500.0"#));
+
+        let (status, _) = send(allowed(), get("/power?callback=alert(1)//")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(state(meters, &[]), get("/power?callback=show")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn only_operators_change_meter_state() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "writable = [\"0x1000\"]", &fake).await];
+        let keys = ["viewer=look", "touch", "admin=all"];
+        let write = |key, role| {
+            let body = r#"{"device": "main", "address": 4096, "values": [7]}"#;
+            let mut request = post("/api/v1/modbus/write", key, body);
+            if let Some(role) = role {
+                let name = "ops-laptop".to_owned();
+                request
+                    .extensions_mut()
+                    .insert(ClientCertificate { name, role });
+            }
+            request
+        };
+        let viewer = Some(config::Role::Viewer);
+        let (status, _) = send(state(meters.clone(), &keys), write(None, viewer)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(state(meters.clone(), &keys), write(Some("look"), None)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(fake.get(1, Function::Holding, 0x1000), None);
+
+        for key in ["touch", "all"] {
+            let (status, _) = send(state(meters.clone(), &keys), write(Some(key), None)).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+        }
+        // A certificate's role needs no key.
+        let operator = Some(config::Role::Operator);
+        let (status, _) = send(state(meters, &[]), write(None, operator)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(fake.get(1, Function::Holding, 0x1000), Some(7));
+    }
+
+    #[tokio::test]
+    async fn audits_changes_for_admins() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "writable = [\"0x1000\"]", &fake).await];
+        let keys = ["touch", "admin=all"];
+        let audit: Arc<audit::Audit> = Default::default();
+        let state = || AppState {
+            audit: audit.clone(),
+            ..state(meters.clone(), &keys)
+        };
+        let body = r#"{"device": "main", "address": 4096, "values": [7]}"#;
+        let write = post("/api/v1/modbus/write", Some("touch"), body);
+        assert_eq!(send(state(), write).await.0, StatusCode::NO_CONTENT);
+        let pause = post("/meters/main/pause", Some("touch"), "");
+        assert_eq!(send(state(), pause).await.0, StatusCode::NO_CONTENT);
+        assert!(meters[0].status().status.paused);
+        let pause = post("/meters/nowhere/pause", Some("touch"), "");
+        assert_eq!(send(state(), pause).await.0, StatusCode::NOT_FOUND);
+
+        // Only an admin may read it.
+        let mut request = get("/audit?action=modbus-write");
+        let (status, _) = send(state(), request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        request = get("/audit?action=modbus-write");
+        let bearer = header::HeaderValue::from_static("Bearer all");
+        request.headers_mut().insert(header::AUTHORIZATION, bearer);
+        let (status, body) = send(state(), request).await;
+        assert_eq!(status, StatusCode::OK);
+        let entries = json(&body);
+        assert_eq!(entries.as_array().unwrap().len(), 1);
+        assert_eq!(entries[0]["actor"], "127.0.0.1");
+        assert_eq!(entries[0]["meter"], "main");
+        assert_eq!(entries[0]["details"]["values"], serde_json::json!([7]));
+        let actions: Vec<_> = audit
+            .query(&Default::default())
+            .into_iter()
+            .map(|e| e.action)
+            .collect();
+        assert_eq!(actions, ["modbus-write", "pause"]);
+    }
+
+    #[tokio::test]
+    async fn changes_settings_and_saves_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sharkmon.toml");
+        let text = "# Settings\n\
+            [[meter]]\nname = \"main\"\naddress = \"fake:502\"\n\
+            [[alert]]\nname = \"overload\"\nreading = \"watts\"\nabove = 5000.0\n";
+        std::fs::write(&path, text).unwrap();
+        let config: config::Config = toml::from_str(text).unwrap();
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let main = meter::Meter::new(&config.meters[0])
+            .unwrap()
+            .fake(fake.clone());
+        let meters = vec![Arc::new(main)];
+        let alerts = Arc::new(alert::Alerts::new(&config.alerts, None));
+        let state = || AppState {
+            alerts: Some(alerts.clone()),
+            config_path: Some(path.clone()),
+            ..state(meters.clone(), &["admin=all"])
+        };
+        let put = |body: &str| {
+            let mut request = post("/api/v1/config", Some("all"), body);
+            *request.method_mut() = Method::PUT;
+            request
+        };
+
+        let (status, body) = send(state(), get("/api/v1/config")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{body:?}");
+
+        // Nothing changes unless everything can.
+        let body = r#"{"meters": {"main": {"poll_interval": "5s"}},
+            "alerts": {"overload": {"below": 6000}}}"#;
+        assert_eq!(send(state(), put(body)).await.0, StatusCode::BAD_REQUEST);
+        let body = r#"{"meters": {"main": {"ewma_alpha": 0}}}"#;
+        assert_eq!(send(state(), put(body)).await.0, StatusCode::BAD_REQUEST);
+        let body = r#"{"alerts": {"nothing": {"above": 1}}}"#;
+        assert_eq!(send(state(), put(body)).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(meters[0].settings().poll_interval, None);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+
+        let body = r#"{"meters": {"main": {"poll_interval": "5s", "ewma_alpha": 0.5}},
+            "alerts": {"overload": {"above": null, "below": 100}}}"#;
+        let (status, body) = send(state(), put(body)).await;
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+        let settings = json(&body);
+        assert_eq!(settings["meters"]["main"]["poll_interval"], "5s");
+        assert_eq!(settings["meters"]["main"]["ewma_alpha"], 0.5);
+        assert_eq!(
+            settings["alerts"]["overload"]["above"],
+            serde_json::Value::Null
+        );
+        assert_eq!(settings["alerts"]["overload"]["below"], 100.0);
+        assert_eq!(settings["persisted"], true);
+        assert_eq!(
+            meters[0].settings().poll_interval,
+            Some(Duration::from_secs(5))
+        );
+
+        // The file now starts with them.
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let saved: config::Config = toml::from_str(&saved).unwrap();
+        assert_eq!(saved.meters[0].poll_interval, Some(Duration::from_secs(5)));
+        assert_eq!(saved.meters[0].ewma_alpha, 0.5);
+        assert_eq!(saved.alerts[0].above, None);
+        assert_eq!(saved.alerts[0].below, Some(100.0));
+    }
+
+    #[tokio::test]
+    async fn says_when_changed_settings_wont_outlast_a_restart() {
+        let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+        let meters = vec![meter("main", "", &fake).await];
+        let mut request = post(
+            "/api/v1/config",
+            Some("all"),
+            r#"{"meters": {"main": {"poll_interval": "5s"}}}"#,
+        );
+        *request.method_mut() = Method::PUT;
+        let (status, body) = send(state(meters.clone(), &["admin=all"]), request).await;
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+        let settings = json(&body);
+        assert_eq!(settings["meters"]["main"]["poll_interval"], "5s");
+        assert_eq!(settings["persisted"], false);
+        assert_eq!(
+            meters[0].settings().poll_interval,
+            Some(Duration::from_secs(5))
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! The whole gateway, run as a separate process against a meter served over
+//! Modbus/TCP from this one, checked through its web server: what it serves,
+//! how it reconnects, and when its alerts fire; and the configuration it
+//! runs with, from its file, environment and flags.
+
+#![cfg(feature = "web")]
+
+use sharkmon::client::{FakeMeter, MeterClient};
+use sharkmon::registers::Function;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// How long anything the tests wait for may take.
+const PATIENCE: Duration = Duration::from_secs(15);
+
+/// Serve `fake` over Modbus/TCP until the test ends. While it is failing,
+/// connections are closed as soon as they are made, as if the meter had
+/// gone away.
+async fn serve_meter(fake: FakeMeter) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let Ok(meter) = fake.connect() else {
+                continue;
+            };
+            tokio::spawn(serve_connection(stream, meter));
+        }
+    });
+    addr
+}
+
+/// Answer reads and writes of holding and input registers until either end
+/// hangs up or the meter fails.
+async fn serve_connection(mut stream: tokio::net::TcpStream, mut meter: FakeMeter) {
+    loop {
+        let mut header = [0; 7];
+        if stream.read_exact(&mut header).await.is_err() {
+            return;
+        }
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut pdu = vec![0; len.saturating_sub(1)];
+        if stream.read_exact(&mut pdu).await.is_err() || pdu.len() < 5 {
+            return;
+        }
+        meter.set_unit(header[6]);
+        let function = pdu[0];
+        let address = u16::from_be_bytes([pdu[1], pdu[2]]);
+        let count = u16::from_be_bytes([pdu[3], pdu[4]]);
+        let result = match function {
+            3 | 4 => {
+                let kind = if function == 3 {
+                    Function::Holding
+                } else {
+                    Function::Input
+                };
+                meter.read(kind, address, count).await.map(|values| {
+                    let mut reply = vec![function, (values.len() * 2) as u8];
+                    reply.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+                    reply
+                })
+            }
+            16 => {
+                let values: Vec<u16> = pdu[6..]
+                    .chunks(2)
+                    .map(|w| u16::from_be_bytes([w[0], w[1]]))
+                    .collect();
+                let written = meter.write(address, &values).await;
+                written.map(|()| pdu[..5].to_vec())
+            }
+            _ => Ok(vec![function | 0x80, 1]),
+        };
+        let reply = match result {
+            Ok(reply) => reply,
+            // Refused registers get "illegal data address", as on a meter.
+            Err(e) if e.kind() == ErrorKind::Other => vec![function | 0x80, 2],
+            Err(_) => return,
+        };
+        let mut frame = header[..4].to_vec();
+        frame.extend(((reply.len() + 1) as u16).to_be_bytes());
+        frame.push(header[6]);
+        frame.extend(reply);
+        if stream.write_all(&frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// A sharkmon process, stopped when dropped.
+struct Gateway {
+    child: Child,
+    web: SocketAddr,
+    log: PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl Gateway {
+    /// Run sharkmon with the configuration file `config`, after a top-level
+    /// `web_listen` on a free port.
+    fn start(config: &str) -> Gateway {
+        let dir = tempfile::tempdir().unwrap();
+        let web = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let path = dir.path().join("sharkmon.toml");
+        let text = format!("web_listen = \"{web}\"\n{config}");
+        std::fs::write(&path, text).unwrap();
+        let log = dir.path().join("sharkmon.log");
+        let child = Command::new(env!("CARGO_BIN_EXE_sharkmon"))
+            .arg("--config")
+            .arg(&path)
+            .args(["--log-format", "json"])
+            .stdout(Stdio::null())
+            .stderr(std::fs::File::create(&log).unwrap())
+            .spawn()
+            .unwrap();
+        let gateway = Gateway {
+            child,
+            web,
+            log,
+            _dir: dir,
+        };
+        gateway.wait_for("the web server", || gateway.try_get("/status").is_some());
+        gateway
+    }
+
+    /// The body of `path` if it was served with 200 OK.
+    fn try_get(&self, path: &str) -> Option<String> {
+        let mut stream = TcpStream::connect(self.web).ok()?;
+        let request = format!("GET {path} HTTP/1.0\r\nHost: {}\r\n\r\n", self.web);
+        stream.write_all(request.as_bytes()).ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        let (head, body) = response.split_once("\r\n\r\n")?;
+        let status = head.split_whitespace().nth(1)?;
+        (status == "200").then(|| body.to_owned())
+    }
+
+    fn get(&self, path: &str) -> String {
+        self.try_get(path)
+            .unwrap_or_else(|| panic!("{path} was not served"))
+    }
+
+    fn json(&self, path: &str) -> serde_json::Value {
+        serde_json::from_str(&self.get(path)).unwrap()
+    }
+
+    /// What has been logged so far.
+    fn log(&self) -> String {
+        std::fs::read_to_string(&self.log).unwrap()
+    }
+
+    /// Wait until `done`, failing the test if it takes too long.
+    fn wait_for(&self, what: &str, mut done: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            if start.elapsed() > PATIENCE {
+                panic!(
+                    "gave up waiting for {what}; sharkmon logged:\n{}",
+                    self.log()
+                );
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A `[[meter]]` table for the meter at `addr`, polled quickly and reported
+/// without smoothing, so readings follow the meter within a poll.
+fn meter_config(addr: SocketAddr) -> String {
+    format!(
+        "[[meter]]\nname = \"main\"\naddress = \"{addr}\"\n\
+         poll_interval = \"100ms\"\newma_alpha = 1.0\n"
+    )
+}
+
+/// A metric's value in `/metrics`, given the start of its line.
+fn metric(metrics: &str, line: &str) -> Option<f64> {
+    metrics
+        .lines()
+        .find_map(|l| l.strip_prefix(line)?.trim().parse().ok())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn serves_what_the_meter_reads() {
+    let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+    let addr = serve_meter(fake.clone()).await;
+    let gateway = Gateway::start(&meter_config(addr));
+
+    gateway.wait_for("the first poll", || {
+        gateway.json("/power")["watts"] == 1500.0
+    });
+    let power = gateway.json("/power");
+    assert_eq!(power["volts"], 240.0);
+    assert_eq!(power["frequency"], 60.0);
+    assert_eq!(gateway.json("/power/main")["watts"], 1500.0);
+
+    // Changes at the meter come through.
+    fake.set_float(1, 0x0383, 2500.0);
+    gateway.wait_for("the new reading", || {
+        gateway.json("/power")["watts"] == 2500.0
+    });
+
+    let metrics = gateway.get("/metrics");
+    let watts = "sharkmon_reading{meter=\"main\",device=\"main\",reading=\"watts\",units=\"W\"}";
+    assert_eq!(metric(&metrics, watts), Some(2500.0), "{metrics}");
+    assert_eq!(
+        metric(&metrics, "sharkmon_connected{meter=\"main\"}"),
+        Some(1.0)
+    );
+    assert!(metric(&metrics, "sharkmon_polls_total{meter=\"main\"}").unwrap() >= 2.0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnects_when_the_meter_comes_back() {
+    let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+    let addr = serve_meter(fake.clone()).await;
+    let gateway = Gateway::start(&meter_config(addr));
+    let connected = || gateway.json("/status")["meters"][0]["connected"].as_bool();
+    gateway.wait_for("the first connection", || connected() == Some(true));
+
+    fake.fail(Some(ErrorKind::ConnectionReset));
+    gateway.wait_for("the meter to be missed", || connected() == Some(false));
+    let metrics = gateway.get("/metrics");
+    assert_eq!(
+        metric(&metrics, "sharkmon_connected{meter=\"main\"}"),
+        Some(0.0)
+    );
+    let failures = "sharkmon_connection_failures_total{meter=\"main\"}";
+    assert!(metric(&metrics, failures).unwrap() >= 1.0, "{metrics}");
+
+    fake.fail(None);
+    gateway.wait_for("the meter to be reconnected", || connected() == Some(true));
+    gateway.wait_for("readings again", || {
+        gateway.json("/power")["watts"] == 1500.0
+    });
+    assert!(fake.connects() >= 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn alerts_fire_and_clear() {
+    let fake = FakeMeter::shark100(1500.0, 240.0, 60.0);
+    let addr = serve_meter(fake.clone()).await;
+    let alert = "[[alert]]\nname = \"overload\"\nreading = \"watts\"\nabove = 2000.0\n";
+    let gateway = Gateway::start(&format!("{}{alert}", meter_config(addr)));
+    gateway.wait_for("the first poll", || {
+        gateway.json("/power")["watts"] == 1500.0
+    });
+    assert!(!gateway.log().contains("alert firing"));
+
+    fake.set_float(1, 0x0383, 2500.0);
+    gateway.wait_for("the alert to fire", || {
+        gateway.log().contains("alert firing")
+    });
+    fake.set_float(1, 0x0383, 1500.0);
+    gateway.wait_for("the alert to clear", || {
+        gateway.log().contains("alert cleared")
+    });
+
+    let log = gateway.log();
+    let firing: Vec<_> = log.lines().filter(|l| l.contains("alert firing")).collect();
+    assert_eq!(firing.len(), 1, "{log}");
+    assert!(firing[0].contains("\"alert\":\"overload\""), "{log}");
+    assert!(firing[0].contains("\"limit\":2000.0"), "{log}");
+}
+
+#[test]
+fn merges_the_configuration_from_file_environment_and_flags() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sharkmon.toml");
+    let text = format!(
+        "web_listen = \"127.0.0.1:1\"\ntimezone = \"UTC\"\n{}",
+        meter_config("127.0.0.1:502".parse().unwrap())
+    );
+    std::fs::write(&path, text).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_sharkmon"))
+        .args(["config", "print", "--timezone", "Europe/Paris"])
+        .env("SHARKMON_CONFIG", &path)
+        .env("SHARKMON_WEB_LISTEN", "127.0.0.1:18080")
+        .env("SHARKMON_TIMEZONE", "America/New_York")
+        .env("SHARKMON_API_KEY", "viewer=hunter2")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let text = String::from_utf8(output.stdout).unwrap();
+    let config: toml::Table = toml::from_str(&text).unwrap();
+    assert_eq!(config["web_listen"].as_str(), Some("127.0.0.1:18080"));
+    // Flags override the environment, which overrides the file.
+    assert_eq!(config["timezone"].as_str(), Some("Europe/Paris"));
+    assert!(!text.contains("hunter2"), "{text}");
+    assert_eq!(config["meter"][0]["name"].as_str(), Some("main"));
+}